@@ -0,0 +1,618 @@
+use crate::crypto::{self, RsaPublicKey};
+use crate::session::{ActiveSession, SessionStore, COOKIE_NAME};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, Ready};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Roles in increasing order of privilege; a role grants everything the
+/// roles below it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Role, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Editor => write!(f, "editor"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The scope a caller needs to read contacts' emails, phones and
+/// addresses; without it they only get names.
+pub const PII_SCOPE: &str = "contacts:pii";
+
+/// The scopes signing in with a password grants, however the caller signs
+/// in: people see their own address books whole.
+pub fn login_scopes() -> Vec<String> {
+    vec![PII_SCOPE.to_owned()]
+}
+
+/// The authenticated caller, taken from a verified token or API key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrentUser {
+    pub id: String,
+    pub role: Role,
+    pub scopes: Vec<String>,
+    pub tenant: Option<String>,
+}
+
+impl CurrentUser {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Malformed,
+    UnsupportedAlgorithm(String),
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    NotConfigured,
+    UnknownApiKey,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Malformed => write!(f, "malformed token"),
+            AuthError::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {}", alg),
+            AuthError::InvalidSignature => write!(f, "invalid token signature"),
+            AuthError::Expired => write!(f, "token expired"),
+            AuthError::NotYetValid => write!(f, "token not yet valid"),
+            AuthError::NotConfigured => write!(f, "token authentication is not configured"),
+            AuthError::UnknownApiKey => write!(f, "unknown api key"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Key used to verify tokens; the algorithm is pinned by the key type so a
+/// token can't pick a weaker one.
+#[derive(Clone)]
+pub enum JwtKey {
+    Hs256(Vec<u8>),
+    Rs256(RsaPublicKey),
+}
+
+impl JwtKey {
+    /// Reads `JWT_HS256_SECRET` or, failing that, the PEM file named by
+    /// `JWT_RS256_PUBLIC_KEY`.
+    pub fn from_env() -> Result<Option<JwtKey>, String> {
+        if let Ok(secret) = std::env::var("JWT_HS256_SECRET") {
+            return Ok(Some(JwtKey::Hs256(secret.into_bytes())));
+        }
+        if let Ok(path) = std::env::var("JWT_RS256_PUBLIC_KEY") {
+            let pem = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            return RsaPublicKey::from_pem(&pem).map(|key| Some(JwtKey::Rs256(key)));
+        }
+        Ok(None)
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            JwtKey::Hs256(_) => "HS256",
+            JwtKey::Rs256(_) => "RS256",
+        }
+    }
+
+    fn sign(&self, signing_input: &[u8]) -> Result<Vec<u8>, AuthError> {
+        match self {
+            JwtKey::Hs256(secret) => Ok(crypto::hmac_sha256(secret, signing_input).to_vec()),
+            JwtKey::Rs256(_) => Err(AuthError::NotConfigured),
+        }
+    }
+
+    fn verify(&self, signing_input: &[u8], signature: &[u8]) -> bool {
+        match self {
+            JwtKey::Hs256(secret) => {
+                crypto::constant_time_eq(&crypto::hmac_sha256(secret, signing_input), signature)
+            }
+            JwtKey::Rs256(key) => key.verify_sha256(signing_input, signature),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(default)]
+    nbf: Option<u64>,
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, AuthError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::Malformed)
+}
+
+/// Verifies a compact JWS and returns the caller it was issued to.
+pub fn decode(token: &str, key: &JwtKey) -> Result<CurrentUser, AuthError> {
+    let mut parts = token.split('.');
+    let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(h), Some(p), Some(s)) if parts.next().is_none() => (h, p, s),
+        _ => return Err(AuthError::Malformed),
+    };
+
+    let jwt_header: JwtHeader =
+        serde_json::from_slice(&decode_part(header)?).map_err(|_| AuthError::Malformed)?;
+    if jwt_header.alg != key.algorithm() {
+        return Err(AuthError::UnsupportedAlgorithm(jwt_header.alg));
+    }
+    let signing_input = &token[..header.len() + 1 + payload.len()];
+    if !key.verify(signing_input.as_bytes(), &decode_part(signature)?) {
+        return Err(AuthError::InvalidSignature);
+    }
+
+    let claims: Claims =
+        serde_json::from_slice(&decode_part(payload)?).map_err(|_| AuthError::Malformed)?;
+    if let Some(exp) = claims.exp {
+        if exp <= now() {
+            return Err(AuthError::Expired);
+        }
+    }
+    if let Some(nbf) = claims.nbf {
+        if nbf > now() {
+            return Err(AuthError::NotYetValid);
+        }
+    }
+
+    let role = match claims.role {
+        Some(role) => role.parse().map_err(|_| AuthError::Malformed)?,
+        None => Role::Viewer,
+    };
+    let mut scopes = claims.scopes;
+    if let Some(scope) = claims.scope {
+        scopes.extend(scope.split_whitespace().map(|s| s.to_owned()));
+    }
+    Ok(CurrentUser {
+        id: claims.sub,
+        role,
+        scopes,
+        tenant: claims.tenant,
+    })
+}
+
+/// Lifetime of the access tokens handed out by `encode`; callers renew them
+/// with a refresh token.
+pub const TOKEN_TTL_SECS: u64 = 15 * 60;
+
+/// Lifetime of a refresh token.
+pub const REFRESH_TTL_SECS: u64 = 30 * 24 * 3600;
+
+/// Issues a token for `user`. Only HS256 keys can sign; an RS256
+/// deployment verifies tokens minted elsewhere.
+pub fn encode(user: &CurrentUser, key: &JwtKey) -> Result<String, AuthError> {
+    let header = serde_json::json!({ "alg": key.algorithm(), "typ": "JWT" });
+    let mut claims = serde_json::json!({
+        "sub": user.id,
+        "role": user.role,
+        "scopes": user.scopes,
+        "exp": now() + TOKEN_TTL_SECS,
+    });
+    if let Some(tenant) = &user.tenant {
+        claims["tenant"] = serde_json::json!(tenant);
+    }
+    let signing_input = format!(
+        "{}.{}",
+        encode_part(header.to_string().as_bytes()),
+        encode_part(claims.to_string().as_bytes())
+    );
+    let signature = key.sign(signing_input.as_bytes())?;
+    Ok(format!("{}.{}", signing_input, encode_part(&signature)))
+}
+
+fn encode_part(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+const PASSWORD_ITERATIONS: u32 = 100_000;
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+
+/// Hashes a password as `$pbkdf2-sha256$<iterations>$<salt>$<hash>` with a
+/// fresh random salt.
+///
+/// PBKDF2-HMAC-SHA256 stands in for argon2 here: there is no argon2
+/// implementation among the dependencies, and `crypto` only builds on
+/// SHA-256. The hash names its scheme and iterations, so hashes made with
+/// weaker parameters, or by another scheme once there is one, are told by
+/// `needs_rehash` and replaced the next time their user signs in.
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let hash = crypto::pbkdf2_sha256(password.as_bytes(), &salt, PASSWORD_ITERATIONS);
+    format!(
+        "${}${}${}${}",
+        PASSWORD_SCHEME,
+        PASSWORD_ITERATIONS,
+        base64::encode_config(salt, base64::STANDARD_NO_PAD),
+        base64::encode_config(hash, base64::STANDARD_NO_PAD)
+    )
+}
+
+/// Checks a password against a hash produced by `hash_password`.
+pub fn verify_password(password: &str, encoded: &str) -> bool {
+    let mut parts = encoded.split('$');
+    let (iterations, salt, hash) = match (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some(""), Some(PASSWORD_SCHEME), Some(i), Some(s), Some(h)) => (i, s, h),
+        _ => return false,
+    };
+    let decoded = (
+        iterations.parse::<u32>(),
+        base64::decode_config(salt, base64::STANDARD_NO_PAD),
+        base64::decode_config(hash, base64::STANDARD_NO_PAD),
+    );
+    match decoded {
+        (Ok(iterations), Ok(salt), Ok(hash)) if iterations > 0 => crypto::constant_time_eq(
+            &crypto::pbkdf2_sha256(password.as_bytes(), &salt, iterations),
+            &hash,
+        ),
+        _ => false,
+    }
+}
+
+/// Whether a hash `verify_password` accepted was made by another scheme or
+/// with fewer iterations than `hash_password` uses now.
+pub fn needs_rehash(encoded: &str) -> bool {
+    let mut parts = encoded.split('$').skip(1);
+    match (parts.next(), parts.next().map(str::parse::<u32>)) {
+        (Some(PASSWORD_SCHEME), Some(Ok(iterations))) => iterations < PASSWORD_ITERATIONS,
+        _ => true,
+    }
+}
+
+/// API keys for machine clients, each mapped to a named client identity.
+/// Keys are kept as SHA-256 digests so the plain values aren't held in memory.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    clients: HashMap<[u8; 32], CurrentUser>,
+}
+
+impl ApiKeys {
+    /// Loads keys from the file named by `API_KEYS_FILE` (one entry per line)
+    /// and from `API_KEYS` (comma separated). Entries are
+    /// `name:key[:role[:scope scope...]]`, the role defaulting to viewer.
+    pub fn from_env() -> Result<ApiKeys, String> {
+        let mut keys = ApiKeys::default();
+        if let Ok(path) = std::env::var("API_KEYS_FILE") {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            for line in contents.lines() {
+                keys.add_entry(line)?;
+            }
+        }
+        if let Ok(entries) = std::env::var("API_KEYS") {
+            for entry in entries.split(',') {
+                keys.add_entry(entry)?;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn add_entry(&mut self, entry: &str) -> Result<(), String> {
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            return Ok(());
+        }
+        let invalid = || format!("invalid api key entry for {:?}", entry.split(':').next());
+        let mut parts = entry.splitn(4, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(key)) if !name.is_empty() && !key.is_empty() => {
+                let role = match parts.next() {
+                    Some(role) => role.parse().map_err(|_| invalid())?,
+                    None => Role::Viewer,
+                };
+                let scopes = parts
+                    .next()
+                    .map(|s| s.split_whitespace().map(|s| s.to_owned()).collect())
+                    .unwrap_or_default();
+                let client = CurrentUser {
+                    id: name.to_owned(),
+                    role,
+                    scopes,
+                    tenant: None,
+                };
+                self.clients.insert(crypto::sha256(key.as_bytes()), client);
+                Ok(())
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<&CurrentUser> {
+        self.clients.get(&crypto::sha256(key.as_bytes()))
+    }
+}
+
+/// Resolves the caller from the `X-Api-Key` header or, failing that, the
+/// `Authorization: Bearer` header. No header means an anonymous caller; a
+/// header that doesn't verify is an error. `Basic` credentials are left to
+/// the CardDAV routes, which check them against the stored users.
+pub fn authenticate(
+    headers: &HeaderMap,
+    key: Option<&JwtKey>,
+    api_keys: &ApiKeys,
+) -> Result<Option<CurrentUser>, AuthError> {
+    if let Some(api_key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return match api_keys.lookup(api_key.trim()) {
+            Some(client) => Ok(Some(client.clone())),
+            None => Err(AuthError::UnknownApiKey),
+        };
+    }
+
+    let value = match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if value.starts_with("Basic ") {
+        return Ok(None);
+    }
+    let token = match value.strip_prefix("Bearer ") {
+        Some(token) => token.trim(),
+        None => return Err(AuthError::Malformed),
+    };
+    match key {
+        Some(key) => decode(token, key).map(Some),
+        None => Err(AuthError::NotConfigured),
+    }
+}
+
+/// Actix middleware validating API keys, bearer tokens and session cookies
+/// and storing the `CurrentUser` in the request extensions for the GraphQL
+/// handler. A stale session cookie leaves the request anonymous.
+pub struct Authentication {
+    key: Rc<Option<JwtKey>>,
+    api_keys: Rc<ApiKeys>,
+    sessions: Rc<Option<SessionStore>>,
+}
+
+impl Authentication {
+    pub fn new(
+        key: Option<JwtKey>,
+        api_keys: ApiKeys,
+        sessions: Option<SessionStore>,
+    ) -> Authentication {
+        Authentication {
+            key: Rc::new(key),
+            api_keys: Rc::new(api_keys),
+            sessions: Rc::new(sessions),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service,
+            key: self.key.clone(),
+            api_keys: self.api_keys.clone(),
+            sessions: self.sessions.clone(),
+        })
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: S,
+    key: Rc<Option<JwtKey>>,
+    api_keys: Rc<ApiKeys>,
+    sessions: Rc<Option<SessionStore>>,
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match authenticate(req.headers(), self.key.as_ref().as_ref(), &self.api_keys) {
+            Ok(Some(user)) => {
+                req.extensions_mut().insert(user);
+                Box::pin(self.service.call(req))
+            }
+            Ok(None) => {
+                let session = match (self.sessions.as_ref(), req.cookie(COOKIE_NAME)) {
+                    (Some(sessions), Some(cookie)) => sessions.load(cookie.value()),
+                    _ => None,
+                };
+                if let Some(session) = session {
+                    req.extensions_mut().insert(session.current_user());
+                    req.extensions_mut().insert(ActiveSession(session.id));
+                }
+                Box::pin(self.service.call(req))
+            }
+            Err(e) => {
+                debug!("rejected credentials: {}", e);
+                let e = actix_web::error::ErrorUnauthorized(e);
+                Box::pin(ok(req.error_response(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    /// A token of `claims` with the header `{"alg": alg}`, signed with
+    /// `SECRET`.
+    fn token(alg: &str, claims: serde_json::Value) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            encode_part(serde_json::json!({ "alg": alg }).to_string().as_bytes()),
+            encode_part(claims.to_string().as_bytes())
+        );
+        let signature = crypto::hmac_sha256(SECRET, signing_input.as_bytes());
+        format!("{}.{}", signing_input, encode_part(&signature))
+    }
+
+    fn key() -> JwtKey {
+        JwtKey::Hs256(SECRET.to_vec())
+    }
+
+    #[test]
+    fn decodes_the_caller_from_its_claims() {
+        let claims = serde_json::json!({
+            "sub": "ada",
+            "role": "editor",
+            "scope": "contacts:read contacts:pii",
+            "tenant": "acme",
+            "exp": now() + 60,
+            "nbf": now() - 60,
+        });
+        let user = decode(&token("HS256", claims), &key()).unwrap();
+        assert_eq!(user.id, "ada");
+        assert_eq!(user.role, Role::Editor);
+        assert!(user.has_scope(PII_SCOPE));
+        assert_eq!(user.tenant.as_deref(), Some("acme"));
+    }
+
+    #[test]
+    fn encoded_tokens_decode_to_the_same_caller() {
+        let user = CurrentUser {
+            id: "ada".to_owned(),
+            role: Role::Admin,
+            scopes: vec![PII_SCOPE.to_owned()],
+            tenant: None,
+        };
+        assert_eq!(
+            decode(&encode(&user, &key()).unwrap(), &key()).unwrap(),
+            user
+        );
+    }
+
+    #[test]
+    fn refuses_expired_and_not_yet_valid_tokens() {
+        let expired = token(
+            "HS256",
+            serde_json::json!({ "sub": "ada", "exp": now() - 1 }),
+        );
+        assert!(matches!(decode(&expired, &key()), Err(AuthError::Expired)));
+        let early = token(
+            "HS256",
+            serde_json::json!({ "sub": "ada", "nbf": now() + 60 }),
+        );
+        assert!(matches!(
+            decode(&early, &key()),
+            Err(AuthError::NotYetValid)
+        ));
+    }
+
+    #[test]
+    fn refuses_algorithms_other_than_the_keys() {
+        for alg in &["none", "RS256", "HS512"] {
+            let token = token(alg, serde_json::json!({ "sub": "ada" }));
+            assert!(matches!(
+                decode(&token, &key()),
+                Err(AuthError::UnsupportedAlgorithm(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn refuses_tampered_tokens() {
+        let token = token(
+            "HS256",
+            serde_json::json!({ "sub": "ada", "role": "viewer" }),
+        );
+        let parts: Vec<&str> = token.split('.').collect();
+        let admin = encode_part(br#"{"sub":"ada","role":"admin"}"#);
+        let forged = format!("{}.{}.{}", parts[0], admin, parts[2]);
+        assert!(matches!(
+            decode(&forged, &key()),
+            Err(AuthError::InvalidSignature)
+        ));
+        let other_key = JwtKey::Hs256(b"other".to_vec());
+        assert!(matches!(
+            decode(&token, &other_key),
+            Err(AuthError::InvalidSignature)
+        ));
+        assert!(matches!(
+            decode(&parts[..2].join("."), &key()),
+            Err(AuthError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+//! Avatar thumbnails. Uploads store the original image and queue an
+//! `AvatarTask`; the thumbnail job makes a thumbnail in every `AvatarSize`
+//! next to it, then points the contact at the new version and deletes the
+//! old one. Blobs live under `avatars/<owner>/<contact>/<version>/`.
+//! `GET /avatars/{contactId}/{size}` serves the thumbnails to callers who
+//! can read the contact.
+
+use crate::auth::{CurrentUser, Role};
+use crate::events::{Storage, StorageMode};
+use crate::graphql::on_blocking_pool;
+use crate::jobs::{partitions, Job};
+use crate::models::*;
+use crate::outbox::OutboxMessage;
+use crate::png;
+use crate::repo::*;
+use crate::tenant;
+use crate::usecases::{avatar_key, set_avatar};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+
+const INTERVAL: Duration = Duration::from_secs(1);
+/// URLs naming the current version never change, so they're cached for a
+/// year; without a version, or with an old one, clients check back.
+const VERSIONED_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+const UNVERSIONED_CACHE_CONTROL: &str = "private, no-cache";
+
+/// The blob key of a contact's avatar thumbnail.
+pub fn thumbnail_key(owner_id: &str, id: &str, version: &str, size: AvatarSize) -> String {
+    format!(
+        "{}/{}.png",
+        avatar_key(owner_id, id, Some(version)),
+        size.name()
+    )
+}
+
+/// Makes the thumbnails of every uploaded avatar.
+pub fn thumbnails(repo: FileRepository<'static>) -> Job {
+    Job::new(
+        "avatar-thumbnails",
+        INTERVAL,
+        Box::new(move || {
+            let repo = repo.clone();
+            Box::pin(async move {
+                let mut made = 0;
+                for repo in partitions(&repo) {
+                    let storage = Storage::new(repo, StorageMode::from_env());
+                    let mut tasks: Vec<AvatarTask> = storage.list("").map_err(|e| e.to_string())?;
+                    tasks.sort_by(|a, b| a.id.cmp(&b.id));
+                    for task in tasks {
+                        match make(&task, &storage) {
+                            Ok(()) => made += 1,
+                            Err(e) => {
+                                warn!("avatar of contact {:?} failed: {}", task.contact_id, e)
+                            }
+                        }
+                        Repository::<AvatarTask>::delete(&storage, &task.id)
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+                Ok(format!("made thumbnails of {} avatars", made))
+            })
+        }),
+    )
+}
+
+/// Makes the task's thumbnails and switches the contact to them. The
+/// uploaded version is dropped when it can't be used.
+fn make<R>(task: &AvatarTask, repo: &R) -> Result<(), Box<dyn Error>>
+where
+    R: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>
+        + Blobs,
+{
+    let (owner_id, id, version) = (&task.owner_id, &task.contact_id, &task.version);
+    let version_key = avatar_key(owner_id, id, Some(version));
+    let previous = match Repository::<Contact>::get(repo, &Contact::key_for(owner_id, id)) {
+        Ok(contact) if contact.avatar.as_ref() == Some(version) => return Ok(()),
+        Ok(contact) => contact.avatar,
+        Err(e) => {
+            repo.delete_blobs(&version_key)?;
+            return Err(e);
+        }
+    };
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let original = repo.get_blob(&format!("{}/original.png", version_key))?;
+        let image = png::decode(&original)?;
+        for size in &AvatarSize::ALL {
+            let thumbnail = png::encode(&png::thumbnail(&image, size.pixels()));
+            repo.put_blob(&thumbnail_key(owner_id, id, version, *size), &thumbnail)?;
+        }
+        set_avatar(&task.actor, owner_id, id, version, repo)?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => match previous {
+            Some(previous) => repo.delete_blobs(&avatar_key(owner_id, id, Some(&previous))),
+            None => Ok(()),
+        },
+        Err(e) => {
+            repo.delete_blobs(&version_key)?;
+            Err(e)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AvatarQuery {
+    owner_id: Option<String>,
+    v: Option<String>,
+}
+
+/// Serves a contact's avatar thumbnail. Like the API, callers see their own
+/// contacts and admins anyone's, within the request's tenant.
+pub async fn serve(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    path: web::Path<(String, String)>,
+    query: web::Query<AvatarQuery>,
+    req: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let user = req
+        .extensions()
+        .get::<CurrentUser>()
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Unauthorized"))?;
+    let tenant =
+        tenant::resolve(req.headers(), Some(&user)).map_err(actix_web::error::ErrorForbidden)?;
+    let (id, size) = path.into_inner();
+    let size = AvatarSize::ALL
+        .iter()
+        .copied()
+        .find(|s| s.name() == size)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("unknown avatar size"))?;
+    let owner_id = match &query.owner_id {
+        Some(owner_id) if owner_id != &user.id && user.role < Role::Admin => {
+            return Err(actix_web::error::ErrorForbidden(
+                "Forbidden, only admins can access other owners' contacts",
+            ))
+        }
+        Some(owner_id) => owner_id.clone(),
+        None => user.id.clone(),
+    };
+    let files = match tenant {
+        Some(tenant) => repo.for_tenant(&tenant.0),
+        None => repo.get_ref().clone(),
+    };
+    let storage = Storage::new(files, *mode.get_ref());
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    // The contact and the thumbnail are files, read off the worker. Without
+    // an image the client's copy is current.
+    let found = on_blocking_pool(move || async move {
+        let contact: Contact = storage.get(&Contact::key_for(&owner_id, &id)).ok()?;
+        let version = contact.avatar?;
+        let etag = format!("\"{}-{}\"", version, size.name());
+        let unchanged = if_none_match
+            .map(|v| v.split(',').any(|tag| tag.trim() == etag))
+            .unwrap_or(false);
+        let image = if unchanged {
+            None
+        } else {
+            let key = thumbnail_key(&owner_id, &id, &version, size);
+            Some(storage.get_blob(&key).ok()?)
+        };
+        Some((version, etag, image))
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let (version, etag, image) =
+        found.ok_or_else(|| actix_web::error::ErrorNotFound("no avatar"))?;
+    let cache_control = if query.v.as_ref() == Some(&version) {
+        VERSIONED_CACHE_CONTROL
+    } else {
+        UNVERSIONED_CACHE_CONTROL
+    };
+    match image {
+        None => Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .finish()),
+        Some(image) => Ok(HttpResponse::Ok()
+            .content_type("image/png")
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .body(image)),
+    }
+}
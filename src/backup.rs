@@ -0,0 +1,654 @@
+//! Backups of the file repository as a single gzip compressed archive of
+//! JSON lines: a header, one line per stored record with the SHA-256 of its
+//! contents, and a trailer with the record count and a digest over all
+//! records. Records are the entity files of every kind, owner partitions and
+//! tenants included; the directory layout they're stored in is the
+//! repository's only index, so restoring the records restores the indexes.
+//! An archive is read and verified in full before anything is restored.
+//! With a backup interval configured the server also backs itself up on a
+//! schedule, keeping the newest archives only.
+
+use crate::crypto;
+use crate::events::{Snapshot, StoredEvent};
+use crate::idempotency::StoredResponse;
+use crate::jobs::Job;
+use crate::metrics;
+use crate::models::*;
+use crate::outbox::OutboxMessage;
+use crate::repo::{Entity, FileRepository};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const FORMAT: &str = "backend-backup";
+const VERSION: u32 = 1;
+
+static LAST_SCHEDULED: Mutex<Option<BackupStatus>> = Mutex::new(None);
+
+/// The kinds of entity a backup holds.
+const KINDS: &[&str] = &[
+    Contact::KIND,
+    Group::KIND,
+    AuditEntry::KIND,
+    User::KIND,
+    Session::KIND,
+    RefreshToken::KIND,
+    RefreshFamily::KIND,
+    Webhook::KIND,
+    WebhookDelivery::KIND,
+    OutboxMessage::KIND,
+    StoredEvent::KIND,
+    Snapshot::KIND,
+    StoredResponse::KIND,
+    AvatarTask::KIND,
+    ContactIndex::KIND,
+];
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    Header {
+        format: String,
+        version: u32,
+        created_at: u64,
+    },
+    Record {
+        /// Relative to the repository directory, e.g. `contacts/u1/c1.json`.
+        path: String,
+        sha256: String,
+        data: String,
+    },
+    Trailer {
+        records: usize,
+        sha256: String,
+    },
+}
+
+/// A record's path and contents.
+type Record = (String, String);
+
+/// The directory the server writes backups to.
+pub struct BackupDir(pub PathBuf);
+
+/// What a backup holds: the number of records and the digest over them,
+/// which is the same for the archive written and the archive restored.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub records: usize,
+    pub sha256: String,
+}
+
+/// The digest over all records, a SHA-256 of their paths and digests.
+#[derive(Default)]
+struct Digest(String);
+
+impl Digest {
+    fn add(&mut self, path: &str, sha256: &str) {
+        self.0.push_str(path);
+        self.0.push(' ');
+        self.0.push_str(sha256);
+        self.0.push('\n');
+    }
+
+    fn finish(self) -> String {
+        crypto::to_hex(&crypto::sha256(self.0.as_bytes()))
+    }
+}
+
+/// Writes a backup of everything `repo` stores. For a repository narrowed
+/// to a tenant that's the tenant's partition, otherwise every tenant's too.
+pub fn write<W: Write>(repo: &FileRepository, out: W) -> Result<Summary, Box<dyn Error>> {
+    let dir = repo.dir();
+    let mut out = GzEncoder::new(out, Compression::default());
+    write_line(
+        &mut out,
+        &Line::Header {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+            created_at: crate::auth::now(),
+        },
+    )?;
+    let mut digest = Digest::default();
+    let mut records = 0;
+    for unit in kind_dirs(&dir, repo.tenant().is_none())? {
+        let mut paths = vec![];
+        walk(&dir, &unit, &mut paths)?;
+        paths.sort();
+        for path in paths {
+            let data = fs::read_to_string(dir.join(&path))?;
+            let sha256 = crypto::to_hex(&crypto::sha256(data.as_bytes()));
+            digest.add(&path, &sha256);
+            write_line(&mut out, &Line::Record { path, sha256, data })?;
+            records += 1;
+        }
+    }
+    let summary = Summary {
+        records,
+        sha256: digest.finish(),
+    };
+    write_line(
+        &mut out,
+        &Line::Trailer {
+            records,
+            sha256: summary.sha256.clone(),
+        },
+    )?;
+    out.finish()?.flush()?;
+    Ok(summary)
+}
+
+fn write_line<W: Write>(out: &mut W, line: &Line) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(&mut *out, line)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Writes a backup into `dir` as `backup-<unix millis>.gz`. The archive only
+/// gets its name once complete, so a failed backup leaves no archive behind.
+pub fn write_to_dir(
+    repo: &FileRepository,
+    dir: &Path,
+) -> Result<(PathBuf, Summary), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let path = dir.join(format!("backup-{}.gz", millis));
+    let partial = dir.join(format!(".backup-{}.gz.partial", millis));
+    let result = fs::File::create(&partial)
+        .map_err(|e| e.into())
+        .and_then(|f| write(repo, BufWriter::new(f)));
+    match result {
+        Ok(summary) => {
+            fs::rename(&partial, &path)?;
+            Ok((path, summary))
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// The archives `write_to_dir` wrote into `dir`, oldest first, with the unix
+/// millis they were written at.
+fn archives(dir: &Path) -> Vec<(u128, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut archives: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let millis = name.strip_prefix("backup-")?.strip_suffix(".gz")?;
+            Some((millis.parse().ok()?, e.path()))
+        })
+        .collect();
+    archives.sort();
+    archives
+}
+
+/// Deletes all but the newest `keep` archives in `dir`.
+fn prune(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let archives = archives(dir);
+    for (_, path) in archives.iter().take(archives.len().saturating_sub(keep)) {
+        fs::remove_file(path)?;
+        debug!("deleted backup {}", path.display());
+    }
+    Ok(())
+}
+
+/// How the last scheduled backup went, `None` before the first.
+pub fn last_scheduled() -> Option<BackupStatus> {
+    LAST_SCHEDULED.lock().unwrap().clone()
+}
+
+/// The scheduled backup job, when `interval` is set. It backs up the whole
+/// repository into `dir` every `interval`, counted from the newest archive
+/// there so restarts don't delay backups, then deletes all but the newest
+/// `keep` archives, manual ones included.
+pub fn job(
+    repo: FileRepository<'static>,
+    dir: PathBuf,
+    interval: Option<Duration>,
+    keep: usize,
+) -> Option<Job> {
+    let interval = interval?;
+    let newest = archives(&dir)
+        .last()
+        .map(|(millis, _)| UNIX_EPOCH + Duration::from_millis(*millis as u64));
+    let elapsed = newest
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .unwrap_or(interval);
+    let job = Job::new(
+        "backup",
+        interval,
+        Box::new(move || {
+            let result = run_scheduled(&repo, &dir, keep);
+            Box::pin(async move { result })
+        }),
+    );
+    Some(job.first_run_after(interval.checked_sub(elapsed).unwrap_or_default()))
+}
+
+fn run_scheduled(repo: &FileRepository, dir: &Path, keep: usize) -> Result<String, String> {
+    let result = write_to_dir(repo, dir);
+    let finished_at = crate::auth::now();
+    let (status, done) = match result {
+        Ok((path, summary)) => {
+            if let Err(e) = prune(dir, keep) {
+                warn!("deleting old backups failed: {}", e);
+            }
+            metrics::REGISTRY.backup(Some(summary.records), finished_at);
+            let done = format!(
+                "backed up {} records to {}",
+                summary.records,
+                path.display()
+            );
+            let status = BackupStatus {
+                finished_at: finished_at as i64,
+                file: Some(path.display().to_string()),
+                records: summary.records as i32,
+                error: None,
+            };
+            (status, Ok(done))
+        }
+        Err(e) => {
+            crate::sentry::capture(format!("scheduled backup failed: {}", e));
+            metrics::REGISTRY.backup(None, finished_at);
+            let status = BackupStatus {
+                finished_at: finished_at as i64,
+                file: None,
+                records: 0,
+                error: Some(e.to_string()),
+            };
+            (status, Err(e.to_string()))
+        }
+    };
+    *LAST_SCHEDULED.lock().unwrap() = Some(status);
+    done
+}
+
+/// Where the backups of `repo` go under the backup directory `base`: tenants
+/// get a directory each.
+pub fn dir_for(base: &Path, repo: &FileRepository) -> PathBuf {
+    match repo.tenant() {
+        Some(tenant) => base.join("tenants").join(tenant),
+        None => base.to_path_buf(),
+    }
+}
+
+/// The kind directories under `dir` that hold records, relative to it.
+fn kind_dirs(dir: &Path, with_tenants: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut units: Vec<String> = KINDS
+        .iter()
+        .filter(|kind| dir.join(kind).is_dir())
+        .map(|kind| kind.to_string())
+        .collect();
+    if !with_tenants {
+        return Ok(units);
+    }
+    let tenants = match fs::read_dir(dir.join("tenants")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(units),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = vec![];
+    for entry in tenants {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    for name in names {
+        for kind in KINDS {
+            let unit = format!("tenants/{}/{}", name, kind);
+            if dir.join(&unit).is_dir() {
+                units.push(unit);
+            }
+        }
+    }
+    Ok(units)
+}
+
+/// The records under `dir/unit`, relative to `dir`.
+fn walk(dir: &Path, unit: &str, paths: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir.join(unit))? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = format!("{}/{}", unit, name);
+        if entry.path().is_dir() {
+            walk(dir, &path, paths)?;
+        } else if name.ends_with(".json") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a record path stays inside a kind directory of the repository
+/// restored to; tenant partitions only take their own records.
+fn valid_path(path: &str, tenant: bool) -> bool {
+    let parts: Vec<&str> = path.split('/').collect();
+    let kind = match parts.as_slice() {
+        ["tenants", _, kind, _, ..] if !tenant => kind,
+        [kind, _, ..] => kind,
+        _ => return false,
+    };
+    KINDS.contains(kind)
+        && path.ends_with(".json")
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && *p != "." && *p != ".." && !p.contains('\\'))
+}
+
+/// Reads and verifies a whole archive.
+fn read<R: Read>(input: R, tenant: bool) -> Result<(Vec<Record>, Summary), Box<dyn Error>> {
+    let mut lines = BufReader::new(GzDecoder::new(input)).lines();
+    let mut next = || -> Result<Option<Line>, Box<dyn Error>> {
+        let line = match lines.next() {
+            Some(line) => line.map_err(|e| format!("not a readable backup archive: {}", e))?,
+            None => return Ok(None),
+        };
+        match serde_json::from_str(&line) {
+            Ok(line) => Ok(Some(line)),
+            Err(e) => Err(format!("not a readable backup archive: {}", e).into()),
+        }
+    };
+    match next()? {
+        Some(Line::Header {
+            format, version, ..
+        }) if format == FORMAT => {
+            if version != VERSION {
+                return Err(format!("unsupported backup version {}", version).into());
+            }
+        }
+        _ => return Err("not a backup archive".into()),
+    }
+    let mut records = vec![];
+    let mut digest = Digest::default();
+    loop {
+        match next()? {
+            Some(Line::Record { path, sha256, data }) => {
+                if !valid_path(&path, tenant) {
+                    return Err(format!(
+                        "the backup has a record outside the repository: {:?}",
+                        path
+                    )
+                    .into());
+                }
+                if crypto::to_hex(&crypto::sha256(data.as_bytes())) != sha256 {
+                    return Err(format!("checksum mismatch for {}", path).into());
+                }
+                digest.add(&path, &sha256);
+                records.push((path, data));
+            }
+            Some(Line::Trailer {
+                records: count,
+                sha256,
+            }) => {
+                if count != records.len() || digest.finish() != sha256 {
+                    return Err("the backup's checksum doesn't match its records".into());
+                }
+                if next()?.is_some() {
+                    return Err("the backup continues past its trailer".into());
+                }
+                let summary = Summary {
+                    records: count,
+                    sha256,
+                };
+                return Ok((records, summary));
+            }
+            Some(Line::Header { .. }) => return Err("the backup has a second header".into()),
+            None => return Err("the backup is truncated".into()),
+        }
+    }
+}
+
+/// Restores a backup into `repo`. Replacing swaps each kind directory for
+/// the backup's, so no kind is ever seen half restored and records the
+/// backup doesn't have are gone; merging writes the backup's records over
+/// the stored ones and keeps the rest.
+pub fn restore<R: Read>(
+    repo: &FileRepository,
+    input: R,
+    merge: bool,
+) -> Result<Summary, Box<dyn Error>> {
+    let (records, summary) = read(input, repo.tenant().is_some())?;
+    let dir = repo.dir();
+    if merge {
+        for (path, data) in &records {
+            write_file(&dir.join(path), data)?;
+        }
+        // Merged contacts are missing from the indexes, which get rebuilt.
+        for unit in kind_dirs(&dir, repo.tenant().is_none())? {
+            if unit.rsplit('/').next() == Some(ContactIndex::KIND) {
+                fs::remove_dir_all(dir.join(unit))?;
+            }
+        }
+        return Ok(summary);
+    }
+    let staging = dir.join(format!(
+        ".restore-{}",
+        crypto::to_hex(&rand::random::<[u8; 8]>())
+    ));
+    let staged = (|| -> Result<(), Box<dyn Error>> {
+        for (path, data) in &records {
+            write_file(&staging.join("new").join(path), data)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = staged {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    let with_tenants = repo.tenant().is_none();
+    let mut units: BTreeSet<String> = kind_dirs(&dir, with_tenants)?.into_iter().collect();
+    units.extend(kind_dirs(&staging.join("new"), with_tenants)?);
+    for unit in &units {
+        swap(&dir, &staging, unit).map_err(|e| {
+            format!(
+                "restoring {} failed, the replaced records are kept in {}: {}",
+                unit,
+                staging.join("old").display(),
+                e
+            )
+        })?;
+    }
+    fs::remove_dir_all(&staging)?;
+    Ok(summary)
+}
+
+/// Moves the kind directory `unit` out of the way and the staged one in.
+fn swap(dir: &Path, staging: &Path, unit: &str) -> std::io::Result<()> {
+    let current = dir.join(unit);
+    if current.exists() {
+        let old = staging.join("old").join(unit);
+        fs::create_dir_all(old.parent().unwrap())?;
+        fs::rename(&current, &old)?;
+    }
+    let new = staging.join("new").join(unit);
+    if new.exists() {
+        fs::create_dir_all(current.parent().unwrap())?;
+        fs::rename(&new, &current)?;
+    }
+    Ok(())
+}
+
+/// Writes a record under a temporary name and renames it into place, so
+/// readers never see it half written.
+fn write_file(path: &Path, data: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, data)?;
+    fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Repository;
+    use crate::testing::ContactFixture;
+
+    fn scratch() -> (tempfile::TempDir, FileRepository<'static>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        (dir, FileRepository::new(Box::leak(path.into_boxed_str())))
+    }
+
+    fn group(id: &str, member_ids: &[&str]) -> Group {
+        Group {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            member_ids: member_ids.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    /// `repo`'s contacts as JSON, to compare them whole.
+    fn contacts(repo: &FileRepository) -> Vec<serde_json::Value> {
+        let mut all = vec![];
+        for owner in &["u1", "u2"] {
+            let listed: Vec<Contact> = repo.list(owner).unwrap();
+            all.extend(listed.iter().map(|c| serde_json::to_value(c).unwrap()));
+        }
+        all.sort_by_key(|c| c.to_string());
+        all
+    }
+
+    /// An archive of `repo` with Ada and Alan in it, in a group, with Ada
+    /// also stored for the tenant t1.
+    fn backed_up(repo: &FileRepository) -> (Vec<u8>, Summary) {
+        repo.set(ContactFixture::new("ada").email("ada@example.com").build())
+            .unwrap();
+        repo.set(
+            ContactFixture::new("alan")
+                .owner("u2")
+                .name("Alan", "Turing")
+                .build(),
+        )
+        .unwrap();
+        repo.set(group("friends", &["u1/ada", "u2/alan"])).unwrap();
+        repo.for_tenant("t1")
+            .set(ContactFixture::new("ada").build())
+            .unwrap();
+        let mut archive = vec![];
+        let summary = write(repo, &mut archive).unwrap();
+        (archive, summary)
+    }
+
+    #[test]
+    fn restoring_replaces_the_records_with_the_backups() {
+        let (_dir, repo) = scratch();
+        let (archive, written) = backed_up(&repo);
+        let before = contacts(&repo);
+        assert_eq!(written.records, 4);
+
+        repo.set(ContactFixture::new("ada").name("Augusta", "King").build())
+            .unwrap();
+        repo.set(ContactFixture::new("grace").build()).unwrap();
+        Repository::<Group>::delete(&repo, "friends").unwrap();
+        Repository::<Contact>::delete(&repo.for_tenant("t1"), "u1/ada").unwrap();
+
+        let restored = restore(&repo, archive.as_slice(), false).unwrap();
+        assert_eq!(restored.records, written.records);
+        assert_eq!(restored.sha256, written.sha256);
+        assert_eq!(contacts(&repo), before);
+        let friends: Group = repo.get("friends").unwrap();
+        assert_eq!(friends.member_ids, vec!["u1/ada", "u2/alan"]);
+        let _: Contact = repo.for_tenant("t1").get("u1/ada").unwrap();
+        assert!(!repo.dir().read_dir().unwrap().any(|e| e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".restore")));
+
+        let mut again = vec![];
+        assert_eq!(write(&repo, &mut again).unwrap().sha256, written.sha256);
+    }
+
+    #[test]
+    fn merging_writes_the_backups_records_over_the_rest() {
+        let (_dir, repo) = scratch();
+        let (archive, _) = backed_up(&repo);
+        repo.set(ContactFixture::new("ada").name("Augusta", "King").build())
+            .unwrap();
+        repo.set(ContactFixture::new("grace").name("Grace", "Hopper").build())
+            .unwrap();
+        fs::create_dir_all(repo.dir().join(ContactIndex::KIND)).unwrap();
+
+        restore(&repo, archive.as_slice(), true).unwrap();
+        let ada: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(ada.first_name, "Ada");
+        assert_eq!(ada.emails, vec!["ada@example.com"]);
+        let grace: Contact = repo.get("u1/grace").unwrap();
+        assert_eq!(grace.last_name, "Hopper");
+        let _: Contact = repo.get("u2/alan").unwrap();
+        assert!(!repo.dir().join(ContactIndex::KIND).exists());
+    }
+
+    #[test]
+    fn a_tenant_restores_its_own_partition_only() {
+        let (_dir, repo) = scratch();
+        let (archive, _) = backed_up(&repo);
+        let tenant = repo.for_tenant("t1");
+        let mut partition = vec![];
+        assert_eq!(write(&tenant, &mut partition).unwrap().records, 1);
+        Repository::<Contact>::delete(&tenant, "u1/ada").unwrap();
+
+        restore(&tenant, partition.as_slice(), false).unwrap();
+        let _: Contact = tenant.get("u1/ada").unwrap();
+        let error = restore(&tenant, archive.as_slice(), false).unwrap_err();
+        assert!(error.to_string().contains("outside the repository"));
+    }
+
+    #[test]
+    fn damaged_archives_restore_nothing() {
+        let (_dir, repo) = scratch();
+        let (archive, _) = backed_up(&repo);
+        let mut lines: Vec<String> = BufReader::new(GzDecoder::new(archive.as_slice()))
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        let archive_of = |lines: &[String]| {
+            let mut out = GzEncoder::new(vec![], Compression::default());
+            for line in lines {
+                writeln!(out, "{}", line).unwrap();
+            }
+            out.finish().unwrap()
+        };
+        repo.set(ContactFixture::new("grace").build()).unwrap();
+
+        let truncated = archive_of(&lines[..lines.len() - 1]);
+        let error = restore(&repo, truncated.as_slice(), false).unwrap_err();
+        assert_eq!(error.to_string(), "the backup is truncated");
+
+        lines[1] = lines[1].replace("Ada", "Eve");
+        let tampered = archive_of(&lines);
+        let error = restore(&repo, tampered.as_slice(), false).unwrap_err();
+        assert!(error.to_string().starts_with("checksum mismatch for "));
+
+        let error = restore(&repo, &b"not gzip"[..], true).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("not a readable backup archive"));
+        let _: Contact = repo.get("u1/grace").unwrap();
+    }
+}
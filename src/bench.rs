@@ -0,0 +1,153 @@
+//! `bench` times the repository calls and GraphQL operations requests are
+//! made of, against a scratch repository in the temp directory so the
+//! configured one is left untouched. The repository calls are timed again
+//! in memory, which leaves what the files cost. GraphQL operations run
+//! in-process over the memory backend, through the schema and its
+//! extensions but without HTTP or files, so they time the schema alone.
+
+use crate::auth::{CurrentUser, Role, PII_SCOPE};
+use crate::crypto;
+use crate::events::StorageMode;
+use crate::graphql::{self, ContactsSchema};
+use crate::models::*;
+use crate::repo::*;
+use async_graphql::QueryBuilder;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_ITERATIONS: usize = 1000;
+const OWNER: &str = "bench";
+
+/// The latencies of each benchmark, by name.
+type Samples = Vec<(&'static str, Vec<Duration>)>;
+
+/// Runs every benchmark `iterations` times, listing a tenth as often as
+/// each list reads every contact, and prints the latencies.
+pub async fn run(iterations: usize) -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!(
+        "backend-bench-{}",
+        crypto::to_hex(&rand::random::<[u8; 8]>())
+    ));
+    let path: &'static str = Box::leak(dir.to_string_lossy().into_owned().into_boxed_str());
+    let result = benchmarks(FileRepository::new(path), iterations).await;
+    let _ = std::fs::remove_dir_all(&dir);
+    let results = result?;
+    println!(
+        "{:<20} {:>10} {:>10} {:>10} {:>10}",
+        "operation", "runs", "mean µs", "p50 µs", "p99 µs"
+    );
+    for (name, mut samples) in results {
+        samples.sort();
+        let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+        println!(
+            "{:<20} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            samples.len(),
+            mean.as_micros(),
+            percentile(&samples, 50).as_micros(),
+            percentile(&samples, 99).as_micros()
+        );
+    }
+    Ok(())
+}
+
+async fn benchmarks(
+    repo: FileRepository<'static>,
+    iterations: usize,
+) -> Result<Samples, Box<dyn Error>> {
+    let ids: Vec<String> = (0..iterations).map(|i| format!("b{}", i)).collect();
+    let mut results = calls(
+        &repo,
+        &ids,
+        ["repository.set", "repository.get", "repository.list"],
+    )?;
+    let memory = MemoryRepository::new();
+    results.extend(calls(
+        &memory,
+        &ids,
+        ["memory.set", "memory.get", "memory.list"],
+    )?);
+
+    let schema =
+        graphql::schema_builder(StorageBackend::Memory(memory), StorageMode::from_env()).finish();
+    let mut samples = vec![];
+    for id in &ids {
+        let query = format!("{{ contact(id: \"{}\") {{ firstName lastName }} }}", id);
+        samples.push(execute(&schema, query).await?);
+    }
+    results.push(("graphql.get", samples));
+    let mut samples = vec![];
+    for id in &ids {
+        let query = format!(
+            "mutation {{ createContact(contact: {{ id: \"{}\", firstName: \"Grace\", lastName: \"Hopper\" }}) {{ firstName }} }}",
+            id
+        );
+        samples.push(execute(&schema, query).await?);
+    }
+    results.push(("graphql.create", samples));
+    Ok(results)
+}
+
+/// Times setting, getting and listing contacts, named by `names`.
+fn calls<R: Repository<Contact>>(
+    repo: &R,
+    ids: &[String],
+    names: [&'static str; 3],
+) -> Result<Samples, Box<dyn Error>> {
+    let sets = time(ids, |id| repo.set(contact(id)).map(drop))?;
+    let gets = time(ids, |id| repo.get(&format!("{}/{}", OWNER, id)).map(drop))?;
+    let lists = &ids[..(ids.len() / 10).max(1)];
+    let lists = time(lists, |_| repo.list(OWNER).map(drop))?;
+    Ok(vec![(names[0], sets), (names[1], gets), (names[2], lists)])
+}
+
+fn time<F: FnMut(&str) -> Result<(), Box<dyn Error>>>(
+    ids: &[String],
+    mut run: F,
+) -> Result<Vec<Duration>, Box<dyn Error>> {
+    ids.iter()
+        .map(|id| {
+            let start = Instant::now();
+            run(id)?;
+            Ok(start.elapsed())
+        })
+        .collect()
+}
+
+/// Times one operation, run as an admin owning the benchmark's contacts.
+async fn execute(schema: &ContactsSchema, query: String) -> Result<Duration, Box<dyn Error>> {
+    let start = Instant::now();
+    let response = QueryBuilder::new(query)
+        .data(CurrentUser {
+            id: OWNER.to_owned(),
+            role: Role::Admin,
+            scopes: vec![PII_SCOPE.to_owned()],
+            tenant: None,
+        })
+        .execute(schema)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let elapsed = start.elapsed();
+    if response.data.is_null() {
+        return Err("the operation returned no data".into());
+    }
+    Ok(elapsed)
+}
+
+fn contact(id: &str) -> Contact {
+    Contact {
+        id: id.to_owned(),
+        owner_id: OWNER.to_owned(),
+        first_name: "Ada".to_owned(),
+        last_name: "Lovelace".to_owned(),
+        emails: vec![format!("{}@example.com", id)],
+        phones: vec!["+44 20 7946 0000".to_owned()],
+        addresses: vec![],
+        avatar: None,
+    }
+}
+
+/// The `p`th percentile of sorted samples.
+fn percentile(samples: &[Duration], p: usize) -> Duration {
+    samples[(samples.len() - 1) * p / 100]
+}
@@ -0,0 +1,562 @@
+use crate::auth::Role;
+use crate::nats::{self, Nats};
+use crate::shutdown::Shutdown;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Subject instances tell each other about invalidations on.
+const FANOUT_SUBJECT: &str = "cache.invalidate";
+const FANOUT_POLL: Duration = Duration::from_millis(100);
+const FANOUT_RETRY: Duration = Duration::from_secs(1);
+
+/// Identifies a cacheable response: the operation, its variables, the
+/// tenant, id, role and scopes of the caller, so private data is never
+/// shared across users or tenants, nor unmasked with callers who may not
+/// see it, and the locale its messages are in. `scopes` are sorted, so the
+/// order a token lists them in doesn't matter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub query: String,
+    pub operation_name: Option<String>,
+    pub variables: String,
+    pub tenant: Option<String>,
+    pub user: Option<String>,
+    pub role: Option<Role>,
+    pub scopes: Vec<String>,
+    pub locale: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub body: String,
+    pub cache_control: String,
+}
+
+struct Entry {
+    response: CachedResponse,
+    entity_types: HashSet<&'static str>,
+    expires_at: Instant,
+}
+
+/// In-process response cache, entries live for the `max_age` of the
+/// aggregated cache hints and are dropped as soon as a mutation touches
+/// one of the entity types they were built from. With a fan-out, the
+/// mutations of other instances behind the same load balancer drop them
+/// too.
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<HashMap<CacheKey, Entry>>>,
+    fanout: Option<Sender<Invalidation>>,
+}
+
+/// An invalidation told to the other instances; no entity type clears
+/// every entry.
+#[derive(Serialize, Deserialize)]
+struct Invalidation {
+    instance: String,
+    entity_type: Option<String>,
+}
+
+impl ResponseCache {
+    pub fn from_env() -> Option<ResponseCache> {
+        match std::env::var("RESPONSE_CACHE") {
+            Ok(v) if v == "1" || v == "true" => Some(ResponseCache::default()),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.response.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        response: CachedResponse,
+        max_age: usize,
+        entity_types: HashSet<&'static str>,
+    ) {
+        let entry = Entry {
+            response,
+            entity_types,
+            expires_at: Instant::now() + Duration::from_secs(max_age as u64),
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+
+    pub fn invalidate(&self, entity_type: &str) {
+        self.drop_entries(Some(entity_type));
+        self.tell_peers(Some(entity_type));
+    }
+
+    /// Drops every entry, e.g. after the repository was restored.
+    pub fn clear(&self) {
+        self.drop_entries(None);
+        self.tell_peers(None);
+    }
+
+    fn drop_entries(&self, entity_type: Option<&str>) {
+        let mut entries = self.entries.lock().unwrap();
+        match entity_type {
+            Some(entity_type) => {
+                entries.retain(|_, entry| !entry.entity_types.contains(entity_type));
+                debug!("cache invalidated for {}", entity_type);
+            }
+            None => {
+                entries.clear();
+                debug!("cache cleared");
+            }
+        }
+    }
+
+    fn tell_peers(&self, entity_type: Option<&str>) {
+        if let Some(fanout) = &self.fanout {
+            let _ = fanout.send(Invalidation {
+                instance: String::new(),
+                entity_type: entity_type.map(str::to_owned),
+            });
+        }
+    }
+
+    /// Starts sharing invalidations with the other instances over NATS when
+    /// `NATS_URL` is set. Has to be called before the cache is cloned, as
+    /// only this cache and its later clones tell the others.
+    pub fn spawn_fanout(&mut self, shutdown: Shutdown) -> Option<thread::JoinHandle<()>> {
+        let address = nats::address()?;
+        let (sender, receiver) = mpsc::channel();
+        let local = self.clone();
+        self.fanout = Some(sender);
+        let instance = crate::crypto::to_hex(&rand::random::<[u8; 8]>());
+        Some(thread::spawn(move || {
+            while !shutdown.is_triggered() {
+                if let Err(e) = fan_out(&address, &instance, &local, &receiver, &shutdown) {
+                    warn!("cache fan-out failed: {}", e);
+                    // Entries may have missed invalidations while cut off.
+                    local.drop_entries(None);
+                    shutdown.sleep(FANOUT_RETRY);
+                }
+            }
+        }))
+    }
+}
+
+/// Relays this instance's invalidations to the others and applies theirs,
+/// until the connection fails or shutdown.
+fn fan_out(
+    address: &str,
+    instance: &str,
+    cache: &ResponseCache,
+    outgoing: &Receiver<Invalidation>,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nats = Nats::connect(address, FANOUT_POLL)?;
+    nats.subscribe(FANOUT_SUBJECT)?;
+    while !shutdown.is_triggered() {
+        while let Ok(mut invalidation) = outgoing.try_recv() {
+            invalidation.instance = instance.to_owned();
+            nats.publish(FANOUT_SUBJECT, &serde_json::to_vec(&invalidation)?)?;
+        }
+        while let Some(payload) = nats.next_message()? {
+            match serde_json::from_slice::<Invalidation>(&payload) {
+                Ok(i) if i.instance == instance => {}
+                Ok(i) => cache.drop_entries(i.entity_type.as_deref()),
+                Err(e) => warn!("unreadable cache invalidation: {}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Entity types read while resolving a single operation.
+#[derive(Clone, Default)]
+pub struct CacheTags(Arc<Mutex<HashSet<&'static str>>>);
+
+impl CacheTags {
+    pub fn record(&self, entity_type: &'static str) {
+        self.0.lock().unwrap().insert(entity_type);
+    }
+
+    pub fn take(&self) -> HashSet<&'static str> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::CurrentUser;
+    use crate::backup::{self, BackupDir};
+    use crate::events::StorageMode;
+    use crate::graphql::{self, ContactsSchema};
+    use crate::idempotency::IdempotencyStore;
+    use crate::jobs::Jobs;
+    use crate::models::Group;
+    use crate::png::{self, Image};
+    use crate::repo::{FileRepository, Repository, StorageBackend};
+    use crate::settings::{Config, Limits, LiveLimits};
+    use crate::testing::{caller, ContactFixture};
+    use crate::{carddav, rest};
+    use actix_service::Service;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpMessage};
+    use async_graphql::{EmptyMutation, EmptySubscription, QueryBuilder, Schema};
+    use serde_json::{json, Value};
+
+    const CONTACTS: &str = "{ contacts { edges { node { id firstName } } } }";
+    const GROUP: &str = "{ group(id: \"friends\") { id } }";
+
+    fn key(query: &str) -> CacheKey {
+        CacheKey {
+            query: query.to_owned(),
+            operation_name: None,
+            variables: String::new(),
+            tenant: None,
+            user: Some("u1".to_owned()),
+            role: Some(Role::Viewer),
+            scopes: vec![],
+            locale: "en",
+        }
+    }
+
+    fn response(body: &str) -> CachedResponse {
+        CachedResponse {
+            body: body.to_owned(),
+            cache_control: "max-age=60".to_owned(),
+        }
+    }
+
+    fn tags(entity_types: &[&'static str]) -> HashSet<&'static str> {
+        entity_types.iter().copied().collect()
+    }
+
+    /// The entity types of each cached entry, by query.
+    fn cached(cache: &ResponseCache) -> Vec<(String, Vec<&'static str>)> {
+        let entries = cache.entries.lock().unwrap();
+        let mut cached: Vec<_> = entries
+            .iter()
+            .map(|(key, entry)| {
+                let mut entity_types: Vec<_> = entry.entity_types.iter().copied().collect();
+                entity_types.sort();
+                (key.query.clone(), entity_types)
+            })
+            .collect();
+        cached.sort();
+        cached
+    }
+
+    #[test]
+    fn entries_expire_after_their_max_age() {
+        let cache = ResponseCache::default();
+        cache.insert(key("short"), response("1"), 1, tags(&["Contact"]));
+        cache.insert(key("long"), response("2"), 60, tags(&["Contact"]));
+        cache.insert(key("uncacheable"), response("3"), 0, tags(&["Contact"]));
+        assert_eq!(cache.get(&key("short")).unwrap().body, "1");
+        assert!(cache.get(&key("uncacheable")).is_none());
+
+        thread::sleep(Duration::from_millis(1100));
+        assert!(cache.get(&key("short")).is_none());
+        assert_eq!(cache.get(&key("long")).unwrap().body, "2");
+        // Expired entries are dropped as they're found.
+        assert_eq!(cached(&cache).len(), 1);
+    }
+
+    #[test]
+    fn invalidations_drop_the_entries_built_from_the_entity_type() {
+        let cache = ResponseCache::default();
+        cache.insert(key("contacts"), response("1"), 60, tags(&["Contact"]));
+        cache.insert(key("groups"), response("2"), 60, tags(&["Group"]));
+        cache.insert(key("both"), response("3"), 60, tags(&["Contact", "Group"]));
+        cache.insert(key("neither"), response("4"), 60, tags(&[]));
+
+        cache.invalidate("Contact");
+        assert!(cache.get(&key("contacts")).is_none());
+        assert!(cache.get(&key("both")).is_none());
+        assert!(cache.get(&key("groups")).is_some());
+        cache.invalidate("Group");
+        assert!(cache.get(&key("groups")).is_none());
+        assert!(cache.get(&key("neither")).is_some());
+        cache.clear();
+        assert!(cached(&cache).is_empty());
+    }
+
+    #[derive(Default)]
+    struct Hinted;
+
+    #[async_graphql::Object]
+    impl Hinted {
+        #[field(cache_control(max_age = 60))]
+        async fn slow(&self) -> i32 {
+            1
+        }
+
+        #[field(cache_control(max_age = 5))]
+        async fn fast(&self) -> i32 {
+            2
+        }
+
+        async fn unhinted(&self) -> i32 {
+            3
+        }
+    }
+
+    #[tokio::test]
+    async fn the_lowest_max_age_hint_wins() {
+        let schema = Schema::new(Hinted, EmptyMutation, EmptySubscription);
+        let max_age = |query: &'static str| {
+            let schema = schema.clone();
+            async move {
+                let control = QueryBuilder::new(query)
+                    .execute(&schema)
+                    .await
+                    .unwrap()
+                    .cache_control;
+                (control.max_age, control.value())
+            }
+        };
+        assert_eq!(
+            max_age("{ slow fast unhinted }").await,
+            (5, Some("max-age=5".to_owned()))
+        );
+        assert_eq!(max_age("{ slow unhinted }").await.0, 60);
+        assert_eq!(max_age("{ unhinted }").await, (0, None));
+    }
+
+    /// Serves `req` to an admin through the GraphQL, REST and CardDAV
+    /// routes, all sharing `cache`. Answers the status, the
+    /// `Cache-Control` header and the body.
+    async fn send(
+        repo: &FileRepository<'static>,
+        backups: &std::path::Path,
+        cache: &ResponseCache,
+        req: TestRequest,
+    ) -> (actix_web::http::StatusCode, Option<String>, String) {
+        let schema: ContactsSchema =
+            graphql::schema_builder(StorageBackend::Files(repo.clone()), StorageMode::State)
+                .data(cache.clone())
+                .data(BackupDir(backups.to_owned()))
+                .data(Jobs::default())
+                .finish();
+        let limits = Limits::load(&Config::default()).unwrap();
+        let user: CurrentUser = caller("u1", Role::Admin);
+        let mut app = test::init_service(
+            App::new()
+                .data(schema)
+                .data(Some(cache.clone()))
+                .data(IdempotencyStore::new(repo.clone()))
+                .data(LiveLimits::new(limits))
+                .data(repo.clone())
+                .data(StorageMode::State)
+                .wrap_fn(move |req, srv| {
+                    req.extensions_mut().insert(user.clone());
+                    srv.call(req)
+                })
+                .configure(|cfg| rest::configure(cfg, &limits))
+                .service(web::resource("/carddav/{tail:.*}").to(carddav::serve))
+                .service(web::resource("/").to(graphql::index)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let status = resp.status();
+        let cache_control = resp
+            .headers()
+            .get("cache-control")
+            .map(|v| v.to_str().unwrap().to_owned());
+        let body = test::read_body(resp).await;
+        (
+            status,
+            cache_control,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    fn graphql(query: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/")
+            .set_json(&json!({ "query": query }))
+    }
+
+    /// A GraphQL multipart request uploading `file` as `$file`.
+    fn upload(query: &str, file: &[u8]) -> TestRequest {
+        let operations = json!({ "query": query, "variables": { "file": null } });
+        let mut body = format!(
+            "--B\r\nContent-Disposition: form-data; name=\"operations\"\r\n\r\n{}\r\n\
+             --B\r\nContent-Disposition: form-data; name=\"map\"\r\n\r\n{}\r\n\
+             --B\r\nContent-Disposition: form-data; name=\"0\"; filename=\"upload\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            operations,
+            json!({ "0": ["variables.file"] })
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n--B--\r\n");
+        TestRequest::post()
+            .uri("/")
+            .header("content-type", "multipart/form-data; boundary=B")
+            .set_payload(body)
+    }
+
+    fn vcard(id: &str) -> String {
+        format!(
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:{}\r\nFN:Barbara Liskov\r\nN:Liskov;Barbara;;;\r\nEND:VCARD\r\n",
+            id
+        )
+    }
+
+    // Multipart requests are read on the actix system, not just a runtime.
+    #[actix_rt::test]
+    async fn every_writer_makes_cached_queries_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repo").to_str().unwrap().to_owned();
+        let repo = FileRepository::new(Box::leak(path.into_boxed_str()));
+        let backups = dir.path().join("backups");
+        for id in &["ada", "grace", "alan", "edsger"] {
+            repo.set(ContactFixture::new(id).build()).unwrap();
+        }
+        repo.set(Group {
+            id: "friends".to_owned(),
+            name: "Friends".to_owned(),
+            member_ids: vec![],
+        })
+        .unwrap();
+        let (backup, _) = backup::write_to_dir(&repo, &backups).unwrap();
+        let backup = std::fs::read(backup).unwrap();
+        let avatar = png::encode(&Image {
+            width: 1,
+            height: 1,
+            rgba: vec![0, 0, 0, 255],
+        });
+        let cache = ResponseCache::default();
+
+        let contact: &[&str] = &["Contact"];
+        let group: &[&str] = &["Group"];
+        let both: &[&str] = &["Contact", "Group"];
+        let writers: Vec<(&str, &[&str], TestRequest)> = vec![
+            (
+                "createContact",
+                contact,
+                graphql("mutation { createContact(contact: { id: \"barbara\", firstName: \"Barbara\", lastName: \"Liskov\" }) { id } }"),
+            ),
+            (
+                "updateContactPartial",
+                contact,
+                graphql("mutation { updateContactPartial(id: \"barbara\", patch: { firstName: \"B\" }) { id } }"),
+            ),
+            (
+                "deleteContact",
+                contact,
+                graphql("mutation { deleteContact(id: \"barbara\") { id } }"),
+            ),
+            (
+                "importContactsCsv",
+                contact,
+                graphql("mutation { importContactsCsv(csv: \"id,first_name,last_name\\nbarbara,Barbara,Liskov\\n\") { imported } }"),
+            ),
+            (
+                "importVcard",
+                contact,
+                upload(
+                    "mutation($file: Upload!) { importVcard(file: $file) { imported } }",
+                    vcard("frances").as_bytes(),
+                ),
+            ),
+            (
+                "uploadAvatar",
+                contact,
+                upload(
+                    "mutation($file: Upload!) { uploadAvatar(id: \"ada\", file: $file) { id } }",
+                    &avatar,
+                ),
+            ),
+            (
+                "eraseContact",
+                both,
+                graphql("mutation { eraseContact(id: \"frances\") }"),
+            ),
+            (
+                "createGroup",
+                group,
+                graphql("mutation { createGroup(group: { id: \"family\", name: \"Family\" }) { id } }"),
+            ),
+            (
+                "addGroupMember",
+                group,
+                graphql("mutation { addGroupMember(groupId: \"friends\", contactId: \"ada\") { id } }"),
+            ),
+            (
+                "restore",
+                both,
+                upload(
+                    "mutation($file: Upload!) { restore(file: $file) { records } }",
+                    &backup,
+                ),
+            ),
+            (
+                "REST POST",
+                contact,
+                TestRequest::post().uri("/api/v1/contacts").set_json(
+                    &json!({ "id": "barbara", "firstName": "Barbara", "lastName": "Liskov" }),
+                ),
+            ),
+            (
+                "REST PUT",
+                contact,
+                TestRequest::put().uri("/api/v1/contacts/barbara").set_json(
+                    &json!({ "firstName": "B", "lastName": "Liskov" }),
+                ),
+            ),
+            (
+                "REST DELETE",
+                contact,
+                TestRequest::delete().uri("/api/v1/contacts/barbara"),
+            ),
+            (
+                "CardDAV PUT",
+                contact,
+                TestRequest::put()
+                    .uri("/carddav/contacts/barbara.vcf")
+                    .set_payload(vcard("barbara")),
+            ),
+            (
+                "CardDAV DELETE",
+                contact,
+                TestRequest::delete().uri("/carddav/contacts/barbara.vcf"),
+            ),
+        ];
+
+        let watched = vec![
+            (CONTACTS.to_owned(), vec!["Contact"]),
+            (GROUP.to_owned(), vec!["Group"]),
+        ];
+        for (writer, stale, req) in writers {
+            for query in &[CONTACTS, GROUP] {
+                let (status, cache_control, body) =
+                    send(&repo, &backups, &cache, graphql(query)).await;
+                assert_eq!(status, 200, "{}", body);
+                assert_eq!(cache_control.as_deref(), Some("max-age=60"));
+            }
+            assert_eq!(cached(&cache), watched);
+
+            let (status, _, body) = send(&repo, &backups, &cache, req).await;
+            assert!(status.is_success(), "{}: {} {}", writer, status, body);
+            let errors = serde_json::from_str::<Value>(&body).map(|b| b["errors"].clone());
+            assert!(errors.map_or(true, |e| e.is_null()), "{}: {}", writer, body);
+
+            let mut fresh = watched.clone();
+            fresh.retain(|(_, entity_types)| !entity_types.iter().any(|t| stale.contains(t)));
+            assert_eq!(cached(&cache), fresh, "after {}", writer);
+            cache.clear();
+        }
+    }
+}
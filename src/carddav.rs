@@ -0,0 +1,923 @@
+//! A minimal CardDAV server so native address books can sync the caller's
+//! contacts. The caller's principal and address book home is `/carddav/`,
+//! holding a single address book, `/carddav/contacts/`, with a card
+//! `<id>.vcf` per contact. Clients sign in with HTTP Basic credentials of a
+//! stored user or with any credentials the API accepts.
+//!
+//! Supported are `PROPFIND` on the home and the address book,
+//! `addressbook-multiget` and `addressbook-query` reports (queries return
+//! every card, filters are ignored), and `GET`, `PUT` and `DELETE` on cards
+//! with their ETags. Writes need the same roles as through the API.
+
+use crate::auth::{self, CurrentUser, Role};
+use crate::cache::ResponseCache;
+use crate::crypto;
+use crate::events::{Storage, StorageMode};
+use crate::graphql::on_blocking_pool;
+use crate::instrumented::InstrumentedRepository;
+use crate::models::Contact;
+use crate::repo::{self, FileRepository, Transactional};
+use crate::tenant;
+use crate::usecases;
+use crate::vcard;
+use actix_web::http::{header, HeaderMap, HeaderName, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::error::Error;
+
+const HOME: &str = "/carddav/";
+const BOOK: &str = "/carddav/contacts/";
+const REALM: &str = "Basic realm=\"contacts\", charset=\"UTF-8\"";
+const XML: &str = "application/xml; charset=utf-8";
+const VCARD: &str = "text/vcard; charset=utf-8";
+
+/// Points clients discovering the service at the home (RFC 6764).
+pub async fn well_known() -> HttpResponse {
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, HOME)
+        .finish()
+}
+
+pub async fn serve(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    cache: web::Data<Option<ResponseCache>>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    if req.method() == "OPTIONS" {
+        return HttpResponse::Ok()
+            .header("DAV", "1, 3, addressbook")
+            .header(header::ALLOW, "OPTIONS, PROPFIND, REPORT, GET, PUT, DELETE")
+            .finish();
+    }
+    let request = Request {
+        method: req.method().as_str().to_owned(),
+        path: req.path().to_owned(),
+        headers: req.headers().clone(),
+        signed_in: req.extensions().get::<CurrentUser>().cloned(),
+        body,
+    };
+    let (repo, mode, cache) = (repo.get_ref().clone(), **mode, cache.get_ref().clone());
+    // Signing in hashes the password and every answer reads the
+    // repository, so both are kept off the worker.
+    let reply = on_blocking_pool(move || async move {
+        answer(&request, &repo, mode, &cache).unwrap_or_else(|e| e)
+    })
+    .await;
+    match reply {
+        Ok(reply) => reply.into_response(),
+        Err(e) => status(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()).into_response(),
+    }
+}
+
+/// What `serve` needs of a request, taken so it can be answered on the
+/// blocking thread pool.
+struct Request {
+    method: String,
+    path: String,
+    headers: HeaderMap,
+    /// The caller the middleware resolved, if any.
+    signed_in: Option<CurrentUser>,
+    body: web::Bytes,
+}
+
+impl Request {
+    fn header(&self, name: HeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// A response made off the worker, which sends it.
+struct Reply {
+    code: StatusCode,
+    headers: Vec<(HeaderName, String)>,
+    body: Option<(&'static str, String)>,
+}
+
+impl Reply {
+    fn new(code: StatusCode) -> Reply {
+        Reply {
+            code,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    fn header(mut self, name: HeaderName, value: impl Into<String>) -> Reply {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn body(mut self, content_type: &'static str, body: String) -> Reply {
+        self.body = Some((content_type, body));
+        self
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.code);
+        for (name, value) in self.headers {
+            resp.header(name, value);
+        }
+        match self.body {
+            Some((content_type, body)) => resp.content_type(content_type).body(body),
+            None => resp.finish(),
+        }
+    }
+}
+
+fn answer(
+    req: &Request,
+    repo: &FileRepository<'static>,
+    mode: StorageMode,
+    cache: &Option<ResponseCache>,
+) -> Result<Reply, Reply> {
+    let caller = caller(req, repo, mode)?;
+    match (req.method.as_str(), req.path.as_str()) {
+        ("PROPFIND", HOME) => Ok(propfind_home(req)),
+        ("PROPFIND", BOOK) => propfind_book(&caller, req),
+        ("REPORT", BOOK) => report(&caller, &req.body),
+        (method, path) => match card_id(path) {
+            Some(id) => match method {
+                "GET" => get(&caller, &id),
+                "PUT" => put(&caller, &id, req, cache),
+                "DELETE" => delete(&caller, &id, req, cache),
+                "PROPFIND" => propfind_card(&caller, &id),
+                _ => Err(status(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+            },
+            None if path == HOME || path == BOOK => {
+                Err(status(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"))
+            }
+            None => Err(status(StatusCode::NOT_FOUND, "not found")),
+        },
+    }
+}
+
+/// The signed in caller and the partition of their tenant.
+struct Caller {
+    user: CurrentUser,
+    repo: Storage<InstrumentedRepository<FileRepository<'static>>>,
+}
+
+/// Takes the caller the middleware resolved or checks their Basic
+/// credentials, answering with a challenge when there are neither.
+fn caller(
+    req: &Request,
+    repo: &FileRepository<'static>,
+    mode: StorageMode,
+) -> Result<Caller, Reply> {
+    let challenge = || Reply::new(StatusCode::UNAUTHORIZED).header(header::WWW_AUTHENTICATE, REALM);
+    let tenant = tenant::resolve(&req.headers, req.signed_in.as_ref())
+        .map_err(|e| status(StatusCode::FORBIDDEN, &e))?;
+    let files = match &tenant {
+        Some(tenant) => repo.for_tenant(&tenant.0),
+        None => repo.clone(),
+    };
+    let repo = Storage::new(InstrumentedRepository::new("file", files), mode);
+    let user = match &req.signed_in {
+        Some(user) => user.clone(),
+        None => {
+            let (email, password) = basic_credentials(req).ok_or_else(challenge)?;
+            let user = usecases::login(&email, &password, &repo).map_err(|_| challenge())?;
+            CurrentUser {
+                id: user.id,
+                role: user.role,
+                scopes: auth::login_scopes(),
+                tenant: tenant.map(|t| t.0),
+            }
+        }
+    };
+    Ok(Caller { user, repo })
+}
+
+fn basic_credentials(req: &Request) -> Option<(String, String)> {
+    let value = req.header(header::AUTHORIZATION)?;
+    let encoded = value.strip_prefix("Basic ")?.trim();
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+}
+
+fn status(code: StatusCode, message: &str) -> Reply {
+    Reply::new(code).body("text/plain; charset=utf-8", message.to_owned())
+}
+
+fn require(caller: &Caller, role: Role) -> Result<(), Reply> {
+    if caller.user.role < role {
+        return Err(status(
+            StatusCode::FORBIDDEN,
+            &format!("Forbidden, requires role {}", role),
+        ));
+    }
+    Ok(())
+}
+
+/// The contact id of a card path, `/carddav/contacts/<id>.vcf`.
+fn card_id(path: &str) -> Option<String> {
+    let name = path.strip_prefix(BOOK)?.strip_suffix(".vcf")?;
+    let id = percent_decode(name)?;
+    if id.is_empty() || id.contains('/') {
+        return None;
+    }
+    Some(id)
+}
+
+fn card_href(id: &str) -> String {
+    let mut href = BOOK.to_owned();
+    for byte in id.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                href.push(byte as char)
+            }
+            _ => href.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    href.push_str(".vcf");
+    href
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// A card's ETag, quoted: a digest of the vCard it's served as.
+fn etag(card: &str) -> String {
+    format!(
+        "\"{}\"",
+        crypto::to_hex(&crypto::sha256(card.as_bytes())[..12])
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn multistatus(responses: &[String]) -> Reply {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <d:multistatus xmlns:d=\"DAV:\" xmlns:card=\"urn:ietf:params:xml:ns:carddav\" \
+         xmlns:cs=\"http://calendarserver.org/ns/\">",
+    );
+    for response in responses {
+        xml.push_str(response);
+    }
+    xml.push_str("</d:multistatus>\n");
+    Reply::new(StatusCode::MULTI_STATUS).body(XML, xml)
+}
+
+fn response(href: &str, props: &str) -> String {
+    format!(
+        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+         <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        escape(href),
+        props
+    )
+}
+
+fn missing(href: &str) -> String {
+    format!(
+        "<d:response><d:href>{}</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+        escape(href)
+    )
+}
+
+fn depth(req: &Request) -> &str {
+    req.header(HeaderName::from_static("depth"))
+        .unwrap_or("infinity")
+}
+
+fn home_props() -> String {
+    format!(
+        "<d:resourcetype><d:collection/></d:resourcetype>\
+         <d:current-user-principal><d:href>{home}</d:href></d:current-user-principal>\
+         <d:principal-URL><d:href>{home}</d:href></d:principal-URL>\
+         <card:addressbook-home-set><d:href>{home}</d:href></card:addressbook-home-set>",
+        home = HOME
+    )
+}
+
+fn book_props(tag: &str) -> String {
+    format!(
+        "<d:resourcetype><d:collection/><card:addressbook/></d:resourcetype>\
+         <d:displayname>Contacts</d:displayname>\
+         <d:current-user-principal><d:href>{}</d:href></d:current-user-principal>\
+         <cs:getctag>{}</cs:getctag>\
+         <d:supported-report-set>\
+         <d:supported-report><d:report><card:addressbook-multiget/></d:report></d:supported-report>\
+         <d:supported-report><d:report><card:addressbook-query/></d:report></d:supported-report>\
+         </d:supported-report-set>",
+        HOME,
+        escape(tag)
+    )
+}
+
+fn card_props(card: &str, with_data: bool) -> String {
+    let mut props = format!(
+        "<d:getetag>{}</d:getetag><d:getcontenttype>{}</d:getcontenttype>",
+        escape(&etag(card)),
+        VCARD
+    );
+    if with_data {
+        props.push_str(&format!(
+            "<card:address-data>{}</card:address-data>",
+            escape(card)
+        ));
+    }
+    props
+}
+
+/// The caller's contacts as cards, by id.
+fn cards(caller: &Caller) -> Result<Vec<(String, String)>, Reply> {
+    let contacts = usecases::list_contacts(&caller.user.id, &caller.repo)
+        .map_err(|e| status(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?;
+    Ok(contacts
+        .into_iter()
+        .map(|c| (c.id.clone(), card(caller, c)))
+        .collect())
+}
+
+/// `contact`'s card, as the caller may read it.
+fn card(caller: &Caller, contact: Contact) -> String {
+    vcard::write(&contact.masked_for(&caller.user))
+}
+
+fn propfind_home(req: &Request) -> Reply {
+    let mut responses = vec![response(HOME, &home_props())];
+    if depth(req) != "0" {
+        responses.push(response(BOOK, &book_props("")));
+    }
+    multistatus(&responses)
+}
+
+fn propfind_book(caller: &Caller, req: &Request) -> Result<Reply, Reply> {
+    let cards = cards(caller)?;
+    // The address book changes whenever one of its cards does.
+    let tags: String = cards.iter().map(|(_, card)| etag(card)).collect();
+    let mut responses = vec![response(BOOK, &book_props(&etag(&tags)))];
+    if depth(req) != "0" {
+        for (id, card) in &cards {
+            responses.push(response(&card_href(id), &card_props(card, false)));
+        }
+    }
+    Ok(multistatus(&responses))
+}
+
+fn propfind_card(caller: &Caller, id: &str) -> Result<Reply, Reply> {
+    let card = card(caller, find(caller, id)?);
+    Ok(multistatus(&[response(
+        &card_href(id),
+        &card_props(&card, false),
+    )]))
+}
+
+fn report(caller: &Caller, body: &[u8]) -> Result<Reply, Reply> {
+    let body = String::from_utf8_lossy(body);
+    let elements = elements(&body);
+    let kind = elements.first().map(|(name, _)| name.as_str());
+    let responses: Vec<String> = match kind {
+        Some("addressbook-multiget") => elements
+            .iter()
+            .filter(|(name, _)| name == "href")
+            .map(|(_, href)| {
+                let href = href.trim();
+                match card_id(href).map(|id| find(caller, &id)) {
+                    Some(Ok(contact)) => response(href, &card_props(&card(caller, contact), true)),
+                    _ => missing(href),
+                }
+            })
+            .collect(),
+        Some("addressbook-query") => cards(caller)?
+            .iter()
+            .map(|(id, card)| response(&card_href(id), &card_props(card, true)))
+            .collect(),
+        _ => return Err(status(StatusCode::FORBIDDEN, "unsupported report")),
+    };
+    Ok(multistatus(&responses))
+}
+
+/// The local names of a document's opening tags with the text following
+/// each, in document order. Enough to read the reports clients send
+/// without an XML parser.
+fn elements(xml: &str) -> Vec<(String, String)> {
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        let local = name.rsplit(':').next().unwrap_or_default();
+        let text = rest.split('<').next().unwrap_or_default();
+        found.push((local.to_owned(), unescape(text)));
+    }
+    found
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn find(caller: &Caller, id: &str) -> Result<Contact, Reply> {
+    usecases::get(&caller.user.id, id, &caller.repo)
+        .map_err(|_| status(StatusCode::NOT_FOUND, "no such card"))
+}
+
+fn get(caller: &Caller, id: &str) -> Result<Reply, Reply> {
+    let card = card(caller, find(caller, id)?);
+    Ok(Reply::new(StatusCode::OK)
+        .header(header::ETAG, etag(&card))
+        .body(VCARD, card))
+}
+
+/// Holds a write to the client's `If-Match` and `If-None-Match: *`
+/// preconditions, given the card as it's stored now.
+fn check_preconditions(req: &Request, current: Option<&str>) -> Result<(), Reply> {
+    let failed = || status(StatusCode::PRECONDITION_FAILED, "the card has changed");
+    let header = |name| req.header(name).map(|v| v.trim().to_owned());
+    if let Some(expected) = header(header::IF_MATCH) {
+        match current {
+            Some(card) if expected == "*" || expected == etag(card) => {}
+            _ => return Err(failed()),
+        }
+    }
+    if header(header::IF_NONE_MATCH).as_deref() == Some("*") && current.is_some() {
+        return Err(failed());
+    }
+    Ok(())
+}
+
+fn put(
+    caller: &Caller,
+    id: &str,
+    req: &Request,
+    cache: &Option<ResponseCache>,
+) -> Result<Reply, Reply> {
+    require(caller, Role::Editor)?;
+    let text = std::str::from_utf8(&req.body)
+        .map_err(|_| status(StatusCode::BAD_REQUEST, "the card is not UTF-8"))?;
+    let mut contact = match vcard::read(text).into_iter().next() {
+        Some((_, Ok(contact))) => contact,
+        Some((_, Err(e))) => return Err(status(StatusCode::BAD_REQUEST, &e)),
+        None => return Err(status(StatusCode::BAD_REQUEST, "no card in the request")),
+    };
+    // The path names the contact, whatever the card's UID says.
+    contact.id = id.to_owned();
+    let (existed, stored) = caller
+        .repo
+        .with_tx(|tx| {
+            let current = usecases::get(&caller.user.id, id, tx)
+                .ok()
+                .map(|c| card(caller, c));
+            if let Err(reply) = check_preconditions(req, current.as_deref()) {
+                return Ok(Err(reply));
+            }
+            let stored = usecases::create(&caller.user.id, &caller.user.id, contact, tx)?;
+            Ok(Ok((current.is_some(), stored)))
+        })
+        .map_err(|e| write_failed(e, StatusCode::BAD_REQUEST))??;
+    if let Some(cache) = cache {
+        cache.invalidate("Contact");
+    }
+    let code = if existed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    Ok(Reply::new(code).header(header::ETAG, etag(&card(caller, stored))))
+}
+
+fn delete(
+    caller: &Caller,
+    id: &str,
+    req: &Request,
+    cache: &Option<ResponseCache>,
+) -> Result<Reply, Reply> {
+    require(caller, Role::Admin)?;
+    caller
+        .repo
+        .with_tx(|tx| {
+            let current = match usecases::get(&caller.user.id, id, tx) {
+                Ok(contact) => card(caller, contact),
+                Err(_) => return Ok(Err(status(StatusCode::NOT_FOUND, "no such card"))),
+            };
+            if let Err(reply) = check_preconditions(req, Some(&current)) {
+                return Ok(Err(reply));
+            }
+            usecases::delete(&caller.user.id, &caller.user.id, id, tx)?;
+            Ok(Ok(()))
+        })
+        .map_err(|e| write_failed(e, StatusCode::INTERNAL_SERVER_ERROR))??;
+    if let Some(cache) = cache {
+        cache.invalidate("Contact");
+    }
+    Ok(Reply::new(StatusCode::NO_CONTENT))
+}
+
+/// A write's failure: a 409 when a concurrent write to the card won,
+/// `code` otherwise.
+fn write_failed(e: Box<dyn Error>, code: StatusCode) -> Reply {
+    if repo::is_conflict(e.as_ref()) {
+        return status(StatusCode::CONFLICT, "the card changed concurrently");
+    }
+    status(code, &e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+    use crate::repo::Repository;
+    use crate::testing::{caller as signed_in, ContactFixture};
+    use actix_web::body::{Body, ResponseBody};
+    use actix_web::test::TestRequest;
+
+    fn scratch() -> (tempfile::TempDir, FileRepository<'static>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        let repo = FileRepository::new(Box::leak(path.into_boxed_str()));
+        for contact in [
+            ContactFixture::new("ada").email("ada@example.com").build(),
+            ContactFixture::new("grace").name("Grace", "Hopper").build(),
+            ContactFixture::new("alan").owner("u2").build(),
+        ] {
+            repo.set(contact).unwrap();
+        }
+        (dir, repo)
+    }
+
+    /// Serves `req` to `user`, or to whoever its Basic credentials name.
+    async fn call(
+        repo: &FileRepository<'static>,
+        user: Option<CurrentUser>,
+        req: TestRequest,
+        body: &str,
+    ) -> HttpResponse {
+        let req = req.to_http_request();
+        if let Some(user) = user {
+            req.extensions_mut().insert(user);
+        }
+        serve(
+            web::Data::new(repo.clone()),
+            web::Data::new(StorageMode::State),
+            web::Data::new(None),
+            req,
+            web::Bytes::from(body.to_owned()),
+        )
+        .await
+    }
+
+    fn request(method: &str, path: &str) -> TestRequest {
+        TestRequest::with_uri(path).method(method.parse().unwrap())
+    }
+
+    fn text(resp: &HttpResponse) -> String {
+        match resp.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => String::new(),
+        }
+    }
+
+    fn hrefs(xml: &str) -> Vec<String> {
+        elements(xml)
+            .into_iter()
+            .filter(|(name, _)| name == "href")
+            .map(|(_, href)| href)
+            .collect()
+    }
+
+    fn header_of(resp: &HttpResponse, name: header::HeaderName) -> String {
+        resp.headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn propfind_lists_cards_unless_depth_is_0() {
+        let (_dir, repo) = scratch();
+        let ada = || Some(signed_in("u1", Role::Viewer));
+
+        let home = call(
+            &repo,
+            ada(),
+            request("PROPFIND", HOME).header("Depth", "0"),
+            "",
+        )
+        .await;
+        assert_eq!(home.status(), StatusCode::MULTI_STATUS);
+        assert!(!hrefs(&text(&home)).contains(&BOOK.to_owned()));
+        let home = call(&repo, ada(), request("PROPFIND", HOME), "").await;
+        assert!(hrefs(&text(&home)).contains(&BOOK.to_owned()));
+
+        let book = call(
+            &repo,
+            ada(),
+            request("PROPFIND", BOOK).header("Depth", "0"),
+            "",
+        )
+        .await;
+        let book = text(&book);
+        assert!(!hrefs(&book).iter().any(|h| h.ends_with(".vcf")));
+        let listed = call(
+            &repo,
+            ada(),
+            request("PROPFIND", BOOK).header("Depth", "1"),
+            "",
+        )
+        .await;
+        let listed = text(&listed);
+        let mut cards: Vec<String> = hrefs(&listed)
+            .into_iter()
+            .filter(|h| h.ends_with(".vcf"))
+            .collect();
+        cards.sort();
+        assert_eq!(cards, vec![card_href("ada"), card_href("grace")]);
+        assert!(listed.contains("<d:getetag>"));
+        assert!(!listed.contains("address-data"));
+
+        let ctag = |xml: &str| {
+            elements(xml)
+                .into_iter()
+                .find(|(name, _)| name == "getctag")
+                .unwrap()
+                .1
+        };
+        repo.set(ContactFixture::new("grace").name("Grace", "Murray").build())
+            .unwrap();
+        let changed = call(
+            &repo,
+            ada(),
+            request("PROPFIND", BOOK).header("Depth", "0"),
+            "",
+        )
+        .await;
+        assert_ne!(ctag(&text(&changed)), ctag(&book));
+    }
+
+    #[tokio::test]
+    async fn reports_return_the_cards_asked_for() {
+        let (_dir, repo) = scratch();
+        let multiget = format!(
+            "<?xml version=\"1.0\"?>\
+             <card:addressbook-multiget xmlns:d=\"DAV:\" xmlns:card=\"urn:ietf:params:xml:ns:carddav\">\
+             <d:prop><d:getetag/><card:address-data/></d:prop>\
+             <d:href>{}</d:href><d:href>{}</d:href><d:href>{}</d:href>\
+             </card:addressbook-multiget>",
+            card_href("ada"),
+            card_href("missing"),
+            card_href("alan")
+        );
+        let resp = call(
+            &repo,
+            Some(signed_in("u1", Role::Viewer)),
+            request("REPORT", BOOK),
+            &multiget,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let xml = text(&resp);
+        assert_eq!(
+            xml.matches("<d:status>HTTP/1.1 404 Not Found</d:status>")
+                .count(),
+            2
+        );
+        let data: Vec<String> = elements(&xml)
+            .into_iter()
+            .filter(|(name, _)| name == "address-data")
+            .map(|(_, card)| card)
+            .collect();
+        assert_eq!(data.len(), 1);
+        assert!(data[0].contains("FN:Ada Lovelace"));
+        assert!(data[0].contains("EMAIL"));
+
+        let query = "<card:addressbook-query xmlns:card=\"urn:ietf:params:xml:ns:carddav\">\
+                     <card:filter/></card:addressbook-query>";
+        let without_pii = CurrentUser {
+            scopes: vec![],
+            ..signed_in("u1", Role::Viewer)
+        };
+        let resp = call(&repo, Some(without_pii), request("REPORT", BOOK), query).await;
+        let xml = text(&resp);
+        assert_eq!(xml.matches("<card:address-data>").count(), 2);
+        assert!(!xml.contains("ada@example.com"));
+
+        let resp = call(
+            &repo,
+            Some(signed_in("u1", Role::Viewer)),
+            request("REPORT", BOOK),
+            "<d:sync-collection xmlns:d=\"DAV:\"/>",
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn writes_hold_to_their_etag_preconditions() {
+        let (_dir, repo) = scratch();
+        let editor = || Some(signed_in("u1", Role::Editor));
+        let href = card_href("edsger");
+        let card = "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:Edsger Dijkstra\r\n\
+                    N:Dijkstra;Edsger;;;\r\nEND:VCARD\r\n";
+
+        let created = call(
+            &repo,
+            editor(),
+            request("PUT", &href).header("If-None-Match", "*"),
+            card,
+        )
+        .await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let tag = header_of(&created, header::ETAG);
+        let again = call(
+            &repo,
+            editor(),
+            request("PUT", &href).header("If-None-Match", "*"),
+            card,
+        )
+        .await;
+        assert_eq!(again.status(), StatusCode::PRECONDITION_FAILED);
+
+        let got = call(&repo, editor(), request("GET", &href), "").await;
+        assert_eq!(got.status(), StatusCode::OK);
+        assert_eq!(header_of(&got, header::ETAG), tag);
+        assert!(text(&got).contains("FN:Edsger Dijkstra"));
+
+        let renamed = card.replace("Edsger", "Edsger Wybe");
+        let stale = call(
+            &repo,
+            editor(),
+            request("PUT", &href).header("If-Match", "\"stale\""),
+            &renamed,
+        )
+        .await;
+        assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+        let updated = call(
+            &repo,
+            editor(),
+            request("PUT", &href).header("If-Match", tag.as_str()),
+            &renamed,
+        )
+        .await;
+        assert_eq!(updated.status(), StatusCode::NO_CONTENT);
+        let new_tag = header_of(&updated, header::ETAG);
+        assert_ne!(new_tag, tag);
+
+        let viewer = Some(signed_in("u1", Role::Viewer));
+        let refused = call(&repo, viewer, request("PUT", &href), card).await;
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+
+        let admin = || Some(signed_in("u1", Role::Admin));
+        let stale = call(
+            &repo,
+            admin(),
+            request("DELETE", &href).header("If-Match", tag.as_str()),
+            "",
+        )
+        .await;
+        assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+        let deleted = call(
+            &repo,
+            admin(),
+            request("DELETE", &href).header("If-Match", new_tag.as_str()),
+            "",
+        )
+        .await;
+        assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+        let gone = call(&repo, admin(), request("GET", &href), "").await;
+        assert_eq!(gone.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn basic_credentials_sign_stored_users_in() {
+        let (_dir, repo) = scratch();
+        let id = User::id_for("ada@example.com");
+        repo.set(
+            ContactFixture::new("augusta")
+                .owner(&id)
+                .email("augusta@example.com")
+                .build(),
+        )
+        .unwrap();
+        // Few iterations keep the test fast; signing in rehashes them once.
+        let salt = b"0123456789abcdef";
+        repo.set(User {
+            id,
+            email: "ada@example.com".to_owned(),
+            password_hash: format!(
+                "$pbkdf2-sha256$1000${}${}",
+                base64::encode_config(salt, base64::STANDARD_NO_PAD),
+                base64::encode_config(
+                    crypto::pbkdf2_sha256(b"se:cret", salt, 1000),
+                    base64::STANDARD_NO_PAD
+                )
+            ),
+            role: Role::Viewer,
+        })
+        .unwrap();
+        let basic = |credentials: &str| {
+            request("PROPFIND", BOOK).header(
+                header::AUTHORIZATION,
+                format!("Basic {}", base64::encode(credentials)),
+            )
+        };
+
+        let anonymous = call(&repo, None, request("PROPFIND", BOOK), "").await;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(header_of(&anonymous, header::WWW_AUTHENTICATE), REALM);
+        let wrong = call(&repo, None, basic("ada@example.com:secret"), "").await;
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let garbled = request("PROPFIND", BOOK).header(header::AUTHORIZATION, "Basic !!");
+        let garbled = call(&repo, None, garbled, "").await;
+        assert_eq!(garbled.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = call(&repo, None, basic("ada@example.com:se:cret"), "").await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        assert!(hrefs(&text(&resp)).contains(&card_href("augusta")));
+        // A password login grants what it does for tokens and sessions.
+        let card = request("GET", &card_href("augusta")).header(
+            header::AUTHORIZATION,
+            format!("Basic {}", base64::encode("ada@example.com:se:cret")),
+        );
+        let card = call(&repo, None, card, "").await;
+        assert!(
+            text(&card).contains("augusta@example.com"),
+            "{}",
+            text(&card)
+        );
+    }
+
+    /// A card whose file is a FIFO can't be read until a writer turns up a
+    /// second later; requests for other cards meanwhile don't wait for it.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_slow_read_doesnt_hold_up_other_requests() {
+        use std::io::Write;
+        use std::time::{Duration, Instant};
+
+        let (dir, repo) = scratch();
+        let path = dir.path().join("contacts/u1/ada.json");
+        let stored = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let made = std::process::Command::new("mkfifo").arg(&path).status();
+        assert!(made.unwrap().success());
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(1));
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|mut fifo| fifo.write_all(&stored))
+                .unwrap();
+        });
+
+        let ada = || Some(signed_in("u1", Role::Viewer));
+        let start = Instant::now();
+        let slow = async {
+            let resp = call(&repo, ada(), request("GET", &card_href("ada")), "").await;
+            (resp, start.elapsed())
+        };
+        let fast = futures::future::join_all((0..20).map(|_| async {
+            let resp = call(&repo, ada(), request("GET", &card_href("grace")), "").await;
+            (resp, start.elapsed())
+        }));
+        let ((slow, slow_took), fast) = futures::future::join(slow, fast).await;
+        writer.join().unwrap();
+
+        assert_eq!(slow.status(), StatusCode::OK);
+        assert!(text(&slow).contains("FN:Ada Lovelace"));
+        assert!(slow_took >= Duration::from_secs(1));
+        for (resp, took) in fast {
+            assert!(text(&resp).contains("FN:Grace Hopper"));
+            assert!(took < Duration::from_millis(500), "a GET took {:?}", took);
+        }
+    }
+}
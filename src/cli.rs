@@ -0,0 +1,524 @@
+//! The command line. `serve` (the default) runs the server; `import` and
+//! `export` move contacts in and out of the configured repository as JSON
+//! lines, one contact per line, `import-csv` and `export-csv` as CSV;
+//! `check` reads back every stored record and lists the ones that are
+//! corrupt; `backup` and `restore` write and read back a compressed archive
+//! of the whole repository; `import-google` imports an owner's Google
+//! contacts and `import-ldap` the people of an LDAP directory; `bench`
+//! times the read and write paths.
+
+use crate::backup;
+use crate::csv::{self, Columns};
+use crate::events::{Snapshot, Storage, StorageMode, StoredEvent};
+use crate::google::{self, GoogleConfig};
+use crate::idempotency::StoredResponse;
+use crate::ldap::{self, LdapConfig};
+use crate::logging;
+use crate::models::*;
+use crate::outbox::OutboxMessage;
+use crate::repo::*;
+use crate::settings::{Config, Mode};
+use crate::tenant::Tenant;
+use crate::usecases::{self, ImportOptions};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+pub const USAGE: &str = "\
+usage: backend [command] [options]
+
+commands:
+  serve                     run the server (default)
+  import [FILE]             import contacts from JSON lines, stdin without FILE
+  export [FILE]             export contacts as JSON lines, stdout without FILE
+  import-csv [FILE]         import contacts from CSV, stdin without FILE
+  export-csv [FILE]         export contacts as CSV, stdout without FILE
+  check                     list stored records that can't be read back
+  backup [FILE]             write a backup archive, into the backup
+                            directory without FILE
+  restore [FILE]            replace the repository with a backup, read from
+                            stdin without FILE
+  import-google             import the Google contacts of an account into
+                            --owner's contacts, after granting access
+  import-ldap               import the people of the configured LDAP
+                            directory into --owner's contacts
+  print-schema              print the GraphQL schema
+  bench [ITERATIONS]        time repository calls and GraphQL operations on
+                            a scratch repository, 1000 iterations by default
+
+options:
+  --tenant ID               use a tenant's partition of the repository
+  --columns LIST            CSV columns, e.g. id,first_name,-,last_name
+  --header yes|no           whether the CSV starts with a header row;
+                            detected by default
+  --owner ID                owner of imported CSV rows without one, or of
+                            imported Google or LDAP contacts
+  --on-conflict STRATEGY    what import-csv, import-google and import-ldap
+                            do with contacts that have the id or an email
+                            of a stored one: skip, overwrite (the default),
+                            merge or duplicate
+  --dry-run                 print what an import would do without writing
+  --merge                   restore over the stored records, keeping the
+                            ones the backup doesn't have
+  -h, --help                show this help
+
+The repository is the one the server is configured with.";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub columns: Option<Columns>,
+    pub header: Option<bool>,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    Import {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+    },
+    Export {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+    },
+    ImportCsv {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+        options: CsvOptions,
+        import: ImportOptions,
+    },
+    ExportCsv {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+        columns: Option<Columns>,
+    },
+    Check {
+        tenant: Option<Tenant>,
+    },
+    Backup {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+    },
+    Restore {
+        file: Option<String>,
+        tenant: Option<Tenant>,
+        merge: bool,
+    },
+    ImportGoogle {
+        owner: String,
+        tenant: Option<Tenant>,
+        import: ImportOptions,
+    },
+    ImportLdap {
+        owner: String,
+        tenant: Option<Tenant>,
+        import: ImportOptions,
+    },
+    PrintSchema,
+    Bench {
+        iterations: usize,
+    },
+    Help,
+}
+
+impl Command {
+    /// Parses the arguments following the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+        let mut command = None;
+        let mut file = None;
+        let mut tenant = None;
+        let mut csv = CsvOptions::default();
+        let mut import = ImportOptions::default();
+        let mut merge = false;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                // Kept from before there were subcommands.
+                "--print-schema" => return Ok(Command::PrintSchema),
+                "--tenant" => tenant = Some(Tenant::parse(&value()?)?),
+                "--columns" => csv.columns = Some(Columns::parse(&value()?)?),
+                "--header" => {
+                    csv.header = match value()?.as_str() {
+                        "yes" => Some(true),
+                        "no" => Some(false),
+                        v => return Err(format!("--header must be yes or no, got {:?}", v)),
+                    }
+                }
+                "--owner" => csv.owner = Some(value()?),
+                "--on-conflict" => {
+                    import.strategy = match value()?.as_str() {
+                        "skip" => ConflictStrategy::Skip,
+                        "overwrite" => ConflictStrategy::Overwrite,
+                        "merge" => ConflictStrategy::Merge,
+                        "duplicate" => ConflictStrategy::Duplicate,
+                        v => {
+                            return Err(format!(
+                            "--on-conflict must be skip, overwrite, merge or duplicate, got {:?}",
+                            v
+                        ))
+                        }
+                    }
+                }
+                "--dry-run" => import.dry_run = true,
+                "--merge" => merge = true,
+                a if a.starts_with('-') => return Err(format!("unknown option {}", a)),
+                a if command.is_none() => command = Some(a.to_owned()),
+                a if file.is_none() => file = Some(a.to_owned()),
+                a => return Err(format!("unexpected argument {}", a)),
+            }
+        }
+        let command = command.unwrap_or_else(|| "serve".to_owned());
+        if merge && command != "restore" {
+            return Err(format!("--merge doesn't apply to {}", command));
+        }
+        let importing = matches!(
+            command.as_str(),
+            "import-csv" | "import-google" | "import-ldap"
+        );
+        if import != ImportOptions::default() && !importing {
+            return Err(format!(
+                "--on-conflict and --dry-run don't apply to {}",
+                command
+            ));
+        }
+        let plain = file.is_none() && csv == CsvOptions::default();
+        match command.as_str() {
+            "serve" | "print-schema" if !plain || tenant.is_some() => {
+                Err(format!("too many arguments for {}", command))
+            }
+            "serve" => Ok(Command::Serve),
+            "bench" if csv != CsvOptions::default() || tenant.is_some() => {
+                Err("bench takes no options".to_owned())
+            }
+            "bench" => match file.as_deref().map(str::parse) {
+                None => Ok(Command::Bench {
+                    iterations: crate::bench::DEFAULT_ITERATIONS,
+                }),
+                Some(Ok(iterations)) if iterations > 0 => Ok(Command::Bench { iterations }),
+                Some(_) => Err(format!(
+                    "bench iterations must be a positive number, got {:?}",
+                    file.unwrap_or_default()
+                )),
+            },
+            "print-schema" => Ok(Command::PrintSchema),
+            "import" | "export" | "check" | "backup" | "restore"
+                if csv != CsvOptions::default() =>
+            {
+                Err(format!("CSV options don't apply to {}", command))
+            }
+            "import" => Ok(Command::Import { file, tenant }),
+            "export" => Ok(Command::Export { file, tenant }),
+            "check" if file.is_some() => Err("too many arguments for check".to_owned()),
+            "check" => Ok(Command::Check { tenant }),
+            "backup" => Ok(Command::Backup { file, tenant }),
+            "restore" => Ok(Command::Restore {
+                file,
+                tenant,
+                merge,
+            }),
+            "import-csv" => Ok(Command::ImportCsv {
+                file,
+                tenant,
+                options: csv,
+                import,
+            }),
+            "import-google" | "import-ldap" if file.is_some() => {
+                Err(format!("too many arguments for {}", command))
+            }
+            "import-google" | "import-ldap" if csv.columns.is_some() || csv.header.is_some() => {
+                Err(format!("{} only takes --owner", command))
+            }
+            "import-google" | "import-ldap" => match csv.owner {
+                Some(owner) if command == "import-google" => Ok(Command::ImportGoogle {
+                    owner,
+                    tenant,
+                    import,
+                }),
+                Some(owner) => Ok(Command::ImportLdap {
+                    owner,
+                    tenant,
+                    import,
+                }),
+                None => Err(format!("{} needs --owner", command)),
+            },
+            "export-csv" if csv.header.is_some() || csv.owner.is_some() => {
+                Err("export-csv only takes --columns".to_owned())
+            }
+            "export-csv" => Ok(Command::ExportCsv {
+                file,
+                tenant,
+                columns: csv.columns,
+            }),
+            c => Err(format!("unknown command {}", c)),
+        }
+    }
+}
+
+/// The configured repository, narrowed to `tenant`.
+fn repository(tenant: Option<&Tenant>) -> Result<FileRepository<'static>, Box<dyn Error>> {
+    Ok(configured(tenant)?.1)
+}
+
+fn configured(
+    tenant: Option<&Tenant>,
+) -> Result<(Config, FileRepository<'static>), Box<dyn Error>> {
+    let mode = Mode::from_env();
+    logging::init(mode);
+    let config = Config::load(mode)?;
+    logging::set_filter(logging::filter(&config)?);
+    let repo = config.repository();
+    let repo = match tenant {
+        Some(tenant) => repo.for_tenant(&tenant.0),
+        None => repo,
+    };
+    Ok((config, repo))
+}
+
+/// Imports the contacts in `file`, each line a contact with its `owner_id`.
+/// Lines that fail are reported and skipped.
+pub fn import(file: Option<&str>, tenant: Option<&Tenant>) -> Result<(), Box<dyn Error>> {
+    let repo = Storage::new(repository(tenant)?, StorageMode::from_env());
+    let input: Box<dyn BufRead> = match file {
+        Some(path) => Box::new(BufReader::new(std::fs::File::open(path)?)),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+    let (mut imported, mut failed) = (0, 0);
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = serde_json::from_str::<Contact>(&line)
+            .map_err(|e| e.into())
+            .and_then(|c| usecases::create("cli:import", &c.owner_id.clone(), c, &repo));
+        match result {
+            Ok(_) => imported += 1,
+            Err(e) => {
+                eprintln!("line {}: {}", n + 1, e);
+                failed += 1;
+            }
+        }
+    }
+    eprintln!("imported {} contacts, {} failed", imported, failed);
+    if failed > 0 {
+        return Err(format!("contacts not imported: {}", failed).into());
+    }
+    Ok(())
+}
+
+/// Writes every contact to `file` as a JSON line.
+pub fn export(file: Option<&str>, tenant: Option<&Tenant>) -> Result<(), Box<dyn Error>> {
+    let repo = repository(tenant)?;
+    let mut output: Box<dyn Write> = match file {
+        Some(path) => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    let mut exported = 0;
+    for key in repo.keys(Contact::KIND)? {
+        let contact: Contact = repo.get(&key)?;
+        serde_json::to_writer(&mut output, &contact)?;
+        output.write_all(b"\n")?;
+        exported += 1;
+    }
+    output.flush()?;
+    eprintln!("exported {} contacts", exported);
+    Ok(())
+}
+
+/// Imports the contacts of a CSV file. Rows that fail are reported and
+/// skipped.
+pub fn import_csv(
+    file: Option<&str>,
+    tenant: Option<&Tenant>,
+    options: CsvOptions,
+    import: ImportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let repo = Storage::new(repository(tenant)?, StorageMode::from_env());
+    let mut input = String::new();
+    match file {
+        Some(path) => std::fs::File::open(path)?.read_to_string(&mut input)?,
+        None => std::io::stdin().read_to_string(&mut input)?,
+    };
+    let rows = csv::read_contacts(&input, options.columns, options.header)?;
+    let report =
+        usecases::import_contacts("cli:import", options.owner.as_deref(), rows, import, &repo);
+    print_report(&report, "row", import);
+    if report.failed > 0 {
+        return Err(format!("contacts not imported: {}", report.failed).into());
+    }
+    Ok(())
+}
+
+/// Writes every contact to `file` as CSV with a header row.
+pub fn export_csv(
+    file: Option<&str>,
+    tenant: Option<&Tenant>,
+    columns: Option<Columns>,
+) -> Result<(), Box<dyn Error>> {
+    let repo = repository(tenant)?;
+    let mut contacts = vec![];
+    for key in repo.keys(Contact::KIND)? {
+        contacts.push(repo.get(&key)?);
+    }
+    let mut output: Box<dyn Write> = match file {
+        Some(path) => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    csv::write_contacts(&mut output, &columns.unwrap_or_default(), &contacts)?;
+    output.flush()?;
+    eprintln!("exported {} contacts", contacts.len());
+    Ok(())
+}
+
+/// Writes a backup archive to `file`, or a new one in the backup directory.
+pub fn backup(file: Option<&str>, tenant: Option<&Tenant>) -> Result<(), Box<dyn Error>> {
+    let (config, repo) = configured(tenant)?;
+    let (path, summary) = match file {
+        Some(path) => {
+            let summary = backup::write(&repo, BufWriter::new(std::fs::File::create(path)?))?;
+            (path.into(), summary)
+        }
+        None => backup::write_to_dir(&repo, &backup::dir_for(&config.backup_dir(), &repo))?,
+    };
+    eprintln!(
+        "backed up {} records to {}, sha256 {}",
+        summary.records,
+        path.display(),
+        summary.sha256
+    );
+    Ok(())
+}
+
+/// Restores a backup archive, replacing the repository's records unless
+/// `merge`.
+pub fn restore(
+    file: Option<&str>,
+    tenant: Option<&Tenant>,
+    merge: bool,
+) -> Result<(), Box<dyn Error>> {
+    let repo = repository(tenant)?;
+    let summary = match file {
+        Some(path) => backup::restore(&repo, std::fs::File::open(path)?, merge)?,
+        None => backup::restore(&repo, std::io::stdin(), merge)?,
+    };
+    eprintln!(
+        "restored {} records, sha256 {}",
+        summary.records, summary.sha256
+    );
+    Ok(())
+}
+
+/// Imports the Google contacts of the account the user grants access to,
+/// showing the code to grant it with and the import's progress.
+pub fn import_google(
+    owner: &str,
+    tenant: Option<&Tenant>,
+    import: ImportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let repo = Storage::new(repository(tenant)?, StorageMode::from_env());
+    let config = GoogleConfig::from_env()?.ok_or("GOOGLE_CLIENT_ID is not set")?;
+    let people = futures::executor::block_on(google::exchange(move |client| async move {
+        let code = google::start(&client, &config).await?;
+        eprintln!(
+            "to grant access, open {} and enter the code {}",
+            code.verification_url, code.user_code
+        );
+        let token = google::wait_for_token(&client, &config, &code).await?;
+        google::connections(&client, &config, &token, |fetched| {
+            eprintln!("fetched {} contacts", fetched)
+        })
+        .await
+    }))?;
+    let rows = google::rows(people);
+    let report = usecases::import_contacts("cli:import-google", Some(owner), rows, import, &repo);
+    print_report(&report, "contact", import);
+    Ok(())
+}
+
+/// Imports the people of the configured LDAP directory, showing the
+/// import's progress.
+pub fn import_ldap(
+    owner: &str,
+    tenant: Option<&Tenant>,
+    import: ImportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let repo = Storage::new(repository(tenant)?, StorageMode::from_env());
+    let config = LdapConfig::from_env()?.ok_or("LDAP_URL is not set")?;
+    let entries = ldap::entries(&config, |fetched| eprintln!("fetched {} entries", fetched))?;
+    let rows = ldap::rows(entries);
+    let report = usecases::import_contacts("cli:import-ldap", Some(owner), rows, import, &repo);
+    print_report(&report, "entry", import);
+    Ok(())
+}
+
+/// Prints the failures of an import, its preview for a dry run, and what
+/// it came to; `row` names what the rows were read from.
+fn print_report(report: &ImportReport, row: &str, import: ImportOptions) {
+    for error in &report.errors {
+        eprintln!("{} {}: {}", row, error.row, error.message);
+    }
+    for preview in &report.preview {
+        let action = format!("{:?}", preview.action).to_lowercase();
+        match &preview.conflicts_with {
+            Some(id) => eprintln!(
+                "{} {}: {} {}, conflicting with {}",
+                row, preview.row, action, preview.id, id
+            ),
+            None => eprintln!("{} {}: {} {}", row, preview.row, action, preview.id),
+        }
+    }
+    eprintln!(
+        "{} {} contacts, {} skipped, {} failed",
+        if import.dry_run {
+            "would import"
+        } else {
+            "imported"
+        },
+        report.imported,
+        report.skipped,
+        report.failed
+    );
+}
+
+/// Reads back every record of every kind, printing the ones that fail.
+pub fn check(tenant: Option<&Tenant>) -> Result<(), Box<dyn Error>> {
+    let repo = repository(tenant)?;
+    let mut corrupt = 0;
+    corrupt += check_kind::<Contact>(&repo)?;
+    corrupt += check_kind::<Group>(&repo)?;
+    corrupt += check_kind::<AuditEntry>(&repo)?;
+    corrupt += check_kind::<User>(&repo)?;
+    corrupt += check_kind::<Session>(&repo)?;
+    corrupt += check_kind::<RefreshToken>(&repo)?;
+    corrupt += check_kind::<RefreshFamily>(&repo)?;
+    corrupt += check_kind::<Webhook>(&repo)?;
+    corrupt += check_kind::<WebhookDelivery>(&repo)?;
+    corrupt += check_kind::<OutboxMessage>(&repo)?;
+    corrupt += check_kind::<StoredEvent>(&repo)?;
+    corrupt += check_kind::<Snapshot>(&repo)?;
+    corrupt += check_kind::<StoredResponse>(&repo)?;
+    corrupt += check_kind::<AvatarTask>(&repo)?;
+    corrupt += check_kind::<ContactIndex>(&repo)?;
+    if corrupt > 0 {
+        return Err(format!("corrupt records found: {}", corrupt).into());
+    }
+    eprintln!("no corrupt records");
+    Ok(())
+}
+
+fn check_kind<T>(repo: &FileRepository<'static>) -> Result<usize, Box<dyn Error>>
+where
+    T: DeserializeOwned + serde::Serialize + Entity,
+{
+    let mut corrupt = 0;
+    for key in repo.keys(T::KIND)? {
+        if let Err(e) = Repository::<T>::get(repo, &key) {
+            println!("{}/{}: {}", T::KIND, key, e);
+            corrupt += 1;
+        }
+    }
+    Ok(corrupt)
+}
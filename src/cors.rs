@@ -0,0 +1,228 @@
+//! Cross-origin resource sharing, so browser apps on other origins can call
+//! the API. Preflight requests are answered here; other requests from an
+//! allowed origin get the CORS headers added to their response, rejections
+//! by the inner middleware included, so the browser lets the app read them.
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap, HeaderValue, Method};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Ready};
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+const DEFAULT_HEADERS: &str =
+    "authorization, content-type, idempotency-key, traceparent, x-api-key, x-apollo-tracing, x-request-id, x-tenant-id";
+const EXPOSED_HEADERS: &str = "idempotent-replayed, retry-after, x-request-id";
+
+#[derive(Clone)]
+enum Origins {
+    Any,
+    List(HashSet<String>),
+}
+
+#[derive(Clone)]
+pub struct CorsConfig {
+    origins: Origins,
+    methods: HashSet<String>,
+    headers: HashSet<String>,
+    max_age: u32,
+}
+
+impl CorsConfig {
+    /// Enabled by `CORS_ALLOWED_ORIGINS`, a comma separated list of origins
+    /// or `*`. `CORS_ALLOWED_METHODS` (default `GET, POST`),
+    /// `CORS_ALLOWED_HEADERS` and `CORS_MAX_AGE` (seconds, default 3600)
+    /// tune the preflight answers. Credentials such as the session cookie
+    /// are only allowed for listed origins.
+    pub fn from_env() -> Result<Option<CorsConfig>, String> {
+        let origins = match std::env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(v) if v.trim() == "*" => Origins::Any,
+            Ok(v) => Origins::List(
+                v.split(',')
+                    .map(|o| o.trim().trim_end_matches('/').to_owned())
+                    .filter(|o| !o.is_empty())
+                    .collect(),
+            ),
+            Err(_) => return Ok(None),
+        };
+        let methods = std::env::var("CORS_ALLOWED_METHODS").unwrap_or_else(|_| "GET, POST".into());
+        let headers =
+            std::env::var("CORS_ALLOWED_HEADERS").unwrap_or_else(|_| DEFAULT_HEADERS.into());
+        let max_age = match std::env::var("CORS_MAX_AGE") {
+            Ok(v) => v
+                .trim()
+                .parse()
+                .map_err(|_| format!("CORS_MAX_AGE must be a number, got {:?}", v))?,
+            Err(_) => 3600,
+        };
+        Ok(Some(CorsConfig {
+            origins,
+            methods: list(&methods, |m| m.to_ascii_uppercase()),
+            headers: list(&headers, |h| h.to_ascii_lowercase()),
+            max_age,
+        }))
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        match &self.origins {
+            Origins::Any => true,
+            Origins::List(origins) => origins.contains(origin),
+        }
+    }
+
+    /// Whether a preflight asks for a method and headers that are allowed.
+    fn allows_preflight(&self, headers: &HeaderMap) -> bool {
+        let method = headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let requested = headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        self.methods.contains(method)
+            && list(requested, |h| h.to_ascii_lowercase()).is_subset(&self.headers)
+    }
+
+    fn add_headers(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        match self.origins {
+            Origins::Any => {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                    HeaderValue::from_static("*"),
+                );
+            }
+            Origins::List(_) => {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+                headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            }
+        }
+    }
+
+    fn preflight(&self, origin: HeaderValue) -> HttpResponse {
+        let mut resp = HttpResponse::NoContent()
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, join(&self.methods))
+            .header(header::ACCESS_CONTROL_ALLOW_HEADERS, join(&self.headers))
+            .header(header::ACCESS_CONTROL_MAX_AGE, self.max_age.to_string())
+            .finish();
+        self.add_headers(resp.headers_mut(), origin);
+        resp
+    }
+}
+
+fn list(value: &str, normalize: fn(&str) -> String) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(normalize)
+        .collect()
+}
+
+fn join(values: &HashSet<String>) -> String {
+    let mut values: Vec<_> = values.iter().map(|v| v.as_str()).collect();
+    values.sort_unstable();
+    values.join(", ")
+}
+
+/// Actix middleware applying the `CorsConfig`. It has to be the outermost
+/// middleware so preflights never reach authentication or rate limiting.
+pub struct Cors {
+    config: Rc<Option<CorsConfig>>,
+}
+
+impl Cors {
+    pub fn new(config: Option<CorsConfig>) -> Cors {
+        Cors {
+            config: Rc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    config: Rc<Option<CorsConfig>>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        let origin = match (self.config.as_ref(), origin) {
+            (Some(config), Some(origin)) if config.allows(origin.to_str().unwrap_or_default()) => {
+                origin
+            }
+            _ => return Box::pin(self.service.call(req)),
+        };
+
+        if req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            let config = self.config.as_ref().as_ref().unwrap();
+            let resp = if config.allows_preflight(req.headers()) {
+                config.preflight(origin)
+            } else {
+                debug!("rejected preflight from {:?}", origin);
+                HttpResponse::Forbidden().body("CORS request not allowed")
+            };
+            return Box::pin(ok(req.into_response(resp.into_body())));
+        }
+
+        let config = self.config.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            let config = config.as_ref().as_ref().unwrap();
+            config.add_headers(resp.headers_mut(), origin);
+            resp.headers_mut().insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+            Ok(resp)
+        })
+    }
+}
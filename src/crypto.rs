@@ -0,0 +1,468 @@
+//! The few primitives the auth code needs (SHA-256, HMAC-SHA256, PBKDF2 and
+//! RSA PKCS#1 v1.5 signature verification), kept small and dependency free.
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+        for (h, v) in h.iter_mut().zip(v.iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// PBKDF2-HMAC-SHA256 with a single 32 byte output block.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut input = salt.to_vec();
+    input.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac_sha256(password, &input);
+    let mut out = u;
+    for _ in 1..iterations {
+        u = hmac_sha256(password, &u);
+        for (o, b) in out.iter_mut().zip(u.iter()) {
+            *o ^= b;
+        }
+    }
+    out
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// An RSA public key, `n` and `e` as big-endian magnitudes.
+#[derive(Debug, Clone)]
+pub struct RsaPublicKey {
+    n: Vec<u8>,
+    e: Vec<u8>,
+}
+
+impl RsaPublicKey {
+    /// Parses a PEM encoded `PUBLIC KEY` (SubjectPublicKeyInfo) or
+    /// `RSA PUBLIC KEY` (PKCS#1).
+    pub fn from_pem(pem: &str) -> Result<RsaPublicKey, String> {
+        let body: String = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .map(|line| line.trim())
+            .collect();
+        let der = base64::decode(&body).map_err(|e| format!("invalid PEM: {}", e))?;
+        let mut outer = Der::new(&der).sequence()?;
+        let first = outer.peek_tag();
+        if first == Some(0x30) {
+            // SubjectPublicKeyInfo: skip the algorithm identifier and unwrap the bit string
+            outer.sequence()?;
+            let bits = outer.read(0x03)?;
+            let (_unused, key) = bits.split_first().ok_or("empty bit string")?;
+            let mut key = Der::new(key).sequence()?;
+            Self::from_parts(&mut key)
+        } else {
+            Self::from_parts(&mut outer)
+        }
+    }
+
+    fn from_parts(der: &mut Der<'_>) -> Result<RsaPublicKey, String> {
+        let n = strip_zeros(der.read(0x02)?).to_vec();
+        let e = strip_zeros(der.read(0x02)?).to_vec();
+        Ok(RsaPublicKey { n, e })
+    }
+
+    /// Verifies an RSASSA-PKCS1-v1_5 signature over the SHA-256 of `message`.
+    pub fn verify_sha256(&self, message: &[u8], signature: &[u8]) -> bool {
+        const DIGEST_INFO: [u8; 19] = [
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+            0x01, 0x05, 0x00, 0x04, 0x20,
+        ];
+        let k = self.n.len();
+        if signature.len() != k || k < DIGEST_INFO.len() + 32 + 11 {
+            return false;
+        }
+        let n = BigUint::from_be_bytes(&self.n);
+        let s = BigUint::from_be_bytes(signature);
+        if s.cmp(&n) != std::cmp::Ordering::Less {
+            return false;
+        }
+        let em = s
+            .modpow(&BigUint::from_be_bytes(&self.e), &n)
+            .to_be_bytes(k);
+
+        let mut expected = vec![0x00, 0x01];
+        expected.resize(k - DIGEST_INFO.len() - 32 - 1, 0xff);
+        expected.push(0x00);
+        expected.extend_from_slice(&DIGEST_INFO);
+        expected.extend_from_slice(&sha256(message));
+        constant_time_eq(&em, &expected)
+    }
+}
+
+fn strip_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// Just enough of a DER reader to walk an RSA public key.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn new(bytes: &'a [u8]) -> Der<'a> {
+        Der(bytes)
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    fn read(&mut self, tag: u8) -> Result<&'a [u8], String> {
+        let bytes = self.0;
+        if bytes.len() < 2 || bytes[0] != tag {
+            return Err(format!("expected DER tag {:#x}", tag));
+        }
+        let (len, header) = if bytes[1] & 0x80 == 0 {
+            (bytes[1] as usize, 2)
+        } else {
+            let n = (bytes[1] & 0x7f) as usize;
+            if n == 0 || n > 4 || bytes.len() < 2 + n {
+                return Err("unsupported DER length".to_string());
+            }
+            let len = bytes[2..2 + n]
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len, 2 + n)
+        };
+        if bytes.len() < header + len {
+            return Err("truncated DER".to_string());
+        }
+        self.0 = &bytes[header + len..];
+        Ok(&bytes[header..header + len])
+    }
+
+    fn sequence(&mut self) -> Result<Der<'a>, String> {
+        self.read(0x30).map(Der::new)
+    }
+}
+
+/// Unsigned big integer, little-endian 32-bit limbs. Only what modular
+/// exponentiation needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BigUint(Vec<u32>);
+
+impl BigUint {
+    fn from_be_bytes(bytes: &[u8]) -> BigUint {
+        let mut limbs: Vec<u32> = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+            .collect();
+        normalize(&mut limbs);
+        BigUint(limbs)
+    }
+
+    fn to_be_bytes(&self, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        for (i, limb) in self.0.iter().enumerate() {
+            for (j, b) in limb.to_le_bytes().iter().enumerate() {
+                let pos = i * 4 + j;
+                if pos < len {
+                    out[len - 1 - pos] = *b;
+                }
+            }
+        }
+        out
+    }
+
+    fn bits(&self) -> usize {
+        match self.0.last() {
+            Some(top) => self.0.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0
+            .get(i / 32)
+            .map(|limb| (limb >> (i % 32)) & 1 == 1)
+            .unwrap_or(false)
+    }
+
+    fn cmp(&self, other: &BigUint) -> std::cmp::Ordering {
+        self.0
+            .len()
+            .cmp(&other.0.len())
+            .then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+    }
+
+    fn mul(&self, other: &BigUint) -> BigUint {
+        let mut out = vec![0u32; self.0.len() + other.0.len()];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.0.iter().enumerate() {
+                let t = out[i + j] as u64 + (*a as u64) * (*b as u64) + carry;
+                out[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            out[i + other.0.len()] = carry as u32;
+        }
+        normalize(&mut out);
+        BigUint(out)
+    }
+
+    fn rem(&self, m: &BigUint) -> BigUint {
+        let mut r = BigUint(Vec::new());
+        for i in (0..self.bits()).rev() {
+            r.shl1(self.bit(i));
+            if r.cmp(m) != std::cmp::Ordering::Less {
+                r.sub_assign(m);
+            }
+        }
+        r
+    }
+
+    fn shl1(&mut self, low: bool) {
+        let mut carry = low as u32;
+        for limb in self.0.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            self.0.push(carry);
+        }
+    }
+
+    fn sub_assign(&mut self, other: &BigUint) {
+        let mut borrow = 0i64;
+        for i in 0..self.0.len() {
+            let b = other.0.get(i).copied().unwrap_or(0) as i64;
+            let mut t = self.0[i] as i64 - b - borrow;
+            borrow = 0;
+            if t < 0 {
+                t += 1 << 32;
+                borrow = 1;
+            }
+            self.0[i] = t as u32;
+        }
+        normalize(&mut self.0);
+    }
+
+    fn modpow(&self, exp: &BigUint, m: &BigUint) -> BigUint {
+        let base = self.rem(m);
+        let mut result = BigUint(vec![1]).rem(m);
+        for i in (0..exp.bits()).rev() {
+            result = result.mul(&result).rem(m);
+            if exp.bit(i) {
+                result = result.mul(&base).rem(m);
+            }
+        }
+        result
+    }
+}
+
+fn normalize(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_fips_180_4() {
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            to_hex(&sha256(&vec![b'a'; 1_000_000])),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        assert_eq!(
+            to_hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // A key longer than the block is hashed first.
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    /// The RFC 6070 inputs, with SHA-256 in place of SHA-1.
+    #[test]
+    fn pbkdf2_sha256_matches_the_rfc_6070_inputs() {
+        let cases: [(&[u8], &[u8], u32, &str); 4] = [
+            (
+                b"password",
+                b"salt",
+                1,
+                "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b",
+            ),
+            (
+                b"password",
+                b"salt",
+                2,
+                "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43",
+            ),
+            (
+                b"password",
+                b"salt",
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+            (
+                b"passwordPASSWORDpassword",
+                b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096,
+                "348c89dbcbd32b2f32d814b8116e84cf2b17347ebc1800181c4e2a1fb8dd53e1",
+            ),
+        ];
+        for (password, salt, iterations, expected) in &cases {
+            assert_eq!(
+                to_hex(&pbkdf2_sha256(password, salt, *iterations)),
+                *expected
+            );
+        }
+    }
+
+    const PUBLIC_KEY: &str = "\
+-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtHKEwcpq1wINQCRs2IuO
+GZjd/vGBZ06AeuTqFXXoxL3dVcf8i90mlC5GlA9VTgAqduwmpp5jmhR4o+eRuD4F
+DD4K1wuyCAL4Tqm0pwaZDxjv85gRmC3ntlDilVuVpbCI3aRkSLdYDA5g50Fp4ewA
+Jsd8RUoDXeFlbIlKlDmJQsBM9YkgOtL2ECVdy7w94OR3N3ee8XUq5Hqfx23BvKGr
+Zda6PoLNDreIOIvXRb8hbwJPHm0u2S4F9B9bZ2VqlvbDDsYCT6TQoJEhLq0WzPTG
+uHQ4lmpTKIdpmaou/2OvjHQtT3lMyaPNdVLpgEt9pZ1iiIM7WC+6XT41T+WwpuBa
+PwIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    /// Signed with `openssl dgst -sha256 -sign` by the key `PUBLIC_KEY`
+    /// belongs to.
+    const RS256_JWT: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.\
+        eyJzdWIiOiJhZGEiLCJyb2xlIjoiZWRpdG9yIiwic2NvcGUiOiJjb250YWN0czpyZWFkIGNvbnRhY3RzOnBpaSIsInRlbmFudCI6ImFjbWUiLCJleHAiOjQxMDI0NDQ4MDB9.\
+        IhvttzYxv3zMgQ8zRC0ab5DDuLo2r5OuZKi0vYTaq-r9q5XZkVO_IR9DNKMl41hJ-TUy8u51iModn7td6O69jv-yeKosRgNVmrqEFv7Ww9dRlRXVlk6CwBA5oVLQGwVHR5vxIVOJqqjmgrDRslfy87ZKNJIIgoYisHYQo6iWBT9qP6HctG4dBAPgAzshzfciMxFb_UeJhfYruJBk_HZJme43aihlR_82x3bDggpBpX8UTgOHKoy6CzsVgwpt3BV52UfPOJDW-xOjr9R_zzJUINjGMtXm4l7OrDWh00f7AdVC1WONWvhZsY8bPGzkjdDA-39XEmns588Mcr8M3WCpVA";
+
+    #[test]
+    fn rsa_verifies_a_fixed_rs256_jwt() {
+        let key = RsaPublicKey::from_pem(PUBLIC_KEY).unwrap();
+        let (signing_input, signature) = RS256_JWT.split_at(RS256_JWT.rfind('.').unwrap());
+        let signature = base64::decode_config(&signature[1..], base64::URL_SAFE_NO_PAD).unwrap();
+        assert!(key.verify_sha256(signing_input.as_bytes(), &signature));
+
+        let mut tampered = signing_input.as_bytes().to_vec();
+        tampered[40] ^= 1;
+        assert!(!key.verify_sha256(&tampered, &signature));
+        let mut forged = signature.clone();
+        forged[0] ^= 1;
+        assert!(!key.verify_sha256(signing_input.as_bytes(), &forged));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_lengths_and_bytes() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,360 @@
+//! Contacts as CSV, the way people bring their address books in. Fields are
+//! separated by commas and quoted with `"` where needed (RFC 4180). Which
+//! column holds which contact field is given by a column list such as
+//! `id,first_name,-,last_name`, `-` skipping a column; without one the
+//! header row names the columns, or the columns are `id,owner_id,
+//! first_name,last_name`. A first row naming the id, first and last name
+//! columns is taken for a header.
+
+use crate::models::Contact;
+use crate::usecases::ImportRow;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Id,
+    OwnerId,
+    FirstName,
+    LastName,
+}
+
+impl Field {
+    /// Accepts `first_name`, `firstName`, `First Name` and the like.
+    fn parse(name: &str) -> Option<Field> {
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match name.as_str() {
+            "id" => Some(Field::Id),
+            "owner" | "ownerid" => Some(Field::OwnerId),
+            "firstname" => Some(Field::FirstName),
+            "lastname" => Some(Field::LastName),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::OwnerId => "owner_id",
+            Field::FirstName => "first_name",
+            Field::LastName => "last_name",
+        }
+    }
+
+    fn of(self, contact: &Contact) -> &str {
+        match self {
+            Field::Id => &contact.id,
+            Field::OwnerId => &contact.owner_id,
+            Field::FirstName => &contact.first_name,
+            Field::LastName => &contact.last_name,
+        }
+    }
+
+    fn max_len(self) -> usize {
+        match self {
+            Field::Id | Field::OwnerId => 64,
+            Field::FirstName | Field::LastName => 100,
+        }
+    }
+}
+
+/// The contact field each column holds, `None` for skipped columns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns(Vec<Option<Field>>);
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns(vec![
+            Some(Field::Id),
+            Some(Field::OwnerId),
+            Some(Field::FirstName),
+            Some(Field::LastName),
+        ])
+    }
+}
+
+impl Columns {
+    /// Parses a comma separated column list.
+    pub fn parse(spec: &str) -> Result<Columns, String> {
+        let mut columns = vec![];
+        for name in spec.split(',').map(|n| n.trim()) {
+            columns.push(match name {
+                "-" => None,
+                name => {
+                    Some(Field::parse(name).ok_or_else(|| format!("unknown column {:?}", name))?)
+                }
+            });
+        }
+        let columns = Columns(columns);
+        columns.check()?;
+        Ok(columns)
+    }
+
+    /// The columns a header row names, if it names the required fields.
+    /// Columns it names no field for are skipped.
+    fn from_header(row: &[String]) -> Option<Columns> {
+        let columns = Columns(row.iter().map(|name| Field::parse(name)).collect());
+        columns.check_readable().ok()?;
+        Some(columns)
+    }
+
+    /// Whether contacts can be read with these columns.
+    fn check_readable(&self) -> Result<(), String> {
+        for field in &[Field::Id, Field::FirstName, Field::LastName] {
+            if !self.0.contains(&Some(*field)) {
+                return Err(format!("no column for {}", field.name()));
+            }
+        }
+        self.check()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let mut fields: Vec<_> = self.0.iter().flatten().collect();
+        fields.sort_by_key(|f| f.name());
+        fields.dedup();
+        if fields.len() != self.0.iter().flatten().count() {
+            return Err("a field is mapped to more than one column".to_owned());
+        }
+        Ok(())
+    }
+
+    fn contact(&self, row: &[String]) -> Result<Contact, String> {
+        if row.len() != self.0.len() {
+            return Err(format!(
+                "expected {} columns, got {}",
+                self.0.len(),
+                row.len()
+            ));
+        }
+        let mut contact = Contact::default();
+        for (field, value) in self.0.iter().zip(row) {
+            let field = match field {
+                Some(field) => *field,
+                None => continue,
+            };
+            let value = value.trim();
+            if value.chars().count() > field.max_len() {
+                return Err(format!(
+                    "{} is longer than {} characters",
+                    field.name(),
+                    field.max_len()
+                ));
+            }
+            let slot = match field {
+                Field::Id => &mut contact.id,
+                Field::OwnerId => &mut contact.owner_id,
+                Field::FirstName => &mut contact.first_name,
+                Field::LastName => &mut contact.last_name,
+            };
+            *slot = value.to_owned();
+        }
+        for field in &[Field::Id, Field::FirstName, Field::LastName] {
+            if field.of(&contact).is_empty() {
+                return Err(format!("{} is empty", field.name()));
+            }
+        }
+        Ok(contact)
+    }
+}
+
+/// Reads contacts, each with the number of the row it came from, or why the
+/// row isn't a valid contact. `header` says whether the first row is a
+/// header; `None` detects it.
+pub fn read_contacts(
+    input: &str,
+    columns: Option<Columns>,
+    header: Option<bool>,
+) -> Result<Vec<ImportRow>, String> {
+    let rows = parse(input)?;
+    let named = rows.first().and_then(|row| Columns::from_header(row));
+    let has_header = header.unwrap_or_else(|| named.is_some());
+    let columns = match (columns, has_header, named) {
+        (Some(columns), _, _) => {
+            columns.check_readable()?;
+            columns
+        }
+        (None, true, Some(named)) => named,
+        (None, true, None) => return Err("the header doesn't name the contact fields".to_owned()),
+        (None, false, _) => Columns::default(),
+    };
+    Ok(rows
+        .iter()
+        .enumerate()
+        .skip(if has_header { 1 } else { 0 })
+        .filter(|(_, row)| !(row.len() == 1 && row[0].trim().is_empty()))
+        .map(|(i, row)| (i + 1, columns.contact(row)))
+        .collect())
+}
+
+/// Writes a header row and a row per contact.
+pub fn write_contacts<W: Write>(
+    out: &mut W,
+    columns: &Columns,
+    contacts: &[Contact],
+) -> io::Result<()> {
+    let names: Vec<_> = columns
+        .0
+        .iter()
+        .map(|f| f.map(Field::name).unwrap_or(""))
+        .collect();
+    write_row(out, &names)?;
+    for contact in contacts {
+        let values: Vec<_> = columns
+            .0
+            .iter()
+            .map(|f| f.map(|f| f.of(contact)).unwrap_or(""))
+            .collect();
+        write_row(out, &values)?;
+    }
+    Ok(())
+}
+
+fn write_row<W: Write>(out: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if field.contains(&[',', '"', '\n', '\r'][..]) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\r\n")
+}
+
+/// Splits CSV text into rows of fields.
+fn parse(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut chars = input.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            c if quoted => field.push(c),
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                line += 1;
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("unterminated quoted field on line {}", line));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ContactFixture;
+
+    fn contacts(rows: Vec<ImportRow>) -> Vec<Contact> {
+        rows.into_iter().map(|(_, c)| c.unwrap()).collect()
+    }
+
+    #[test]
+    fn written_contacts_read_back_the_same() {
+        let written = vec![
+            ContactFixture::new("ada")
+                .name("Ada", "King, Countess")
+                .build(),
+            ContactFixture::new("grace")
+                .name("Grace \"Amazing\"", "Hopper")
+                .build(),
+            ContactFixture::new("alan")
+                .name("Alan", "Turing\nBletchley")
+                .build(),
+        ];
+        let mut out = vec![];
+        write_contacts(&mut out, &Columns::default(), &written).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("id,owner_id,first_name,last_name\r\n"));
+        assert!(text.contains("\"Grace \"\"Amazing\"\"\""));
+
+        let read = contacts(read_contacts(&text, None, None).unwrap());
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(&written) {
+            assert_eq!(
+                (&read.id, &read.first_name, &read.last_name),
+                (&written.id, &written.first_name, &written.last_name)
+            );
+        }
+    }
+
+    #[test]
+    fn reads_past_a_bom_quoted_newlines_and_blank_rows() {
+        let text =
+            "\u{feff}First Name,Last Name,ID\n\"Ada\",\"Love\nlace\",ada\n\nGrace,Hopper,grace\n";
+        let rows = read_contacts(text, None, None).unwrap();
+        let numbers: Vec<usize> = rows.iter().map(|(n, _)| *n).collect();
+        assert_eq!(numbers, vec![2, 4]);
+        let read = contacts(rows);
+        assert_eq!(read[0].last_name, "Love\nlace");
+        assert_eq!(read[1].id, "grace");
+    }
+
+    #[test]
+    fn reports_bad_rows_and_reads_the_rest() {
+        let long = "x".repeat(101);
+        let text = format!(
+            "ada,u1,Ada,Lovelace\ngrace,u1,Grace\n,u1,Alan,Turing\nbob,u1,{},Smith\n",
+            long
+        );
+        let rows = read_contacts(&text, None, Some(false)).unwrap();
+        let errors: Vec<(usize, String)> = rows
+            .iter()
+            .filter_map(|(n, r)| r.as_ref().err().map(|e| (*n, e.clone())))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "expected 4 columns, got 3".to_owned()),
+                (3, "id is empty".to_owned()),
+                (4, "first_name is longer than 100 characters".to_owned()),
+            ]
+        );
+        assert!(rows[0].1.is_ok());
+    }
+
+    #[test]
+    fn refuses_unterminated_quotes_and_bad_column_lists() {
+        assert_eq!(
+            read_contacts("ada,u1,\"Ada,Lovelace\n", None, Some(false)).unwrap_err(),
+            "unterminated quoted field on line 2"
+        );
+        assert!(Columns::parse("id,first_name,first_name").is_err());
+        assert!(Columns::parse("id,nickname").is_err());
+        let columns = Columns::parse("first_name,-,id,last_name").unwrap();
+        assert_eq!(
+            read_contacts("Ada,,ada,\"\"\n", Some(columns), Some(false)).unwrap()[0]
+                .1
+                .as_ref()
+                .unwrap_err(),
+            "last_name is empty"
+        );
+    }
+}
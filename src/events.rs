@@ -0,0 +1,403 @@
+//! Event-sourced storage for contacts. With `CONTACT_STORAGE=events` every
+//! change to a contact is appended to its stream as an event and the stored
+//! contact becomes a projection of that stream. Snapshots taken every
+//! `SNAPSHOT_EVERY` events keep point-in-time reads from replaying a whole
+//! stream.
+
+use crate::auth::now;
+use crate::idempotency::StoredResponse;
+use crate::models::*;
+use crate::outbox::OutboxMessage;
+use crate::repo::*;
+use futures::stream::LocalBoxStream;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const SNAPSHOT_EVERY: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    State,
+    Events,
+}
+
+impl StorageMode {
+    pub fn from_env() -> StorageMode {
+        match std::env::var("CONTACT_STORAGE") {
+            Ok(v) if v.eq_ignore_ascii_case("events") => StorageMode::Events,
+            _ => StorageMode::State,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ContactEvent {
+    ContactCreated {
+        contact: Contact,
+    },
+    FieldChanged {
+        field: String,
+        value: serde_json::Value,
+    },
+    ContactDeleted,
+}
+
+/// An event at position `seq` of the stream of the contact keyed `stream`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredEvent {
+    pub stream: String,
+    pub seq: u64,
+    pub at: u64,
+    pub event: ContactEvent,
+}
+
+impl Entity for StoredEvent {
+    const KIND: &'static str = "contact_events";
+
+    fn id(&self) -> &str {
+        &self.stream
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{:010}", self.stream, self.seq)
+    }
+}
+
+/// The state of a stream after event `seq`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub stream: String,
+    pub seq: u64,
+    pub at: u64,
+    pub state: Option<Contact>,
+}
+
+impl Entity for Snapshot {
+    const KIND: &'static str = "contact_snapshots";
+
+    fn id(&self) -> &str {
+        &self.stream
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{:010}", self.stream, self.seq)
+    }
+}
+
+/// Reads of a contact's past, only available from event-sourced storage.
+pub trait ContactHistory {
+    /// The contact as it was at unix time `at`, `None` if it didn't exist.
+    fn contact_at(&self, key: &str, at: u64) -> Result<Option<Contact>, Box<dyn Error>>;
+    /// Drops the stream and its snapshots for good.
+    fn forget(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Wraps a repository, storing contacts according to the `StorageMode` and
+/// passing every other entity straight through.
+#[derive(Clone)]
+pub struct Storage<R> {
+    inner: R,
+    mode: StorageMode,
+}
+
+impl<R> Storage<R>
+where
+    R: Repository<Contact> + Repository<StoredEvent> + Repository<Snapshot>,
+{
+    pub fn new(inner: R, mode: StorageMode) -> Storage<R> {
+        Storage { inner, mode }
+    }
+
+    /// Latest snapshot of the stream taken at or before `at`.
+    fn snapshot(&self, stream: &str, at: u64) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        let snapshots: Vec<Snapshot> = self.inner.list(stream)?;
+        Ok(snapshots
+            .into_iter()
+            .filter(|s| s.at <= at)
+            .max_by_key(|s| s.seq))
+    }
+
+    /// Replays the stream up to `at` and returns the state with the position
+    /// of the last event applied.
+    fn replay(&self, stream: &str, at: u64) -> Result<(Option<Contact>, u64), Box<dyn Error>> {
+        let (mut state, mut seq) = match self.snapshot(stream, at)? {
+            Some(snapshot) => (snapshot.state, snapshot.seq),
+            None => (None, 0),
+        };
+        while let Some(stored) = optional(Repository::<StoredEvent>::get(
+            &self.inner,
+            &format!("{}/{:010}", stream, seq + 1),
+        ))? {
+            if stored.at > at {
+                break;
+            }
+            state = apply(state, stored.event)?;
+            seq = stored.seq;
+        }
+        Ok((state, seq))
+    }
+
+    fn append(&self, stream: &str, events: Vec<ContactEvent>) -> Result<(), Box<dyn Error>> {
+        let (mut state, mut seq) = self.replay(stream, u64::MAX)?;
+        let at = now();
+        for event in events {
+            seq += 1;
+            state = apply(state, event.clone())?;
+            self.inner.set(StoredEvent {
+                stream: stream.to_owned(),
+                seq,
+                at,
+                event,
+            })?;
+            if seq % SNAPSHOT_EVERY == 0 {
+                self.inner.set(Snapshot {
+                    stream: stream.to_owned(),
+                    seq,
+                    at,
+                    state: state.clone(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A record that may be missing; errors other than its absence are passed
+/// on, so a stream that can't be read isn't mistaken for one that ended.
+fn optional<T>(result: Result<T, Box<dyn Error>>) -> Result<Option<T>, Box<dyn Error>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if is_not_found(e.as_ref()) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl<'a> Storage<FileRepository<'a>> {
+    /// Replays every stream and rewrites the contacts whose stored state
+    /// differs from it, returning how many were rewritten.
+    pub fn rebuild_projections(&self) -> Result<usize, Box<dyn Error>> {
+        let mut streams: Vec<String> = self
+            .inner
+            .keys(StoredEvent::KIND)?
+            .into_iter()
+            .filter_map(|key| key.rfind('/').map(|i| key[..i].to_owned()))
+            .collect();
+        streams.sort();
+        streams.dedup();
+        let mut rebuilt = 0;
+        for stream in streams {
+            let stored: Option<Contact> = optional(self.inner.get(&stream))?;
+            let replayed = self.replay(&stream, u64::MAX)?.0;
+            let same = match (&stored, &replayed) {
+                (Some(a), Some(b)) => serde_json::to_value(a)? == serde_json::to_value(b)?,
+                (None, None) => true,
+                _ => false,
+            };
+            if same {
+                continue;
+            }
+            match replayed {
+                Some(contact) => {
+                    self.inner.set(contact)?;
+                }
+                None => Repository::<Contact>::delete(&self.inner, &stream)?,
+            }
+            warn!("contact {} was behind its event stream, rebuilt", stream);
+            rebuilt += 1;
+        }
+        Ok(rebuilt)
+    }
+}
+
+fn apply(state: Option<Contact>, event: ContactEvent) -> Result<Option<Contact>, Box<dyn Error>> {
+    match event {
+        ContactEvent::ContactCreated { contact } => Ok(Some(contact)),
+        ContactEvent::ContactDeleted => Ok(None),
+        ContactEvent::FieldChanged { field, value } => match state {
+            Some(contact) => {
+                let mut fields = serde_json::to_value(contact)?;
+                fields[field.as_str()] = value;
+                Ok(Some(serde_json::from_value(fields)?))
+            }
+            None => Err(format!("{} changed on a missing contact", field).into()),
+        },
+    }
+}
+
+/// The events turning `before` into `after`.
+fn changes(before: &Contact, after: &Contact) -> Result<Vec<ContactEvent>, Box<dyn Error>> {
+    let (before, after) = match (serde_json::to_value(before)?, serde_json::to_value(after)?) {
+        (serde_json::Value::Object(b), serde_json::Value::Object(a)) => (b, a),
+        _ => return Ok(vec![]),
+    };
+    Ok(after
+        .into_iter()
+        .filter(|(field, value)| before.get(field) != Some(value))
+        .map(|(field, value)| ContactEvent::FieldChanged { field, value })
+        .collect())
+}
+
+impl<R> Repository<Contact> for Storage<R>
+where
+    R: Repository<Contact> + Repository<StoredEvent> + Repository<Snapshot>,
+{
+    fn set(&self, contact: Contact) -> Result<Contact, Box<dyn Error>> {
+        if self.mode == StorageMode::Events {
+            let stream = contact.key();
+            let events = match self.replay(&stream, u64::MAX)?.0 {
+                Some(current) => changes(&current, &contact)?,
+                None => vec![ContactEvent::ContactCreated {
+                    contact: contact.clone(),
+                }],
+            };
+            self.append(&stream, events)?;
+        }
+        self.inner.set(contact)
+    }
+
+    fn get(&self, key: &str) -> Result<Contact, Box<dyn Error>> {
+        self.inner.get(key)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        if self.mode == StorageMode::Events {
+            self.append(key, vec![ContactEvent::ContactDeleted])?;
+        }
+        Repository::<Contact>::delete(&self.inner, key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Contact>, Box<dyn Error>> {
+        self.inner.list(prefix)
+    }
+
+    fn stream(&self, prefix: &str) -> LocalBoxStream<'static, Result<Contact, Box<dyn Error>>> {
+        self.inner.stream(prefix)
+    }
+}
+
+impl<R> ContactHistory for Storage<R>
+where
+    R: Repository<Contact> + Repository<StoredEvent> + Repository<Snapshot>,
+{
+    fn contact_at(&self, key: &str, at: u64) -> Result<Option<Contact>, Box<dyn Error>> {
+        match self.mode {
+            StorageMode::Events => Ok(self.replay(key, at)?.0),
+            StorageMode::State => Err("point-in-time reads need CONTACT_STORAGE=events".into()),
+        }
+    }
+
+    fn forget(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let events: Vec<StoredEvent> = self.inner.list(key)?;
+        for event in events {
+            Repository::<StoredEvent>::delete(&self.inner, &event.key())?;
+        }
+        let snapshots: Vec<Snapshot> = self.inner.list(key)?;
+        for snapshot in snapshots {
+            Repository::<Snapshot>::delete(&self.inner, &snapshot.key())?;
+        }
+        Ok(())
+    }
+}
+
+/// Entities other than contacts are stored as they are.
+macro_rules! pass_through {
+    ($($entity:ty),+ $(,)?) => {
+        $(
+            impl<R: Repository<$entity>> Repository<$entity> for Storage<R> {
+                fn set(&self, obj: $entity) -> Result<$entity, Box<dyn Error>> {
+                    self.inner.set(obj)
+                }
+
+                fn get(&self, key: &str) -> Result<$entity, Box<dyn Error>> {
+                    self.inner.get(key)
+                }
+
+                fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+                    self.inner.delete(key)
+                }
+
+                fn list(&self, prefix: &str) -> Result<Vec<$entity>, Box<dyn Error>> {
+                    self.inner.list(prefix)
+                }
+
+                fn stream(
+                    &self,
+                    prefix: &str,
+                ) -> LocalBoxStream<'static, Result<$entity, Box<dyn Error>>> {
+                    self.inner.stream(prefix)
+                }
+            }
+        )+
+    };
+}
+
+pass_through!(
+    Group,
+    AuditEntry,
+    OutboxMessage,
+    Webhook,
+    WebhookDelivery,
+    User,
+    RefreshToken,
+    RefreshFamily,
+    Session,
+    AvatarTask,
+    ContactIndex,
+    StoredResponse
+);
+
+impl<R: Blobs> Blobs for Storage<R> {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.inner.put_blob(key, data)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.inner.get_blob(key)
+    }
+
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.inner.delete_blobs(key)
+    }
+}
+
+impl<R: Transactional> Transactional for Storage<R> {
+    type Transaction = Storage<R::Transaction>;
+
+    fn begin(&self) -> Self::Transaction {
+        Storage {
+            inner: self.inner.begin(),
+            mode: self.mode,
+        }
+    }
+
+    fn commit(&self, tx: Self::Transaction) -> Result<(), Box<dyn Error>> {
+        self.inner.commit(tx.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ContactFixture, MockRepository, Operation};
+
+    #[test]
+    fn unreadable_events_fail_the_replay() {
+        let repo = Storage::new(MockRepository::default(), StorageMode::Events);
+        repo.set(ContactFixture::new("ada").build()).unwrap();
+        repo.set(ContactFixture::new("ada").name("Ada", "King").build())
+            .unwrap();
+        assert_eq!(
+            repo.contact_at("u1/ada", u64::MAX)
+                .unwrap()
+                .unwrap()
+                .last_name,
+            "King"
+        );
+
+        repo.inner
+            .fail(Operation::Get, StoredEvent::KIND, "unreadable");
+        let err = repo.contact_at("u1/ada", u64::MAX).unwrap_err();
+        assert_eq!(err.to_string(), "unreadable");
+    }
+}
@@ -0,0 +1,356 @@
+//! Contacts imported from Google through the People API. Access is granted
+//! with OAuth's device flow (RFC 8628): the user opens the verification URL
+//! and enters the code shown, while the import polls for the token. Google
+//! contacts keep their resource name as id, `google-c123`, so importing
+//! again updates them; people sharing an email with one of the owner's
+//! other contacts are reported as duplicates and left out.
+//!
+//! Configured by `GOOGLE_CLIENT_ID` and `GOOGLE_CLIENT_SECRET`, of an OAuth
+//! client for limited input devices. Only plain HTTP is spoken, so
+//! `GOOGLE_OAUTH_URL` and `GOOGLE_PEOPLE_URL` have to name an egress proxy
+//! that forwards to `https://oauth2.googleapis.com` and
+//! `https://people.googleapis.com`.
+
+use crate::models::{Address, Contact, GoogleDeviceCode};
+use crate::usecases::ImportRow;
+use futures::channel::oneshot;
+use serde::Deserialize;
+use std::future::Future;
+use std::time::Duration;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/contacts.readonly";
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const PERSON_FIELDS: &str = "names,emailAddresses,phoneNumbers,addresses";
+const PAGE_SIZE: usize = 1000;
+/// Responses larger than this are refused.
+const MAX_RESPONSE_BYTES: usize = 16 << 20;
+const MAX_ID_LEN: usize = 64;
+
+#[derive(Clone)]
+pub struct GoogleConfig {
+    client_id: String,
+    client_secret: String,
+    oauth_url: String,
+    people_url: String,
+}
+
+impl GoogleConfig {
+    /// The configured client, `None` without `GOOGLE_CLIENT_ID`.
+    pub fn from_env() -> Result<Option<GoogleConfig>, String> {
+        let client_id = match std::env::var("GOOGLE_CLIENT_ID") {
+            Ok(id) if !id.trim().is_empty() => id.trim().to_owned(),
+            _ => return Ok(None),
+        };
+        let client_secret = std::env::var("GOOGLE_CLIENT_SECRET")
+            .map_err(|_| "GOOGLE_CLIENT_ID is set but GOOGLE_CLIENT_SECRET isn't".to_owned())?;
+        let url = |name: &str, default: &str| -> Result<String, String> {
+            let url = std::env::var(name).unwrap_or_else(|_| default.to_owned());
+            let url = url.trim().trim_end_matches('/').to_owned();
+            if url.starts_with("https://") {
+                return Err(format!(
+                    "{} over https needs a TLS-enabled build, point it at an egress proxy",
+                    name
+                ));
+            }
+            if !url.starts_with("http://") {
+                return Err(format!("{} must start with http://", name));
+            }
+            Ok(url)
+        };
+        Ok(Some(GoogleConfig {
+            client_id,
+            client_secret: client_secret.trim().to_owned(),
+            oauth_url: url("GOOGLE_OAUTH_URL", "https://oauth2.googleapis.com")?,
+            people_url: url("GOOGLE_PEOPLE_URL", "https://people.googleapis.com")?,
+        }))
+    }
+}
+
+/// Runs `run` with an HTTP client on a thread of its own. The client is
+/// tied to the actix system it's made on, which neither resolvers, whose
+/// futures must be `Send`, nor the command line can lend it.
+pub async fn exchange<F, Fut, T>(run: F) -> Result<T, String>
+where
+    F: FnOnce(awc::Client) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, String>> + 'static,
+    T: Send + 'static,
+{
+    let (done, result) = oneshot::channel();
+    std::thread::spawn(move || {
+        let mut sys = actix_rt::System::new("google");
+        let client = awc::Client::build()
+            .timeout(Duration::from_secs(30))
+            .finish();
+        let _ = done.send(sys.block_on(run(client)));
+    });
+    result
+        .await
+        .map_err(|_| "the Google exchange failed".to_owned())?
+}
+
+/// Asks for a device code for the user to approve.
+pub async fn start(
+    client: &awc::Client,
+    config: &GoogleConfig,
+) -> Result<GoogleDeviceCode, String> {
+    #[derive(Deserialize)]
+    struct Response {
+        device_code: String,
+        user_code: String,
+        verification_url: String,
+        expires_in: i32,
+        #[serde(default = "default_interval")]
+        interval: i32,
+    }
+    let mut response = client
+        .post(format!("{}/device/code", config.oauth_url))
+        .send_form(&[("client_id", config.client_id.as_str()), ("scope", SCOPE)])
+        .await
+        .map_err(|e| format!("requesting a device code failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "requesting a device code failed: {}",
+            response.status()
+        ));
+    }
+    let code: Response = response
+        .json()
+        .limit(MAX_RESPONSE_BYTES)
+        .await
+        .map_err(|e| format!("unreadable device code: {}", e))?;
+    Ok(GoogleDeviceCode {
+        device_code: code.device_code,
+        user_code: code.user_code,
+        verification_url: code.verification_url,
+        expires_in: code.expires_in,
+        interval: code.interval,
+    })
+}
+
+fn default_interval() -> i32 {
+    5
+}
+
+/// Where granting access stands.
+pub enum Grant {
+    /// The user hasn't approved yet; poll again after the interval.
+    Pending,
+    /// Polling too often; poll again after a longer interval.
+    SlowDown,
+    Token(String),
+}
+
+/// Asks once whether the user approved the device code.
+pub async fn poll(
+    client: &awc::Client,
+    config: &GoogleConfig,
+    device_code: &str,
+) -> Result<Grant, String> {
+    #[derive(Deserialize)]
+    struct Response {
+        access_token: Option<String>,
+        error: Option<String>,
+    }
+    let mut response = client
+        .post(format!("{}/token", config.oauth_url))
+        .send_form(&[
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("device_code", device_code),
+            ("grant_type", GRANT_TYPE),
+        ])
+        .await
+        .map_err(|e| format!("requesting a token failed: {}", e))?;
+    let status = response.status();
+    let body: Response = response
+        .json()
+        .limit(MAX_RESPONSE_BYTES)
+        .await
+        .map_err(|e| format!("unreadable token response ({}): {}", status, e))?;
+    match (body.access_token, body.error.as_deref()) {
+        (Some(token), _) if status.is_success() => Ok(Grant::Token(token)),
+        (_, Some("authorization_pending")) => Ok(Grant::Pending),
+        (_, Some("slow_down")) => Ok(Grant::SlowDown),
+        (_, Some("access_denied")) => Err("access was denied".to_owned()),
+        (_, Some("expired_token")) => Err("the device code expired".to_owned()),
+        (_, Some(e)) => Err(format!("requesting a token failed: {}", e)),
+        (_, None) => Err(format!("requesting a token failed: {}", status)),
+    }
+}
+
+/// Polls until the user approves the device code or it expires.
+pub async fn wait_for_token(
+    client: &awc::Client,
+    config: &GoogleConfig,
+    code: &GoogleDeviceCode,
+) -> Result<String, String> {
+    let mut interval = Duration::from_secs(code.interval.max(1) as u64);
+    loop {
+        tokio::time::delay_for(interval).await;
+        match poll(client, config, &code.device_code).await? {
+            Grant::Token(token) => return Ok(token),
+            Grant::Pending => {}
+            Grant::SlowDown => interval += Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Person {
+    resource_name: String,
+    names: Vec<Name>,
+    email_addresses: Vec<Value>,
+    phone_numbers: Vec<Value>,
+    addresses: Vec<PostalAddress>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Name {
+    display_name: String,
+    given_name: String,
+    family_name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Value {
+    value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct PostalAddress {
+    street_address: String,
+    city: String,
+    region: String,
+    postal_code: String,
+    country: String,
+}
+
+/// Every connection of the signed in user, a page at a time; `progress`
+/// hears how many were fetched so far.
+pub async fn connections(
+    client: &awc::Client,
+    config: &GoogleConfig,
+    token: &str,
+    mut progress: impl FnMut(usize),
+) -> Result<Vec<Person>, String> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Page {
+        #[serde(default)]
+        connections: Vec<Person>,
+        next_page_token: Option<String>,
+    }
+    let mut people = vec![];
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut url = format!(
+            "{}/v1/people/me/connections?personFields={}&pageSize={}",
+            config.people_url, PERSON_FIELDS, PAGE_SIZE
+        );
+        if let Some(page_token) = &page_token {
+            url.push_str("&pageToken=");
+            url.push_str(&encode(page_token));
+        }
+        let mut response = client
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| format!("fetching contacts failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("fetching contacts failed: {}", response.status()));
+        }
+        let page: Page = response
+            .json()
+            .limit(MAX_RESPONSE_BYTES)
+            .await
+            .map_err(|e| format!("unreadable contacts page: {}", e))?;
+        people.extend(page.connections);
+        progress(people.len());
+        match page.next_page_token {
+            Some(next) if !next.is_empty() => page_token = Some(next),
+            _ => return Ok(people),
+        }
+    }
+}
+
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn contact(person: Person) -> Result<Contact, String> {
+    let id = match person.resource_name.strip_prefix("people/") {
+        Some(id) if !id.is_empty() && !id.contains('/') => format!("google-{}", id),
+        _ => {
+            return Err(format!(
+                "unexpected resource name {:?}",
+                person.resource_name
+            ))
+        }
+    };
+    if id.len() > MAX_ID_LEN {
+        return Err(format!(
+            "resource name {:?} is too long",
+            person.resource_name
+        ));
+    }
+    let name = person.names.into_iter().next().unwrap_or_default();
+    let (mut first_name, mut last_name) = (name.given_name, name.family_name);
+    if first_name.trim().is_empty() && last_name.trim().is_empty() {
+        let mut words = name.display_name.trim().rsplitn(2, ' ');
+        last_name = words.next().unwrap_or_default().to_owned();
+        first_name = words.next().unwrap_or_default().to_owned();
+    }
+    let (first_name, last_name) = (first_name.trim().to_owned(), last_name.trim().to_owned());
+    if first_name.is_empty() && last_name.is_empty() {
+        return Err("person has no name".to_owned());
+    }
+    let values = |values: Vec<Value>| -> Vec<String> {
+        values
+            .into_iter()
+            .map(|v| v.value.trim().to_owned())
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+    Ok(Contact {
+        id,
+        owner_id: String::new(),
+        first_name,
+        last_name,
+        emails: values(person.email_addresses),
+        phones: values(person.phone_numbers),
+        addresses: person
+            .addresses
+            .into_iter()
+            .map(|a| Address {
+                street: a.street_address,
+                locality: a.city,
+                region: a.region,
+                postal_code: a.postal_code,
+                country: a.country,
+            })
+            .collect(),
+        avatar: None,
+    })
+}
+
+/// The people as numbered import rows.
+pub fn rows(people: Vec<Person>) -> Vec<ImportRow> {
+    people
+        .into_iter()
+        .enumerate()
+        .map(|(i, person)| (i + 1, contact(person)))
+        .collect()
+}
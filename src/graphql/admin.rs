@@ -0,0 +1,166 @@
+use super::contacts::owner;
+use super::directives::{Auth, RoleGuard};
+use super::{blocking, tenant_files, tenant_repo};
+use crate::auth::{CurrentUser, Role};
+use crate::backup::{self, BackupDir};
+use crate::cache::ResponseCache;
+use crate::google::{self, GoogleConfig, Grant};
+use crate::jobs::Jobs;
+use crate::logging::{self, Filter};
+use crate::models::*;
+use crate::usecases::{import_contacts, ImportOptions};
+use async_graphql::guard::Guard;
+use async_graphql::*;
+
+#[derive(Default)]
+pub struct AdminQuery;
+
+#[Object]
+impl AdminQuery {
+    /// How the last scheduled backup went; null before the first or without
+    /// scheduled backups.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn last_backup(&self) -> Option<BackupStatus> {
+        backup::last_scheduled()
+    }
+
+    /// The background jobs with how their runs went.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn jobs(&self, ctx: &Context<'_>) -> Vec<JobStatus> {
+        ctx.data_unchecked::<Jobs>().list()
+    }
+
+    /// What the server logs, like `info,repo=debug`.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn log_filter(&self) -> String {
+        logging::current_filter().to_string()
+    }
+}
+
+#[derive(Default)]
+pub struct AdminMutation;
+
+#[Object]
+impl AdminMutation {
+    /// Writes a backup of the repository into the server's backup directory.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn backup(&self, ctx: &Context<'_>) -> FieldResult<BackupReport> {
+        let repo = tenant_files(ctx)?;
+        let dir = backup::dir_for(&ctx.data_unchecked::<BackupDir>().0, &repo);
+        let (path, summary) = blocking(move || backup::write_to_dir(&repo, &dir)).await?;
+        Ok(BackupReport {
+            file: Some(path.display().to_string()),
+            records: summary.records as i32,
+            sha256: summary.sha256,
+        })
+    }
+
+    /// Restores an uploaded backup, replacing the stored records unless
+    /// `merge`, which keeps the records the backup doesn't have.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn restore(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "backup archive")] file: Upload,
+        #[arg(desc = "merge into the stored records", default = false)] merge: bool,
+    ) -> FieldResult<BackupReport> {
+        let repo = tenant_files(ctx)?;
+        let result = blocking(move || backup::restore(&repo, file.into_read(), merge)).await;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.clear();
+        }
+        let summary = result?;
+        Ok(BackupReport {
+            file: None,
+            records: summary.records as i32,
+            sha256: summary.sha256,
+        })
+    }
+
+    /// Changes what the server logs until the next change or config reload,
+    /// say to `info,repo=debug` while diagnosing an issue. Returns the filter
+    /// now in effect.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn set_log_filter(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "a level, then target=level for targets that differ")] filter: String,
+    ) -> FieldResult<String> {
+        let filter: Filter = match filter.parse() {
+            Ok(filter) => filter,
+            Err(e) => return Err(FieldError(e, None)),
+        };
+        let actor = &ctx.data_unchecked::<CurrentUser>().id;
+        warn!("log filter set to {} by {}", filter, actor);
+        logging::set_filter(filter.clone());
+        Ok(filter.to_string())
+    }
+
+    /// Starts importing Google contacts: the user grants access with the
+    /// returned code, then `finishGoogleImport` imports.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn start_google_import(&self, ctx: &Context<'_>) -> FieldResult<GoogleDeviceCode> {
+        let config = google_config(ctx)?.clone();
+        let start =
+            google::exchange(move |client| async move { google::start(&client, &config).await });
+        match start.await {
+            Ok(code) => Ok(code),
+            Err(e) => Err(FieldError(e, None)),
+        }
+    }
+
+    /// Imports the Google contacts of the account that granted access to
+    /// `deviceCode`, resolving conflicts with contacts by `onConflict`;
+    /// fails while access is still pending.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn finish_google_import(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "device code from startGoogleImport")] device_code: String,
+        #[arg(desc = "owner of the imported contacts")] owner_id: Option<String>,
+        #[arg(desc = "what to do with rows conflicting with a contact, OVERWRITE by default")]
+        on_conflict: Option<ConflictStrategy>,
+        #[arg(desc = "report what would be imported without writing")] dry_run: Option<bool>,
+    ) -> FieldResult<ImportReport> {
+        let config = google_config(ctx)?.clone();
+        let owner_id = owner(ctx, owner_id)?;
+        let options = ImportOptions {
+            strategy: on_conflict.unwrap_or_default(),
+            dry_run: dry_run.unwrap_or_default(),
+        };
+        let fetch = google::exchange(move |client| async move {
+            match google::poll(&client, &config, &device_code).await? {
+                Grant::Token(token) => google::connections(&client, &config, &token, |_| {}).await,
+                Grant::Pending | Grant::SlowDown => Err("access is not granted yet".to_owned()),
+            }
+        });
+        let people = match fetch.await {
+            Ok(people) => people,
+            Err(e) => return Err(FieldError(e, None)),
+        };
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let rows = google::rows(people);
+        let report = blocking(move || {
+            Ok(import_contacts(
+                &actor,
+                Some(&owner_id),
+                rows,
+                options,
+                &repo,
+            ))
+        })
+        .await?;
+        if report.imported > 0 && !options.dry_run {
+            if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+                cache.invalidate("Contact");
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn google_config<'a>(ctx: &'a Context<'_>) -> FieldResult<&'a GoogleConfig> {
+    ctx.data_opt::<GoogleConfig>()
+        .ok_or_else(|| FieldError("Google import is not configured".to_owned(), None))
+}
@@ -0,0 +1,597 @@
+use super::directives::{forbidden, Auth, Length, RoleGuard, ScopeGuard, Trimmed};
+use super::{blocking, blocking_async, tenant_repo, Page};
+use crate::auth::{CurrentUser, Role, PII_SCOPE};
+use crate::cache::*;
+use crate::csv;
+use crate::i18n;
+use crate::models::*;
+use crate::repo::Transactional;
+use crate::stats;
+use crate::usecases::*;
+use crate::vcard;
+use async_graphql::connection::{self, Connection, Edge, EmptyFields};
+use async_graphql::guard::Guard;
+use async_graphql::*;
+use std::io::Read;
+
+#[derive(Default)]
+pub struct ContactsQuery;
+
+#[Object]
+impl ContactsQuery {
+    #[field(guard(Auth()), cache_control(max_age = 60))]
+    async fn contact(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        if let Some(tags) = ctx.data_opt::<CacheTags>() {
+            tags.record("Contact");
+        }
+        blocking(move || get(&owner_id, &id, &repo)).await
+    }
+
+    /// An address book by id, a page at a time: `first` contacts, at most
+    /// and by default the server's page size, after the `after` cursor.
+    #[field(guard(Auth()), cache_control(max_age = 60))]
+    async fn contacts(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+        #[arg(desc = "contacts to return, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "the cursor of the contact to start after")] after: Option<String>,
+    ) -> FieldResult<Connection<String, Contact, EmptyFields, EmptyFields>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let size = Page::new(ctx, first, None)?.size;
+        let repo = tenant_repo(ctx);
+        if let Some(tags) = ctx.data_opt::<CacheTags>() {
+            tags.record("Contact");
+        }
+        connection::query(after, None, None, None, |after, _, _, _| async move {
+            let started_after = after.is_some();
+            let (contacts, more) = blocking_async(move || async move {
+                contacts_after(&owner_id, after.as_deref(), size, &repo).await
+            })
+            .await?;
+            let mut connection = Connection::new(started_after, more);
+            connection.append(contacts.into_iter().map(|c| Edge::new(c.id.clone(), c)));
+            Ok(connection)
+        })
+        .await
+    }
+
+    #[field(
+        guard(Auth()),
+        cache_control(max_age = 60),
+        deprecation = "use contact"
+    )]
+    async fn get(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        self.contact(ctx, id, owner_id).await
+    }
+
+    /// The contact as it was at a unix timestamp; needs event-sourced
+    /// storage.
+    #[field(guard(Auth()))]
+    async fn contact_at(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "unix timestamp in seconds")] timestamp: i64,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Option<Contact>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        blocking(move || contact_at(&owner_id, &id, timestamp.max(0) as u64, &repo)).await
+    }
+
+    /// Who changed a contact, when and what, oldest revision first.
+    #[field(guard(Auth()))]
+    async fn contact_history(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+        #[arg(desc = "revisions to return, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "revisions to skip")] offset: Option<i32>,
+    ) -> FieldResult<Vec<Revision>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        let history = blocking(move || contact_history(&owner_id, &id, &repo)).await?;
+        Ok(page.of(history))
+    }
+
+    /// Every recorded change to a contact, oldest first.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn audit_log(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "contact id")] contact_id: String,
+        #[arg(desc = "owner")] owner_id: Option<String>,
+        #[arg(desc = "entries to return, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "entries to skip")] offset: Option<i32>,
+    ) -> FieldResult<Vec<AuditEntry>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        let entries = blocking(move || audit_log(&owner_id, &contact_id, &repo)).await?;
+        Ok(page.of(entries))
+    }
+
+    /// Numbers about an address book, with contacts created on each of the
+    /// last `days` days (30 by default).
+    #[field(guard(Auth()))]
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "days of created contacts, today included")] days: Option<i32>,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<ContactStats> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        blocking(move || stats::contact_stats(&owner_id, days.unwrap_or(30), &repo)).await
+    }
+
+    /// How many of an address book's contacts are in each group or country.
+    #[field(guard(Auth()))]
+    async fn contacts_aggregate(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "what to bucket contacts by")] group_by: AggregateBy,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Vec<Bucket>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        blocking(move || stats::aggregate(&owner_id, group_by, &repo)).await
+    }
+
+    /// Everything stored about a contact, as one JSON document.
+    #[field(guard(
+        Auth(),
+        RoleGuard(role = "Role::Admin"),
+        ScopeGuard(scope = "PII_SCOPE")
+    ))]
+    async fn export_contact_data(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner")] owner_id: Option<String>,
+    ) -> FieldResult<OutputJson<ContactExport>> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let export = blocking(move || export_contact(&owner_id, &id, &repo)).await?;
+        Ok(OutputJson(export))
+    }
+
+    /// A contact, or without `id` a page of the address book by id, as
+    /// vCards.
+    #[field(guard(Auth(), ScopeGuard(scope = "PII_SCOPE")))]
+    async fn export_vcard(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: Option<String>,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+        #[arg(desc = "contacts to export, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "contacts to skip")] offset: Option<i32>,
+    ) -> FieldResult<String> {
+        let owner_id = owner(ctx, owner_id)?;
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        let contacts = blocking(move || match id {
+            Some(id) => get(&owner_id, &id, &repo).map(|c| vec![c]),
+            None => list_contacts(&owner_id, &repo).map(|all| page.of(all)),
+        })
+        .await?;
+        Ok(contacts.iter().map(vcard::write).collect())
+    }
+
+    /// A page of an address book by id as CSV with a header row. `columns`
+    /// lists the fields to write, e.g. `id,first_name,last_name`.
+    #[field(guard(
+        Auth(),
+        RoleGuard(role = "Role::Admin"),
+        ScopeGuard(scope = "PII_SCOPE")
+    ))]
+    async fn export_contacts_csv(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "owner")] owner_id: Option<String>,
+        #[arg(desc = "comma separated fields, `-` for an empty column")] columns: Option<String>,
+        #[arg(desc = "contacts to export, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "contacts to skip")] offset: Option<i32>,
+    ) -> FieldResult<String> {
+        let owner_id = owner(ctx, owner_id)?;
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        let columns = match columns {
+            Some(spec) => csv::Columns::parse(&spec).map_err(|e| FieldError(e, None))?,
+            None => csv::Columns::default(),
+        };
+        let contacts = blocking(move || list_contacts(&owner_id, &repo)).await?;
+        let contacts = page.of(contacts);
+        let mut out = vec![];
+        csv::write_contacts(&mut out, &columns, &contacts)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+/// The address book an operation applies to: the caller's own, or for admins
+/// any owner they name.
+pub(super) fn owner(ctx: &Context<'_>, requested: Option<String>) -> FieldResult<String> {
+    let user = ctx.data_unchecked::<CurrentUser>();
+    match requested {
+        Some(owner_id) if owner_id != user.id && user.role < Role::Admin => {
+            Err(forbidden(i18n::text("forbidden_owner", &[])))
+        }
+        Some(owner_id) => Ok(owner_id),
+        None => Ok(user.id.clone()),
+    }
+}
+
+#[SimpleObject]
+struct QueryContact {
+    first_name: String,
+    last_name: String,
+}
+
+impl std::convert::From<Contact> for QueryContact {
+    fn from(c: Contact) -> Self {
+        Self {
+            first_name: c.first_name,
+            last_name: c.last_name,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ContactsMutation;
+
+#[Object]
+impl ContactsMutation {
+    /// Creates the contact, or replaces the one with its id.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn create_contact(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "contact")] contact: MutationCreate,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let contact = contact.into();
+        let c = blocking(move || repo.with_tx(|tx| create(&actor, &owner_id, contact, tx))).await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Contact");
+        }
+        Ok(c)
+    }
+
+    /// Returns the names only.
+    #[field(
+        guard(Auth(), RoleGuard(role = "Role::Editor")),
+        deprecation = "use createContact, which returns the whole contact"
+    )]
+    async fn create(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "contact")] contact: MutationCreate,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<QueryContact> {
+        Ok(self.create_contact(ctx, contact, owner_id).await?.into())
+    }
+
+    /// Changes the fields `patch` sets and keeps the others; null clears a
+    /// list field.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn update_contact_partial(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "fields to change")] patch: ContactPatchInput,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let patch = patch.into_patch()?;
+        let c =
+            blocking(move || repo.with_tx(|tx| update_partial(&actor, &owner_id, &id, patch, tx)))
+                .await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Contact");
+        }
+        Ok(c)
+    }
+
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn delete_contact(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let c = blocking(move || repo.with_tx(|tx| delete(&actor, &owner_id, &id, tx))).await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Contact");
+        }
+        Ok(c)
+    }
+
+    #[field(
+        guard(Auth(), RoleGuard(role = "Role::Admin")),
+        deprecation = "use deleteContact"
+    )]
+    async fn delete(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Contact> {
+        self.delete_contact(ctx, id, owner_id).await
+    }
+
+    /// Imports contacts from CSV. Without `columns` a header row names the
+    /// columns, or they are `id,owner_id,first_name,last_name`; `header`
+    /// defaults to detecting one. Rows without an owner go to `ownerId`.
+    /// A row with the id or an email of a contact is resolved by
+    /// `onConflict`.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    #[allow(clippy::too_many_arguments)]
+    async fn import_contacts_csv(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "CSV text")] csv: String,
+        #[arg(desc = "comma separated fields, `-` to skip a column")] columns: Option<String>,
+        #[arg(desc = "whether the first row is a header")] header: Option<bool>,
+        #[arg(desc = "owner")] owner_id: Option<String>,
+        #[arg(desc = "what to do with rows conflicting with a contact, OVERWRITE by default")]
+        on_conflict: Option<ConflictStrategy>,
+        #[arg(desc = "report what would be imported without writing")] dry_run: Option<bool>,
+    ) -> FieldResult<ImportReport> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let options = ImportOptions {
+            strategy: on_conflict.unwrap_or_default(),
+            dry_run: dry_run.unwrap_or_default(),
+        };
+        let columns = match columns {
+            Some(spec) => Some(csv::Columns::parse(&spec).map_err(|e| FieldError(e, None))?),
+            None => None,
+        };
+        let rows = csv::read_contacts(&csv, columns, header).map_err(|e| FieldError(e, None))?;
+        let report = blocking(move || {
+            Ok(import_contacts(
+                &actor,
+                Some(&owner_id),
+                rows,
+                options,
+                &repo,
+            ))
+        })
+        .await?;
+        if report.imported > 0 && !options.dry_run {
+            if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+                cache.invalidate("Contact");
+            }
+        }
+        Ok(report)
+    }
+
+    /// Imports the cards of an uploaded .vcf file. Cards conflict with the
+    /// contacts with their `UID` as id, or sharing an email.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn import_vcard(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "vCard 3.0 or 4.0 file")] file: Upload,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+        #[arg(desc = "what to do with rows conflicting with a contact, OVERWRITE by default")]
+        on_conflict: Option<ConflictStrategy>,
+        #[arg(desc = "report what would be imported without writing")] dry_run: Option<bool>,
+    ) -> FieldResult<ImportReport> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let options = ImportOptions {
+            strategy: on_conflict.unwrap_or_default(),
+            dry_run: dry_run.unwrap_or_default(),
+        };
+        let report = blocking(move || {
+            let mut input = String::new();
+            file.into_read().read_to_string(&mut input)?;
+            Ok(import_contacts(
+                &actor,
+                Some(&owner_id),
+                vcard::read(&input),
+                options,
+                &repo,
+            ))
+        })
+        .await?;
+        if report.imported > 0 && !options.dry_run {
+            if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+                cache.invalidate("Contact");
+            }
+        }
+        Ok(report)
+    }
+
+    /// Uploads a PNG as the contact's avatar. Its thumbnails are made in the
+    /// background; `avatarUrl` serves the previous avatar until then.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn upload_avatar(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner, admins only")] owner_id: Option<String>,
+        #[arg(desc = "PNG image")] file: Upload,
+    ) -> FieldResult<Contact> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        let c = blocking(move || {
+            let mut image = vec![];
+            file.into_read().read_to_end(&mut image)?;
+            upload_avatar(&actor, &owner_id, &id, &image, &repo)
+        })
+        .await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Contact");
+        }
+        Ok(c)
+    }
+
+    /// Hard-deletes a contact together with the data derived from it.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn erase_contact(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+        #[arg(desc = "owner")] owner_id: Option<String>,
+    ) -> FieldResult<bool> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let actor = ctx.data_unchecked::<CurrentUser>().id.clone();
+        blocking(move || erase_contact(&actor, &owner_id, &id, &repo)).await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Contact");
+            cache.invalidate("Group");
+        }
+        Ok(true)
+    }
+}
+
+#[InputObject]
+struct MutationCreate {
+    #[field(validator(Length(min = "1", max = "64")))]
+    id: Trimmed,
+    #[field(validator(Length(min = "1", max = "100")))]
+    first_name: Trimmed,
+    #[field(validator(Length(min = "1", max = "100")))]
+    last_name: Trimmed,
+    #[field(default)]
+    emails: Vec<String>,
+    #[field(default)]
+    phones: Vec<String>,
+    #[field(default)]
+    addresses: Vec<AddressInput>,
+}
+
+#[InputObject]
+struct AddressInput {
+    #[field(default)]
+    street: String,
+    #[field(default)]
+    locality: String,
+    #[field(default)]
+    region: String,
+    #[field(default)]
+    postal_code: String,
+    #[field(default)]
+    country: String,
+}
+
+/// The fields of a partial update. Omitted fields are kept; null clears
+/// a list, while names can only be replaced. The validator rejects a null
+/// list written inline, so null has to come in a variable; `phones: []`
+/// clears too.
+#[InputObject]
+struct ContactPatchInput {
+    first_name: MaybeUndefined<Trimmed>,
+    last_name: MaybeUndefined<Trimmed>,
+    emails: MaybeUndefined<Vec<String>>,
+    phones: MaybeUndefined<Vec<String>>,
+    addresses: MaybeUndefined<Vec<AddressInput>>,
+}
+
+impl ContactPatchInput {
+    fn into_patch(self) -> FieldResult<ContactPatch> {
+        let name = |field: &str, value: MaybeUndefined<Trimmed>| match value {
+            MaybeUndefined::Undefined => Ok(None),
+            MaybeUndefined::Value(Trimmed(name)) if (1..=100).contains(&name.chars().count()) => {
+                Ok(Some(name))
+            }
+            _ => Err(FieldError(
+                i18n::text("name_length", &[("field", &field)]),
+                None,
+            )),
+        };
+        fn list<T, U: From<T>>(value: MaybeUndefined<Vec<T>>) -> Option<Vec<U>> {
+            match value {
+                MaybeUndefined::Undefined => None,
+                MaybeUndefined::Null => Some(vec![]),
+                MaybeUndefined::Value(values) => Some(values.into_iter().map(U::from).collect()),
+            }
+        }
+        Ok(ContactPatch {
+            first_name: name("firstName", self.first_name)?,
+            last_name: name("lastName", self.last_name)?,
+            emails: list(self.emails),
+            phones: list(self.phones),
+            addresses: list(self.addresses),
+        })
+    }
+}
+
+impl From<AddressInput> for Address {
+    fn from(a: AddressInput) -> Self {
+        Self {
+            street: a.street,
+            locality: a.locality,
+            region: a.region,
+            postal_code: a.postal_code,
+            country: a.country,
+        }
+    }
+}
+
+impl std::convert::From<Contact> for MutationCreate {
+    fn from(c: Contact) -> Self {
+        Self {
+            id: Trimmed(c.id),
+            first_name: Trimmed(c.first_name),
+            last_name: Trimmed(c.last_name),
+            emails: c.emails,
+            phones: c.phones,
+            addresses: c
+                .addresses
+                .into_iter()
+                .map(|a| AddressInput {
+                    street: a.street,
+                    locality: a.locality,
+                    region: a.region,
+                    postal_code: a.postal_code,
+                    country: a.country,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::convert::From<MutationCreate> for Contact {
+    fn from(c: MutationCreate) -> Self {
+        Self {
+            id: c.id.into(),
+            owner_id: String::new(),
+            first_name: c.first_name.into(),
+            last_name: c.last_name.into(),
+            emails: c.emails,
+            phones: c.phones,
+            addresses: c.addresses.into_iter().map(Address::from).collect(),
+            avatar: None,
+        }
+    }
+}
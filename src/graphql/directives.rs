@@ -0,0 +1,141 @@
+//! Schema directives. async-graphql has no hook for custom executable
+//! directives, so each one maps onto an extension point it already has:
+//! `@auth`, `@hasRole` and `@hasScope` are field guards, `@mask` a function
+//! the resolver calls, `@length` an input validator and `@trim` a scalar
+//! that trims on parse.
+
+use crate::auth::{CurrentUser, Role};
+use crate::i18n;
+use async_graphql::guard::Guard;
+use async_graphql::validators::InputValueValidator;
+use async_graphql::*;
+
+/// Directive definitions prepended to the exported SDL.
+pub const SDL: &str = "\
+directive @auth on FIELD_DEFINITION
+directive @hasRole(role: String!) on FIELD_DEFINITION
+directive @hasScope(scope: String!) on FIELD_DEFINITION
+directive @mask(scope: String!) on FIELD_DEFINITION
+directive @length(min: Int, max: Int) on ARGUMENT_DEFINITION | INPUT_FIELD_DEFINITION
+directive @trim on ARGUMENT_DEFINITION | INPUT_FIELD_DEFINITION
+";
+
+/// `@auth`: the field is only resolved for authenticated callers.
+pub struct Auth;
+
+#[async_trait::async_trait]
+impl Guard for Auth {
+    async fn check(&self, ctx: &Context<'_>) -> FieldResult<()> {
+        match ctx.data_opt::<CurrentUser>() {
+            Some(_) => Ok(()),
+            None => Err(unauthenticated()),
+        }
+    }
+}
+
+/// `@hasRole(role)`: the caller needs at least `role`.
+pub struct RoleGuard {
+    pub role: Role,
+}
+
+#[async_trait::async_trait]
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> FieldResult<()> {
+        match ctx.data_opt::<CurrentUser>() {
+            Some(user) if user.role >= self.role => Ok(()),
+            Some(_) => Err(forbidden(i18n::text(
+                "forbidden_role",
+                &[("role", &self.role)],
+            ))),
+            None => Err(unauthenticated()),
+        }
+    }
+}
+
+/// `@hasScope(scope)`: the caller's token needs to carry `scope`.
+pub struct ScopeGuard {
+    pub scope: &'static str,
+}
+
+#[async_trait::async_trait]
+impl Guard for ScopeGuard {
+    async fn check(&self, ctx: &Context<'_>) -> FieldResult<()> {
+        match ctx.data_opt::<CurrentUser>() {
+            Some(user) if user.has_scope(self.scope) => Ok(()),
+            Some(_) => Err(forbidden(i18n::text(
+                "forbidden_scope",
+                &[("scope", &self.scope)],
+            ))),
+            None => Err(unauthenticated()),
+        }
+    }
+}
+
+/// `@mask(scope)`: the field is null for callers without `scope`. A guard
+/// would fail the whole response, where a masked field leaves the rest of
+/// the object readable.
+pub fn masked<'a, T: ?Sized>(ctx: &Context<'_>, scope: &str, value: &'a T) -> Option<&'a T> {
+    match ctx.data_opt::<CurrentUser>() {
+        Some(user) if user.has_scope(scope) => Some(value),
+        _ => None,
+    }
+}
+
+pub fn forbidden(message: String) -> FieldError {
+    FieldError(message, Some(serde_json::json!({ "code": "FORBIDDEN" })))
+}
+
+fn unauthenticated() -> FieldError {
+    FieldError(
+        i18n::text("unauthenticated", &[]),
+        Some(serde_json::json!({ "code": "UNAUTHENTICATED" })),
+    )
+}
+
+/// `@length(min, max)`: bounds the number of characters of a string input.
+/// The length is measured after trimming, so `@trim @length(min: 1)` rejects
+/// blank values.
+pub struct Length {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl InputValueValidator for Length {
+    fn is_valid(&self, value: &Value) -> Option<String> {
+        if let Value::String(s) = value {
+            let len = s.trim().chars().count();
+            if len < self.min as usize || len > self.max as usize {
+                return Some(i18n::text(
+                    "value_length",
+                    &[("len", &len), ("min", &self.min), ("max", &self.max)],
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// `@trim`: a string input with surrounding whitespace removed. It is
+/// registered under the built-in `String` name so the schema is unchanged.
+#[derive(Debug, Clone)]
+pub struct Trimmed(pub String);
+
+#[Scalar(name = "String")]
+impl ScalarType for Trimmed {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match value {
+            Value::String(s) => Ok(Trimmed(s.trim().to_owned())),
+            _ => Err(InputValueError::ExpectedType(value)),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.0.clone())
+    }
+}
+
+impl From<Trimmed> for String {
+    fn from(t: Trimmed) -> Self {
+        t.0
+    }
+}
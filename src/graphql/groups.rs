@@ -0,0 +1,84 @@
+use super::contacts::owner;
+use super::directives::{Auth, Length, RoleGuard, Trimmed};
+use super::{blocking, tenant_repo};
+use crate::auth::Role;
+use crate::cache::*;
+use crate::models::*;
+use crate::repo::Transactional;
+use crate::usecases::*;
+use async_graphql::guard::Guard;
+use async_graphql::*;
+
+#[derive(Default)]
+pub struct GroupsQuery;
+
+#[Object]
+impl GroupsQuery {
+    #[field(guard(Auth()), cache_control(max_age = 60))]
+    async fn group(&self, ctx: &Context<'_>, #[arg(desc = "id")] id: String) -> FieldResult<Group> {
+        let repo = tenant_repo(ctx);
+        if let Some(tags) = ctx.data_opt::<CacheTags>() {
+            tags.record("Group");
+        }
+        blocking(move || get_group(&id, &repo)).await
+    }
+}
+
+#[derive(Default)]
+pub struct GroupsMutation;
+
+#[Object]
+impl GroupsMutation {
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn create_group(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "group")] group: MutationCreateGroup,
+    ) -> FieldResult<Group> {
+        let repo = tenant_repo(ctx);
+        let group = group.into();
+        let g = blocking(move || create_group(group, &repo)).await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Group");
+        }
+        Ok(g)
+    }
+
+    #[field(guard(Auth(), RoleGuard(role = "Role::Editor")))]
+    async fn add_group_member(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "group id")] group_id: String,
+        #[arg(desc = "contact id")] contact_id: String,
+        #[arg(desc = "contact owner, admins only")] owner_id: Option<String>,
+    ) -> FieldResult<Group> {
+        let owner_id = owner(ctx, owner_id)?;
+        let repo = tenant_repo(ctx);
+        let g = blocking(move || {
+            repo.with_tx(|tx| add_group_member(&group_id, &owner_id, &contact_id, tx))
+        })
+        .await?;
+        if let Some(cache) = ctx.data_opt::<ResponseCache>() {
+            cache.invalidate("Group");
+        }
+        Ok(g)
+    }
+}
+
+#[InputObject]
+struct MutationCreateGroup {
+    #[field(validator(Length(min = "1", max = "64")))]
+    id: Trimmed,
+    #[field(validator(Length(min = "1", max = "100")))]
+    name: Trimmed,
+}
+
+impl std::convert::From<MutationCreateGroup> for Group {
+    fn from(g: MutationCreateGroup) -> Self {
+        Self {
+            id: g.id.into(),
+            name: g.name.into(),
+            member_ids: Vec::new(),
+        }
+    }
+}
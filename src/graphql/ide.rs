@@ -0,0 +1,93 @@
+//! The pages of the IDEs served on `GET /`. Endpoints are passed as paths
+//! and resolved against the page's own URL in the browser, so the pages
+//! hold no host taken from the request.
+
+use crate::settings::Ide;
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+
+const GRAPHIQL: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>GraphiQL</title>
+    <link rel="stylesheet" href="https://unpkg.com/graphiql@2/graphiql.min.css" />
+  </head>
+  <body style="margin: 0;">
+    <div id="graphiql" style="height: 100vh;"></div>
+    <script crossorigin src="https://unpkg.com/react@18/umd/react.production.min.js"></script>
+    <script crossorigin src="https://unpkg.com/react-dom@18/umd/react-dom.production.min.js"></script>
+    <script crossorigin src="https://unpkg.com/subscriptions-transport-ws@0.9/browser/client.js"></script>
+    <script crossorigin src="https://unpkg.com/graphiql@2/graphiql.min.js"></script>
+    <script>
+      URLS
+      var options = { url: endpoint };
+      if (subscriptions) {
+        options.legacyClient = new SubscriptionsTransportWs.SubscriptionClient(subscriptions, {
+          reconnect: true,
+        });
+      }
+      ReactDOM.createRoot(document.getElementById('graphiql')).render(
+        React.createElement(GraphiQL, { fetcher: GraphiQL.createFetcher(options) })
+      );
+    </script>
+  </body>
+</html>
+"#;
+
+const APOLLO_SANDBOX: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>Apollo Sandbox</title>
+  </head>
+  <body style="margin: 0;">
+    <div id="sandbox" style="height: 100vh;"></div>
+    <script src="https://embeddable-sandbox.cdn.apollographql.com/_latest/embeddable-sandbox.umd.production.min.js"></script>
+    <script>
+      URLS
+      var options = { target: '#sandbox', initialEndpoint: endpoint };
+      if (subscriptions) {
+        options.initialSubscriptionEndpoint = subscriptions;
+      }
+      new window.EmbeddedSandbox(options);
+    </script>
+  </body>
+</html>
+"#;
+
+/// The page of `ide` for the API at `endpoint`, with subscriptions at
+/// `subscriptions` if the schema has any.
+pub fn page(ide: Ide, endpoint: &str, subscriptions: Option<&str>) -> String {
+    let template = match ide {
+        Ide::Graphiql => GRAPHIQL,
+        Ide::ApolloSandbox => APOLLO_SANDBOX,
+        Ide::Playground => {
+            let mut config = GraphQLPlaygroundConfig::new(endpoint);
+            if let Some(subscriptions) = subscriptions {
+                config = config.subscription_endpoint(subscriptions);
+            }
+            return playground_source(config);
+        }
+        Ide::None => return String::new(),
+    };
+    let urls = format!(
+        "var endpoint = new URL({}, window.location.href).href;\n      \
+         var subscriptions = {};",
+        js_string(endpoint),
+        match subscriptions {
+            Some(path) => format!(
+                "new URL({}, window.location.href.replace(/^http/, 'ws')).href",
+                js_string(path)
+            ),
+            None => "null".to_owned(),
+        }
+    );
+    template.replace("URLS", &urls)
+}
+
+/// `value` as a JavaScript string literal that can't end the script.
+fn js_string(value: &str) -> String {
+    serde_json::Value::from(value)
+        .to_string()
+        .replace('<', "\\u003c")
+}
@@ -0,0 +1,78 @@
+/// Declares a root object whose fields are the union of several `#[Object]`
+/// parts, so each domain area keeps its own resolvers. The parts are folded
+/// into a single type in the registry and never show up in the schema.
+macro_rules! merged_object {
+    ($name:ident, $($part:ident),+ $(,)?) => {
+        #[allow(non_snake_case)]
+        #[derive(Default)]
+        pub struct $name {
+            $($part: $part),+
+        }
+
+        impl async_graphql::Type for $name {
+            fn type_name() -> std::borrow::Cow<'static, str> {
+                std::borrow::Cow::Borrowed(stringify!($name))
+            }
+
+            fn create_type_info(registry: &mut async_graphql::registry::Registry) -> String {
+                registry.create_type::<Self, _>(|registry| {
+                    let mut fields = async_graphql::indexmap::IndexMap::new();
+                    $(
+                        <$part as async_graphql::Type>::create_type_info(registry);
+                        if let Some(async_graphql::registry::MetaType::Object {
+                            fields: part_fields,
+                            ..
+                        }) = registry
+                            .types
+                            .remove(<$part as async_graphql::Type>::type_name().as_ref())
+                        {
+                            fields.extend(part_fields);
+                        }
+                    )+
+                    async_graphql::registry::MetaType::Object {
+                        name: stringify!($name).to_string(),
+                        description: None,
+                        fields,
+                        cache_control: Default::default(),
+                        extends: false,
+                        keys: None,
+                    }
+                })
+            }
+        }
+
+        #[async_graphql::async_trait::async_trait]
+        impl async_graphql::ObjectType for $name {
+            async fn resolve_field(
+                &self,
+                ctx: &async_graphql::Context<'_>,
+            ) -> async_graphql::Result<async_graphql::serde_json::Value> {
+                $(
+                    match self.$part.resolve_field(ctx).await {
+                        Err(async_graphql::Error::Query {
+                            err: async_graphql::QueryError::FieldNotFound { .. },
+                            ..
+                        }) => {}
+                        res => return res,
+                    }
+                )+
+                Err(async_graphql::QueryError::FieldNotFound {
+                    field_name: ctx.name.to_string(),
+                    object: stringify!($name).to_string(),
+                }
+                .into_error(ctx.position()))
+            }
+        }
+
+        #[async_graphql::async_trait::async_trait]
+        impl async_graphql::OutputValueType for $name {
+            async fn resolve(
+                &self,
+                ctx: &async_graphql::ContextSelectionSet<'_>,
+                _field: &async_graphql::Positioned<async_graphql::parser::query::Field>,
+            ) -> async_graphql::Result<async_graphql::serde_json::Value> {
+                async_graphql::do_resolve(ctx, self).await
+            }
+        }
+    };
+}
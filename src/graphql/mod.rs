@@ -0,0 +1,1064 @@
+#[macro_use]
+mod merged;
+mod admin;
+mod contacts;
+mod directives;
+mod groups;
+mod ide;
+#[cfg(test)]
+mod tests;
+mod users;
+mod webhooks;
+
+use crate::auth::{ApiKeys, Authentication, CurrentUser, JwtKey};
+use crate::avatars;
+use crate::backup::{self, BackupDir};
+use crate::cache::*;
+use crate::carddav;
+use crate::cors::{Cors, CorsConfig};
+use crate::events::{Storage, StorageMode};
+use crate::google::GoogleConfig;
+use crate::i18n::{self, Locale, Localize};
+use crate::idempotency::{self, IdempotencyStore};
+use crate::instrumented::InstrumentedRepository;
+use crate::jobs::{self, Jobs};
+use crate::logging::{self, RequestId};
+use crate::metrics::{self, HttpMetrics, OperationMetrics};
+use crate::outbox;
+use crate::ratelimit::{Rate, RateLimit, RateLimiter};
+use crate::reload::{self, Reloader};
+use crate::repo::*;
+use crate::rest;
+use crate::sentry::{self, OperationContext};
+use crate::session::{ActiveSession, SessionCookie, SessionStore};
+use crate::settings::{Config, Ide, Limits, LiveLimits, Mode, DEFAULT_PAGE_SIZE};
+use crate::shutdown::{self, Shutdown};
+use crate::telemetry::{self, OperationSpan, Tracing};
+use crate::tenant::{self, Tenant};
+use actix_web::dev::Payload;
+use actix_web::error::{JsonPayloadError, PayloadError};
+use actix_web::{
+    guard,
+    http::{header, StatusCode},
+    web, App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use admin::{AdminMutation, AdminQuery};
+use async_graphql::extensions::ApolloTracing;
+use async_graphql::parser::query::OperationType;
+use async_graphql::*;
+use async_graphql_actix_web::{GQLRequest, GQLResponse};
+use contacts::{ContactsMutation, ContactsQuery};
+use futures::{FutureExt, StreamExt};
+use groups::{GroupsMutation, GroupsQuery};
+use std::panic::AssertUnwindSafe;
+use tokio::task::JoinError;
+use users::{UsersMutation, UsersQuery};
+
+pub(crate) use directives::masked;
+use webhooks::{WebhooksMutation, WebhooksQuery};
+
+pub type ContactsSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+const TRACING_HEADER: &str = "x-apollo-tracing";
+
+/// Serves a GraphQL request within the request timeout. Dropping the
+/// handler on timeout cancels the resolvers at their next await point;
+/// a resolver blocked in synchronous I/O finishes that call first. A
+/// panicking resolver is reported and answered with a 500.
+pub(crate) async fn index(
+    schema: web::Data<ContactsSchema>,
+    cache: web::Data<Option<ResponseCache>>,
+    responses: web::Data<IdempotencyStore>,
+    limits: web::Data<LiveLimits>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let limits = limits.get();
+    let payload = limit_body(&req, payload, &limits)?;
+    let timeout = limits.request_timeout;
+    let handler = sentry::scope(async move {
+        let handler = handle(schema, cache, responses, limits, req, payload);
+        match AssertUnwindSafe(handler).catch_unwind().await {
+            Ok(resp) => resp,
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|m| m.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_owned());
+                error!("request handler panicked: {}", message);
+                sentry::capture(format!("panic: {}", message));
+                Err(Panicked.into())
+            }
+        }
+    });
+    match tokio::time::timeout(timeout, handler).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            warn!("request cancelled after {:?}", timeout);
+            Err(TimedOut.into())
+        }
+    }
+}
+
+async fn handle(
+    schema: web::Data<ContactsSchema>,
+    cache: web::Data<Option<ResponseCache>>,
+    responses: web::Data<IdempotencyStore>,
+    limits: Limits,
+    req: HttpRequest,
+    mut payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    debug!("request");
+    let user = req.extensions().get::<CurrentUser>().cloned();
+    let tenant =
+        tenant::resolve(req.headers(), user.as_ref()).map_err(actix_web::error::ErrorForbidden)?;
+    let cookie = SessionCookie::default();
+    let key = idempotency::key(req.headers()).map_err(actix_web::error::ErrorBadRequest)?;
+    if key.is_some() && !is_json(&req) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Idempotency-Key needs an application/json request",
+        ));
+    }
+    let request = if is_json(&req) {
+        match json_request(&req, &mut payload, &limits).await? {
+            Operations::Single(request) => Some(request),
+            Operations::Batch(_) if key.is_some() => {
+                return Err(actix_web::error::ErrorBadRequest(
+                    "Idempotency-Key can't be used with a batch",
+                ))
+            }
+            Operations::Batch(requests) => {
+                return batch(&schema, &req, requests, tenant, &cookie).await
+            }
+        }
+    } else {
+        None
+    };
+    let request = match (key, request) {
+        (Some(key), Some(request)) => {
+            let id = IdempotencyStore::id(user.as_ref().map(|u| u.id.as_str()), &key);
+            return idempotent(
+                &schema, &responses, id, &req, request, tenant, &cookie, &limits,
+            )
+            .await;
+        }
+        (_, request) => request,
+    };
+    let (cache, request) = match (cache.get_ref(), request) {
+        (Some(cache), Some(request)) if !wants_tracing(&req) => (cache, request),
+        (_, request) => {
+            let builder = match request {
+                Some(request) => request
+                    .into_query_builder()
+                    .await
+                    .map_err(actix_web::error::ErrorBadRequest)?,
+                None => GQLRequest::from_request(&req, &mut payload.0)
+                    .await?
+                    .into_inner(),
+            };
+            let resp: GQLResponse = with_caller(builder, &req, tenant, &cookie)
+                .execute(&schema)
+                .await
+                .into();
+            let mut resp = resp.respond_to(&req).await?;
+            if let Some(cookie) = cookie.take() {
+                resp.add_cookie(&cookie)?;
+            }
+            return Ok(resp);
+        }
+    };
+
+    let key = cache_key(&request, tenant.as_ref(), user.as_ref());
+    if let Some(hit) = cache.get(&key) {
+        debug!("cache hit");
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .header(header::CACHE_CONTROL, hit.cache_control)
+            .body(hit.body));
+    }
+
+    let cacheable = is_query(&request);
+    let tags = CacheTags::default();
+    let builder = request
+        .into_query_builder()
+        .await
+        .map_err(actix_web::error::ErrorBadRequest)?
+        .data(tags.clone());
+    let builder = with_caller(builder, &req, tenant, &cookie);
+    let resp = builder.execute(&schema).await;
+    let cache_control = match &resp {
+        Ok(r) if cacheable && r.cache_control.max_age > 0 => Some(r.cache_control),
+        _ => None,
+    };
+    let body = serde_json::to_string(&http::GQLResponse(resp))?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    if let Some(cache_control) = cache_control {
+        let value = cache_control.value().unwrap_or_default();
+        builder.header(header::CACHE_CONTROL, value.as_str());
+        cache.insert(
+            key,
+            CachedResponse {
+                body: body.clone(),
+                cache_control: value,
+            },
+            cache_control.max_age,
+            tags.take(),
+        );
+    }
+    if let Some(cookie) = cookie.take() {
+        builder.cookie(cookie);
+    }
+    Ok(builder.body(body))
+}
+
+/// The key `request`'s response is cached under for `user` of `tenant`, in
+/// the locale the request negotiated.
+fn cache_key(
+    request: &http::GQLRequest,
+    tenant: Option<&Tenant>,
+    user: Option<&CurrentUser>,
+) -> CacheKey {
+    let mut scopes = user.map(|u| u.scopes.clone()).unwrap_or_default();
+    scopes.sort();
+    scopes.dedup();
+    CacheKey {
+        query: request.query.clone(),
+        operation_name: request.operation_name.clone(),
+        variables: request
+            .variables
+            .as_ref()
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+        tenant: tenant.map(|t| t.0.clone()),
+        user: user.map(|u| u.id.clone()),
+        role: user.map(|u| u.role),
+        scopes,
+        locale: Locale::current().tag(),
+    }
+}
+
+/// Runs a request sent with an idempotency key. It claims the key first,
+/// answering 409 while another request holds it. A successful mutation's
+/// response is stored under the key and replayed for later requests with
+/// it; any other response lets go of the key.
+#[allow(clippy::too_many_arguments)]
+async fn idempotent(
+    schema: &ContactsSchema,
+    responses: &IdempotencyStore,
+    id: String,
+    req: &HttpRequest,
+    request: http::GQLRequest,
+    tenant: Option<Tenant>,
+    cookie: &SessionCookie,
+    limits: &Limits,
+) -> actix_web::Result<HttpResponse> {
+    let fingerprint = idempotency::fingerprint(
+        &request.query,
+        request.operation_name.as_deref(),
+        &request
+            .variables
+            .as_ref()
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+    );
+    let partition = tenant.as_ref().map(|t| t.0.clone());
+    let claimed = {
+        let (responses, partition, id, fingerprint) = (
+            responses.clone(),
+            partition.clone(),
+            id.clone(),
+            fingerprint.clone(),
+        );
+        let hold_secs = limits.request_timeout.as_secs() + 1;
+        on_blocking_pool(move || async move {
+            responses
+                .claim(partition.as_deref(), &id, &fingerprint, hold_secs)
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+    if let Some(stored) = claimed {
+        if stored.fingerprint != fingerprint {
+            return Err(actix_web::error::ErrorUnprocessableEntity(
+                "Idempotency-Key was already used for a different request",
+            ));
+        }
+        if stored.in_progress {
+            return Err(actix_web::error::ErrorConflict(
+                "Idempotency-Key in progress",
+            ));
+        }
+        debug!("idempotent replay");
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .header("Idempotent-Replayed", "true")
+            .body(stored.body));
+    }
+
+    let mutation = operation_type(&request) == Some(OperationType::Mutation);
+    let builder = match request.into_query_builder().await {
+        Ok(builder) => builder,
+        Err(e) => {
+            settle(responses, partition, id, fingerprint, None).await;
+            return Err(actix_web::error::ErrorBadRequest(e));
+        }
+    };
+    let resp = with_caller(builder, req, tenant, cookie)
+        .execute(schema)
+        .await;
+    let succeeded = resp.is_ok();
+    let body = serde_json::to_string(&http::GQLResponse(resp));
+    let replayed = match &body {
+        Ok(body) if mutation && succeeded => Some(body.clone()),
+        _ => None,
+    };
+    settle(responses, partition, id, fingerprint, replayed).await;
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    if let Some(cookie) = cookie.take() {
+        builder.cookie(cookie);
+    }
+    Ok(builder.body(body?))
+}
+
+/// Stores `body` as the response to the request holding the claim on `id`,
+/// or without one lets go of the claim.
+async fn settle(
+    responses: &IdempotencyStore,
+    partition: Option<String>,
+    id: String,
+    fingerprint: String,
+    body: Option<String>,
+) {
+    let responses = responses.clone();
+    let settled = on_blocking_pool(move || async move {
+        match body {
+            Some(body) => responses.put(partition.as_deref(), id, fingerprint, body),
+            None => responses.release(partition.as_deref(), &id),
+        }
+        .map_err(|e| e.to_string())
+    })
+    .await;
+    if let Err(e) = settled.map_err(|e| e.to_string()).and_then(|r| r) {
+        warn!("storing idempotent response failed: {}", e);
+    }
+}
+
+/// Rejects bodies over the size limit, up front when they declare their
+/// length and otherwise once they've grown past it.
+fn limit_body(
+    req: &HttpRequest,
+    payload: web::Payload,
+    limits: &Limits,
+) -> actix_web::Result<web::Payload> {
+    let limit = limits.body_limit(!is_json(req));
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.map(|l| l > limit).unwrap_or(false) {
+        return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+            "request body exceeds {} bytes",
+            limit
+        )));
+    }
+    let mut seen = 0;
+    let stream = payload.map(move |chunk| {
+        let chunk = chunk?;
+        seen += chunk.len();
+        if seen > limit {
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    });
+    Ok(web::Payload(Payload::Stream(Box::pin(stream))))
+}
+
+/// A JSON encoded body: one operation, or an array of them answered with
+/// an array of responses.
+enum Operations {
+    Single(http::GQLRequest),
+    Batch(Vec<http::GQLRequest>),
+}
+
+/// Reads a JSON encoded request, holding batches to the batch size limit
+/// and the variables of each operation to the size limit.
+async fn json_request(
+    req: &HttpRequest,
+    payload: &mut web::Payload,
+    limits: &Limits,
+) -> actix_web::Result<Operations> {
+    let body = web::Json::<serde_json::Value>::from_request(req, &mut payload.0)
+        .await?
+        .into_inner();
+    let operations = if body.is_array() {
+        let requests: Vec<http::GQLRequest> =
+            serde_json::from_value(body).map_err(actix_web::error::ErrorBadRequest)?;
+        if requests.is_empty() {
+            return Err(actix_web::error::ErrorBadRequest("the batch is empty"));
+        }
+        if requests.len() > limits.max_batch_size {
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                "batch exceeds {} operations",
+                limits.max_batch_size
+            )));
+        }
+        Operations::Batch(requests)
+    } else {
+        Operations::Single(serde_json::from_value(body).map_err(actix_web::error::ErrorBadRequest)?)
+    };
+    let requests = match &operations {
+        Operations::Single(request) => std::slice::from_ref(request),
+        Operations::Batch(requests) => requests.as_slice(),
+    };
+    for request in requests {
+        let variables = request
+            .variables
+            .as_ref()
+            .map(|v| v.to_string().len())
+            .unwrap_or_default();
+        if variables > limits.max_variables_bytes {
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                "variables exceed {} bytes",
+                limits.max_variables_bytes
+            )));
+        }
+    }
+    Ok(operations)
+}
+
+/// Runs a batch of operations one after the other, in the order sent, and
+/// answers with their responses in that order. An operation failing
+/// doesn't stop the rest. Batches bypass the response cache.
+async fn batch(
+    schema: &ContactsSchema,
+    req: &HttpRequest,
+    requests: Vec<http::GQLRequest>,
+    tenant: Option<Tenant>,
+    cookie: &SessionCookie,
+) -> actix_web::Result<HttpResponse> {
+    debug!("batch of {}", requests.len());
+    let mut responses = Vec::with_capacity(requests.len());
+    for request in requests {
+        let builder = request
+            .into_query_builder()
+            .await
+            .map_err(actix_web::error::ErrorBadRequest)?;
+        let resp = with_caller(builder, req, tenant.clone(), cookie)
+            .execute(schema)
+            .await;
+        responses.push(http::GQLResponse(resp));
+    }
+    let body = serde_json::to_string(&responses)?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    if let Some(cookie) = cookie.take() {
+        builder.cookie(cookie);
+    }
+    Ok(builder.body(body))
+}
+
+/// A resolver panicked while serving the request.
+#[derive(Debug)]
+struct Panicked;
+
+impl std::fmt::Display for Panicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "internal server error")
+    }
+}
+
+impl actix_web::ResponseError for Panicked {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError().json(serde_json::json!({
+            "errors": [{
+                "message": self.to_string(),
+                "extensions": { "code": "INTERNAL_SERVER_ERROR" },
+            }]
+        }))
+    }
+}
+
+/// The request ran past the request timeout.
+#[derive(Debug)]
+struct TimedOut;
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request timed out")
+    }
+}
+
+impl actix_web::ResponseError for TimedOut {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "errors": [{
+                "message": self.to_string(),
+                "extensions": { "code": "TIMEOUT" },
+            }]
+        }))
+    }
+}
+
+/// Hands the caller, its tenant and session, if any, to the resolvers along
+/// with the slot they can use to update the session cookie.
+fn with_caller(
+    builder: QueryBuilder,
+    req: &HttpRequest,
+    tenant: Option<Tenant>,
+    cookie: &SessionCookie,
+) -> QueryBuilder {
+    let extensions = req.extensions();
+    let mut builder = builder.data(cookie.clone());
+    if let Some(tenant) = tenant {
+        builder = builder.data(tenant);
+    }
+    if let Some(user) = extensions.get::<CurrentUser>() {
+        builder = builder.data(user.clone());
+    }
+    if let Some(session) = extensions.get::<ActiveSession>() {
+        builder = builder.data(session.clone());
+    }
+    if wants_tracing(req) {
+        builder = builder.extension(ApolloTracing::default);
+    }
+    builder
+}
+
+/// Whether the client asked for per-resolver timings, returned in the
+/// Apollo tracing format under the response's `extensions.tracing`. Traced
+/// requests bypass the response cache so the timings are always fresh.
+fn wants_tracing(req: &HttpRequest) -> bool {
+    matches!(
+        req.headers()
+            .get(TRACING_HEADER)
+            .and_then(|v| v.to_str().ok()),
+        Some("1") | Some("true")
+    )
+}
+
+/// The repository partition of the tenant the request is served for.
+fn tenant_repo(ctx: &Context<'_>) -> Storage<InstrumentedRepository<StorageBackend>> {
+    let repo = tenant_backend(ctx);
+    Storage::new(
+        InstrumentedRepository::new(repo.name(), repo),
+        *ctx.data_unchecked::<StorageMode>(),
+    )
+}
+
+fn tenant_backend(ctx: &Context<'_>) -> StorageBackend {
+    let repo = ctx.data_unchecked::<StorageBackend>();
+    match ctx.data_opt::<Tenant>() {
+        Some(tenant) => repo.for_tenant(&tenant.0),
+        None => repo.clone(),
+    }
+}
+
+/// The entries of a list field a request asked for: `first` of them, at
+/// most and by default the configured page size, after skipping `offset`.
+pub(crate) struct Page {
+    pub(crate) offset: usize,
+    pub(crate) size: usize,
+}
+
+impl Page {
+    fn new(ctx: &Context<'_>, first: Option<i32>, offset: Option<i32>) -> FieldResult<Page> {
+        let max = ctx
+            .data_opt::<LiveLimits>()
+            .map_or(DEFAULT_PAGE_SIZE as usize, |l| l.get().max_page_size);
+        Page::within(max, "first_range", first, offset).map_err(|e| FieldError(e, None))
+    }
+
+    /// The page of `size` entries, at most and by default `max`; a size out
+    /// of range is refused with the message `range` names.
+    pub(crate) fn within(
+        max: usize,
+        range: &str,
+        size: Option<i32>,
+        offset: Option<i32>,
+    ) -> std::result::Result<Page, String> {
+        let size = match size {
+            None => max,
+            Some(n) if n >= 1 && n as usize <= max => n as usize,
+            Some(n) => return Err(i18n::text(range, &[("max", &max), ("got", &n)])),
+        };
+        let offset = match offset {
+            None => 0,
+            Some(n) if n >= 0 => n as usize,
+            Some(n) => return Err(i18n::text("offset_negative", &[("got", &n)])),
+        };
+        Ok(Page { offset, size })
+    }
+
+    fn of<T>(&self, entries: Vec<T>) -> Vec<T> {
+        entries
+            .into_iter()
+            .skip(self.offset)
+            .take(self.size)
+            .collect()
+    }
+}
+
+/// Runs repository work on the blocking thread pool, as the repositories
+/// read and write files synchronously and would otherwise hold up every
+/// request on the worker. The work keeps the request id and trace for its
+/// logs and spans. A request cancelled on timeout stops waiting for it, but
+/// the work itself runs to the end. A missing record is `NOT_FOUND`.
+async fn blocking<T, F>(work: F) -> FieldResult<T>
+where
+    F: FnOnce() -> std::result::Result<T, Box<dyn std::error::Error>> + Send + 'static,
+    T: Send + 'static,
+{
+    blocking_async(move || async move { work() }).await
+}
+
+/// `blocking` for work that awaits, such as reading a stream, run to the
+/// end on the blocking thread.
+async fn blocking_async<T, F, W>(work: F) -> FieldResult<T>
+where
+    F: FnOnce() -> W + Send + 'static,
+    W: std::future::Future<Output = std::result::Result<T, Box<dyn std::error::Error>>>,
+    T: Send + 'static,
+{
+    let result = on_blocking_pool(move || async move { work().await.map_err(field_error) }).await;
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(FieldError(format!("{}", e), None)),
+    }
+}
+
+/// Runs the future `work` makes on the blocking thread pool, with the
+/// request's id, trace and locale, for handlers outside the schema as much
+/// as for resolvers: anything reading or writing the repository would hold
+/// up every request on the worker otherwise. Fails only if `work` panics.
+pub(crate) async fn on_blocking_pool<T, F, W>(work: F) -> std::result::Result<T, JoinError>
+where
+    F: FnOnce() -> W + Send + 'static,
+    W: std::future::Future<Output = T>,
+    T: Send + 'static,
+{
+    let request_id = logging::request_id();
+    let trace = telemetry::TraceContext::current();
+    let locale = Locale::current();
+    tokio::task::spawn_blocking(move || {
+        let work = async move { work().await };
+        futures::executor::block_on(logging::with_request_id(
+            request_id,
+            telemetry::TraceContext::within(trace, i18n::with_locale(locale, work)),
+        ))
+    })
+    .await
+}
+
+fn field_error(e: Box<dyn std::error::Error>) -> FieldError {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound => FieldError(
+            i18n::text("not_found", &[]),
+            Some(serde_json::json!({ "code": "NOT_FOUND" })),
+        ),
+        _ => FieldError(e.to_string(), None),
+    }
+}
+
+/// The tenant's partition as files, for operations on the stored records
+/// themselves; a schema served from memory has none.
+fn tenant_files(ctx: &Context<'_>) -> FieldResult<FileRepository<'static>> {
+    match tenant_backend(ctx).files() {
+        Some(repo) => Ok(repo.clone()),
+        None => Err(FieldError(
+            "the repository is in memory, not files".to_owned(),
+            None,
+        )),
+    }
+}
+
+fn is_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json"))
+        .unwrap_or(false)
+}
+
+fn is_query(request: &http::GQLRequest) -> bool {
+    operation_type(request) == Some(OperationType::Query)
+}
+
+/// Type of the operation the request runs, `None` if it doesn't parse.
+fn operation_type(request: &http::GQLRequest) -> Option<OperationType> {
+    let mut document = parser::parse_query(&request.query).ok()?;
+    if !document.retain_operation(request.operation_name.as_deref()) {
+        return None;
+    }
+    Some(document.current_operation().ty)
+}
+
+async fn gql_ide(ide: web::Data<Ide>) -> HttpResponse {
+    debug!("ide");
+    // The schema has no subscriptions to point the IDE at.
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ide::page(*ide.get_ref(), "/", None))
+}
+
+/// Liveness: the process is up and serving requests.
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body("ok")
+}
+
+/// Readiness: the repository can be written to.
+async fn readyz(repo: web::Data<FileRepository<'static>>) -> HttpResponse {
+    match repo.probe() {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body("ready"),
+        Err(e) => {
+            warn!("readiness probe failed: {}", e);
+            HttpResponse::ServiceUnavailable()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("repository unavailable: {}", e))
+        }
+    }
+}
+
+async fn prometheus() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::REGISTRY.render())
+}
+
+async fn schema_sdl() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(sdl())
+}
+
+/// The types of the schema, without building a full `Schema`.
+fn registry() -> registry::Registry {
+    let mut registry = registry::Registry {
+        types: Default::default(),
+        directives: Default::default(),
+        implements: Default::default(),
+        query_type: QueryRoot::type_name().to_string(),
+        mutation_type: Some(MutationRoot::type_name().to_string()),
+        subscription_type: None,
+    };
+    QueryRoot::create_type_info(&mut registry);
+    MutationRoot::create_type_info(&mut registry);
+    registry
+}
+
+/// Every deprecated field and enum value the schema still serves, as
+/// `Type.field` and the reason given, in name order.
+pub fn deprecated() -> Vec<(String, &'static str)> {
+    let mut deprecated = vec![];
+    for (name, ty) in registry().types {
+        match ty {
+            registry::MetaType::Object { fields, .. }
+            | registry::MetaType::Interface { fields, .. } => {
+                for field in fields.values() {
+                    if let Some(reason) = field.deprecation {
+                        deprecated.push((format!("{}.{}", name, field.name), reason));
+                    }
+                }
+            }
+            registry::MetaType::Enum { enum_values, .. } => {
+                for value in enum_values.values() {
+                    if let Some(reason) = value.deprecation {
+                        deprecated.push((format!("{}.{}", name, value.name), reason));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    deprecated.sort();
+    deprecated
+}
+
+/// Renders the schema as SDL without building a full `Schema`.
+pub fn sdl() -> String {
+    let registry = registry();
+    format!(
+        "schema {{\n\tquery: {}\n\tmutation: {}\n}}\n{}{}",
+        QueryRoot::type_name(),
+        MutationRoot::type_name(),
+        directives::SDL,
+        registry.create_federation_sdl()
+    )
+}
+
+merged_object!(
+    QueryRoot,
+    AdminQuery,
+    ContactsQuery,
+    GroupsQuery,
+    UsersQuery,
+    WebhooksQuery
+);
+merged_object!(
+    MutationRoot,
+    AdminMutation,
+    ContactsMutation,
+    GroupsMutation,
+    UsersMutation,
+    WebhooksMutation
+);
+
+/// The schema over `repo` with the extensions every operation runs
+/// through; the server adds its configuration on top.
+pub fn schema_builder(
+    repo: StorageBackend,
+    storage_mode: StorageMode,
+) -> SchemaBuilder<QueryRoot, MutationRoot, EmptySubscription> {
+    Schema::build(
+        QueryRoot::default(),
+        MutationRoot::default(),
+        EmptySubscription,
+    )
+    .data(repo)
+    .data(storage_mode)
+    .extension(OperationMetrics::default)
+    .extension(OperationSpan::default)
+    .extension(OperationContext::default)
+}
+
+pub async fn start_server() -> std::io::Result<()> {
+    let local = tokio::task::LocalSet::new();
+    let sys = actix_rt::System::run_in_tokio("server", &local);
+
+    let mode = Mode::from_env();
+    logging::init(mode);
+    let config =
+        Config::load(mode).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let repo = config.repository();
+    for repo in std::iter::once(repo.clone()).chain(repo.tenants()) {
+        let recovered = repo
+            .recover()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        if recovered > 0 {
+            info!(
+                "finished {} interrupted transactions in {}",
+                recovered,
+                repo.dir().display()
+            );
+        }
+    }
+    let ide = config.ide(mode);
+    let address = config.address();
+    let mut cache = ResponseCache::from_env();
+    let jwt_key =
+        JwtKey::from_env().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let api_keys = ApiKeys::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    logging::set_filter(
+        logging::filter(&config)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let limits = Limits::load(&config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let live_limits = LiveLimits::new(limits);
+    let cors = CorsConfig::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let limiter = RateLimiter::new(
+        Rate::load(&config)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let reloader = Reloader::new(mode, config.clone(), live_limits.clone(), limiter.clone());
+    let google = GoogleConfig::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let sessions = SessionStore::from_env(repo.clone(), mode == Mode::Production);
+    let responses = IdempotencyStore::new(repo.clone());
+    let stop = Shutdown::default();
+    let mut jobs = vec![
+        crate::webhooks::deliveries(repo.clone()),
+        jobs::compact(repo.clone()),
+        avatars::thumbnails(repo.clone()),
+    ];
+    let storage_mode = StorageMode::from_env();
+    if storage_mode == StorageMode::Events {
+        jobs.push(jobs::rebuild_projections(repo.clone()));
+    }
+    if let Some(backup) = backup::job(
+        repo.clone(),
+        config.backup_dir(),
+        config.backup_interval(),
+        config.backup_keep,
+    ) {
+        info!("backing up to {}", config.backup_dir().display());
+        jobs.push(backup);
+    }
+    let job_status = Jobs::default();
+    let mut workers = vec![jobs::spawn_runner(jobs, job_status.clone(), stop.clone())];
+    if let Some(publisher) = outbox::spawn_publisher(repo.clone(), stop.clone()) {
+        info!("publishing outbox events to NATS");
+        workers.push(publisher);
+    }
+    if let Some(fanout) = cache
+        .as_mut()
+        .and_then(|cache| cache.spawn_fanout(stop.clone()))
+    {
+        info!("sharing cache invalidations over NATS");
+        workers.push(fanout);
+    }
+    if let Some(reporter) = sentry::spawn_reporter(stop.clone())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+    {
+        info!("reporting errors to Sentry");
+        workers.push(reporter);
+    }
+    if let Some(exporter) = telemetry::spawn_exporter(stop.clone()) {
+        info!("exporting traces over OTLP");
+        workers.push(exporter);
+    }
+
+    let mut builder = schema_builder(StorageBackend::Files(repo.clone()), storage_mode)
+        .data(BackupDir(config.backup_dir()))
+        .data(job_status)
+        .data(live_limits.clone());
+    if let Some(cache) = &cache {
+        builder = builder.data(cache.clone());
+    }
+    if let Some(google) = google {
+        builder = builder.data(google);
+    }
+    if let Some(key) = &jwt_key {
+        builder = builder.data(key.clone());
+    }
+    if let Some(sessions) = &sessions {
+        builder = builder.data(sessions.clone());
+    }
+    if !mode.allows_introspection() {
+        builder = builder.disable_introspection();
+    }
+    let schema = builder.finish();
+    for (field, reason) in deprecated() {
+        info!("serving deprecated {}: {}", field, reason);
+    }
+
+    if let Some(ide) = ide {
+        info!("{:?} IDE: http://{}", ide, address);
+    }
+
+    // The server's command loop runs on the system's local task set, so the
+    // system has to be driven alongside it for stop requests to arrive.
+    let serve = async move {
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(RateLimit::new(limiter.clone()))
+                .wrap(Authentication::new(
+                    jwt_key.clone(),
+                    api_keys.clone(),
+                    sessions.clone(),
+                ))
+                .wrap(Localize)
+                .wrap(Cors::new(cors.clone()))
+                .wrap(HttpMetrics)
+                .wrap(Tracing)
+                .wrap(RequestId)
+                .data(schema.clone())
+                .data(cache.clone())
+                .data(responses.clone())
+                .data(live_limits.clone())
+                .data(repo.clone())
+                .data(storage_mode)
+                .service(web::resource("/healthz").guard(guard::Get()).to(healthz))
+                .service(web::resource("/readyz").guard(guard::Get()).to(readyz))
+                .service(web::resource("/metrics").guard(guard::Get()).to(prometheus))
+                .service(
+                    web::resource("/avatars/{contact_id}/{size}")
+                        .guard(guard::Get())
+                        .to(avatars::serve),
+                )
+                .configure(|cfg| rest::configure(cfg, &limits))
+                .service(web::resource("/.well-known/carddav").to(carddav::well_known))
+                .service(web::resource("/carddav").to(carddav::well_known))
+                .service(
+                    web::resource("/carddav/{tail:.*}")
+                        .app_data(web::PayloadConfig::new(limits.max_body_bytes))
+                        .to(carddav::serve),
+                )
+                .service(
+                    web::resource("/")
+                        .guard(guard::Post())
+                        .to(index)
+                        .app_data({
+                            // `limit_body` holds bodies to the current limit.
+                            let limits = live_limits.clone();
+                            web::JsonConfig::default().limit(usize::MAX).error_handler(
+                                move |e, _| match e {
+                                    JsonPayloadError::Overflow
+                                    | JsonPayloadError::Payload(PayloadError::Overflow) => {
+                                        actix_web::error::ErrorPayloadTooLarge(format!(
+                                            "request body exceeds {} bytes",
+                                            limits.get().max_body_bytes
+                                        ))
+                                    }
+                                    e => e.into(),
+                                },
+                            )
+                        })
+                        .app_data(IntoQueryBuilderOpts {
+                            max_file_size: Some(limits.max_upload_bytes),
+                            max_num_files: Some(limits.max_uploads),
+                        }),
+                )
+                .service(
+                    web::resource("/schema.graphql")
+                        .guard(guard::Get())
+                        .to(schema_sdl),
+                )
+                .configure(|cfg| {
+                    if let Some(ide) = ide {
+                        cfg.service(web::resource("/").data(ide).guard(guard::Get()).to(gql_ide));
+                    }
+                })
+        })
+        .disable_signals()
+        .shutdown_timeout(limits.request_timeout.as_secs())
+        .keep_alive(Some(config.keep_alive_secs as usize).filter(|secs| *secs > 0))
+        .client_timeout(config.client_timeout_ms)
+        .backlog(config.backlog);
+        let server = match config.workers {
+            Some(workers) => server.workers(workers),
+            None => server,
+        };
+        let server = server.bind(&address)?.run();
+
+        let handle = server.clone();
+        actix_rt::spawn(async move {
+            match shutdown::signal().await {
+                Ok(signal) => info!("{} received, draining in-flight requests", signal),
+                Err(e) => warn!("no signal handling, stopping: {}", e),
+            }
+            handle.stop(true).await;
+        });
+        actix_rt::spawn(reload::on_hangup(reloader));
+        server.await?;
+
+        info!("server stopped, waiting for background workers");
+        stop.trigger();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        actix_rt::System::current().stop();
+        Ok::<(), std::io::Error>(())
+    };
+    let (served, stopped) = local.run_until(async { futures::join!(serve, sys) }).await;
+    served?;
+    stopped
+}
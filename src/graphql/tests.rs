@@ -0,0 +1,607 @@
+use crate::auth::Role;
+use crate::cache::{CachedResponse, ResponseCache};
+use crate::models::Contact;
+use crate::repo::Repository;
+use crate::testing::{assert_snapshot, caller, errors, TestApp};
+use serde_json::json;
+
+const CREATE: &str =
+    "mutation($contact: MutationCreate!) { create(contact: $contact) { firstName lastName } }";
+const GET: &str = "query($id: String!, $ownerId: String) { get(id: $id, ownerId: $ownerId) { id ownerId firstName lastName emails } }";
+
+fn ada() -> serde_json::Value {
+    json!({ "contact": { "id": "ada", "firstName": " Ada ", "lastName": "Lovelace", "emails": ["ada@example.com"] } })
+}
+
+#[tokio::test]
+async fn creates_and_gets_a_contact() {
+    let app = TestApp::new();
+    let editor = caller("u1", Role::Editor);
+    let created = app.execute(Some(&editor), CREATE, ada()).await;
+    assert_eq!(
+        created,
+        json!({ "data": { "create": { "firstName": "Ada", "lastName": "Lovelace" } } })
+    );
+
+    let got = app
+        .execute(Some(&editor), GET, json!({ "id": "ada" }))
+        .await;
+    assert_eq!(
+        got["data"]["get"],
+        json!({
+            "id": "ada",
+            "ownerId": "u1",
+            "firstName": "Ada",
+            "lastName": "Lovelace",
+            "emails": ["ada@example.com"],
+        })
+    );
+    let stored: Contact = app.repo.get("u1/ada").unwrap();
+    assert_eq!(stored.first_name, "Ada");
+}
+
+#[tokio::test]
+async fn creating_again_replaces_the_contact() {
+    let app = TestApp::new();
+    let editor = caller("u1", Role::Editor);
+    app.execute(Some(&editor), CREATE, ada()).await;
+    let mut renamed = ada();
+    renamed["contact"]["lastName"] = json!("King");
+    app.execute(Some(&editor), CREATE, renamed).await;
+
+    let got = app
+        .execute(Some(&editor), GET, json!({ "id": "ada" }))
+        .await;
+    assert_eq!(got["data"]["get"]["lastName"], "King");
+}
+
+#[tokio::test]
+async fn lists_the_owners_contacts() {
+    let app = TestApp::new();
+    let admin = caller("u1", Role::Admin);
+    for (id, first_name) in &[("ada", "Ada"), ("grace", "Grace")] {
+        let contact =
+            json!({ "contact": { "id": id, "firstName": first_name, "lastName": "Test" } });
+        assert!(errors(&app.execute(Some(&admin), CREATE, contact).await).is_empty());
+    }
+    let other = json!({ "contact": { "id": "alan", "firstName": "Alan", "lastName": "Turing" } });
+    let other_owner = caller("u2", Role::Editor);
+    app.execute(Some(&other_owner), CREATE, other).await;
+
+    let csv = app
+        .execute(
+            Some(&admin),
+            "{ exportContactsCsv(columns: \"id,first_name\") }",
+            json!({}),
+        )
+        .await;
+    let csv = csv["data"]["exportContactsCsv"].as_str().unwrap();
+    let mut rows: Vec<&str> = csv.lines().skip(1).collect();
+    rows.sort();
+    assert_eq!(rows, vec!["ada,Ada", "grace,Grace"]);
+}
+
+#[tokio::test]
+async fn exports_are_paged_up_to_the_page_size() {
+    let app = TestApp::new();
+    let admin = caller("u1", Role::Admin);
+    for id in &["grace", "ada", "alan"] {
+        let contact = json!({ "contact": { "id": id, "firstName": id, "lastName": "Test" } });
+        assert!(errors(&app.execute(Some(&admin), CREATE, contact).await).is_empty());
+    }
+    let export = |query: &'static str| {
+        let app = &app;
+        let admin = &admin;
+        async move { app.execute(Some(admin), query, json!({})).await }
+    };
+
+    let csv = export("{ exportContactsCsv(columns: \"id\", first: 2, offset: 1) }").await;
+    assert_eq!(csv["data"]["exportContactsCsv"], "id\r\nalan\r\ngrace\r\n");
+    let vcards = export("{ exportVcard(first: 1) }").await;
+    let vcards = vcards["data"]["exportVcard"].as_str().unwrap();
+    assert_eq!(vcards.matches("BEGIN:VCARD").count(), 1);
+    assert!(vcards.contains("FN:ada Test"), "{}", vcards);
+    for query in &[
+        "{ exportVcard(first: 101) }",
+        "{ exportContactsCsv(first: 101) }",
+    ] {
+        assert_eq!(
+            errors(&export(query).await),
+            vec!["first must be between 1 and 100, got 101"]
+        );
+    }
+    let got = export("{ exportVcard(offset: -1) }").await;
+    assert_eq!(errors(&got), vec!["offset can't be negative, got -1"]);
+}
+
+#[tokio::test]
+async fn getting_a_missing_contact_fails() {
+    let app = TestApp::new();
+    let viewer = caller("u1", Role::Viewer);
+    let got = app
+        .execute(Some(&viewer), GET, json!({ "id": "nobody" }))
+        .await;
+    assert_eq!(got["data"], serde_json::Value::Null);
+    assert_eq!(errors(&got).len(), 1);
+}
+
+#[tokio::test]
+async fn owners_only_see_their_own_contacts() {
+    let app = TestApp::new();
+    app.execute(Some(&caller("u1", Role::Editor)), CREATE, ada())
+        .await;
+    let other = caller("u2", Role::Editor);
+
+    let got = app.execute(Some(&other), GET, json!({ "id": "ada" })).await;
+    assert_eq!(errors(&got).len(), 1);
+    let got = app
+        .execute(Some(&other), GET, json!({ "id": "ada", "ownerId": "u1" }))
+        .await;
+    assert_eq!(
+        errors(&got),
+        vec!["Forbidden, only admins can access other owners' contacts"]
+    );
+
+    let admin = caller("root", Role::Admin);
+    let got = app
+        .execute(Some(&admin), GET, json!({ "id": "ada", "ownerId": "u1" }))
+        .await;
+    assert_eq!(got["data"]["get"]["firstName"], "Ada");
+}
+
+#[tokio::test]
+async fn anonymous_callers_are_unauthenticated() {
+    let app = TestApp::new();
+    let created = app.execute(None, CREATE, ada()).await;
+    assert_eq!(errors(&created), vec!["Unauthenticated"]);
+    assert_eq!(
+        created["errors"][0]["extensions"]["code"],
+        json!("UNAUTHENTICATED")
+    );
+
+    let group = app
+        .execute(None, "{ group(id: \"friends\") { name } }", json!({}))
+        .await;
+    assert_eq!(errors(&group), vec!["Unauthenticated"]);
+}
+
+#[tokio::test]
+async fn viewers_cannot_create() {
+    let app = TestApp::new();
+    let created = app
+        .execute(Some(&caller("u1", Role::Viewer)), CREATE, ada())
+        .await;
+    assert_eq!(
+        created["errors"][0]["extensions"]["code"],
+        json!("FORBIDDEN")
+    );
+    let got = app
+        .execute(
+            Some(&caller("u1", Role::Viewer)),
+            GET,
+            json!({ "id": "ada" }),
+        )
+        .await;
+    assert_eq!(errors(&got).len(), 1);
+}
+
+/// Inline, as async-graphql only runs input validators on literals.
+#[tokio::test]
+async fn rejects_invalid_contacts() {
+    let app = TestApp::new();
+    let editor = caller("u1", Role::Editor);
+    let created = app
+        .execute(
+            Some(&editor),
+            "mutation { create(contact: { id: \"ada\", firstName: \"   \", lastName: \"Lovelace\" }) { firstName } }",
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        errors(&created),
+        vec!["Invalid value for argument \"contact.firstName\", the value length is 0, must be between 1 and 100"]
+    );
+
+    let got = app
+        .execute(Some(&editor), GET, json!({ "id": "ada" }))
+        .await;
+    assert_eq!(errors(&got).len(), 1);
+}
+
+/// Runs each document as `u1` with `role` on a store holding `ada`, and
+/// compares the whole response with its golden file.
+async fn snapshot(name: &str, role: Option<Role>, query: &str, variables: serde_json::Value) {
+    let app = TestApp::new();
+    let editor = caller("u1", Role::Editor);
+    let contact = json!({ "contact": {
+        "id": "ada",
+        "firstName": "Ada",
+        "lastName": "Lovelace",
+        "emails": ["ada@example.com"],
+        "phones": ["+44 20 7946 0000"],
+        "addresses": [{ "street": "12 St James's Square", "locality": "London", "postalCode": "SW1Y 4JH", "country": "GB" }],
+    } });
+    assert!(errors(&app.execute(Some(&editor), CREATE, contact).await).is_empty());
+    let caller = role.map(|role| caller("u1", role));
+    assert_snapshot(name, &app.execute(caller.as_ref(), query, variables).await);
+}
+
+#[tokio::test]
+async fn snapshots_of_contact_operations() {
+    snapshot(
+        "create",
+        Some(Role::Editor),
+        CREATE,
+        json!({ "contact": { "id": "grace", "firstName": "Grace", "lastName": "Hopper" } }),
+    )
+    .await;
+    snapshot(
+        "get",
+        Some(Role::Viewer),
+        "{ get(id: \"ada\") { id ownerId firstName lastName emails phones addresses { street locality region postalCode country } avatarUrl(size: SMALL) } }",
+        json!({}),
+    )
+    .await;
+    snapshot(
+        "update_partial",
+        Some(Role::Editor),
+        "mutation { updateContactPartial(id: \"ada\", patch: { lastName: \"King\", phones: [] }) { firstName lastName emails phones } }",
+        json!({}),
+    )
+    .await;
+    snapshot(
+        "aggregate_by_country",
+        Some(Role::Viewer),
+        "{ contactsAggregate(groupBy: COUNTRY) { key count } }",
+        json!({}),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn snapshots_of_errors() {
+    snapshot("error_unauthenticated", None, GET, json!({ "id": "ada" })).await;
+    snapshot(
+        "error_forbidden_role",
+        Some(Role::Viewer),
+        CREATE,
+        json!({ "contact": { "id": "grace", "firstName": "Grace", "lastName": "Hopper" } }),
+    )
+    .await;
+    snapshot(
+        "error_forbidden_owner",
+        Some(Role::Editor),
+        GET,
+        json!({ "id": "ada", "ownerId": "u2" }),
+    )
+    .await;
+    snapshot(
+        "error_not_found",
+        Some(Role::Viewer),
+        GET,
+        json!({ "id": "nobody" }),
+    )
+    .await;
+    snapshot(
+        "error_invalid_input",
+        Some(Role::Editor),
+        "mutation { create(contact: { id: \"grace\", firstName: \"\", lastName: \"Hopper\" }) { firstName } }",
+        json!({}),
+    )
+    .await;
+    snapshot(
+        "error_unknown_field",
+        Some(Role::Viewer),
+        "{ get(id: \"ada\") { nickname } }",
+        json!({}),
+    )
+    .await;
+    snapshot(
+        "error_syntax",
+        Some(Role::Viewer),
+        "{ get(id: \"ada\" { id } }",
+        json!({}),
+    )
+    .await;
+    snapshot("error_missing_variable", Some(Role::Viewer), GET, json!({})).await;
+}
+
+/// A contact whose file is a FIFO can't be read until a writer turns up a
+/// second later; gets of other contacts meanwhile don't wait for it.
+#[cfg(unix)]
+#[tokio::test]
+async fn a_slow_read_doesnt_hold_up_other_gets() {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    let app = TestApp::with_files();
+    let editor = caller("u1", Role::Editor);
+    app.execute(Some(&editor), CREATE, ada()).await;
+    let grace = json!({ "contact": { "id": "grace", "firstName": "Grace", "lastName": "Hopper" } });
+    app.execute(Some(&editor), CREATE, grace).await;
+
+    let path = app.repo.files().unwrap().dir().join("contacts/u1/ada.json");
+    let stored = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let made = std::process::Command::new("mkfifo").arg(&path).status();
+    assert!(made.unwrap().success());
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(1));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|mut fifo| fifo.write_all(&stored))
+            .unwrap();
+    });
+
+    let start = Instant::now();
+    let slow = async {
+        let got = app
+            .execute(Some(&editor), GET, json!({ "id": "ada" }))
+            .await;
+        (got, start.elapsed())
+    };
+    let fast = futures::future::join_all((0..20).map(|_| async {
+        let got = app
+            .execute(Some(&editor), GET, json!({ "id": "grace" }))
+            .await;
+        (got, start.elapsed())
+    }));
+    let ((slow, slow_took), fast) = futures::future::join(slow, fast).await;
+    writer.join().unwrap();
+
+    assert_eq!(slow["data"]["get"]["firstName"], "Ada");
+    assert!(slow_took >= Duration::from_secs(1));
+    for (got, took) in fast {
+        assert_eq!(got["data"]["get"]["firstName"], "Grace");
+        assert!(took < Duration::from_millis(500), "a get took {:?}", took);
+    }
+}
+
+#[tokio::test]
+async fn lists_are_paged_up_to_the_page_size() {
+    let app = TestApp::new();
+    let admin = caller("u1", Role::Admin);
+    app.execute(Some(&admin), CREATE, ada()).await;
+    for name in &["King", "Byron"] {
+        let patch = format!(
+            "mutation {{ updateContactPartial(id: \"ada\", patch: {{ lastName: \"{}\" }}) {{ lastName }} }}",
+            name
+        );
+        assert!(errors(&app.execute(Some(&admin), &patch, json!({})).await).is_empty());
+    }
+    let log = |first: i32, offset: i32| {
+        let query = format!(
+            "{{ auditLog(contactId: \"ada\", first: {}, offset: {}) {{ action }} }}",
+            first, offset
+        );
+        let app = &app;
+        let admin = &admin;
+        async move { app.execute(Some(admin), &query, json!({})).await }
+    };
+
+    let got = log(2, 0).await;
+    assert_eq!(
+        got["data"]["auditLog"],
+        json!([{ "action": "create" }, { "action": "update" }])
+    );
+    let got = log(2, 2).await;
+    assert_eq!(got["data"]["auditLog"], json!([{ "action": "update" }]));
+    let got = log(101, 0).await;
+    assert_eq!(
+        errors(&got),
+        vec!["first must be between 1 and 100, got 101"]
+    );
+}
+
+#[tokio::test]
+async fn contacts_are_paged_by_cursor() {
+    // Over files, so the contacts are read off the file repository's stream.
+    let app = TestApp::with_files();
+    let editor = caller("u1", Role::Editor);
+    for id in &["grace", "ada", "edsger", "alan", "barbara"] {
+        let contact = json!({ "contact": { "id": id, "firstName": id, "lastName": "Doe" } });
+        assert!(errors(&app.execute(Some(&editor), CREATE, contact).await).is_empty());
+    }
+    let page = |first: i32, after: Option<&str>| {
+        let query = "query($first: Int, $after: String) { contacts(first: $first, after: $after) { edges { cursor node { id } } pageInfo { hasPreviousPage hasNextPage endCursor } } }";
+        let variables = json!({ "first": first, "after": after });
+        let app = &app;
+        let editor = &editor;
+        async move { app.execute(Some(editor), query, variables).await }
+    };
+
+    let got = page(2, None).await;
+    assert_eq!(
+        got["data"]["contacts"],
+        json!({
+            "edges": [
+                { "cursor": "ada", "node": { "id": "ada" } },
+                { "cursor": "alan", "node": { "id": "alan" } },
+            ],
+            "pageInfo": { "hasPreviousPage": false, "hasNextPage": true, "endCursor": "alan" },
+        })
+    );
+    let got = page(2, Some("alan")).await;
+    let ids: Vec<&str> = got["data"]["contacts"]["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["node"]["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["barbara", "edsger"]);
+    let got = page(2, Some("edsger")).await;
+    assert_eq!(
+        got["data"]["contacts"]["pageInfo"],
+        json!({ "hasPreviousPage": true, "hasNextPage": false, "endCursor": "grace" })
+    );
+    let got = page(101, None).await;
+    assert_eq!(
+        errors(&got),
+        vec!["first must be between 1 and 100, got 101"]
+    );
+}
+
+#[tokio::test]
+async fn deprecated_fields_are_served_over_their_replacements() {
+    assert_eq!(
+        super::deprecated(),
+        vec![
+            (
+                "MutationRoot.create".to_owned(),
+                "use createContact, which returns the whole contact"
+            ),
+            ("MutationRoot.delete".to_owned(), "use deleteContact"),
+            ("QueryRoot.get".to_owned(), "use contact"),
+        ]
+    );
+
+    let app = TestApp::new();
+    let admin = caller("u1", Role::Admin);
+    let created = app
+        .execute(
+            Some(&admin),
+            "mutation { createContact(contact: { id: \"ada\", firstName: \"Ada\", lastName: \"Lovelace\" }) { id ownerId } }",
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        created["data"]["createContact"],
+        json!({ "id": "ada", "ownerId": "u1" })
+    );
+    let got = app.execute(Some(&admin), GET, json!({ "id": "ada" })).await;
+    assert_eq!(got["data"]["get"]["lastName"], "Lovelace");
+    let deleted = app
+        .execute(
+            Some(&admin),
+            "mutation { delete(id: \"ada\") { id } }",
+            json!({}),
+        )
+        .await;
+    assert_eq!(deleted["data"]["delete"]["id"], "ada");
+    let got = app
+        .execute(Some(&admin), "{ contact(id: \"ada\") { id } }", json!({}))
+        .await;
+    assert_eq!(errors(&got).len(), 1);
+}
+
+#[tokio::test]
+async fn errors_are_in_the_callers_language_with_the_same_codes() {
+    use crate::i18n::{with_locale, Locale};
+
+    let app = TestApp::new();
+    let viewer = caller("u1", Role::Viewer);
+    let got = with_locale(
+        Locale::De,
+        app.execute(Some(&viewer), GET, json!({ "id": "nobody" })),
+    )
+    .await;
+    assert_eq!(errors(&got), vec!["nicht gefunden"]);
+    assert_eq!(got["errors"][0]["extensions"]["code"], "NOT_FOUND");
+
+    let created = with_locale(Locale::De, app.execute(Some(&viewer), CREATE, ada())).await;
+    assert_eq!(
+        errors(&created),
+        vec!["Verboten, erfordert die Rolle editor"]
+    );
+    assert_eq!(created["errors"][0]["extensions"]["code"], "FORBIDDEN");
+}
+
+#[tokio::test]
+async fn callers_without_the_pii_scope_see_names_but_not_contact_details() {
+    let app = TestApp::new();
+    let editor = caller("u1", Role::Editor);
+    app.execute(Some(&editor), CREATE, ada()).await;
+    let rename = json!({ "contact": { "id": "ada", "firstName": "Ada", "lastName": "Lovelace", "emails": ["countess@example.com"] } });
+    app.execute(Some(&editor), CREATE, rename).await;
+    let history = "{ contactHistory(id: \"ada\") { changes { field before after } } }";
+
+    let mut masked = caller("u1", Role::Viewer);
+    masked.scopes.clear();
+    let got = app
+        .execute(
+            Some(&masked),
+            "{ contact(id: \"ada\") { firstName emails phones addresses { country } } }",
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        got["data"]["contact"],
+        json!({ "firstName": "Ada", "emails": null, "phones": null, "addresses": null })
+    );
+    let changes = app.execute(Some(&masked), history, json!({})).await;
+    assert_eq!(
+        changes["data"]["contactHistory"][1]["changes"],
+        json!([{ "field": "emails", "before": null, "after": null }])
+    );
+    let vcard = app
+        .execute(Some(&masked), "{ exportVcard }", json!({}))
+        .await;
+    assert_eq!(
+        errors(&vcard),
+        vec!["Forbidden, requires scope contacts:pii"]
+    );
+    assert_eq!(vcard["errors"][0]["extensions"]["code"], "FORBIDDEN");
+    let mut admin = caller("u1", Role::Admin);
+    admin.scopes.clear();
+    let csv = app
+        .execute(Some(&admin), "{ exportContactsCsv }", json!({}))
+        .await;
+    assert_eq!(errors(&csv), vec!["Forbidden, requires scope contacts:pii"]);
+
+    let changes = app.execute(Some(&editor), history, json!({})).await;
+    assert_eq!(
+        changes["data"]["contactHistory"][1]["changes"][0]["after"],
+        "[\"countess@example.com\"]"
+    );
+}
+
+#[test]
+fn cached_responses_are_not_shared_with_callers_who_may_see_less() {
+    let request: async_graphql::http::GQLRequest =
+        serde_json::from_value(json!({ "query": "{ contact(id: \"ada\") { emails } }" })).unwrap();
+    let cache = ResponseCache::default();
+    let mut editor = caller("u1", Role::Editor);
+    editor.scopes.push("contacts:read".to_owned());
+    cache.insert(
+        super::cache_key(&request, None, Some(&editor)),
+        CachedResponse {
+            body: "{\"data\":{\"contact\":{\"emails\":[\"ada@example.com\"]}}}".to_owned(),
+            cache_control: "max-age=60".to_owned(),
+        },
+        60,
+        Default::default(),
+    );
+
+    let mut masked = editor.clone();
+    masked.scopes.clear();
+    assert!(cache
+        .get(&super::cache_key(&request, None, Some(&masked)))
+        .is_none());
+    let mut viewer = editor.clone();
+    viewer.role = Role::Viewer;
+    assert!(cache
+        .get(&super::cache_key(&request, None, Some(&viewer)))
+        .is_none());
+    let mut reordered = editor.clone();
+    reordered.scopes.reverse();
+    assert!(cache
+        .get(&super::cache_key(&request, None, Some(&reordered)))
+        .is_some());
+}
+
+#[tokio::test]
+async fn cached_responses_are_kept_apart_by_locale() {
+    use crate::i18n::{with_locale, Locale};
+
+    let request: async_graphql::http::GQLRequest =
+        serde_json::from_value(json!({ "query": "{ contact(id: \"nobody\") { id } }" })).unwrap();
+    let editor = caller("u1", Role::Editor);
+    let key = |locale| {
+        with_locale(locale, async {
+            super::cache_key(&request, None, Some(&editor))
+        })
+    };
+    assert_ne!(key(Locale::En).await, key(Locale::De).await);
+    assert_eq!(key(Locale::De).await, key(Locale::De).await);
+}
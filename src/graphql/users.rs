@@ -0,0 +1,193 @@
+use super::directives::{Auth, Length, Trimmed};
+use super::{blocking, tenant_repo};
+use crate::auth::{self, CurrentUser, JwtKey};
+use crate::models::*;
+use crate::session::{ActiveSession, SessionCookie, SessionStore};
+use crate::tenant::Tenant;
+use crate::usecases::*;
+use async_graphql::guard::Guard;
+use async_graphql::*;
+
+#[derive(Default)]
+pub struct UsersQuery;
+
+#[Object]
+impl UsersQuery {
+    /// The caller behind the current token or API key.
+    #[field(guard(Auth()))]
+    async fn me(&self, ctx: &Context<'_>) -> FieldResult<Viewer> {
+        Ok(ctx.data_unchecked::<CurrentUser>().clone().into())
+    }
+}
+
+#[SimpleObject]
+struct Viewer {
+    id: String,
+    role: String,
+    scopes: Vec<String>,
+}
+
+impl std::convert::From<CurrentUser> for Viewer {
+    fn from(u: CurrentUser) -> Self {
+        Self {
+            id: u.id,
+            role: u.role.to_string(),
+            scopes: u.scopes,
+        }
+    }
+}
+
+#[SimpleObject]
+struct Account {
+    id: String,
+    email: String,
+    role: String,
+}
+
+impl std::convert::From<User> for Account {
+    fn from(u: User) -> Self {
+        Self {
+            id: u.id,
+            email: u.email,
+            role: u.role.to_string(),
+        }
+    }
+}
+
+#[SimpleObject]
+struct AuthPayload {
+    token: String,
+    refresh_token: String,
+    user: Account,
+}
+
+#[derive(Default)]
+pub struct UsersMutation;
+
+#[Object]
+impl UsersMutation {
+    async fn sign_up(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "email", validator(Length(min = "3", max = "254")))] email: Trimmed,
+        #[arg(desc = "password", validator(Length(min = "8", max = "128")))] password: String,
+    ) -> FieldResult<AuthPayload> {
+        let repo = tenant_repo(ctx);
+        let (user, refresh_token) = blocking(move || {
+            let user = sign_up(&String::from(email), &password, &repo)?;
+            let refresh_token = issue_refresh_token(&user, &repo)?;
+            Ok((user, refresh_token))
+        })
+        .await?;
+        issue(ctx, user, refresh_token)
+    }
+
+    async fn login(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "email")] email: Trimmed,
+        #[arg(desc = "password")] password: String,
+    ) -> FieldResult<AuthPayload> {
+        let repo = tenant_repo(ctx);
+        let (user, refresh_token) = blocking(move || {
+            let user = login(&String::from(email), &password, &repo)?;
+            let refresh_token = issue_refresh_token(&user, &repo)?;
+            Ok((user, refresh_token))
+        })
+        .await?;
+        issue(ctx, user, refresh_token)
+    }
+
+    /// Trades a refresh token for a new access token and the next refresh
+    /// token; the one presented stops working.
+    async fn refresh_token(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "refresh token")] token: String,
+    ) -> FieldResult<AuthPayload> {
+        let repo = tenant_repo(ctx);
+        let (user, refresh_token) = blocking(move || rotate_refresh_token(&token, &repo)).await?;
+        issue(ctx, user, refresh_token)
+    }
+
+    /// Revokes a refresh token together with every token rotated from the
+    /// same login.
+    async fn revoke_refresh_token(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "refresh token")] token: String,
+    ) -> FieldResult<bool> {
+        let repo = tenant_repo(ctx);
+        blocking(move || revoke_refresh_token(&token, &repo)).await?;
+        Ok(true)
+    }
+
+    /// Logs in with a session cookie instead of a bearer token.
+    async fn session_login(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "email")] email: Trimmed,
+        #[arg(desc = "password")] password: String,
+    ) -> FieldResult<Account> {
+        let sessions = match ctx.data_opt::<SessionStore>() {
+            Some(sessions) => sessions.clone(),
+            None => return Err(FieldError("sessions are not enabled".to_string(), None)),
+        };
+        let repo = tenant_repo(ctx);
+        let tenant = ctx.data_opt::<Tenant>().map(|t| t.0.clone());
+        let store = sessions.clone();
+        let (user, value) = blocking(move || {
+            let user = login(&String::from(email), &password, &repo)?;
+            let value = store.create(&user, auth::login_scopes(), tenant.as_deref())?;
+            Ok((user, value))
+        })
+        .await?;
+        ctx.data_unchecked::<SessionCookie>()
+            .set(sessions.cookie(value));
+        Ok(user.into())
+    }
+
+    /// Ends the current session and clears its cookie.
+    async fn logout(&self, ctx: &Context<'_>) -> FieldResult<bool> {
+        let sessions = match ctx.data_opt::<SessionStore>() {
+            Some(sessions) => sessions.clone(),
+            None => return Ok(false),
+        };
+        ctx.data_unchecked::<SessionCookie>()
+            .set(sessions.removal_cookie());
+        match ctx.data_opt::<ActiveSession>() {
+            Some(session) => {
+                let id = session.0.clone();
+                blocking(move || sessions.destroy(&id)).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn issue(ctx: &Context<'_>, user: User, refresh_token: String) -> FieldResult<AuthPayload> {
+    let key = match ctx.data_opt::<JwtKey>() {
+        Some(key) => key,
+        None => {
+            return Err(FieldError(
+                "token signing is not configured".to_string(),
+                None,
+            ))
+        }
+    };
+    let caller = CurrentUser {
+        id: user.id.clone(),
+        role: user.role,
+        scopes: auth::login_scopes(),
+        tenant: ctx.data_opt::<Tenant>().map(|t| t.0.clone()),
+    };
+    match auth::encode(&caller, key) {
+        Ok(token) => Ok(AuthPayload {
+            token,
+            refresh_token,
+            user: user.into(),
+        }),
+        Err(e) => Err(FieldError(format!("{}", e), None)),
+    }
+}
@@ -0,0 +1,79 @@
+use super::directives::{Auth, RoleGuard};
+use super::{blocking, tenant_repo, Page};
+use crate::auth::Role;
+use crate::models::*;
+use crate::usecases::*;
+use async_graphql::guard::Guard;
+use async_graphql::*;
+
+#[derive(Default)]
+pub struct WebhooksQuery;
+
+#[Object]
+impl WebhooksQuery {
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn webhooks(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "webhooks to return, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "webhooks to skip")] offset: Option<i32>,
+    ) -> FieldResult<Vec<Webhook>> {
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        Ok(page.of(blocking(move || list_webhooks(&repo)).await?))
+    }
+
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn webhook_deliveries(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "webhook id")] webhook_id: String,
+        #[arg(desc = "deliveries to return, at most the server's page size")] first: Option<i32>,
+        #[arg(desc = "deliveries to skip")] offset: Option<i32>,
+    ) -> FieldResult<Vec<WebhookDelivery>> {
+        let page = Page::new(ctx, first, offset)?;
+        let repo = tenant_repo(ctx);
+        Ok(page.of(blocking(move || webhook_deliveries(&webhook_id, &repo)).await?))
+    }
+}
+
+#[derive(Default)]
+pub struct WebhooksMutation;
+
+#[Object]
+impl WebhooksMutation {
+    /// The signing secret is only ever returned here.
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn register_webhook(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "http:// endpoint to POST events to")] url: String,
+    ) -> FieldResult<RegisteredWebhook> {
+        let repo = tenant_repo(ctx);
+        let w = blocking(move || register_webhook(&url, &repo)).await?;
+        Ok(RegisteredWebhook {
+            id: w.id,
+            url: w.url,
+            secret: w.secret,
+        })
+    }
+
+    #[field(guard(Auth(), RoleGuard(role = "Role::Admin")))]
+    async fn delete_webhook(
+        &self,
+        ctx: &Context<'_>,
+        #[arg(desc = "id")] id: String,
+    ) -> FieldResult<bool> {
+        let repo = tenant_repo(ctx);
+        blocking(move || delete_webhook(&id, &repo)).await?;
+        Ok(true)
+    }
+}
+
+#[SimpleObject]
+struct RegisteredWebhook {
+    id: String,
+    url: String,
+    /// Key for the HMAC-SHA256 in `X-Webhook-Signature`.
+    secret: String,
+}
@@ -0,0 +1,269 @@
+//! Messages for people, in the language a request asks for in its
+//! `Accept-Language` header: English, the default, or German. The catalogs
+//! are compiled in. Only the message changes with the language; error codes
+//! stay the same for machines.
+//!
+//! The `Localize` middleware makes the negotiated locale current for the
+//! request, so messages can be written wherever an error is made, down to
+//! validators and use cases that never see the request.
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, Ready};
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+tokio::task_local! {
+    static LOCALE: Locale;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    De,
+}
+
+const EN: &[(&str, &str)] = &[
+    ("not_found", "not found"),
+    ("contact_not_found", "contact not found"),
+    ("unauthenticated", "Unauthenticated"),
+    ("forbidden_role", "Forbidden, requires role {role}"),
+    ("forbidden_scope", "Forbidden, requires scope {scope}"),
+    (
+        "forbidden_owner",
+        "Forbidden, only admins can access other owners' contacts",
+    ),
+    (
+        "value_length",
+        "the value length is {len}, must be between {min} and {max}",
+    ),
+    ("name_length", "{field} must be 1 to 100 characters"),
+    (
+        "first_range",
+        "first must be between 1 and {max}, got {got}",
+    ),
+    (
+        "limit_range",
+        "limit must be between 1 and {max}, got {got}",
+    ),
+    ("offset_negative", "offset can't be negative, got {got}"),
+    ("days_range", "days must be between 0 and {max}"),
+    ("invalid_contact_id", "invalid contact id"),
+    ("invalid_email", "invalid email address"),
+    ("email_registered", "email already registered"),
+    ("invalid_login", "invalid email or password"),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("not_found", "nicht gefunden"),
+    ("contact_not_found", "Kontakt nicht gefunden"),
+    ("unauthenticated", "Nicht angemeldet"),
+    ("forbidden_role", "Verboten, erfordert die Rolle {role}"),
+    ("forbidden_scope", "Verboten, erfordert den Scope {scope}"),
+    (
+        "forbidden_owner",
+        "Verboten, nur Admins haben Zugriff auf die Kontakte anderer",
+    ),
+    (
+        "value_length",
+        "die Länge ist {len}, muss zwischen {min} und {max} liegen",
+    ),
+    ("name_length", "{field} muss 1 bis 100 Zeichen lang sein"),
+    (
+        "first_range",
+        "first muss zwischen 1 und {max} liegen, ist {got}",
+    ),
+    (
+        "limit_range",
+        "limit muss zwischen 1 und {max} liegen, ist {got}",
+    ),
+    (
+        "offset_negative",
+        "offset darf nicht negativ sein, ist {got}",
+    ),
+    ("days_range", "days muss zwischen 0 und {max} liegen"),
+    ("invalid_contact_id", "ungültige Kontakt-ID"),
+    ("invalid_email", "ungültige E-Mail-Adresse"),
+    ("email_registered", "E-Mail-Adresse bereits registriert"),
+    ("invalid_login", "E-Mail-Adresse oder Passwort falsch"),
+];
+
+impl Locale {
+    /// The supported language the `Accept-Language` header prefers most,
+    /// English if it names none.
+    pub fn negotiate(accept_language: &str) -> Locale {
+        let mut best = (Locale::En, 0.0);
+        for range in accept_language.split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let language = tag.split('-').next().unwrap_or_default();
+            let locale = match language.to_ascii_lowercase().as_str() {
+                "en" | "*" => Locale::En,
+                "de" => Locale::De,
+                _ => continue,
+            };
+            if q > best.1 {
+                best = (locale, q);
+            }
+        }
+        best.0
+    }
+
+    /// The locale of the request being served, English outside one.
+    pub fn current() -> Locale {
+        LOCALE.try_with(|l| *l).unwrap_or(Locale::En)
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+        }
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Locale::En => EN,
+            Locale::De => DE,
+        }
+    }
+}
+
+/// The message `key` in the current locale, each `{name}` in it replaced by
+/// the value `args` gives for `name`.
+pub fn text(key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let catalog = Locale::current().catalog();
+    let template = match catalog.iter().find(|(k, _)| *k == key) {
+        Some((_, template)) => template,
+        None => return key.to_owned(),
+    };
+    let mut message = template.to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    message
+}
+
+/// Runs `f` with `locale` current, for work a request hands to another
+/// thread.
+pub async fn with_locale<F: Future>(locale: Locale, f: F) -> F::Output {
+    LOCALE.scope(locale, f).await
+}
+
+/// Actix middleware making the locale a request negotiates current while
+/// it's served, and saying which it was in `Content-Language`.
+pub struct Localize;
+
+impl<S, B> Transform<S> for Localize
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LocalizeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LocalizeMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct LocalizeMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for LocalizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let locale = req
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(Locale::En, Locale::negotiate);
+        let service = self.service.clone();
+        Box::pin(LOCALE.scope(locale, async move {
+            let fut = service.borrow_mut().call(req);
+            let mut resp = fut.await?;
+            let headers = resp.headers_mut();
+            headers.insert(
+                header::CONTENT_LANGUAGE,
+                HeaderValue::from_static(locale.tag()),
+            );
+            headers.append(header::VARY, HeaderValue::from_static("Accept-Language"));
+            Ok(resp)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_the_most_preferred_supported_language() {
+        assert_eq!(Locale::negotiate("de-DE,de;q=0.9,en;q=0.8"), Locale::De);
+        assert_eq!(Locale::negotiate("fr, en;q=0.5, de;q=0.7"), Locale::De);
+        assert_eq!(Locale::negotiate("de;q=0, en"), Locale::En);
+        assert_eq!(Locale::negotiate("fr-CH, fr;q=0.9"), Locale::En);
+        assert_eq!(Locale::negotiate(""), Locale::En);
+    }
+
+    #[test]
+    fn every_message_has_every_translation() {
+        let keys = |catalog: &'static [(&'static str, &'static str)]| {
+            catalog.iter().map(|(k, _)| *k).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(EN), keys(DE));
+        for ((key, en), (_, de)) in EN.iter().zip(DE) {
+            let names = |s: &str| {
+                let mut names: Vec<String> = s
+                    .split('{')
+                    .skip(1)
+                    .filter_map(|p| p.split('}').next().map(str::to_owned))
+                    .collect();
+                names.sort();
+                names
+            };
+            assert_eq!(names(en), names(de), "placeholders of {}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn text_is_in_the_current_locale() {
+        let text = || text("first_range", &[("max", &100), ("got", &101)]);
+        assert_eq!(text(), "first must be between 1 and 100, got 101");
+        assert_eq!(
+            with_locale(Locale::De, async { text() }).await,
+            "first muss zwischen 1 und 100 liegen, ist 101"
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Idempotency keys for mutations. A client that sends an `Idempotency-Key`
+//! header gets the response of the first successful request with that key
+//! replayed on every retry, instead of the mutation running again. The
+//! first request claims the key before it runs, so a retry arriving while
+//! it does is refused rather than run alongside. Keys are scoped to the
+//! caller and tenant, and stored responses expire after `TTL_SECS`.
+
+use crate::auth::now;
+use crate::crypto;
+use crate::repo::*;
+use actix_web::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+pub const HEADER: &str = "idempotency-key";
+
+/// How long a stored response is replayed for.
+pub const TTL_SECS: u64 = 24 * 3600;
+
+const MAX_KEY_LEN: usize = 255;
+
+/// The response to the first request made with an idempotency key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    /// Digest of the caller and the key, see `IdempotencyStore::id`.
+    pub id: String,
+    /// Digest of the request, so a key can't be reused for another one.
+    pub fingerprint: String,
+    pub body: String,
+    pub expires_at: u64,
+    /// Set on the claim of a request still running, which has no body yet.
+    #[serde(default)]
+    pub in_progress: bool,
+}
+
+impl Entity for StoredResponse {
+    const KIND: &'static str = "idempotency";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Expiring for StoredResponse {
+    fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+}
+
+/// The idempotency key of a request, if it sent one.
+pub fn key(headers: &HeaderMap) -> Result<Option<String>, String> {
+    let value = match headers.get(HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => Ok(Some(key.to_owned())),
+        _ => Err(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters",
+            MAX_KEY_LEN
+        )),
+    }
+}
+
+/// Digest of the parts of a GraphQL request that decide what it does.
+pub fn fingerprint(query: &str, operation_name: Option<&str>, variables: &str) -> String {
+    let input = format!(
+        "{}\0{}\0{}",
+        query,
+        operation_name.unwrap_or_default(),
+        variables
+    );
+    crypto::to_hex(&crypto::sha256(input.as_bytes()))
+}
+
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    repo: FileRepository<'static>,
+}
+
+impl IdempotencyStore {
+    pub fn new(repo: FileRepository<'static>) -> IdempotencyStore {
+        IdempotencyStore { repo }
+    }
+
+    /// Storage id of `key` as sent by `caller`, `None` for anonymous callers.
+    pub fn id(caller: Option<&str>, key: &str) -> String {
+        let input = format!("{}\0{}", caller.unwrap_or_default(), key);
+        crypto::to_hex(&crypto::sha256(input.as_bytes()))
+    }
+
+    /// Claims `id` for a request about to run, unless another request holds
+    /// it or has answered under it, whose claim or response comes back
+    /// instead. The claim is held for `hold_secs`, long enough for the
+    /// request to finish, and then lapses should it never be replaced by
+    /// `put` or let go of by `release`.
+    pub fn claim(
+        &self,
+        tenant: Option<&str>,
+        id: &str,
+        fingerprint: &str,
+        hold_secs: u64,
+    ) -> Result<Option<StoredResponse>, Box<dyn Error>> {
+        let partition = self.partition(tenant);
+        loop {
+            let claimed = partition.with_tx(|tx| {
+                let now = now();
+                if let Some(stored) = get_live(tx, id, now) {
+                    return Ok(Some(stored));
+                }
+                tx.set(StoredResponse {
+                    id: id.to_owned(),
+                    fingerprint: fingerprint.to_owned(),
+                    body: String::new(),
+                    expires_at: now + hold_secs,
+                    in_progress: true,
+                })?;
+                Ok(None)
+            });
+            match claimed {
+                // Another request claimed it first: look again.
+                Err(e) if is_conflict(e.as_ref()) => continue,
+                claimed => return claimed,
+            }
+        }
+    }
+
+    /// Stores the response to the request that claimed `id`, replaying it
+    /// from now on.
+    pub fn put(
+        &self,
+        tenant: Option<&str>,
+        id: String,
+        fingerprint: String,
+        body: String,
+    ) -> Result<(), Box<dyn Error>> {
+        self.partition(tenant).set(StoredResponse {
+            id,
+            fingerprint,
+            body,
+            expires_at: now() + TTL_SECS,
+            in_progress: false,
+        })?;
+        Ok(())
+    }
+
+    /// Lets go of the claim on `id`, for a request whose response isn't
+    /// replayed, so the key can be used again.
+    pub fn release(&self, tenant: Option<&str>, id: &str) -> Result<(), Box<dyn Error>> {
+        Repository::<StoredResponse>::delete(&self.partition(tenant), id)
+    }
+
+    fn partition(&self, tenant: Option<&str>) -> FileRepository<'static> {
+        match tenant {
+            Some(tenant) => self.repo.for_tenant(tenant),
+            None => self.repo.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{CurrentUser, Role};
+    use crate::cache::ResponseCache;
+    use crate::events::StorageMode;
+    use crate::graphql::{self, ContactsSchema};
+    use crate::settings::{Config, Limits, LiveLimits};
+    use crate::testing::caller;
+    use actix_service::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpMessage};
+    use serde_json::{json, Value};
+
+    const CREATE: &str = "mutation { createContact(contact: { id: \"ada\", firstName: \"Ada\", lastName: \"Lovelace\" }) { id } }";
+    const GET: &str = "query { get(id: \"ada\") { id } }";
+
+    fn scratch() -> (tempfile::TempDir, FileRepository<'static>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        (dir, FileRepository::new(Box::leak(path.into_boxed_str())))
+    }
+
+    struct Answer {
+        status: StatusCode,
+        replayed: bool,
+        body: String,
+    }
+
+    /// Sends `query` to the GraphQL endpoint as `user`, with `key`.
+    async fn send(
+        repo: &FileRepository<'static>,
+        user: CurrentUser,
+        key: &str,
+        query: &str,
+    ) -> Answer {
+        let schema: ContactsSchema =
+            graphql::schema_builder(StorageBackend::Files(repo.clone()), StorageMode::State)
+                .finish();
+        let mut app = test::init_service(
+            App::new()
+                .data(schema)
+                .data(None::<ResponseCache>)
+                .data(IdempotencyStore::new(repo.clone()))
+                .data(LiveLimits::new(Limits::load(&Config::default()).unwrap()))
+                .wrap_fn(move |req, srv| {
+                    req.extensions_mut().insert(user.clone());
+                    srv.call(req)
+                })
+                .service(web::resource("/").to(graphql::index)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/")
+            .header(HEADER, key)
+            .set_json(&json!({ "query": query }));
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let status = resp.status();
+        let replayed = resp.headers().contains_key("idempotent-replayed");
+        let body = test::read_body(resp).await;
+        Answer {
+            status,
+            replayed,
+            body: String::from_utf8(body.to_vec()).unwrap(),
+        }
+    }
+
+    fn stored(repo: &FileRepository<'static>) -> Vec<StoredResponse> {
+        repo.list("").unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_mutation_is_replayed_for_its_key() {
+        let (_dir, repo) = scratch();
+        let editor = || caller("u1", Role::Editor);
+        let first = send(&repo, editor(), "k1", CREATE).await;
+        assert_eq!(first.status, StatusCode::OK);
+        assert!(!first.replayed);
+        let created: Value = serde_json::from_str(&first.body).unwrap();
+        assert_eq!(created["data"]["createContact"]["id"], "ada");
+
+        // The contact is gone, but the retry doesn't create it again.
+        Repository::<crate::models::Contact>::delete(&repo, "u1/ada").unwrap();
+        let retry = send(&repo, editor(), "k1", CREATE).await;
+        assert_eq!(retry.status, StatusCode::OK);
+        assert!(retry.replayed);
+        assert_eq!(retry.body, first.body);
+        assert!(Repository::<crate::models::Contact>::get(&repo, "u1/ada").is_err());
+
+        let other = send(
+            &repo,
+            editor(),
+            "k1",
+            "mutation { deleteContact(id: \"ada\") }",
+        )
+        .await;
+        assert_eq!(other.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(other.body.contains("already used for a different request"));
+    }
+
+    #[tokio::test]
+    async fn a_key_in_progress_is_refused() {
+        let (_dir, repo) = scratch();
+        let responses = IdempotencyStore::new(repo.clone());
+        let id = IdempotencyStore::id(Some("u1"), "k1");
+        let fingerprint = fingerprint(CREATE, None, "");
+        assert!(responses
+            .claim(None, &id, &fingerprint, 60)
+            .unwrap()
+            .is_none());
+
+        let retry = send(&repo, caller("u1", Role::Editor), "k1", CREATE).await;
+        assert_eq!(retry.status, StatusCode::CONFLICT);
+        assert!(retry.body.contains("Idempotency-Key in progress"));
+        assert!(Repository::<crate::models::Contact>::get(&repo, "u1/ada").is_err());
+
+        responses.release(None, &id).unwrap();
+        let retry = send(&repo, caller("u1", Role::Editor), "k1", CREATE).await;
+        assert_eq!(retry.status, StatusCode::OK);
+        assert!(!retry.replayed);
+    }
+
+    #[tokio::test]
+    async fn queries_and_failures_are_not_stored() {
+        let (_dir, repo) = scratch();
+        let query = send(&repo, caller("u1", Role::Viewer), "k1", GET).await;
+        assert_eq!(query.status, StatusCode::OK);
+        let forbidden = send(&repo, caller("u1", Role::Viewer), "k2", CREATE).await;
+        assert!(forbidden.body.contains("errors"), "{}", forbidden.body);
+        assert!(stored(&repo).is_empty());
+
+        // So the keys are free for other requests.
+        let created = send(&repo, caller("u1", Role::Editor), "k1", CREATE).await;
+        assert!(!created.replayed);
+        assert!(!created.body.contains("errors"), "{}", created.body);
+        let again = send(&repo, caller("u1", Role::Editor), "k2", CREATE).await;
+        assert!(!again.replayed);
+    }
+
+    #[tokio::test]
+    async fn keys_are_partitioned_by_caller_and_tenant() {
+        let (_dir, repo) = scratch();
+        let in_tenant = |id: &str, tenant: &str| CurrentUser {
+            tenant: Some(tenant.to_owned()),
+            ..caller(id, Role::Editor)
+        };
+        let first = send(&repo, caller("u1", Role::Editor), "k1", CREATE).await;
+        assert!(!first.replayed);
+        for user in [
+            caller("u2", Role::Editor),
+            in_tenant("u1", "t1"),
+            in_tenant("u1", "t2"),
+        ] {
+            let answer = send(&repo, user, "k1", CREATE).await;
+            assert_eq!(answer.status, StatusCode::OK);
+            assert!(!answer.replayed);
+        }
+        assert!(
+            send(&repo, in_tenant("u1", "t1"), "k1", CREATE)
+                .await
+                .replayed
+        );
+        assert!(
+            send(&repo, caller("u1", Role::Editor), "k1", CREATE)
+                .await
+                .replayed
+        );
+        assert_eq!(stored(&repo).len(), 2);
+        assert_eq!(stored(&repo.for_tenant("t1")).len(), 1);
+    }
+
+    #[test]
+    fn responses_expire_and_claims_lapse() {
+        let (_dir, repo) = scratch();
+        let responses = IdempotencyStore::new(repo.clone());
+        repo.set(StoredResponse {
+            id: "old".to_owned(),
+            fingerprint: "f".to_owned(),
+            body: "{}".to_owned(),
+            expires_at: now() - 1,
+            in_progress: false,
+        })
+        .unwrap();
+        assert!(responses.claim(None, "old", "f", 60).unwrap().is_none());
+
+        // A claim held for no time lapses at once, as if its request died.
+        assert!(responses.claim(None, "lapsed", "f", 0).unwrap().is_none());
+        assert!(responses.claim(None, "lapsed", "f", 60).unwrap().is_none());
+        let held = responses.claim(None, "lapsed", "f", 60).unwrap().unwrap();
+        assert!(held.in_progress);
+
+        responses
+            .put(None, "lapsed".to_owned(), "f".to_owned(), "{}".to_owned())
+            .unwrap();
+        let done = responses.claim(None, "lapsed", "f", 60).unwrap().unwrap();
+        assert!(!done.in_progress);
+        assert!(done.expires_at >= now() + TTL_SECS - 1);
+    }
+
+    #[test]
+    fn only_one_of_concurrent_claims_wins() {
+        let (_dir, repo) = scratch();
+        let responses = IdempotencyStore::new(repo);
+        let claims: Vec<_> = (0..8)
+            .map(|_| {
+                let responses = responses.clone();
+                std::thread::spawn(move || responses.claim(None, "k", "f", 60).unwrap().is_none())
+            })
+            .collect();
+        let won = claims
+            .into_iter()
+            .map(|claim| claim.join().unwrap())
+            .filter(|won| *won)
+            .count();
+        assert_eq!(won, 1);
+    }
+}
@@ -0,0 +1,342 @@
+//! `InstrumentedRepository` wraps any repository backend and times each of
+//! its calls, counts the calls that fail and the bytes they move, into the
+//! metrics registry labelled with the backend's name, and traces each call.
+//! Sizes are of the entities as JSON and of blobs as they are, so backends
+//! storing them differently are measured alike. The backends themselves
+//! aren't instrumented, so each call is recorded once.
+
+use crate::metrics;
+use crate::repo::{is_not_found, Blobs, Entity, Repository, Transactional};
+use crate::telemetry;
+use futures::stream::{LocalBoxStream, StreamExt};
+use serde::Serialize;
+use std::error::Error;
+use std::time::Instant;
+
+/// The kinds blob calls and commits are recorded under.
+const BLOBS: &str = "blobs";
+const TRANSACTIONS: &str = "transactions";
+
+#[derive(Clone)]
+pub struct InstrumentedRepository<R> {
+    inner: R,
+    backend: &'static str,
+}
+
+impl<R> InstrumentedRepository<R> {
+    /// Instruments `inner`, naming it `backend` in the metrics and spans.
+    pub fn new(backend: &'static str, inner: R) -> InstrumentedRepository<R> {
+        InstrumentedRepository { inner, backend }
+    }
+
+    /// Runs a call of the backend and records it; `bytes` is the size of
+    /// what the call returned.
+    fn observe<T>(
+        &self,
+        operation: &'static str,
+        kind: &'static str,
+        call: impl FnOnce() -> Result<T, Box<dyn Error>>,
+        bytes: impl FnOnce(&T) -> usize,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut observed = self.start(operation, kind);
+        let result = call();
+        match &result {
+            Ok(value) => observed.bytes += bytes(value),
+            Err(e) => observed.failed(e.as_ref()),
+        }
+        result
+    }
+
+    fn start(&self, operation: &'static str, kind: &'static str) -> Observed {
+        Observed {
+            backend: self.backend,
+            operation,
+            kind,
+            span: telemetry::span(format!("{}.{}", self.backend, operation))
+                .map(|s| s.with("repository.kind", kind)),
+            started: Instant::now(),
+            bytes: 0,
+            error: None,
+        }
+    }
+}
+
+/// A call being made, recorded when dropped: when a call returns, or for a
+/// stream, when it ends or is abandoned.
+struct Observed {
+    backend: &'static str,
+    operation: &'static str,
+    kind: &'static str,
+    span: Option<telemetry::Span>,
+    started: Instant,
+    bytes: usize,
+    error: Option<String>,
+}
+
+impl Observed {
+    /// A missing record is an answer rather than a failure, so it isn't
+    /// counted as an error.
+    fn failed(&mut self, e: &(dyn Error + 'static)) {
+        if !is_not_found(e) {
+            self.error = Some(e.to_string());
+        }
+    }
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let failed = self.error.is_some();
+        metrics::REGISTRY.repository(self.operation, self.kind);
+        metrics::REGISTRY.repository_call(
+            self.backend,
+            self.operation,
+            self.kind,
+            seconds,
+            self.bytes,
+            failed,
+        );
+        if let Some(span) = self.span.as_mut() {
+            span.set_attribute("repository.bytes", self.bytes.to_string());
+            if let Some(e) = self.error.take() {
+                span.set_error(e);
+            }
+        }
+    }
+}
+
+/// The length of `value` as JSON, counted without keeping the JSON.
+fn json_len<T: Serialize>(value: &T) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+impl<T: Serialize + Entity, R: Repository<T>> Repository<T> for InstrumentedRepository<R> {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        let size = json_len(&obj);
+        self.observe("set", T::KIND, || self.inner.set(obj), |_| size)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        self.observe("get", T::KIND, || self.inner.get(key), json_len)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.observe("delete", T::KIND, || self.inner.delete(key), |_| 0)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        self.observe(
+            "list",
+            T::KIND,
+            || self.inner.list(prefix),
+            |all| all.iter().map(json_len).sum(),
+        )
+    }
+
+    /// Recorded as one call, of the whole stream, once it ends.
+    fn stream(&self, prefix: &str) -> LocalBoxStream<'static, Result<T, Box<dyn Error>>>
+    where
+        T: 'static,
+    {
+        let mut observed = self.start("stream", T::KIND);
+        self.inner
+            .stream(prefix)
+            .map(move |item| {
+                match &item {
+                    Ok(obj) => observed.bytes += json_len(obj),
+                    Err(e) => observed.failed(e.as_ref()),
+                }
+                item
+            })
+            .boxed_local()
+    }
+}
+
+impl<R: Blobs> Blobs for InstrumentedRepository<R> {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.observe(
+            "put_blob",
+            BLOBS,
+            || self.inner.put_blob(key, data),
+            |_| data.len(),
+        )
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.observe("get_blob", BLOBS, || self.inner.get_blob(key), Vec::len)
+    }
+
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.observe(
+            "delete_blobs",
+            BLOBS,
+            || self.inner.delete_blobs(key),
+            |_| 0,
+        )
+    }
+}
+
+/// A transaction's reads and writes are recorded as the backend's, and its
+/// commit as a call of its own.
+impl<R: Transactional> Transactional for InstrumentedRepository<R> {
+    type Transaction = InstrumentedRepository<R::Transaction>;
+
+    fn begin(&self) -> Self::Transaction {
+        InstrumentedRepository::new(self.backend, self.inner.begin())
+    }
+
+    fn commit(&self, tx: Self::Transaction) -> Result<(), Box<dyn Error>> {
+        self.observe(
+            "commit",
+            TRANSACTIONS,
+            || self.inner.commit(tx.inner),
+            |_| 0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Contact;
+    use crate::repo::{FileRepository, MemoryRepository};
+    use crate::testing::{ContactFixture, MockRepository, Operation};
+
+    repository_contract!(instrumented, |path| InstrumentedRepository::new(
+        "test-contract",
+        FileRepository::new(path)
+    ));
+    repository_contract!(instrumented_transaction, |path| {
+        InstrumentedRepository::new("test-contract", FileRepository::new(path)).begin()
+    });
+
+    /// The lines of the rendered metrics about `backend`.
+    fn lines(backend: &str) -> Vec<String> {
+        let label = format!("backend=\"{}\"", backend);
+        metrics::REGISTRY
+            .render()
+            .lines()
+            .filter(|l| l.contains(&label) && !l.contains("_bucket"))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn records_latency_errors_and_bytes_by_backend() {
+        let repo = InstrumentedRepository::new("test-memory", MemoryRepository::new());
+        let ada = ContactFixture::new("ada").build();
+        let size = serde_json::to_vec(&ada).unwrap().len();
+        repo.set(ada).unwrap();
+        let _: Contact = repo.get("u1/ada").unwrap();
+        assert!(Repository::<Contact>::get(&repo, "u1/nobody").is_err());
+
+        let memory = lines("test-memory");
+        let get = "backend=\"test-memory\",operation=\"get\",kind=\"contacts\"";
+        let set = "backend=\"test-memory\",operation=\"set\",kind=\"contacts\"";
+        assert!(memory.contains(&format!(
+            "repository_call_duration_seconds_count{{{}}} 2",
+            get
+        )));
+        assert!(memory.contains(&format!(
+            "repository_payload_bytes_total{{{}}} {}",
+            set, size
+        )));
+        assert!(memory.contains(&format!(
+            "repository_payload_bytes_total{{{}}} {}",
+            get, size
+        )));
+        assert!(!memory
+            .iter()
+            .any(|l| l.starts_with("repository_errors_total")));
+
+        let failing = MockRepository::default();
+        failing.fail(Operation::Get, Contact::KIND, "unreadable");
+        let repo = InstrumentedRepository::new("test-failing", failing);
+        assert!(Repository::<Contact>::get(&repo, "u1/ada").is_err());
+        assert!(lines("test-failing").contains(
+            &"repository_errors_total{backend=\"test-failing\",operation=\"get\",kind=\"contacts\"} 1"
+                .to_owned()
+        ));
+    }
+
+    #[test]
+    fn records_lists_deletes_blobs_and_commits() {
+        let repo = InstrumentedRepository::new("test-calls", MemoryRepository::new());
+        let ada = ContactFixture::new("ada").build();
+        let size = serde_json::to_vec(&ada).unwrap().len();
+        let tx = repo.begin();
+        tx.set(ada).unwrap();
+        repo.commit(tx).unwrap();
+        let all: Vec<Contact> = repo.list("u1").unwrap();
+        assert_eq!(all.len(), 1);
+        let streamed = futures::executor::block_on(
+            Repository::<Contact>::stream(&repo, "u1").collect::<Vec<_>>(),
+        );
+        assert_eq!(streamed.len(), 1);
+        Repository::<Contact>::delete(&repo, "u1/ada").unwrap();
+        repo.put_blob("avatars/ada", b"png").unwrap();
+        assert_eq!(repo.get_blob("avatars/ada").unwrap(), b"png");
+        repo.delete_blobs("avatars").unwrap();
+
+        let calls = lines("test-calls");
+        let has = |metric: &str, operation: &str, kind: &str, value: usize| {
+            calls.contains(&format!(
+                "{}{{backend=\"test-calls\",operation=\"{}\",kind=\"{}\"}} {}",
+                metric, operation, kind, value
+            ))
+        };
+        assert!(has(
+            "repository_payload_bytes_total",
+            "set",
+            "contacts",
+            size
+        ));
+        assert!(has(
+            "repository_call_duration_seconds_count",
+            "commit",
+            TRANSACTIONS,
+            1
+        ));
+        assert!(has(
+            "repository_payload_bytes_total",
+            "list",
+            "contacts",
+            size
+        ));
+        assert!(has(
+            "repository_payload_bytes_total",
+            "stream",
+            "contacts",
+            size
+        ));
+        assert!(has(
+            "repository_call_duration_seconds_count",
+            "delete",
+            "contacts",
+            1
+        ));
+        assert!(has("repository_payload_bytes_total", "put_blob", BLOBS, 3));
+        assert!(has("repository_payload_bytes_total", "get_blob", BLOBS, 3));
+        assert!(has(
+            "repository_call_duration_seconds_count",
+            "delete_blobs",
+            BLOBS,
+            1
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+//! Background jobs. Recurring maintenance runs on a single runner thread
+//! with its own actix system, started alongside the server: webhook
+//! deliveries and their retries, scheduled backups, rebuilding the contact
+//! projections from their event streams and compacting away expired
+//! records. Jobs run one at a time, each again `interval` after its last
+//! run started; on shutdown the runner finishes the job it's on and exits.
+
+use crate::auth::now;
+use crate::events::{Storage, StorageMode};
+use crate::idempotency::StoredResponse;
+use crate::models::{JobStatus, RefreshToken, Session};
+use crate::repo::*;
+use crate::shutdown::Shutdown;
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the runner looks for due jobs.
+const TICK: Duration = Duration::from_millis(250);
+const REBUILD_INTERVAL: Duration = Duration::from_secs(3600);
+const COMPACT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Runs a job once, describing what it did or why it failed.
+pub type Run = Box<dyn FnMut() -> LocalBoxFuture<'static, Result<String, String>> + Send>;
+
+pub struct Job {
+    name: &'static str,
+    interval: Duration,
+    first_run: Duration,
+    run: Run,
+}
+
+impl Job {
+    pub fn new(name: &'static str, interval: Duration, run: Run) -> Job {
+        Job {
+            name,
+            interval,
+            first_run: interval,
+            run,
+        }
+    }
+
+    /// Runs the job for the first time after `delay` instead of `interval`.
+    pub fn first_run_after(mut self, delay: Duration) -> Job {
+        self.first_run = delay;
+        self
+    }
+}
+
+/// The status of every registered job, shared with the admin API.
+#[derive(Clone, Default)]
+pub struct Jobs(Arc<Mutex<Vec<JobStatus>>>);
+
+impl Jobs {
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update(&self, i: usize, f: impl FnOnce(&mut JobStatus)) {
+        f(&mut self.0.lock().unwrap()[i]);
+    }
+}
+
+/// Starts the runner thread for `jobs`.
+pub fn spawn_runner(jobs: Vec<Job>, status: Jobs, shutdown: Shutdown) -> thread::JoinHandle<()> {
+    let started = Instant::now();
+    *status.0.lock().unwrap() = jobs
+        .iter()
+        .map(|job| JobStatus {
+            name: job.name.to_owned(),
+            interval_secs: job.interval.as_secs() as i32,
+            running: false,
+            runs: 0,
+            failures: 0,
+            last_run_at: None,
+            last_duration_ms: None,
+            last_result: None,
+            last_error: None,
+            next_run_at: (now() + job.first_run.as_secs()) as i64,
+        })
+        .collect();
+    thread::spawn(move || {
+        let mut jobs: Vec<(Job, Instant)> = jobs
+            .into_iter()
+            .map(|job| {
+                let due = started + job.first_run;
+                (job, due)
+            })
+            .collect();
+        let mut sys = actix_rt::System::new("jobs");
+        sys.block_on(async move {
+            while !shutdown.is_triggered() {
+                for (i, (job, due)) in jobs.iter_mut().enumerate() {
+                    if *due > Instant::now() || shutdown.is_triggered() {
+                        continue;
+                    }
+                    let run_start = Instant::now();
+                    *due = run_start + job.interval;
+                    status.update(i, |s| {
+                        s.running = true;
+                        s.last_run_at = Some(now() as i64);
+                    });
+                    let result = (job.run)().await;
+                    let elapsed = run_start.elapsed();
+                    if let Err(e) = &result {
+                        warn!("job {} failed: {}", job.name, e);
+                    }
+                    status.update(i, |s| {
+                        s.running = false;
+                        s.runs += 1;
+                        s.last_duration_ms = Some(elapsed.as_millis() as i32);
+                        s.next_run_at = (now() + job.interval.as_secs()) as i64;
+                        match result {
+                            Ok(done) => {
+                                s.last_result = Some(done);
+                                s.last_error = None;
+                            }
+                            Err(e) => {
+                                s.failures += 1;
+                                s.last_error = Some(e);
+                            }
+                        }
+                    });
+                }
+                shutdown.delay(TICK).await;
+            }
+        })
+    })
+}
+
+/// Rebuilds the stored contacts of every partition from their event
+/// streams, repairing projections a crash left behind their stream. Runs
+/// first at startup; only registered for event-sourced storage.
+pub fn rebuild_projections(repo: FileRepository<'static>) -> Job {
+    Job::new(
+        "rebuild-projections",
+        REBUILD_INTERVAL,
+        Box::new(move || {
+            let repo = repo.clone();
+            Box::pin(async move {
+                let mut repaired = 0;
+                for repo in partitions(&repo) {
+                    let storage = Storage::new(repo, StorageMode::Events);
+                    repaired += storage.rebuild_projections().map_err(|e| e.to_string())?;
+                }
+                Ok(format!("repaired {} contacts", repaired))
+            })
+        }),
+    )
+    .first_run_after(Duration::from_secs(0))
+}
+
+/// Deletes expired sessions, refresh tokens and stored idempotent
+/// responses, which are otherwise only deleted when read. Runs first at
+/// startup.
+pub fn compact(repo: FileRepository<'static>) -> Job {
+    Job::new(
+        "compact",
+        COMPACT_INTERVAL,
+        Box::new(move || {
+            let repo = repo.clone();
+            Box::pin(async move {
+                let mut deleted = 0;
+                for repo in partitions(&repo) {
+                    deleted += sweep::<Session>(&repo)?;
+                    deleted += sweep::<RefreshToken>(&repo)?;
+                    deleted += sweep::<StoredResponse>(&repo)?;
+                }
+                Ok(format!("deleted {} expired records", deleted))
+            })
+        }),
+    )
+    .first_run_after(Duration::from_secs(0))
+}
+
+fn sweep<T>(repo: &FileRepository<'static>) -> Result<usize, String>
+where
+    T: Expiring + DeserializeOwned + Serialize,
+{
+    let now = now();
+    let mut deleted = 0;
+    for key in repo.keys(T::KIND).map_err(|e| e.to_string())? {
+        match Repository::<T>::get(repo, &key) {
+            Ok(obj) if obj.expires_at() <= now => {
+                Repository::<T>::delete(repo, &key).map_err(|e| e.to_string())?;
+                deleted += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(deleted)
+}
+
+/// The repository and each tenant's partition of it.
+pub fn partitions(repo: &FileRepository<'static>) -> Vec<FileRepository<'static>> {
+    let mut repos = vec![repo.clone()];
+    repos.extend(repo.tenants());
+    repos
+}
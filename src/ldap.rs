@@ -0,0 +1,967 @@
+//! Contacts imported from an LDAP directory, Active Directory included.
+//! Entries are searched for a page at a time and their inetOrgPerson
+//! attributes mapped to contacts. Directory contacts keep their `uid`, or
+//! `sAMAccountName` on Active Directory, as id, `ldap-jdoe`, so importing
+//! again updates them; people sharing an email with one of the owner's
+//! other contacts are reported as duplicates and left out.
+//!
+//! Configured by `LDAP_URL`, `ldap://host:port`, and `LDAP_BASE_DN`, with
+//! `LDAP_BIND_DN` and `LDAP_BIND_PASSWORD` for a simple bind, anonymous
+//! without them, and `LDAP_FILTER` to choose the entries, by default
+//! `(objectClass=inetOrgPerson)`. Only plain LDAP is spoken, so `ldaps://`
+//! needs a TLS-terminating proxy in front of the directory.
+
+use crate::models::{Address, Contact};
+use crate::usecases::ImportRow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const DEFAULT_FILTER: &str = "(objectClass=inetOrgPerson)";
+const DEFAULT_PORT: u16 = 389;
+/// Active Directory answers at most 1000 entries a page.
+const PAGE_SIZE: i64 = 500;
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages larger than this are refused.
+const MAX_MESSAGE_BYTES: usize = 16 << 20;
+const MAX_ID_LEN: usize = 64;
+const PAGED_RESULTS: &str = "1.2.840.113556.1.4.319";
+const ATTRIBUTES: &[&str] = &[
+    "uid",
+    "sAMAccountName",
+    "cn",
+    "displayName",
+    "givenName",
+    "sn",
+    "mail",
+    "telephoneNumber",
+    "mobile",
+    "homePhone",
+    "street",
+    "postalAddress",
+    "l",
+    "st",
+    "postalCode",
+    "c",
+];
+
+pub struct LdapConfig {
+    address: String,
+    bind_dn: String,
+    bind_password: String,
+    base_dn: String,
+    filter: String,
+}
+
+impl LdapConfig {
+    /// The configured directory, `None` without `LDAP_URL`.
+    pub fn from_env() -> Result<Option<LdapConfig>, String> {
+        let url = match std::env::var("LDAP_URL") {
+            Ok(url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_owned(),
+            _ => return Ok(None),
+        };
+        if url.starts_with("ldaps://") {
+            return Err(
+                "LDAP_URL over ldaps needs a TLS-enabled build, point it at a proxy".to_owned(),
+            );
+        }
+        let host = url
+            .strip_prefix("ldap://")
+            .ok_or("LDAP_URL must start with ldap://")?;
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("LDAP_URL {:?} must name just a host and port", url));
+        }
+        let address = if host.ends_with(']') || !host.contains(':') {
+            format!("{}:{}", host, DEFAULT_PORT)
+        } else {
+            host.to_owned()
+        };
+        let base_dn = std::env::var("LDAP_BASE_DN")
+            .map_err(|_| "LDAP_URL is set but LDAP_BASE_DN isn't".to_owned())?;
+        let bind_dn = std::env::var("LDAP_BIND_DN").unwrap_or_default();
+        let bind_password = std::env::var("LDAP_BIND_PASSWORD").unwrap_or_default();
+        if !bind_dn.is_empty() && bind_password.is_empty() {
+            return Err("LDAP_BIND_DN is set but LDAP_BIND_PASSWORD isn't".to_owned());
+        }
+        let filter = std::env::var("LDAP_FILTER")
+            .ok()
+            .filter(|f| !f.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_FILTER.to_owned());
+        // Refuse a bad filter before connecting.
+        Filter::parse(filter.trim())?;
+        Ok(Some(LdapConfig {
+            address,
+            bind_dn: bind_dn.trim().to_owned(),
+            bind_password,
+            base_dn: base_dn.trim().to_owned(),
+            filter: filter.trim().to_owned(),
+        }))
+    }
+}
+
+/// A directory entry's attributes, keyed by lowercased name.
+#[derive(Debug, Default)]
+pub struct Entry {
+    dn: String,
+    attributes: HashMap<String, Vec<String>>,
+}
+
+impl Entry {
+    fn values(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.attributes
+            .get(&name.to_ascii_lowercase())
+            .into_iter()
+            .flatten()
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn first(&self, name: &str) -> String {
+        self.values(name).next().unwrap_or_default().to_owned()
+    }
+}
+
+/// Binds, then fetches every entry matching the filter under the base DN;
+/// `progress` hears how many were fetched so far.
+pub fn entries(config: &LdapConfig, mut progress: impl FnMut(usize)) -> Result<Vec<Entry>, String> {
+    let mut connection = Connection::open(config)?;
+    connection.bind(&config.bind_dn, &config.bind_password)?;
+    let filter = Filter::parse(&config.filter)?;
+    let mut entries = vec![];
+    let mut cookie = vec![];
+    loop {
+        let (page, next) = connection.search(&config.base_dn, &filter, &cookie)?;
+        entries.extend(page);
+        progress(entries.len());
+        match next {
+            Some(next) if !next.is_empty() => cookie = next,
+            _ => break,
+        }
+    }
+    connection.unbind();
+    Ok(entries)
+}
+
+struct Connection {
+    stream: TcpStream,
+    next_id: i64,
+}
+
+impl Connection {
+    fn open(config: &LdapConfig) -> Result<Connection, String> {
+        let connect = |e: std::io::Error| format!("connecting to {} failed: {}", config.address, e);
+        let stream = TcpStream::connect(&config.address).map_err(connect)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(connect)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(connect)?;
+        Ok(Connection { stream, next_id: 1 })
+    }
+
+    /// Sends a request, returning its message id.
+    fn send(&mut self, op: Vec<u8>, controls: Option<Vec<u8>>) -> Result<i64, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut message = integer(INTEGER, id);
+        message.extend(op);
+        if let Some(controls) = controls {
+            message.extend(tlv(CONTROLS, &controls));
+        }
+        self.stream
+            .write_all(&tlv(SEQUENCE, &message))
+            .map_err(|e| format!("writing to the directory failed: {}", e))?;
+        Ok(id)
+    }
+
+    /// Reads the next message of request `id`: its protocol op, the op's
+    /// contents and the message's controls.
+    fn receive(&mut self, id: i64) -> Result<(u8, Vec<u8>, Vec<u8>), String> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            let mut fields = Ber::new(&message);
+            let received = fields.integer(INTEGER)?;
+            let (tag, op) = fields.any()?;
+            let controls = match fields.optional(CONTROLS)? {
+                Some(controls) => controls.to_vec(),
+                None => vec![],
+            };
+            if received == 0 {
+                // An unsolicited notification, the server closing the connection.
+                return Err(format!(
+                    "the directory disconnected: {}",
+                    LdapResult::parse(op).map(|r| r.message).unwrap_or_default()
+                ));
+            }
+            if received == id {
+                return Ok((tag, op.to_vec(), controls));
+            }
+        }
+    }
+
+    fn bind(&mut self, dn: &str, password: &str) -> Result<(), String> {
+        let mut op = integer(INTEGER, 3);
+        op.extend(tlv(OCTET_STRING, dn.as_bytes()));
+        op.extend(tlv(SIMPLE_AUTH, password.as_bytes()));
+        let id = self.send(tlv(BIND_REQUEST, &op), None)?;
+        let (tag, op, _) = self.receive(id)?;
+        if tag != BIND_RESPONSE {
+            return Err("unexpected response to binding".to_owned());
+        }
+        LdapResult::parse(&op)?
+            .ok()
+            .map_err(|e| format!("binding failed: {}", e))
+    }
+
+    /// One page of a subtree search, with the cookie of the next page.
+    fn search(
+        &mut self,
+        base: &str,
+        filter: &Filter,
+        cookie: &[u8],
+    ) -> Result<(Vec<Entry>, Option<Vec<u8>>), String> {
+        let mut op = tlv(OCTET_STRING, base.as_bytes());
+        op.extend(integer(ENUMERATED, 2)); // wholeSubtree
+        op.extend(integer(ENUMERATED, 0)); // neverDerefAliases
+        op.extend(integer(INTEGER, 0));
+        op.extend(integer(INTEGER, 0));
+        op.extend(tlv(BOOLEAN, &[0]));
+        op.extend(filter.encode());
+        let attributes: Vec<u8> = ATTRIBUTES
+            .iter()
+            .flat_map(|a| tlv(OCTET_STRING, a.as_bytes()))
+            .collect();
+        op.extend(tlv(SEQUENCE, &attributes));
+        let mut paging = integer(INTEGER, PAGE_SIZE);
+        paging.extend(tlv(OCTET_STRING, cookie));
+        let mut control = tlv(OCTET_STRING, PAGED_RESULTS.as_bytes());
+        control.extend(tlv(OCTET_STRING, &tlv(SEQUENCE, &paging)));
+        let id = self.send(tlv(SEARCH_REQUEST, &op), Some(tlv(SEQUENCE, &control)))?;
+        let mut entries = vec![];
+        loop {
+            let (tag, op, controls) = self.receive(id)?;
+            match tag {
+                SEARCH_ENTRY => entries.push(parse_entry(&op)?),
+                SEARCH_REFERENCE => {}
+                SEARCH_DONE => {
+                    LdapResult::parse(&op)?
+                        .ok()
+                        .map_err(|e| format!("searching failed: {}", e))?;
+                    return Ok((entries, paged_cookie(&controls)?));
+                }
+                _ => return Err("unexpected response to searching".to_owned()),
+            }
+        }
+    }
+
+    fn unbind(mut self) {
+        // The server closes the connection without answering.
+        let _ = self.send(tlv(UNBIND_REQUEST, &[]), None);
+    }
+}
+
+fn parse_entry(op: &[u8]) -> Result<Entry, String> {
+    let mut fields = Ber::new(op);
+    let dn = fields.string(OCTET_STRING)?;
+    let mut attributes = HashMap::new();
+    let mut list = Ber::new(fields.expect(SEQUENCE)?);
+    while !list.is_empty() {
+        let mut attribute = Ber::new(list.expect(SEQUENCE)?);
+        let name = attribute.string(OCTET_STRING)?.to_ascii_lowercase();
+        let mut set = Ber::new(attribute.expect(SET)?);
+        let mut values = vec![];
+        while !set.is_empty() {
+            values.push(set.string(OCTET_STRING)?);
+        }
+        attributes
+            .entry(name)
+            .or_insert_with(Vec::new)
+            .extend(values);
+    }
+    Ok(Entry { dn, attributes })
+}
+
+/// The cookie of the page after a search's, none on the last page.
+fn paged_cookie(controls: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let mut controls = Ber::new(controls);
+    while !controls.is_empty() {
+        let mut control = Ber::new(controls.expect(SEQUENCE)?);
+        if control.string(OCTET_STRING)? != PAGED_RESULTS {
+            continue;
+        }
+        control.optional(BOOLEAN)?;
+        let mut value = Ber::new(control.expect(OCTET_STRING)?);
+        let mut paging = Ber::new(value.expect(SEQUENCE)?);
+        paging.integer(INTEGER)?;
+        return Ok(Some(paging.expect(OCTET_STRING)?.to_vec()));
+    }
+    Ok(None)
+}
+
+struct LdapResult {
+    code: i64,
+    message: String,
+}
+
+impl LdapResult {
+    fn parse(op: &[u8]) -> Result<LdapResult, String> {
+        let mut fields = Ber::new(op);
+        let code = fields.integer(ENUMERATED)?;
+        fields.string(OCTET_STRING)?;
+        let message = fields.string(OCTET_STRING)?;
+        Ok(LdapResult { code, message })
+    }
+
+    fn ok(self) -> Result<(), String> {
+        let name = match self.code {
+            0 => return Ok(()),
+            4 => "size limit exceeded",
+            8 => "stronger authentication required",
+            32 => "no such object",
+            49 => "invalid credentials",
+            50 => "insufficient access rights",
+            52 => "unavailable",
+            53 => "unwilling to perform",
+            _ => "error",
+        };
+        match self.message.trim() {
+            "" => Err(format!("{} ({})", name, self.code)),
+            message => Err(format!("{} ({}): {}", name, self.code, message)),
+        }
+    }
+}
+
+/// A search filter, RFC 4515's string form parsed.
+#[derive(Debug)]
+enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equal(String, Vec<u8>),
+    Approximate(String, Vec<u8>),
+    GreaterOrEqual(String, Vec<u8>),
+    LessOrEqual(String, Vec<u8>),
+    Present(String),
+    Substrings(String, Vec<(u8, Vec<u8>)>),
+}
+
+impl Filter {
+    fn parse(text: &str) -> Result<Filter, String> {
+        let invalid = || format!("invalid LDAP filter {:?}", text);
+        let (filter, rest) = Filter::parse_one(text).ok_or_else(invalid)?;
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(filter)
+    }
+
+    /// The filter at the start of `text` and the text after it.
+    fn parse_one(text: &str) -> Option<(Filter, &str)> {
+        let text = text.strip_prefix('(')?;
+        if let Some(rest) = text.strip_prefix('&') {
+            let (filters, rest) = Filter::parse_list(rest)?;
+            return Some((Filter::And(filters), rest));
+        }
+        if let Some(rest) = text.strip_prefix('|') {
+            let (filters, rest) = Filter::parse_list(rest)?;
+            return Some((Filter::Or(filters), rest));
+        }
+        if let Some(rest) = text.strip_prefix('!') {
+            let (filter, rest) = Filter::parse_one(rest)?;
+            return Some((Filter::Not(Box::new(filter)), rest.strip_prefix(')')?));
+        }
+        let end = text.find(')')?;
+        let (item, rest) = (&text[..end], &text[end + 1..]);
+        let eq = item.find('=')?;
+        let (attribute, value) = (&item[..eq], &item[eq + 1..]);
+        let (attribute, kind) = match attribute.chars().last()? {
+            c @ ('~' | '>' | '<') => (&attribute[..attribute.len() - 1], c),
+            _ => (attribute, '='),
+        };
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == ';';
+        if attribute.is_empty() || !attribute.chars().all(valid) {
+            return None;
+        }
+        let attribute = attribute.to_owned();
+        let filter = match kind {
+            '~' => Filter::Approximate(attribute, unescape(value)?),
+            '>' => Filter::GreaterOrEqual(attribute, unescape(value)?),
+            '<' => Filter::LessOrEqual(attribute, unescape(value)?),
+            _ if value == "*" => Filter::Present(attribute),
+            _ if value.contains('*') => {
+                let parts: Vec<&str> = value.split('*').collect();
+                let last = parts.len() - 1;
+                let mut substrings = vec![];
+                for (i, part) in parts.into_iter().enumerate() {
+                    if part.is_empty() {
+                        continue;
+                    }
+                    let tag = match i {
+                        0 => INITIAL,
+                        i if i == last => FINAL,
+                        _ => ANY,
+                    };
+                    substrings.push((tag, unescape(part)?));
+                }
+                Filter::Substrings(attribute, substrings)
+            }
+            _ => Filter::Equal(attribute, unescape(value)?),
+        };
+        Some((filter, rest))
+    }
+
+    /// The filters up to a closing parenthesis and the text after it.
+    fn parse_list(mut text: &str) -> Option<(Vec<Filter>, &str)> {
+        let mut filters = vec![];
+        while !text.starts_with(')') {
+            let (filter, rest) = Filter::parse_one(text)?;
+            filters.push(filter);
+            text = rest;
+        }
+        Some((filters, &text[1..]))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let assertion = |tag: u8, attribute: &str, value: &[u8]| {
+            let mut body = tlv(OCTET_STRING, attribute.as_bytes());
+            body.extend(tlv(OCTET_STRING, value));
+            tlv(tag, &body)
+        };
+        match self {
+            Filter::And(filters) => tlv(
+                0xa0,
+                &filters.iter().flat_map(Filter::encode).collect::<Vec<_>>(),
+            ),
+            Filter::Or(filters) => tlv(
+                0xa1,
+                &filters.iter().flat_map(Filter::encode).collect::<Vec<_>>(),
+            ),
+            Filter::Not(filter) => tlv(0xa2, &filter.encode()),
+            Filter::Equal(a, v) => assertion(0xa3, a, v),
+            Filter::GreaterOrEqual(a, v) => assertion(0xa5, a, v),
+            Filter::LessOrEqual(a, v) => assertion(0xa6, a, v),
+            Filter::Approximate(a, v) => assertion(0xa8, a, v),
+            Filter::Present(a) => tlv(0x87, a.as_bytes()),
+            Filter::Substrings(a, parts) => {
+                let mut body = tlv(OCTET_STRING, a.as_bytes());
+                let parts: Vec<u8> = parts.iter().flat_map(|(tag, v)| tlv(*tag, v)).collect();
+                body.extend(tlv(SEQUENCE, &parts));
+                tlv(0xa4, &body)
+            }
+        }
+    }
+}
+
+/// A filter value with its `\XX` escapes undone.
+fn unescape(value: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'(' | b')' | b'*' | 0 => return None,
+            b => out.push(b),
+        }
+    }
+    Some(out)
+}
+
+// BER tags of the LDAP messages used.
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_ENTRY: u8 = 0x64;
+const SEARCH_DONE: u8 = 0x65;
+const SEARCH_REFERENCE: u8 = 0x73;
+const SIMPLE_AUTH: u8 = 0x80;
+const CONTROLS: u8 = 0xa0;
+const INITIAL: u8 = 0x80;
+const ANY: u8 = 0x81;
+const FINAL: u8 = 0x82;
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // The shortest two's complement form.
+    let mut start = 0;
+    while start < 7
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    tlv(tag, &bytes[start..])
+}
+
+/// Reads one whole LDAP message, returning the contents of its sequence.
+fn read_message(stream: &mut impl Read) -> Result<Vec<u8>, String> {
+    let read = |e: std::io::Error| format!("reading from the directory failed: {}", e);
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).map_err(read)?;
+    if head[0] != SEQUENCE {
+        return Err("the directory sent an invalid message".to_owned());
+    }
+    let len = if head[1] < 0x80 {
+        head[1] as usize
+    } else {
+        let count = (head[1] & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err("the directory sent an invalid message".to_owned());
+        }
+        let mut bytes = [0u8; 4];
+        stream.read_exact(&mut bytes[4 - count..]).map_err(read)?;
+        u32::from_be_bytes(bytes) as usize
+    };
+    if len > MAX_MESSAGE_BYTES {
+        return Err("the directory sent a message that is too large".to_owned());
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).map_err(read)?;
+    Ok(message)
+}
+
+/// Reads the elements of BER encoded contents one at a time.
+struct Ber<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Ber<'a> {
+    fn new(contents: &'a [u8]) -> Ber<'a> {
+        Ber { rest: contents }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    fn any(&mut self) -> Result<(u8, &'a [u8]), String> {
+        let invalid = || "the directory sent an invalid message".to_owned();
+        let (&tag, rest) = self.rest.split_first().ok_or_else(invalid)?;
+        let (&first, rest) = rest.split_first().ok_or_else(invalid)?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err(invalid());
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, b| len << 8 | *b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err(invalid());
+        }
+        self.rest = &rest[len..];
+        Ok((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], String> {
+        match self.any()? {
+            (t, contents) if t == tag => Ok(contents),
+            _ => Err("the directory sent an unexpected message".to_owned()),
+        }
+    }
+
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, String> {
+        if self.rest.first() == Some(&tag) {
+            return self.expect(tag).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn string(&mut self, tag: u8) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.expect(tag)?).into_owned())
+    }
+
+    fn integer(&mut self, tag: u8) -> Result<i64, String> {
+        let bytes = self.expect(tag)?;
+        if bytes.is_empty() || bytes.len() > 8 {
+            return Err("the directory sent an invalid number".to_owned());
+        }
+        let negative = bytes[0] & 0x80 != 0;
+        Ok(bytes
+            .iter()
+            .fold(if negative { -1 } else { 0 }, |n, b| n << 8 | *b as i64))
+    }
+}
+
+fn contact(entry: Entry) -> Result<Contact, String> {
+    let uid = match entry.first("uid") {
+        uid if uid.is_empty() => entry.first("sAMAccountName"),
+        uid => uid,
+    };
+    if uid.is_empty() {
+        return Err(format!("entry {:?} has no uid", entry.dn));
+    }
+    // Directories compare uids without case.
+    let id = format!("ldap-{}", uid.to_lowercase());
+    if id.contains('/') || id.len() > MAX_ID_LEN {
+        return Err(format!(
+            "entry {:?} has an unusable uid {:?}",
+            entry.dn, uid
+        ));
+    }
+    let (mut first_name, mut last_name) = (entry.first("givenName"), entry.first("sn"));
+    if first_name.is_empty() && last_name.is_empty() {
+        let name = match entry.first("displayName") {
+            name if name.is_empty() => entry.first("cn"),
+            name => name,
+        };
+        let mut words = name.rsplitn(2, ' ');
+        last_name = words.next().unwrap_or_default().trim().to_owned();
+        first_name = words.next().unwrap_or_default().trim().to_owned();
+    }
+    if first_name.is_empty() && last_name.is_empty() {
+        return Err(format!("entry {:?} has no name", entry.dn));
+    }
+    let street = match entry.first("street") {
+        // Lines of a postal address are separated by `$`.
+        street if street.is_empty() => entry
+            .first("postalAddress")
+            .split('$')
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(", "),
+        street => street,
+    };
+    let address = Address {
+        street,
+        locality: entry.first("l"),
+        region: entry.first("st"),
+        postal_code: entry.first("postalCode"),
+        country: entry.first("c"),
+    };
+    let has_address = [
+        &address.street,
+        &address.locality,
+        &address.region,
+        &address.postal_code,
+        &address.country,
+    ]
+    .iter()
+    .any(|part| !part.is_empty());
+    Ok(Contact {
+        id,
+        owner_id: String::new(),
+        first_name,
+        last_name,
+        emails: entry.values("mail").map(str::to_owned).collect(),
+        phones: ["telephoneNumber", "mobile", "homePhone"]
+            .iter()
+            .flat_map(|name| entry.values(name))
+            .map(str::to_owned)
+            .collect(),
+        addresses: if has_address { vec![address] } else { vec![] },
+        avatar: None,
+    })
+}
+
+/// The entries as numbered import rows.
+pub fn rows(entries: Vec<Entry>) -> Vec<ImportRow> {
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| (i + 1, contact(entry)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn encoded(filter: &str) -> Vec<u8> {
+        Filter::parse(filter).unwrap().encode()
+    }
+
+    #[test]
+    fn lengths_and_integers_take_their_shortest_form() {
+        assert_eq!(tlv(OCTET_STRING, b"ab"), [0x04, 0x02, b'a', b'b']);
+        assert_eq!(tlv(OCTET_STRING, &[7; 200])[..3], [0x04, 0x81, 200]);
+        assert_eq!(tlv(OCTET_STRING, &[7; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+        for (value, bytes) in &[
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x00, 0x80]),
+            (256, &[0x01, 0x00]),
+            (-1, &[0xff]),
+            (-128, &[0x80]),
+            (-129, &[0xff, 0x7f]),
+            (i64::MAX, &[0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+        ] {
+            let mut expected = vec![INTEGER, bytes.len() as u8];
+            expected.extend_from_slice(bytes);
+            let encoded = integer(INTEGER, *value);
+            assert_eq!(encoded, expected, "{}", value);
+            assert_eq!(Ber::new(&encoded).integer(INTEGER).unwrap(), *value);
+        }
+        let long = tlv(SEQUENCE, &[1; 300]);
+        let mut ber = Ber::new(&long);
+        assert_eq!(ber.expect(SEQUENCE).unwrap().len(), 300);
+        assert!(ber.is_empty());
+    }
+
+    #[test]
+    fn filters_encode_as_rfc_4511_has_them() {
+        assert_eq!(
+            encoded("(cn=Ada)"),
+            [0xa3, 0x09, 0x04, 0x02, b'c', b'n', 0x04, 0x03, b'A', b'd', b'a']
+        );
+        assert_eq!(encoded("(mail=*)"), [0x87, 0x04, b'm', b'a', b'i', b'l']);
+        assert_eq!(
+            encoded("(!(mail=*))"),
+            [0xa2, 0x06, 0x87, 0x04, b'm', b'a', b'i', b'l']
+        );
+        assert_eq!(
+            encoded("(&(cn=Ada)(mail=*))"),
+            [
+                0xa0, 0x11, 0xa3, 0x09, 0x04, 0x02, b'c', b'n', 0x04, 0x03, b'A', b'd', b'a', 0x87,
+                0x04, b'm', b'a', b'i', b'l'
+            ]
+        );
+        assert_eq!(
+            encoded("(cn=A*d*a)"),
+            [
+                0xa4, 0x0f, 0x04, 0x02, b'c', b'n', 0x30, 0x09, 0x80, 0x01, b'A', 0x81, 0x01, b'd',
+                0x82, 0x01, b'a'
+            ]
+        );
+        assert_eq!(
+            encoded("(sn>=K)"),
+            [0xa5, 0x07, 0x04, 0x02, b's', b'n', 0x04, 0x01, b'K']
+        );
+        assert_eq!(
+            encoded("(cn=\\2a)"),
+            [0xa3, 0x07, 0x04, 0x02, b'c', b'n', 0x04, 0x01, b'*']
+        );
+        assert_eq!(encoded("(|(cn=Ada))")[..2], [0xa1, 0x0b]);
+        for invalid in &[
+            "cn=Ada",
+            "(cn=Ada",
+            "(cn=(Ada))",
+            "(&(cn=Ada)",
+            "(c n=Ada)",
+            "(=Ada)",
+            "(cn=\\zz)",
+            "(cn=Ada)(sn=King)",
+        ] {
+            assert!(Filter::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_ber() {
+        assert!(Ber::new(&[0x04]).any().is_err());
+        assert!(Ber::new(&[0x04, 0x05, 1]).any().is_err());
+        assert!(Ber::new(&[0x04, 0x80]).any().is_err());
+        assert!(Ber::new(&[0x04, 0x85, 0, 0, 0, 0, 1]).any().is_err());
+        assert!(Ber::new(&[0x04, 0x82, 0x01]).any().is_err());
+        assert!(Ber::new(&[INTEGER, 0x00]).integer(INTEGER).is_err());
+        assert!(Ber::new(&[OCTET_STRING, 0x00]).integer(INTEGER).is_err());
+        assert!(read_message(&mut &[0x04, 0x00][..]).is_err());
+        assert!(read_message(&mut &[0x30, 0x05, 1][..]).is_err());
+        assert_eq!(
+            read_message(&mut &[0x30, 0x84, 0x7f, 0xff, 0xff, 0xff][..]).unwrap_err(),
+            "the directory sent a message that is too large"
+        );
+        let result = |code: i64, message: &str| {
+            let mut op = integer(ENUMERATED, code);
+            op.extend(tlv(OCTET_STRING, b""));
+            op.extend(tlv(OCTET_STRING, message.as_bytes()));
+            LdapResult::parse(&op).unwrap().ok()
+        };
+        assert_eq!(result(0, ""), Ok(()));
+        assert_eq!(
+            result(49, "bad password"),
+            Err("invalid credentials (49): bad password".to_owned())
+        );
+        assert_eq!(result(80, " "), Err("error (80)".to_owned()));
+    }
+
+    fn message(id: i64, op: Vec<u8>, controls: Option<Vec<u8>>) -> Vec<u8> {
+        let mut message = integer(INTEGER, id);
+        message.extend(op);
+        if let Some(controls) = controls {
+            message.extend(tlv(CONTROLS, &controls));
+        }
+        tlv(SEQUENCE, &message)
+    }
+
+    fn done(tag: u8) -> Vec<u8> {
+        let mut op = integer(ENUMERATED, 0);
+        op.extend(tlv(OCTET_STRING, b""));
+        op.extend(tlv(OCTET_STRING, b""));
+        tlv(tag, &op)
+    }
+
+    fn entry(uid: &str, attributes: &[(&str, &[&str])]) -> Vec<u8> {
+        let mut op = tlv(OCTET_STRING, format!("uid={},dc=example", uid).as_bytes());
+        let mut list = vec![];
+        for (name, values) in attributes {
+            let mut attribute = tlv(OCTET_STRING, name.as_bytes());
+            let values: Vec<u8> = values
+                .iter()
+                .flat_map(|v| tlv(OCTET_STRING, v.as_bytes()))
+                .collect();
+            attribute.extend(tlv(SET, &values));
+            list.extend(tlv(SEQUENCE, &attribute));
+        }
+        op.extend(tlv(SEQUENCE, &list));
+        tlv(SEARCH_ENTRY, &op)
+    }
+
+    fn paging(cookie: &[u8]) -> Vec<u8> {
+        let mut value = integer(INTEGER, 0);
+        value.extend(tlv(OCTET_STRING, cookie));
+        let mut control = tlv(OCTET_STRING, PAGED_RESULTS.as_bytes());
+        control.extend(tlv(OCTET_STRING, &tlv(SEQUENCE, &value)));
+        tlv(SEQUENCE, &control)
+    }
+
+    /// Writes `data` a few bytes at a time, so messages and their lengths
+    /// arrive split across packets.
+    fn trickle(stream: &mut TcpStream, data: &[u8]) {
+        for piece in data.chunks(7) {
+            stream.write_all(piece).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn pages_through_search_results_split_across_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = LdapConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            bind_dn: "cn=admin".to_owned(),
+            bind_password: "pw".to_owned(),
+            base_dn: "dc=example".to_owned(),
+            filter: "(mail=*)".to_owned(),
+        };
+        let street = "1 Long Road, ".repeat(30);
+        let sent = street.clone();
+        let directory = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let bind = read_message(&mut stream).unwrap();
+            assert_eq!(
+                bind,
+                [
+                    0x02, 0x01, 0x01, 0x60, 0x11, 0x02, 0x01, 0x03, 0x04, 0x08, b'c', b'n', b'=',
+                    b'a', b'd', b'm', b'i', b'n', 0x80, 0x02, b'p', b'w'
+                ]
+            );
+            trickle(&mut stream, &message(1, done(BIND_RESPONSE), None));
+
+            let mut cookies = vec![];
+            for (id, next) in &[(2, &b"page-2"[..]), (3, &b""[..])] {
+                let search = read_message(&mut stream).unwrap();
+                let mut fields = Ber::new(&search);
+                assert_eq!(fields.integer(INTEGER).unwrap(), *id);
+                let mut op = Ber::new(fields.expect(SEARCH_REQUEST).unwrap());
+                assert_eq!(op.string(OCTET_STRING).unwrap(), "dc=example");
+                for _ in 0..5 {
+                    op.any().unwrap();
+                }
+                assert_eq!(op.any().unwrap(), (0x87, &b"mail"[..]));
+                cookies.push(paged_cookie(fields.expect(CONTROLS).unwrap()).unwrap());
+
+                let mut replies = vec![];
+                if *id == 2 {
+                    // Replies to other requests are passed over.
+                    replies.extend(message(99, done(SEARCH_DONE), None));
+                    replies.extend(message(
+                        2,
+                        entry(
+                            "ada",
+                            &[
+                                ("uid", &["ada"]),
+                                ("givenName", &["Ada"]),
+                                ("sn", &["Lovelace"]),
+                                ("mail", &["ada@example.com", "ada@example.org"]),
+                                ("street", &[sent.as_str()]),
+                            ],
+                        ),
+                        None,
+                    ));
+                    replies.extend(message(2, tlv(SEARCH_REFERENCE, &[]), None));
+                    replies.extend(message(
+                        2,
+                        entry("grace", &[("UID", &["Grace"]), ("cn", &["Grace Hopper"])]),
+                        None,
+                    ));
+                } else {
+                    replies.extend(message(
+                        3,
+                        entry(
+                            "alan",
+                            &[("sAMAccountName", &["alan"]), ("sn", &["Turing"])],
+                        ),
+                        None,
+                    ));
+                }
+                replies.extend(message(*id, done(SEARCH_DONE), Some(paging(next))));
+                trickle(&mut stream, &replies);
+            }
+            let unbind = read_message(&mut stream).unwrap();
+            assert_eq!(unbind[3], UNBIND_REQUEST);
+            cookies
+        });
+
+        let mut fetched = vec![];
+        let entries = entries(&config, |n| fetched.push(n)).unwrap();
+        let cookies = directory.join().unwrap();
+        assert_eq!(cookies, vec![Some(vec![]), Some(b"page-2".to_vec())]);
+        assert_eq!(fetched, vec![2, 3]);
+
+        let contacts: Vec<Contact> = rows(entries)
+            .into_iter()
+            .map(|(_, contact)| contact.unwrap())
+            .collect();
+        let ids: Vec<&str> = contacts.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["ldap-ada", "ldap-grace", "ldap-alan"]);
+        assert_eq!(
+            contacts[0].emails,
+            vec!["ada@example.com", "ada@example.org"]
+        );
+        assert_eq!(contacts[0].addresses[0].street, street.trim());
+        assert_eq!(
+            (
+                contacts[1].first_name.as_str(),
+                contacts[1].last_name.as_str()
+            ),
+            ("Grace", "Hopper")
+        );
+        assert_eq!(contacts[2].last_name, "Turing");
+    }
+}
@@ -0,0 +1,386 @@
+//! Logging setup and PII redaction. Anything that can carry personal data is
+//! logged through `Pii`, which prints the value's `Redact` form: ids as they
+//! are, personal fields as a short digest. `LOG_PII=1` reveals the full
+//! `Debug` output, but only in dev mode.
+//!
+//! Every line logged while a request is handled carries its request id, taken
+//! from the `X-Request-Id` header or generated, and echoed back in the
+//! response. `LOG_FORMAT=json` writes one JSON object per line instead of
+//! `key=value` text.
+//!
+//! What gets logged is decided by a `Filter`, which admins can change while
+//! the server runs, say to log `repo` at debug while diagnosing an issue.
+
+use crate::crypto;
+use crate::settings::{Config, Mode};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, Ready};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Incoming request ids longer than this are replaced by a generated one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static REVEAL_PII: AtomicBool = AtomicBool::new(false);
+const DEFAULT_FILTER: Filter = Filter {
+    default: LevelFilter::Info,
+    targets: Vec::new(),
+};
+static FILTER: RwLock<Filter> = RwLock::new(DEFAULT_FILTER);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// How a value is written to the logs when PII is hidden.
+pub trait Redact: fmt::Debug {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+/// Formats a `Redact` value for the logs.
+pub struct Pii<'a, T: Redact>(pub &'a T);
+
+impl<'a, T: Redact> fmt::Display for Pii<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if REVEAL_PII.load(Ordering::Relaxed) {
+            write!(f, "{:?}", self.0)
+        } else {
+            self.0.fmt_redacted(f)
+        }
+    }
+}
+
+/// A personal field shown as the first bytes of its SHA-256, enough to tell
+/// values apart in the logs without revealing them.
+pub struct Hashed<'a>(pub &'a str);
+
+impl<'a> fmt::Debug for Hashed<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digest = crypto::sha256(self.0.as_bytes());
+        write!(f, "sha256:{}", crypto::to_hex(&digest[..4]))
+    }
+}
+
+/// A default level and levels for the targets that differ from it, written
+/// like `info,repo=debug,actix_web=warn`. A target covers the modules under
+/// it; this crate's modules can leave out the `backend::` prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    /// Longest target first, so the most specific one matches.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(name, _)| covers(name, target) || covers(name, local(target)))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level anything is logged at.
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<Filter, String> {
+        let mut default = LevelFilter::Info;
+        let mut targets: Vec<(String, LevelFilter)> = vec![];
+        for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse = |level: &str| {
+                level.trim().parse::<LevelFilter>().map_err(|_| {
+                    format!(
+                        "{:?} isn't error, warn, info, debug, trace or off",
+                        level.trim()
+                    )
+                })
+            };
+            match directive.find('=') {
+                Some(at) => {
+                    let target = directive[..at].trim();
+                    if target.is_empty() {
+                        return Err(format!("{:?} names no target", directive));
+                    }
+                    let level = parse(&directive[at + 1..])?;
+                    targets.retain(|(name, _)| name != target);
+                    targets.push((target.to_owned(), level));
+                }
+                None => default = parse(directive)?,
+            }
+        }
+        targets.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        Ok(Filter { default, targets })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        let mut targets = self.targets.clone();
+        targets.sort();
+        for (name, level) in targets {
+            write!(f, ",{}={}", name, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the target `name` covers `target`, itself or one of its modules.
+fn covers(name: &str, target: &str) -> bool {
+    target
+        .strip_prefix(name)
+        .map(|rest| rest.is_empty() || rest.starts_with("::"))
+        .unwrap_or(false)
+}
+
+/// `target` without this crate's prefix.
+fn local(target: &str) -> &str {
+    target
+        .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+        .unwrap_or(target)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Writes what the current filter lets through to stderr.
+struct StderrLogger {
+    format: Format,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let request_id = request_id();
+        match self.format {
+            Format::Text => match request_id {
+                Some(id) => eprintln!(
+                    "{:<5} {}: {} request_id={}",
+                    record.level(),
+                    record.target(),
+                    record.args(),
+                    id
+                ),
+                None => eprintln!(
+                    "{:<5} {}: {}",
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ),
+            },
+            Format::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                    "request_id": request_id,
+                })
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the stderr logger with the filter in `LOG_LEVEL` (default
+/// `info`), until the config is read and `set_filter` applies its `filter`,
+/// in the `LOG_FORMAT` (`text` or `json`) and decides whether PII is
+/// revealed.
+pub fn init(mode: Mode) {
+    let filter = std::env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(DEFAULT_FILTER);
+    let format = match std::env::var("LOG_FORMAT") {
+        Ok(v) if v.eq_ignore_ascii_case("json") => Format::Json,
+        _ => Format::Text,
+    };
+    if log::set_logger(Box::leak(Box::new(StderrLogger { format }))).is_ok() {
+        set_filter(filter);
+    }
+
+    let reveal = matches!(std::env::var("LOG_PII").as_deref(), Ok("1") | Ok("true"));
+    match mode {
+        Mode::Dev => REVEAL_PII.store(reveal, Ordering::Relaxed),
+        Mode::Production if reveal => warn!("LOG_PII is ignored in production"),
+        Mode::Production => {}
+    }
+}
+
+/// The filter in `LOG_LEVEL`, else in the config file's `log_level`, else
+/// `info`.
+pub fn filter(config: &Config) -> Result<Filter, String> {
+    let (name, filter) = match std::env::var("LOG_LEVEL") {
+        Ok(filter) => ("LOG_LEVEL", filter),
+        Err(_) => match &config.log_level {
+            Some(filter) => ("log_level", filter.clone()),
+            None => return Ok(DEFAULT_FILTER),
+        },
+    };
+    filter.parse().map_err(|e| format!("{}: {}", name, e))
+}
+
+/// The filter records are logged through.
+pub fn current_filter() -> Filter {
+    FILTER.read().unwrap().clone()
+}
+
+/// Changes what is logged from now on.
+pub fn set_filter(filter: Filter) {
+    log::set_max_level(filter.max());
+    *FILTER.write().unwrap() = filter;
+}
+
+/// The id of the request being handled, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs `f` as part of the request with `id`, for work a request hands to
+/// another task.
+pub async fn with_request_id<F: Future>(id: Option<String>, f: F) -> F::Output {
+    match id {
+        Some(id) => REQUEST_ID.scope(id, f).await,
+        None => f.await,
+    }
+}
+
+/// The `X-Request-Id` of a request if it's a sensible id, a new one otherwise.
+fn incoming_request_id(req: &ServiceRequest) -> String {
+    match req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.to_owned()
+        }
+        _ => crypto::to_hex(&rand::random::<[u8; 16]>()),
+    }
+}
+
+/// Actix middleware giving each request its id and making it current for
+/// the logger. It's the outermost middleware, so the middleware inside log
+/// with the id too.
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let id = incoming_request_id(&req);
+        let header = HeaderValue::from_str(&id).unwrap();
+        // The inner service is called within the scope, so the work it does
+        // before returning its future is logged with the id as well.
+        let service = self.service.clone();
+        Box::pin(REQUEST_ID.scope(id, async move {
+            let fut = service.borrow_mut().call(req);
+            let mut resp = fut.await?;
+            resp.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+            Ok(resp)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_target_decides() {
+        let filter: Filter = "warn, repo=debug, repo::tx=off, actix_web=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("backend::graphql"), LevelFilter::Warn);
+        assert_eq!(filter.level("backend::repo"), LevelFilter::Debug);
+        assert_eq!(filter.level("repo"), LevelFilter::Debug);
+        assert_eq!(filter.level("backend::repository"), LevelFilter::Warn);
+        assert_eq!(filter.level("backend::repo::tx"), LevelFilter::Off);
+        assert_eq!(filter.level("actix_web::server"), LevelFilter::Error);
+        assert_eq!(filter.max(), LevelFilter::Debug);
+        assert_eq!(
+            filter.to_string(),
+            "warn,actix_web=error,repo=debug,repo::tx=off"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert!("loud".parse::<Filter>().is_err());
+        assert!("info,repo=loud".parse::<Filter>().is_err());
+        assert!("=debug".parse::<Filter>().is_err());
+        assert_eq!("".parse::<Filter>().unwrap(), DEFAULT_FILTER);
+    }
+}
@@ -1,222 +1,106 @@
 #[macro_use]
 extern crate log;
 
-mod models {
-    use async_graphql::SimpleObject;
-    use serde::{Deserialize, Serialize};
-
-    #[SimpleObject]
-    #[derive(Debug, Serialize, Deserialize, Clone, Hash)]
-    pub struct Contact {
-        pub id: String,
-        pub first_name: String,
-        pub last_name: String,
-    }
-}
-
-mod usecases {
-
-    use super::models::*;
-    use super::repo::*;
-    use std::error::Error;
-
-    pub fn create<T: Repository<Contact>>(
-        contact: Contact,
-        repo: &T,
-    ) -> Result<Contact, Box<dyn Error>> {
-        let r = repo.set(contact.clone())?;
-        println!("contact created {:?}", contact);
-        Ok(r)
-    }
-
-    pub fn get<T: Repository<Contact>>(id: &str, repo: &T) -> Result<Contact, Box<dyn Error>> {
-        repo.get(id)
-    }
-}
-
-mod repo {
-    use serde::de::DeserializeOwned;
-    use serde::Serialize;
-    use std::error::Error;
-    use std::hash::Hash;
-
-    pub trait Repository<T> {
-        fn set(&self, obj: T) -> Result<T, Box<dyn Error>>;
-        fn get(&self, id: &str) -> Result<T, Box<dyn Error>>;
-    }
-
-    pub struct FileRepository<'a> {
-        path: &'a str,
-    }
-
-    impl<'a> FileRepository<'a> {
-        pub fn new(path: &'a str) -> FileRepository<'a> {
-            FileRepository { path }
-        }
-    }
-
-    impl<'a, T: DeserializeOwned + Serialize + Hash> Repository<T> for FileRepository<'a> {
-        fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
-            use std::collections::hash_map::DefaultHasher;
-            use std::fs::File;
-            use std::hash::Hasher;
-            use std::path::Path;
-
-            let mut hasher = DefaultHasher::new();
-            obj.hash(&mut hasher);
-            let hash = hasher.finish();
-            let path = Path::new(&self.path).join(format!("{}.json", hash));
-            println!("{:?}", path);
-
-            let f = File::create(path)?;
-            serde_json::to_writer(f, &obj).expect("Unable to serialized");
-            Ok(obj)
-        }
-
-        fn get(&self, id: &str) -> Result<T, Box<dyn Error>> {
-            use std::fs::File;
-            use std::path::Path;
-            let path = Path::new(&self.path).join(format!("{}.json", id));
-            println!("{:?}", path);
-            let f = File::open(&path)?;
-            let result: T = serde_json::from_reader(f).expect("Unable to serialized");
-            Ok(result)
-        }
-    }
-}
-
-mod graphql {
-
-    use super::models::*;
-    use super::repo::*;
-    use super::usecases::*;
-    use actix_web::{guard, web, App, HttpResponse, HttpServer};
-    use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
-    use async_graphql::*;
-    use async_graphql_actix_web::{GQLRequest, GQLResponse};
-
-    type ContactsSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
-
-    async fn index(schema: web::Data<ContactsSchema>, req: GQLRequest) -> GQLResponse {
-        debug!("request");
-        req.into_inner().execute(&schema).await.into()
-    }
-
-    async fn gql_playgound() -> HttpResponse {
-        debug!("playground");
-        HttpResponse::Ok()
-            .content_type("text/html; charset=utf-8")
-            .body(playground_source(GraphQLPlaygroundConfig::new("/")))
-    }
-
-    struct QueryRoot;
-
-    #[Object]
-    impl QueryRoot {
-        async fn get(
-            &self,
-            ctx: &Context<'_>,
-            #[arg(desc = "id")] id: String,
-        ) -> FieldResult<Contact> {
-            let repo = ctx.data_unchecked::<FileRepository>();
-            match get(id.as_str(), repo) {
-                Ok(c) => Ok(c),
-                Err(e) => Err(FieldError(format!("{}", e), None)),
-            }
-        }
-    }
-
-    #[SimpleObject]
-    struct QueryContact {
-        first_name: String,
-        last_name: String,
-    }
-
-    impl std::convert::From<Contact> for QueryContact {
-        fn from(c: Contact) -> Self {
-            Self {
-                first_name: c.first_name,
-                last_name: c.last_name,
-            }
-        }
-    }
-
-    struct MutationRoot;
+// First, for its macros.
+#[cfg(test)]
+#[macro_use]
+mod testing;
+
+mod auth;
+mod avatars;
+mod backup;
+mod bench;
+mod cache;
+mod carddav;
+mod cli;
+mod cors;
+mod crypto;
+mod csv;
+mod events;
+mod google;
+mod graphql;
+mod i18n;
+mod idempotency;
+mod instrumented;
+mod jobs;
+mod ldap;
+mod logging;
+mod metrics;
+mod models;
+mod nats;
+mod outbox;
+mod png;
+mod ratelimit;
+mod reload;
+mod repo;
+mod rest;
+mod sentry;
+mod session;
+mod settings;
+mod shutdown;
+mod stats;
+mod telemetry;
+mod tenant;
+mod usecases;
+mod vcard;
+mod webhooks;
+
+use cli::Command;
 
-    #[Object]
-    impl MutationRoot {
-        async fn create(
-            &self,
-            ctx: &Context<'_>,
-            #[arg(desc = "contact")] contact: MutationCreate,
-        ) -> FieldResult<QueryContact> {
-            let repo = ctx.data_unchecked::<FileRepository>();
-            match create(contact.into(), repo) {
-                Err(e) => Err(FieldError(format!("{}", e), None)),
-                Ok(c) => Ok(c.into()),
-            }
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
         }
-    }
-
-    #[InputObject]
-    struct MutationCreate {
-        id: String,
-        first_name: String,
-        last_name: String,
-    }
-
-    impl std::convert::From<Contact> for MutationCreate {
-        fn from(c: Contact) -> Self {
-            Self {
-                id: c.id.to_owned(),
-                first_name: c.first_name,
-                last_name: c.last_name,
-            }
+    };
+    let result = match command {
+        Command::Serve => return graphql::start_server().await,
+        Command::PrintSchema => {
+            print!("{}", graphql::sdl());
+            Ok(())
         }
-    }
-
-    impl std::convert::Into<Contact> for MutationCreate {
-        fn into(self) -> Contact {
-            Contact {
-                id: self.id.to_owned(),
-                first_name: self.first_name,
-                last_name: self.last_name,
-            }
+        Command::Bench { iterations } => bench::run(iterations).await,
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
         }
+        Command::Import { file, tenant } => cli::import(file.as_deref(), tenant.as_ref()),
+        Command::Export { file, tenant } => cli::export(file.as_deref(), tenant.as_ref()),
+        Command::ImportCsv {
+            file,
+            tenant,
+            options,
+            import,
+        } => cli::import_csv(file.as_deref(), tenant.as_ref(), options, import),
+        Command::ExportCsv {
+            file,
+            tenant,
+            columns,
+        } => cli::export_csv(file.as_deref(), tenant.as_ref(), columns),
+        Command::Check { tenant } => cli::check(tenant.as_ref()),
+        Command::Backup { file, tenant } => cli::backup(file.as_deref(), tenant.as_ref()),
+        Command::Restore {
+            file,
+            tenant,
+            merge,
+        } => cli::restore(file.as_deref(), tenant.as_ref(), merge),
+        Command::ImportGoogle {
+            owner,
+            tenant,
+            import,
+        } => cli::import_google(&owner, tenant.as_ref(), import),
+        Command::ImportLdap {
+            owner,
+            tenant,
+            import,
+        } => cli::import_ldap(&owner, tenant.as_ref(), import),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
-
-    pub async fn start_server() -> std::io::Result<()> {
-        let repo = FileRepository::new("/tmp");
-        let local = tokio::task::LocalSet::new();
-        let sys = actix_rt::System::run_in_tokio("server", &local);
-
-        let schema = Schema::build(QueryRoot, MutationRoot, EmptySubscription)
-            .data(repo)
-            .finish();
-
-        println!("Playground: http://localhost:8000");
-
-        HttpServer::new(move || {
-            App::new()
-                .data(schema.clone())
-                .service(web::resource("/").guard(guard::Post()).to(index).app_data(
-                    IntoQueryBuilderOpts {
-                        max_num_files: Some(3),
-                        ..IntoQueryBuilderOpts::default()
-                    },
-                ))
-                .service(web::resource("/").guard(guard::Get()).to(gql_playgound))
-        })
-        .bind("127.0.0.1:8000")?
-        .run()
-        .await?;
-        sys.await?;
-        Ok(())
-    }
-}
-
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    graphql::start_server().await?;
     Ok(())
 }
@@ -0,0 +1,422 @@
+//! Prometheus metrics. HTTP requests are counted and timed by the
+//! `HttpMetrics` middleware, GraphQL operations by the `OperationMetrics`
+//! schema extension and repository calls by the file repository itself,
+//! and by backend in more detail by `InstrumentedRepository`; all of them
+//! record into `REGISTRY`, which `/metrics` renders in the text exposition
+//! format.
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use async_graphql::extensions::Extension;
+use async_graphql::parser::query::{Definition, Document, OperationDefinition};
+use async_graphql::Variables;
+use futures::future::{ok, Ready};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub static REGISTRY: Registry = Registry {
+    inner: Mutex::new(Inner {
+        http_requests: BTreeMap::new(),
+        http_latency: BTreeMap::new(),
+        operations: BTreeMap::new(),
+        operation_errors: BTreeMap::new(),
+        repository: BTreeMap::new(),
+        repository_latency: BTreeMap::new(),
+        repository_errors: BTreeMap::new(),
+        repository_bytes: BTreeMap::new(),
+        backup_last_success: None,
+        backup_last_records: 0,
+        backup_failures: 0,
+    }),
+};
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Backend, operation and entity kind.
+type RepositoryCall = (&'static str, &'static str, &'static str);
+
+struct Inner {
+    /// By method, route and status.
+    http_requests: BTreeMap<(String, String, u16), u64>,
+    /// By route.
+    http_latency: BTreeMap<String, Histogram>,
+    /// By operation type and name.
+    operations: BTreeMap<(&'static str, String), Histogram>,
+    /// By operation type and name.
+    operation_errors: BTreeMap<(&'static str, String), u64>,
+    /// By operation and entity kind.
+    repository: BTreeMap<(&'static str, &'static str), u64>,
+    /// By backend, operation and entity kind.
+    repository_latency: BTreeMap<RepositoryCall, Histogram>,
+    /// By backend, operation and entity kind.
+    repository_errors: BTreeMap<RepositoryCall, u64>,
+    /// By backend, operation and entity kind.
+    repository_bytes: BTreeMap<RepositoryCall, u64>,
+    /// Unix seconds of the last scheduled backup that succeeded.
+    backup_last_success: Option<u64>,
+    backup_last_records: usize,
+    backup_failures: u64,
+}
+
+pub struct Registry {
+    inner: Mutex<Inner>,
+}
+
+impl Registry {
+    pub fn http_request(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .http_requests
+            .entry((method.to_owned(), route.to_owned(), status))
+            .or_default() += 1;
+        inner
+            .http_latency
+            .entry(route.to_owned())
+            .or_default()
+            .observe(seconds);
+    }
+
+    pub fn operation(&self, ty: &'static str, name: &str, seconds: f64, failed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (ty, name.to_owned());
+        if failed {
+            *inner.operation_errors.entry(key.clone()).or_default() += 1;
+        }
+        inner.operations.entry(key).or_default().observe(seconds);
+    }
+
+    pub fn repository(&self, operation: &'static str, kind: &'static str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.repository.entry((operation, kind)).or_default() += 1;
+    }
+
+    /// A call of an instrumented repository, which moved `bytes`.
+    pub fn repository_call(
+        &self,
+        backend: &'static str,
+        operation: &'static str,
+        kind: &'static str,
+        seconds: f64,
+        bytes: usize,
+        failed: bool,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (backend, operation, kind);
+        if failed {
+            *inner.repository_errors.entry(key).or_default() += 1;
+        }
+        *inner.repository_bytes.entry(key).or_default() += bytes as u64;
+        inner
+            .repository_latency
+            .entry(key)
+            .or_default()
+            .observe(seconds);
+    }
+
+    /// A scheduled backup finished, with the records it holds or failed.
+    pub fn backup(&self, records: Option<usize>, finished_at: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        match records {
+            Some(records) => {
+                inner.backup_last_success = Some(finished_at);
+                inner.backup_last_records = records;
+            }
+            None => inner.backup_failures += 1,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total HTTP requests served.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((method, route, status), count) in &inner.http_requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method,
+                escape(route),
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP http_request_duration_seconds HTTP request latency.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for (route, histogram) in &inner.http_latency {
+            let labels = format!("route=\"{}\"", escape(route));
+            write_histogram(
+                &mut out,
+                "http_request_duration_seconds",
+                &labels,
+                histogram,
+            );
+        }
+
+        out.push_str("# HELP graphql_operation_duration_seconds GraphQL operation latency.\n");
+        out.push_str("# TYPE graphql_operation_duration_seconds histogram\n");
+        for ((ty, name), histogram) in &inner.operations {
+            let labels = format!("type=\"{}\",operation=\"{}\"", ty, escape(name));
+            write_histogram(
+                &mut out,
+                "graphql_operation_duration_seconds",
+                &labels,
+                histogram,
+            );
+        }
+
+        out.push_str("# HELP graphql_operation_errors_total GraphQL operations that failed.\n");
+        out.push_str("# TYPE graphql_operation_errors_total counter\n");
+        for ((ty, name), count) in &inner.operation_errors {
+            let _ = writeln!(
+                out,
+                "graphql_operation_errors_total{{type=\"{}\",operation=\"{}\"}} {}",
+                ty,
+                escape(name),
+                count
+            );
+        }
+
+        out.push_str("# HELP repository_operations_total Repository calls.\n");
+        out.push_str("# TYPE repository_operations_total counter\n");
+        for ((operation, kind), count) in &inner.repository {
+            let _ = writeln!(
+                out,
+                "repository_operations_total{{operation=\"{}\",kind=\"{}\"}} {}",
+                operation, kind, count
+            );
+        }
+
+        let call = |(backend, operation, kind): &RepositoryCall| {
+            format!(
+                "backend=\"{}\",operation=\"{}\",kind=\"{}\"",
+                backend, operation, kind
+            )
+        };
+        out.push_str(
+            "# HELP repository_call_duration_seconds Repository call latency, by backend.\n",
+        );
+        out.push_str("# TYPE repository_call_duration_seconds histogram\n");
+        for (key, histogram) in &inner.repository_latency {
+            write_histogram(
+                &mut out,
+                "repository_call_duration_seconds",
+                &call(key),
+                histogram,
+            );
+        }
+
+        out.push_str("# HELP repository_errors_total Repository calls that failed, by backend.\n");
+        out.push_str("# TYPE repository_errors_total counter\n");
+        for (key, count) in &inner.repository_errors {
+            let _ = writeln!(out, "repository_errors_total{{{}}} {}", call(key), count);
+        }
+
+        out.push_str(
+            "# HELP repository_payload_bytes_total Bytes repository calls wrote or read, by backend.\n",
+        );
+        out.push_str("# TYPE repository_payload_bytes_total counter\n");
+        for (key, bytes) in &inner.repository_bytes {
+            let _ = writeln!(
+                out,
+                "repository_payload_bytes_total{{{}}} {}",
+                call(key),
+                bytes
+            );
+        }
+
+        if let Some(finished_at) = inner.backup_last_success {
+            out.push_str(
+                "# HELP backup_last_success_timestamp_seconds When the last scheduled backup succeeded.\n",
+            );
+            out.push_str("# TYPE backup_last_success_timestamp_seconds gauge\n");
+            let _ = writeln!(out, "backup_last_success_timestamp_seconds {}", finished_at);
+            out.push_str("# HELP backup_last_records Records in the last scheduled backup.\n");
+            out.push_str("# TYPE backup_last_records gauge\n");
+            let _ = writeln!(out, "backup_last_records {}", inner.backup_last_records);
+        }
+        out.push_str("# HELP backup_failures_total Scheduled backups that failed.\n");
+        out.push_str("# TYPE backup_failures_total counter\n");
+        let _ = writeln!(out, "backup_failures_total {}", inner.backup_failures);
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"{}\"}} {}",
+            name, labels, bound, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{},le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Type and name of the document's first operation.
+pub fn first_operation(document: &Document) -> Option<(&'static str, Option<&str>)> {
+    let operation = document.definitions().iter().find_map(|d| match &d.node {
+        Definition::Operation(operation) => Some(&operation.node),
+        Definition::Fragment(_) => None,
+    })?;
+    let (ty, name) = match operation {
+        OperationDefinition::SelectionSet(_) => ("query", None),
+        OperationDefinition::Query(q) => ("query", q.name.as_ref()),
+        OperationDefinition::Mutation(m) => ("mutation", m.name.as_ref()),
+        OperationDefinition::Subscription(s) => ("subscription", s.name.as_ref()),
+    };
+    Some((ty, name.map(|n| n.node.as_str())))
+}
+
+/// Times one GraphQL operation, recording it when the operation is done.
+/// Operations are labelled with the name of the document's first operation.
+pub struct OperationMetrics {
+    started: Instant,
+    ty: &'static str,
+    name: String,
+    failed: bool,
+}
+
+impl Default for OperationMetrics {
+    fn default() -> Self {
+        OperationMetrics {
+            started: Instant::now(),
+            ty: "unknown",
+            name: "anonymous".to_owned(),
+            failed: false,
+        }
+    }
+}
+
+impl Extension for OperationMetrics {
+    fn parse_start(&mut self, _query_source: &str, _variables: &Variables) {
+        self.started = Instant::now();
+    }
+
+    fn parse_end(&mut self, document: &Document) {
+        if let Some((ty, name)) = first_operation(document) {
+            self.ty = ty;
+            if let Some(name) = name {
+                self.name = name.to_owned();
+            }
+        }
+    }
+
+    fn error(&mut self, _err: &async_graphql::Error) {
+        self.failed = true;
+    }
+}
+
+impl Drop for OperationMetrics {
+    fn drop(&mut self) {
+        let seconds = self.started.elapsed().as_secs_f64();
+        REGISTRY.operation(self.ty, &self.name, seconds, self.failed);
+    }
+}
+
+/// Actix middleware counting and timing requests by route. Every route is a
+/// fixed path, so the path is the route; requests that match none are
+/// counted under `unmatched`, so stray paths can't grow the label set.
+pub struct HttpMetrics;
+
+impl<S, B> Transform<S> for HttpMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = HttpMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HttpMetricsMiddleware { service })
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for HttpMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let path = req.path().to_owned();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            let route = match resp.status() {
+                StatusCode::NOT_FOUND => "unmatched",
+                _ => path.as_str(),
+            };
+            REGISTRY.http_request(
+                &method,
+                route,
+                resp.status().as_u16(),
+                started.elapsed().as_secs_f64(),
+            );
+            Ok(resp)
+        })
+    }
+}
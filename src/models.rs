@@ -0,0 +1,668 @@
+use crate::auth::{CurrentUser, Role, PII_SCOPE};
+use crate::crypto;
+use crate::graphql::masked;
+use crate::logging::{Hashed, Redact};
+use crate::repo::{Entity, Expiring};
+use async_graphql::{Context, Enum, Object, SimpleObject};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Hash)]
+pub struct Contact {
+    pub id: String,
+    pub owner_id: String,
+    pub first_name: String,
+    pub last_name: String,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub phones: Vec<String>,
+    #[serde(default)]
+    pub addresses: Vec<Address>,
+    /// Version of the avatar whose thumbnails are stored, set once they
+    /// are.
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+impl Contact {
+    /// The contact as `user` may read it: without the `contacts:pii` scope,
+    /// its emails, phones and addresses are left out, as they are masked
+    /// in the GraphQL API. Every transport sends contacts through here.
+    pub fn masked_for(mut self, user: &CurrentUser) -> Contact {
+        if !user.has_scope(PII_SCOPE) {
+            self.emails.clear();
+            self.phones.clear();
+            self.addresses.clear();
+        }
+        self
+    }
+}
+
+#[Object]
+impl Contact {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn owner_id(&self) -> &str {
+        &self.owner_id
+    }
+
+    async fn first_name(&self) -> &str {
+        &self.first_name
+    }
+
+    async fn last_name(&self) -> &str {
+        &self.last_name
+    }
+
+    /// Null without the `contacts:pii` scope, like `phones` and
+    /// `addresses`.
+    async fn emails(&self, ctx: &Context<'_>) -> Option<&[String]> {
+        masked(ctx, PII_SCOPE, &self.emails[..])
+    }
+
+    async fn phones(&self, ctx: &Context<'_>) -> Option<&[String]> {
+        masked(ctx, PII_SCOPE, &self.phones[..])
+    }
+
+    async fn addresses(&self, ctx: &Context<'_>) -> Option<&[Address]> {
+        masked(ctx, PII_SCOPE, &self.addresses[..])
+    }
+
+    /// Where the avatar thumbnail of `size` is served, null without an
+    /// avatar or until its thumbnails are made.
+    async fn avatar_url(&self, size: AvatarSize) -> Option<String> {
+        let version = self.avatar.as_ref()?;
+        Some(format!(
+            "/avatars/{}/{}?ownerId={}&v={}",
+            self.id,
+            size.name(),
+            self.owner_id,
+            version
+        ))
+    }
+}
+
+/// The sizes avatar thumbnails are made in.
+#[Enum]
+pub enum AvatarSize {
+    /// 64 by 64 pixels.
+    Small,
+    /// 256 by 256 pixels.
+    Large,
+}
+
+impl AvatarSize {
+    pub const ALL: [AvatarSize; 2] = [AvatarSize::Small, AvatarSize::Large];
+
+    pub fn pixels(self) -> u32 {
+        match self {
+            AvatarSize::Small => 64,
+            AvatarSize::Large => 256,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AvatarSize::Small => "small",
+            AvatarSize::Large => "large",
+        }
+    }
+}
+
+/// A postal address, as the parts vCard splits it into.
+#[SimpleObject]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Hash, PartialEq, Eq)]
+pub struct Address {
+    pub street: String,
+    pub locality: String,
+    pub region: String,
+    pub postal_code: String,
+    pub country: String,
+}
+
+/// A change to some of a contact's fields; fields left `None` are kept.
+/// Lists are replaced whole, an empty one clearing the field.
+#[derive(Debug, Default, Clone)]
+pub struct ContactPatch {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub emails: Option<Vec<String>>,
+    pub phones: Option<Vec<String>>,
+    pub addresses: Option<Vec<Address>>,
+}
+
+impl ContactPatch {
+    /// The contact with the patched fields replaced.
+    pub fn apply(self, mut contact: Contact) -> Contact {
+        if let Some(first_name) = self.first_name {
+            contact.first_name = first_name;
+        }
+        if let Some(last_name) = self.last_name {
+            contact.last_name = last_name;
+        }
+        if let Some(emails) = self.emails {
+            contact.emails = emails;
+        }
+        if let Some(phones) = self.phones {
+            contact.phones = phones;
+        }
+        if let Some(addresses) = self.addresses {
+            contact.addresses = addresses;
+        }
+        contact
+    }
+}
+
+impl Contact {
+    /// Contacts are stored per owner, so ids only need to be unique within
+    /// one owner's address book.
+    pub fn key_for(owner_id: &str, id: &str) -> String {
+        format!("{}/{}", owner_id, id)
+    }
+}
+
+impl Redact for Contact {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Contact")
+            .field("id", &self.id)
+            .field("owner_id", &self.owner_id)
+            .field("first_name", &Hashed(&self.first_name))
+            .field("last_name", &Hashed(&self.last_name))
+            .field(
+                "emails",
+                &self.emails.iter().map(|e| Hashed(e)).collect::<Vec<_>>(),
+            )
+            .field(
+                "phones",
+                &self.phones.iter().map(|p| Hashed(p)).collect::<Vec<_>>(),
+            )
+            .field("addresses", &self.addresses.len())
+            .finish()
+    }
+}
+
+impl Entity for Contact {
+    const KIND: &'static str = "contacts";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn key(&self) -> String {
+        Contact::key_for(&self.owner_id, &self.id)
+    }
+}
+
+#[SimpleObject]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    /// Keys of the member contacts, `owner/id`, as members of a group can
+    /// belong to different owners.
+    pub member_ids: Vec<String>,
+}
+
+impl Redact for Group {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Group")
+            .field("id", &self.id)
+            .field("name", &Hashed(&self.name))
+            .field("member_ids", &self.member_ids)
+            .finish()
+    }
+}
+
+impl Entity for Group {
+    const KIND: &'static str = "groups";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// One recorded change to a contact. Entries are only ever appended, keyed
+/// under the contact so its trail can be listed in one go.
+#[SimpleObject]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub id: String,
+    #[field(skip)]
+    pub contact_key: String,
+    pub action: String,
+    pub actor: String,
+    pub at: i64,
+    pub changes: Vec<FieldChange>,
+}
+
+/// A contact as one change left it: who changed what, and from what.
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct Revision {
+    /// Counts up from 1, the contact's creation.
+    pub revision: i32,
+    /// Unix seconds.
+    pub at: i64,
+    pub actor: String,
+    pub action: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// The values of changes to emails, phones and addresses are null without
+/// the `contacts:pii` scope, as the fields of the contact are.
+#[Object]
+impl FieldChange {
+    async fn field(&self) -> &str {
+        &self.field
+    }
+
+    async fn before(&self, ctx: &Context<'_>) -> Option<&str> {
+        self.value(ctx, &self.before)
+    }
+
+    async fn after(&self, ctx: &Context<'_>) -> Option<&str> {
+        self.value(ctx, &self.after)
+    }
+}
+
+impl FieldChange {
+    const PII_FIELDS: [&'static str; 3] = ["emails", "phones", "addresses"];
+
+    fn value<'a>(&self, ctx: &Context<'_>, value: &'a Option<String>) -> Option<&'a str> {
+        let value = value.as_deref()?;
+        match Self::PII_FIELDS.contains(&self.field.as_str()) {
+            true => masked(ctx, PII_SCOPE, value),
+            false => Some(value),
+        }
+    }
+}
+
+impl Entity for AuditEntry {
+    const KIND: &'static str = "audit";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{}", self.contact_key, self.id)
+    }
+}
+
+/// An endpoint contact changes are POSTed to, signed with `secret`.
+#[SimpleObject]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    #[field(skip)]
+    pub secret: String,
+}
+
+impl Entity for Webhook {
+    const KIND: &'static str = "webhooks";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// One event on its way to a webhook. `status` is `pending` until the
+/// endpoint answers with a 2xx, or `failed` once the retries are used up.
+#[SimpleObject]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub subject: String,
+    #[field(skip)]
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+impl Entity for WebhookDelivery {
+    const KIND: &'static str = "webhook_deliveries";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{}", self.webhook_id, self.id)
+    }
+}
+
+/// An account that can sign in. Stored under a digest of the normalized email
+/// so the address never has to be a safe file name.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: String,
+    pub email: String,
+    pub password_hash: String,
+    pub role: Role,
+}
+
+impl User {
+    pub fn normalize_email(email: &str) -> String {
+        email.trim().to_lowercase()
+    }
+
+    pub fn id_for(email: &str) -> String {
+        crypto::to_hex(&crypto::sha256(User::normalize_email(email).as_bytes()))
+    }
+}
+
+impl Redact for User {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("email", &Hashed(&self.email))
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+impl Entity for User {
+    const KIND: &'static str = "users";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A logged-in browser session, looked up from the session cookie.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub role: Role,
+    pub expires_at: u64,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Granted at login; sessions stored before scopes were get none.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl Entity for Session {
+    const KIND: &'static str = "sessions";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Expiring for Session {
+    fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+}
+
+/// A refresh token, stored under the digest of its value. Each token belongs
+/// to a family started at login; rotating a token marks it used and issues the
+/// next one in the same family.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshToken {
+    pub id: String,
+    pub family_id: String,
+    pub user_id: String,
+    pub expires_at: u64,
+    pub used: bool,
+}
+
+impl Entity for RefreshToken {
+    const KIND: &'static str = "refresh_tokens";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Expiring for RefreshToken {
+    fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+}
+
+/// An uploaded avatar waiting for its thumbnails. The original is stored
+/// as a blob until they're made.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AvatarTask {
+    pub id: String,
+    pub owner_id: String,
+    pub contact_id: String,
+    pub version: String,
+    pub actor: String,
+}
+
+impl Entity for AvatarTask {
+    const KIND: &'static str = "avatar_tasks";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// What stats need of an owner's contacts, by contact id. Every change to
+/// the contacts updates it, so stats read this one record, not each contact.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ContactIndex {
+    pub owner_id: String,
+    pub entries: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct IndexEntry {
+    /// Unix seconds, 0 if the contact predates its audit trail.
+    pub created_at: i64,
+    pub countries: Vec<String>,
+    pub group_ids: Vec<String>,
+    /// Size of the stored contact.
+    pub bytes: u64,
+}
+
+impl Entity for ContactIndex {
+    const KIND: &'static str = "contact_index";
+
+    fn id(&self) -> &str {
+        &self.owner_id
+    }
+}
+
+/// An address book in numbers.
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct ContactStats {
+    pub total: i32,
+    /// Contacts created on each of the last days, oldest first.
+    pub created_per_day: Vec<DayCount>,
+    /// Contacts in each group that has any.
+    pub by_group: Vec<GroupCount>,
+    /// Bytes the contacts take up in storage.
+    pub storage_bytes: i64,
+}
+
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct DayCount {
+    /// UTC day, `2020-01-31`.
+    pub day: String,
+    pub count: i32,
+}
+
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct GroupCount {
+    pub group_id: String,
+    pub count: i32,
+}
+
+/// What `contactsAggregate` buckets contacts by.
+#[Enum]
+pub enum AggregateBy {
+    /// The groups a contact is a member of.
+    Group,
+    /// The countries of a contact's addresses.
+    Country,
+}
+
+/// The contacts sharing a group or country; a contact in several is
+/// counted in each.
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    /// The group id or country, null for contacts without any.
+    pub key: Option<String>,
+    pub count: i32,
+}
+
+/// Revocation record for a refresh token family.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshFamily {
+    pub id: String,
+    pub user_id: String,
+    pub revoked: bool,
+}
+
+impl Entity for RefreshFamily {
+    const KIND: &'static str = "refresh_families";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// What an import does with a row that has the id of one of the owner's
+/// contacts, or one of its emails.
+#[Enum]
+#[derive(Debug, Default)]
+pub enum ConflictStrategy {
+    /// Leaves the contact as it is.
+    Skip,
+    /// Replaces the contact with the row.
+    #[default]
+    Overwrite,
+    /// Takes the row's names and adds its emails, phones and addresses to
+    /// the contact's.
+    Merge,
+    /// Imports the row as another contact, under a new id if its own is
+    /// taken.
+    Duplicate,
+}
+
+/// What an import does with a row: creates a contact, or resolves a
+/// conflict with one by a `ConflictStrategy`.
+#[Enum]
+#[derive(Debug)]
+pub enum ImportAction {
+    Create,
+    Skip,
+    Overwrite,
+    Merge,
+    Duplicate,
+}
+
+/// The outcome of a contact import.
+#[SimpleObject]
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Contacts written, or in a dry run that would be.
+    pub imported: i32,
+    /// Rows conflicting with a contact that were left out.
+    pub skipped: i32,
+    pub failed: i32,
+    pub errors: Vec<RowError>,
+    /// What would be done with each row that didn't fail, for a dry run
+    /// only.
+    pub preview: Vec<RowPreview>,
+}
+
+/// What an import does with row `row`.
+#[SimpleObject]
+#[derive(Debug)]
+pub struct RowPreview {
+    pub row: i32,
+    /// The id the contact is written with.
+    pub id: String,
+    pub action: ImportAction,
+    /// The contact the row conflicts with, if any.
+    pub conflicts_with: Option<String>,
+}
+
+/// A device code for granting access to a Google account: the user opens
+/// `verificationUrl` and enters `userCode`.
+#[SimpleObject]
+#[derive(Debug, Clone)]
+pub struct GoogleDeviceCode {
+    /// Passed back to finish the import once access is granted.
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_url: String,
+    /// Seconds until the code expires.
+    pub expires_in: i32,
+    /// Seconds to wait between attempts to finish.
+    pub interval: i32,
+}
+
+/// Why row `row` of an import was not imported.
+#[SimpleObject]
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: i32,
+    pub message: String,
+}
+
+/// A backup written or restored.
+#[SimpleObject]
+#[derive(Debug, Serialize)]
+pub struct BackupReport {
+    /// Where the archive was written; null for a restore.
+    pub file: Option<String>,
+    pub records: i32,
+    /// Digest over the records, the same for a backup and its restore.
+    pub sha256: String,
+}
+
+/// How the last scheduled backup went.
+#[SimpleObject]
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupStatus {
+    pub finished_at: i64,
+    /// The archive written, null if the backup failed.
+    pub file: Option<String>,
+    pub records: i32,
+    pub error: Option<String>,
+}
+
+/// A background job and how its runs went.
+#[SimpleObject]
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_secs: i32,
+    pub running: bool,
+    pub runs: i32,
+    pub failures: i32,
+    /// Unix seconds the last run started at.
+    pub last_run_at: Option<i64>,
+    pub last_duration_ms: Option<i32>,
+    /// What the last successful run did.
+    pub last_result: Option<String>,
+    pub last_error: Option<String>,
+    pub next_run_at: i64,
+}
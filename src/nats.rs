@@ -0,0 +1,154 @@
+//! Just enough of the NATS text protocol for the outbox to publish with
+//! acknowledgement and for instances to hear each other's messages.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// How long a publish waits for the server's acknowledgement.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Messages larger than this end the connection.
+const MAX_PAYLOAD: usize = 1 << 20;
+
+/// The server named by `NATS_URL`, `nats://host:port`.
+pub fn address() -> Option<String> {
+    let url = std::env::var("NATS_URL").ok()?;
+    Some(url.trim_start_matches("nats://").to_owned())
+}
+
+enum Frame {
+    Line(String),
+    Message(Vec<u8>),
+}
+
+pub struct Nats {
+    stream: TcpStream,
+    /// Bytes read but not parsed into a frame yet.
+    buffer: Vec<u8>,
+    /// Messages that arrived while waiting for an acknowledgement.
+    messages: VecDeque<Vec<u8>>,
+}
+
+impl Nats {
+    /// Connects to the server; reads give up after `poll` without data.
+    pub fn connect(address: &str, poll: Duration) -> Result<Nats, Box<dyn Error>> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(poll))?;
+        let mut nats = Nats {
+            stream,
+            buffer: vec![],
+            messages: VecDeque::new(),
+        };
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        let info = loop {
+            match nats.frame()? {
+                Some(Frame::Line(line)) => break line,
+                Some(Frame::Message(_)) => return Err("unexpected message".into()),
+                None if Instant::now() < deadline => {}
+                None => return Err("no greeting from the server".into()),
+            }
+        };
+        if !info.starts_with("INFO") {
+            return Err(format!("unexpected greeting {:?}", info).into());
+        }
+        nats.stream
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+        Ok(nats)
+    }
+
+    /// Publishes and then round-trips a PING, so the message has reached the
+    /// server when this returns.
+    pub fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        write!(self.stream, "PUB {} {}\r\n", subject, payload.len())?;
+        self.stream.write_all(payload)?;
+        self.stream.write_all(b"\r\nPING\r\n")?;
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        loop {
+            match self.frame()? {
+                Some(Frame::Line(line)) if line == "PONG" => return Ok(()),
+                Some(Frame::Message(payload)) => self.messages.push_back(payload),
+                Some(_) => {}
+                None if Instant::now() < deadline => {}
+                None => return Err("the server didn't acknowledge".into()),
+            }
+        }
+    }
+
+    /// Subscribes to `subject`; its messages come from `next_message`.
+    pub fn subscribe(&mut self, subject: &str) -> Result<(), Box<dyn Error>> {
+        write!(self.stream, "SUB {} 1\r\n", subject)?;
+        Ok(())
+    }
+
+    /// The payload of the next message of the subscription, `None` if none
+    /// arrives within the poll interval.
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if let Some(payload) = self.messages.pop_front() {
+            return Ok(Some(payload));
+        }
+        loop {
+            match self.frame()? {
+                Some(Frame::Message(payload)) => return Ok(Some(payload)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// The next frame, answering PINGs and failing on errors; `None` if
+    /// the poll interval passes first.
+    fn frame(&mut self) -> Result<Option<Frame>, Box<dyn Error>> {
+        loop {
+            if let Some(frame) = self.parse()? {
+                match &frame {
+                    Frame::Line(line) if line == "PING" => self.stream.write_all(b"PONG\r\n")?,
+                    Frame::Line(line) if line.starts_with("-ERR") => {
+                        return Err(line.clone().into())
+                    }
+                    _ => return Ok(Some(frame)),
+                }
+                continue;
+            }
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err("connection closed".into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Takes a complete frame off the buffer, if it holds one.
+    fn parse(&mut self) -> Result<Option<Frame>, Box<dyn Error>> {
+        let end = match self.buffer.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        if !line.starts_with("MSG ") {
+            self.buffer.drain(..end + 2);
+            return Ok(Some(Frame::Line(line)));
+        }
+        // MSG <subject> <sid> [reply-to] <#bytes>
+        let size: usize = line
+            .rsplit(' ')
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| format!("malformed {:?}", line))?;
+        if size > MAX_PAYLOAD {
+            return Err(format!("message of {} bytes is too large", size).into());
+        }
+        let start = end + 2;
+        if self.buffer.len() < start + size + 2 {
+            return Ok(None);
+        }
+        let payload = self.buffer[start..start + size].to_vec();
+        self.buffer.drain(..start + size + 2);
+        Ok(Some(Frame::Message(payload)))
+    }
+}
@@ -0,0 +1,81 @@
+//! Transactional outbox for domain events. Usecases write an
+//! `OutboxMessage` next to the data they change and a background publisher
+//! drains the outbox into NATS, deleting each message once the broker has
+//! acknowledged it. Messages stay in the outbox until they're delivered, so a
+//! broker outage delays events rather than losing them. Only the NATS text
+//! protocol is spoken; Kafka is not supported.
+
+use crate::nats::{self, Nats};
+use crate::repo::*;
+use crate::shutdown::Shutdown;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A domain event waiting to be published on `subject`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub id: String,
+    pub subject: String,
+    pub payload: serde_json::Value,
+}
+
+impl Entity for OutboxMessage {
+    const KIND: &'static str = "outbox";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Starts the publisher thread when `NATS_URL` (`nats://host:port`) is set.
+/// On shutdown the thread publishes what's left in the outbox and exits.
+pub fn spawn_publisher(
+    repo: FileRepository<'static>,
+    shutdown: Shutdown,
+) -> Option<thread::JoinHandle<()>> {
+    let address = nats::address()?;
+    Some(thread::spawn(move || {
+        let mut connection: Option<Nats> = None;
+        loop {
+            let stopping = shutdown.is_triggered();
+            let mut repos = vec![repo.clone()];
+            repos.extend(repo.tenants());
+            for repo in repos {
+                if let Err(e) = drain(&repo, &address, &mut connection) {
+                    warn!("outbox publishing failed: {}", e);
+                    connection = None;
+                }
+            }
+            if stopping {
+                return;
+            }
+            shutdown.sleep(POLL_INTERVAL);
+        }
+    }))
+}
+
+fn drain(
+    repo: &FileRepository<'static>,
+    address: &str,
+    connection: &mut Option<Nats>,
+) -> Result<(), Box<dyn Error>> {
+    let mut messages: Vec<OutboxMessage> = repo.list("")?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+    messages.sort_by(|a, b| a.id.cmp(&b.id));
+    if connection.is_none() {
+        *connection = Some(Nats::connect(address, POLL_INTERVAL)?);
+    }
+    let nats = connection.as_mut().unwrap();
+    for message in messages {
+        nats.publish(&message.subject, message.payload.to_string().as_bytes())?;
+        Repository::<OutboxMessage>::delete(repo, &message.id)?;
+        debug!("published {} {}", message.subject, message.id);
+    }
+    Ok(())
+}
@@ -0,0 +1,500 @@
+//! Just enough PNG for avatars: decoding non-interlaced images of any color
+//! type and bit depth to RGBA, square thumbnails, and encoding RGBA back.
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Larger images are refused rather than decoded.
+const MAX_PIXELS: u64 = 4096 * 4096;
+
+/// An image as rows of RGBA pixels.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+pub fn is_png(data: &[u8]) -> bool {
+    data.starts_with(SIGNATURE)
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    depth: u8,
+    color: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color {
+            0 | 3 => 1,
+            4 => 2,
+            2 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Bytes per complete pixel, at least one, as filtering counts them.
+    fn pixel_bytes(&self) -> usize {
+        (self.channels() * self.depth as usize).div_ceil(8)
+    }
+
+    fn stride(&self) -> usize {
+        (self.width as usize * self.channels() * self.depth as usize).div_ceil(8)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    if !is_png(data) {
+        return Err("not a PNG image".to_owned());
+    }
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut compressed = vec![];
+    let mut rest = &data[SIGNATURE.len()..];
+    loop {
+        if rest.len() < 12 {
+            return Err("the PNG is truncated".to_owned());
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 12 + len {
+            return Err("the PNG is truncated".to_owned());
+        }
+        let (kind, body) = (&rest[4..8], &rest[8..8 + len]);
+        let crc =
+            u32::from_be_bytes([rest[8 + len], rest[9 + len], rest[10 + len], rest[11 + len]]);
+        if crc32(&rest[4..8 + len]) != crc {
+            return Err(format!(
+                "the PNG's {} chunk is corrupt",
+                String::from_utf8_lossy(kind)
+            ));
+        }
+        rest = &rest[12 + len..];
+        match kind {
+            b"IHDR" if body.len() == 13 => {
+                let h = Header {
+                    width: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                    height: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+                    depth: body[8],
+                    color: body[9],
+                };
+                let valid_depth = match h.color {
+                    0 => [1, 2, 4, 8, 16].contains(&h.depth),
+                    3 => [1, 2, 4, 8].contains(&h.depth),
+                    2 | 4 | 6 => [8, 16].contains(&h.depth),
+                    _ => false,
+                };
+                if !valid_depth || body[10] != 0 || body[11] != 0 {
+                    return Err("the PNG header is invalid".to_owned());
+                }
+                if body[12] != 0 {
+                    return Err("interlaced PNGs are not supported".to_owned());
+                }
+                if h.width == 0 || h.height == 0 {
+                    return Err("the PNG has no pixels".to_owned());
+                }
+                if h.width as u64 * h.height as u64 > MAX_PIXELS {
+                    return Err("the PNG is too large".to_owned());
+                }
+                header = Some(h);
+            }
+            b"PLTE" => palette = body,
+            b"tRNS" => transparency = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let header = header.ok_or("the PNG has no header")?;
+    let stride = header.stride();
+    let expected = (stride + 1) * header.height as usize;
+    let mut raw = Vec::with_capacity(expected);
+    ZlibDecoder::new(&compressed[..])
+        .take(expected as u64)
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("the PNG's image data is corrupt: {}", e))?;
+    if raw.len() != expected {
+        return Err("the PNG's image data is truncated".to_owned());
+    }
+    let rows = unfilter(&header, &raw)?;
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        rgba: to_rgba(&header, &rows, palette, transparency)?,
+    })
+}
+
+/// Undoes the per-row filters, returning the rows back to back.
+fn unfilter(header: &Header, raw: &[u8]) -> Result<Vec<u8>, String> {
+    let (stride, bpp) = (header.stride(), header.pixel_bytes());
+    let mut rows = vec![0u8; stride * header.height as usize];
+    for y in 0..header.height as usize {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, current) = rows.split_at_mut(y * stride);
+        let previous = if y > 0 {
+            &done[(y - 1) * stride..]
+        } else {
+            &[][..]
+        };
+        let current = &mut current[..stride];
+        for x in 0..stride {
+            let a = if x >= bpp { current[x - bpp] } else { 0 };
+            let b = previous.get(x).copied().unwrap_or(0);
+            let c = if x >= bpp {
+                previous.get(x - bpp).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            current[x] = line[x].wrapping_add(match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                f => return Err(format!("the PNG uses unknown filter {}", f)),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn to_rgba(
+    header: &Header,
+    rows: &[u8],
+    palette: &[u8],
+    transparency: &[u8],
+) -> Result<Vec<u8>, String> {
+    let (width, stride) = (header.width as usize, header.stride());
+    let depth = header.depth as usize;
+    let mut rgba = Vec::with_capacity(width * header.height as usize * 4);
+    for row in rows.chunks(stride) {
+        // Samples scaled to 8 bits; 16-bit samples keep their high byte.
+        let sample = |i: usize| -> u8 {
+            match depth {
+                8 => row[i],
+                16 => row[i * 2],
+                _ => {
+                    let bits = row[i * depth / 8] >> (8 - depth - i * depth % 8);
+                    let value = bits & ((1u16 << depth) - 1) as u8;
+                    if header.color == 3 {
+                        value
+                    } else {
+                        (value as u16 * 255 / ((1u16 << depth) - 1)) as u8
+                    }
+                }
+            }
+        };
+        for x in 0..width {
+            let pixel = match header.color {
+                0 => {
+                    let v = sample(x);
+                    [v, v, v, 255]
+                }
+                2 => [sample(x * 3), sample(x * 3 + 1), sample(x * 3 + 2), 255],
+                3 => {
+                    let i = sample(x) as usize;
+                    let rgb = palette
+                        .get(i * 3..i * 3 + 3)
+                        .ok_or("the PNG's palette is too short")?;
+                    [rgb[0], rgb[1], rgb[2], *transparency.get(i).unwrap_or(&255)]
+                }
+                4 => {
+                    let v = sample(x * 2);
+                    [v, v, v, sample(x * 2 + 1)]
+                }
+                _ => [
+                    sample(x * 4),
+                    sample(x * 4 + 1),
+                    sample(x * 4 + 2),
+                    sample(x * 4 + 3),
+                ],
+            };
+            rgba.extend_from_slice(&pixel);
+        }
+    }
+    Ok(rgba)
+}
+
+/// A `size` by `size` thumbnail of the image's centered square, each pixel
+/// the average of the pixels it covers.
+pub fn thumbnail(image: &Image, size: u32) -> Image {
+    let side = image.width.min(image.height) as u64;
+    let (left, top) = (
+        (image.width as u64 - side) / 2,
+        (image.height as u64 - side) / 2,
+    );
+    let size64 = size as u64;
+    let mut rgba = Vec::with_capacity((size * size * 4) as usize);
+    for ty in 0..size64 {
+        let (y0, y1) = (
+            ty * side / size64,
+            ((ty + 1) * side / size64).max(ty * side / size64 + 1),
+        );
+        for tx in 0..size64 {
+            let (x0, x1) = (
+                tx * side / size64,
+                ((tx + 1) * side / size64).max(tx * side / size64 + 1),
+            );
+            let mut sum = [0u64; 4];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let i = (((top + y) * image.width as u64 + left + x) * 4) as usize;
+                    for (channel, total) in sum.iter_mut().enumerate() {
+                        *total += image.rgba[i + channel] as u64;
+                    }
+                }
+            }
+            let count = (y1 - y0) * (x1 - x0);
+            rgba.extend(sum.iter().map(|total| (total / count) as u8));
+        }
+    }
+    Image {
+        width: size,
+        height: size,
+        rgba,
+    }
+}
+
+/// Encodes the image as an 8-bit RGBA PNG.
+pub fn encode(image: &Image) -> Vec<u8> {
+    let stride = image.width as usize * 4;
+    let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+    for row in image.rgba.chunks(stride) {
+        // Writing to a Vec can't fail.
+        let _ = zlib.write_all(&[0]);
+        let _ = zlib.write_all(row);
+    }
+    let compressed = zlib.finish().unwrap_or_default();
+    let mut header = vec![];
+    header.extend_from_slice(&image.width.to_be_bytes());
+    header.extend_from_slice(&image.height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    let mut out = SIGNATURE.to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &compressed);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32, depth: u8, color: u8) -> Vec<u8> {
+        let mut body = width.to_be_bytes().to_vec();
+        body.extend_from_slice(&height.to_be_bytes());
+        body.extend_from_slice(&[depth, color, 0, 0, 0]);
+        body
+    }
+
+    fn zlib(raw: &[u8]) -> Vec<u8> {
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(raw).unwrap();
+        zlib.finish().unwrap()
+    }
+
+    /// A PNG of `chunks` followed by an IEND.
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        for (kind, body) in chunks {
+            chunk(&mut out, kind, body);
+        }
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    fn gray(values: &[u8]) -> Vec<u8> {
+        values.iter().flat_map(|&v| vec![v, v, v, 255]).collect()
+    }
+
+    #[test]
+    fn crc_matches_the_pngs_own() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn encoded_images_decode_the_same() {
+        let rgba: Vec<u8> = (0..5 * 3 * 4).map(|i| (i * 7) as u8).collect();
+        let image = Image {
+            width: 5,
+            height: 3,
+            rgba: rgba.clone(),
+        };
+        let encoded = encode(&image);
+        assert!(is_png(&encoded));
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!((decoded.width, decoded.height), (5, 3));
+        assert_eq!(decoded.rgba, rgba);
+    }
+
+    #[test]
+    fn undoes_every_filter() {
+        #[rustfmt::skip]
+        let raw = [
+            1, 10, 10, 10,
+            2, 30, 30, 30,
+            3, 50, 20, 20,
+            4, 30, 10, 10,
+        ];
+        let image = decode(&png(&[
+            (b"IHDR", &header(3, 4, 8, 0)),
+            (b"IDAT", &zlib(&raw)),
+        ]))
+        .unwrap();
+        assert_eq!(
+            image.rgba,
+            gray(&[10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120])
+        );
+    }
+
+    #[test]
+    fn decodes_palettes_low_and_high_bit_depths() {
+        let palette = [255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let image = decode(&png(&[
+            (b"IHDR", &header(3, 1, 2, 3)),
+            (b"PLTE", &palette),
+            (b"tRNS", &[0, 128]),
+            (b"IDAT", &zlib(&[0, 0b0001_1000])),
+        ]))
+        .unwrap();
+        assert_eq!(image.rgba, [255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255]);
+
+        let image = decode(&png(&[
+            (b"IHDR", &header(3, 1, 1, 0)),
+            (b"IDAT", &zlib(&[0, 0b1010_0000])),
+        ]))
+        .unwrap();
+        assert_eq!(image.rgba, gray(&[255, 0, 255]));
+
+        let image = decode(&png(&[
+            (b"IHDR", &header(1, 1, 16, 4)),
+            (b"IDAT", &zlib(&[0, 0x12, 0x34, 0xab, 0xcd])),
+        ]))
+        .unwrap();
+        assert_eq!(image.rgba, [0x12, 0x12, 0x12, 0xab]);
+    }
+
+    #[test]
+    fn refuses_malformed_images() {
+        let pixel = zlib(&[0, 7]);
+        let refused = |data: Vec<u8>| decode(&data).err().unwrap();
+        assert_eq!(refused(b"GIF89a".to_vec()), "not a PNG image");
+        assert_eq!(
+            refused(png(&[(b"IHDR", &header(1, 1, 3, 0))])),
+            "the PNG header is invalid"
+        );
+        let mut interlaced = header(1, 1, 8, 0);
+        interlaced[12] = 1;
+        assert_eq!(
+            refused(png(&[(b"IHDR", &interlaced)])),
+            "interlaced PNGs are not supported"
+        );
+        assert_eq!(
+            refused(png(&[(b"IHDR", &header(0, 1, 8, 0))])),
+            "the PNG has no pixels"
+        );
+        assert_eq!(
+            refused(png(&[(b"IHDR", &header(4097, 4096, 8, 0))])),
+            "the PNG is too large"
+        );
+        assert_eq!(refused(png(&[(b"IDAT", &pixel)])), "the PNG has no header");
+        assert_eq!(
+            refused(png(&[(b"IHDR", &header(2, 1, 8, 0)), (b"IDAT", &pixel)])),
+            "the PNG's image data is truncated"
+        );
+        assert!(
+            refused(png(&[(b"IHDR", &header(1, 1, 8, 0)), (b"IDAT", b"junk")]))
+                .starts_with("the PNG's image data is corrupt")
+        );
+        assert_eq!(
+            refused(png(&[
+                (b"IHDR", &header(1, 1, 8, 0)),
+                (b"IDAT", &zlib(&[5, 7]))
+            ])),
+            "the PNG uses unknown filter 5"
+        );
+        assert_eq!(
+            refused(png(&[
+                (b"IHDR", &header(1, 1, 8, 3)),
+                (b"PLTE", &[0, 0, 0]),
+                (b"IDAT", &pixel)
+            ])),
+            "the PNG's palette is too short"
+        );
+    }
+
+    #[test]
+    fn damaged_chunks_are_errors_not_panics() {
+        let image = Image {
+            width: 2,
+            height: 2,
+            rgba: vec![200; 16],
+        };
+        let encoded = encode(&image);
+        for len in SIGNATURE.len()..encoded.len() {
+            assert_eq!(
+                decode(&encoded[..len]).err().unwrap(),
+                "the PNG is truncated"
+            );
+        }
+        for i in SIGNATURE.len()..encoded.len() {
+            let mut damaged = encoded.clone();
+            damaged[i] ^= 0x40;
+            assert!(decode(&damaged).is_err(), "byte {} went unnoticed", i);
+        }
+        let mut oversized = encoded;
+        oversized[SIGNATURE.len()..SIGNATURE.len() + 4].copy_from_slice(&[0xff; 4]);
+        assert_eq!(decode(&oversized).err().unwrap(), "the PNG is truncated");
+        assert_eq!(
+            decode(&png(&[(b"IHDR", &[0; 5])])).err().unwrap(),
+            "the PNG has no header"
+        );
+    }
+}
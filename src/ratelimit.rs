@@ -0,0 +1,263 @@
+//! Per-client rate limiting with token buckets. Authenticated callers (API
+//! key clients and users) get a bucket of their own, anonymous callers one
+//! per IP address. A request that finds its bucket empty is answered with
+//! `429 Too Many Requests`, a `Retry-After` header and a GraphQL error with
+//! the `RATE_LIMITED` code, so GraphQL clients can handle it like any other
+//! error.
+
+use crate::auth::CurrentUser;
+use crate::settings::Config;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse, ResponseError};
+use futures::future::{ok, Ready};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Buckets beyond this many are pruned of the ones that have refilled.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets holding up to `burst` requests and refilling at `per_minute`
+/// requests a minute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Rate {
+    /// Set by `RATE_LIMIT` (requests per minute), else the config file's
+    /// `rate_limit`; `None` for no limit. `RATE_LIMIT_BURST` or
+    /// `rate_limit_burst` sets the bucket size and defaults to the
+    /// per-minute limit.
+    pub fn load(config: &Config) -> Result<Option<Rate>, String> {
+        let per_minute = match limit("RATE_LIMIT", config.rate_limit)? {
+            Some(per_minute) => per_minute,
+            None => return Ok(None),
+        };
+        let burst = limit("RATE_LIMIT_BURST", config.rate_limit_burst)?.unwrap_or(per_minute);
+        Ok(Some(Rate { per_minute, burst }))
+    }
+}
+
+/// Token buckets filling at the current rate, which a reload can change or
+/// lift. Buckets keep their tokens across a change, capped to the new size.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: Arc<RwLock<Option<Rate>>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<Rate>) -> RateLimiter {
+        RateLimiter {
+            rate: Arc::new(RwLock::new(rate)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn rate(&self) -> Option<Rate> {
+        *self.rate.read().unwrap()
+    }
+
+    pub fn set_rate(&self, rate: Option<Rate>) {
+        *self.rate.write().unwrap() = rate;
+        if rate.is_none() {
+            self.buckets.lock().unwrap().clear();
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or says how many seconds until
+    /// the next one is available.
+    pub fn take(&self, client: &str) -> Result<(), RateLimited> {
+        let rate = match self.rate() {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let burst = f64::from(rate.burst);
+        let per_sec = f64::from(rate.per_minute) / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * per_sec < burst
+            });
+        }
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = ((1.0 - bucket.tokens) / per_sec).ceil() as u64;
+        Err(RateLimited {
+            retry_after: retry_after.max(1),
+        })
+    }
+}
+
+/// A positive limit from the environment variable `name`, else from the
+/// config file.
+fn limit(name: &str, file: Option<u32>) -> Result<Option<u32>, String> {
+    match std::env::var(name) {
+        Ok(value) => match value.trim().parse() {
+            Ok(limit) if limit > 0 => Ok(Some(limit)),
+            _ => Err(format!(
+                "{} must be a positive number, got {:?}",
+                name, value
+            )),
+        },
+        Err(_) if file == Some(0) => Err(format!("{} must not be 0", name.to_ascii_lowercase())),
+        Err(_) => Ok(file),
+    }
+}
+
+/// The bucket of the caller was empty.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// Seconds until the next request will be accepted.
+    pub retry_after: u64,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limit exceeded, retry in {}s", self.retry_after)
+    }
+}
+
+impl ResponseError for RateLimited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let body = serde_json::json!({
+            "errors": [{
+                "message": self.to_string(),
+                "extensions": { "code": "RATE_LIMITED", "retryAfter": self.retry_after },
+            }]
+        });
+        HttpResponse::TooManyRequests()
+            .header(header::RETRY_AFTER, self.retry_after.to_string())
+            .json(body)
+    }
+}
+
+/// Actix middleware enforcing the limits. It has to run inside
+/// `Authentication` to see who the caller is.
+pub struct RateLimit {
+    limiter: Rc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: RateLimiter) -> RateLimit {
+        RateLimit {
+            limiter: Rc::new(limiter),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Rc<RateLimiter>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.limiter.rate().is_none() {
+            return Box::pin(self.service.call(req));
+        }
+        let client = match req.extensions().get::<CurrentUser>() {
+            Some(user) => format!("client:{}", user.id),
+            None => match req.peer_addr() {
+                Some(addr) => format!("ip:{}", addr.ip()),
+                None => "ip:unknown".to_owned(),
+            },
+        };
+        match self.limiter.take(&client) {
+            Ok(()) => Box::pin(self.service.call(req)),
+            Err(e) => {
+                debug!("rate limited {}", client);
+                Box::pin(ok(req.error_response(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_new_rate_applies_to_existing_buckets() {
+        let limiter = RateLimiter::new(Some(Rate {
+            per_minute: 1,
+            burst: 2,
+        }));
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_err());
+
+        limiter.set_rate(None);
+        assert!((0..10).all(|_| limiter.take("a").is_ok()));
+
+        limiter.set_rate(Some(Rate {
+            per_minute: 60,
+            burst: 1,
+        }));
+        assert!(limiter.take("a").is_ok());
+        assert_eq!(limiter.take("a").unwrap_err().retry_after, 1);
+    }
+}
@@ -0,0 +1,80 @@
+//! Reloading settings while the server runs. On SIGHUP the config file is
+//! read again, the environment still overriding it, and the log filter, the
+//! limits and the rate limits take their new values. Requests already
+//! running keep the limits they started with.
+//!
+//! The bind address, the server options, the repository and backup
+//! settings and the IDE are fixed at startup, as are the upload limits and
+//! the body limits of the REST and CardDAV routes; a reload logs that a change to them waits for
+//! a restart. A config that fails to load leaves every setting as it was.
+//! Webhooks are registered through the API and need no reload.
+
+use crate::logging;
+use crate::ratelimit::{Rate, RateLimiter};
+use crate::settings::{Config, Limits, LiveLimits, Mode};
+
+/// The settings in effect and what to update when they change.
+pub struct Reloader {
+    mode: Mode,
+    started: Config,
+    started_limits: Limits,
+    limits: LiveLimits,
+    limiter: RateLimiter,
+}
+
+impl Reloader {
+    pub fn new(mode: Mode, started: Config, limits: LiveLimits, limiter: RateLimiter) -> Reloader {
+        Reloader {
+            mode,
+            started,
+            started_limits: limits.get(),
+            limits,
+            limiter,
+        }
+    }
+
+    /// Loads the config again and applies what can change, all of it or,
+    /// if any of it is invalid, nothing.
+    pub fn reload(&self) -> Result<(), String> {
+        let config = Config::load(self.mode)?;
+        let filter = logging::filter(&config)?;
+        let limits = Limits::load(&config)?;
+        let rate = Rate::load(&config)?;
+
+        let mut fixed = self.started.needs_restart(&config);
+        if limits.max_upload_bytes != self.started_limits.max_upload_bytes
+            || limits.max_uploads != self.started_limits.max_uploads
+        {
+            fixed.push("the upload limits");
+        }
+        if !fixed.is_empty() {
+            warn!("{} changed, which takes a restart", fixed.join(", "));
+        }
+
+        logging::set_filter(filter.clone());
+        self.limits.set(limits);
+        self.limiter.set_rate(rate);
+        info!(
+            "reloaded the config: log filter {}, {:?}, rate limit {:?}",
+            filter, limits, rate
+        );
+        Ok(())
+    }
+}
+
+/// Reloads on every SIGHUP until the runtime stops.
+pub async fn on_hangup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("no reloading on SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(e) = reloader.reload() {
+            warn!("reload failed, keeping the current settings: {}", e);
+        }
+    }
+}
@@ -0,0 +1,1227 @@
+use crate::sentry;
+use futures::stream::{self, LocalBoxStream};
+use futures::{future, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+const BLOBS: &str = "blobs";
+/// Where the journals of committing transactions are kept.
+const TRANSACTIONS: &str = ".transactions";
+
+/// Anything the repository can store, addressed by its kind and key. The key
+/// is the id unless the entity is partitioned, e.g. by owner.
+pub trait Entity {
+    const KIND: &'static str;
+
+    fn id(&self) -> &str;
+
+    fn key(&self) -> String {
+        self.id().to_owned()
+    }
+}
+
+pub trait Repository<T> {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>>;
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>>;
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+    /// Every entity stored directly under `prefix`, `""` for the top level,
+    /// sorted by key.
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>>;
+
+    /// What `list` returns, as a stream for lists too long to hold. Backends
+    /// that can read the entities as the stream gets to them do; the rest
+    /// read the list up front.
+    fn stream(&self, prefix: &str) -> LocalBoxStream<'static, Result<T, Box<dyn Error>>>
+    where
+        T: 'static,
+    {
+        match self.list(prefix) {
+            Ok(all) => stream::iter(all.into_iter().map(Ok)).boxed_local(),
+            Err(e) => stream::once(future::ready(Err(e))).boxed_local(),
+        }
+    }
+}
+
+/// Binary data stored by key, such as images; `/` separates the parts of a
+/// key like it does for entities.
+pub trait Blobs {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+    /// Deletes the blob `key`, or every blob under it; missing blobs are
+    /// already deleted.
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Repositories that can apply a group of writes, across entities and
+/// kinds, all together or not at all. Transactions see their own writes and
+/// don't block each other, but a commit fails with `Conflict` if a record
+/// the transaction got, or found missing, has changed since: of two
+/// transactions reading and writing the same record, only one commits.
+/// What lists return isn't checked.
+pub trait Transactional {
+    type Transaction;
+
+    fn begin(&self) -> Self::Transaction;
+
+    /// Applies the transaction's writes; dropping it instead rolls it back.
+    fn commit(&self, tx: Self::Transaction) -> Result<(), Box<dyn Error>>;
+
+    /// Runs `work` in a transaction committed if it succeeds.
+    fn with_tx<R>(
+        &self,
+        work: impl FnOnce(&Self::Transaction) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        let tx = self.begin();
+        let result = work(&tx)?;
+        self.commit(tx)?;
+        Ok(result)
+    }
+}
+
+/// Entities that stop being valid at `expires_at` (unix seconds).
+pub trait Expiring: Entity {
+    fn expires_at(&self) -> u64;
+}
+
+/// Reads an entity that hasn't expired yet. Expired entities are deleted on
+/// the way, so the store doesn't need a sweeper.
+pub fn get_live<T: Expiring, R: Repository<T>>(repo: &R, key: &str, now: u64) -> Option<T> {
+    let obj = repo.get(key).ok()?;
+    if obj.expires_at() <= now {
+        let _ = repo.delete(key);
+        return None;
+    }
+    Some(obj)
+}
+
+#[derive(Clone)]
+pub struct FileRepository<'a> {
+    path: &'a str,
+    tenant: Option<String>,
+}
+
+/// Writes staged in memory until commit, when each is written next to its
+/// file and a journal of them is stored under `<path>/.transactions`, then
+/// renamed into place. A commit cut short is finished by `recover`, so
+/// either every write of a transaction lands or, without a journal, none.
+/// Commits in the process take turns, so none lands between another's
+/// check of its reads and its writes.
+pub struct FileTransaction<'a> {
+    repo: FileRepository<'a>,
+    staged: Mutex<Staged>,
+    reads: Mutex<Staged>,
+}
+
+/// Staged records by kind and key, `None` for deletes; or the records a
+/// transaction read, `None` for those it found missing.
+type Staged = BTreeMap<(&'static str, String), Option<Vec<u8>>>;
+
+static FILE_COMMITS: Mutex<()> = Mutex::new(());
+
+/// A transaction's commit refused, as a record it read changed before it
+/// was committed. Trying the work again sees the change.
+#[derive(Debug)]
+pub struct Conflict {
+    pub kind: &'static str,
+    pub key: String,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:?} changed concurrently", self.kind, self.key)
+    }
+}
+
+impl Error for Conflict {}
+
+pub fn is_conflict(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<Conflict>().is_some()
+}
+
+/// Fails with the first of `reads` that isn't what `current` says is stored.
+fn check_reads(
+    reads: Staged,
+    mut current: impl FnMut(&'static str, &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    for ((kind, key), seen) in reads {
+        if current(kind, &key)? != seen {
+            return Err(Box::new(Conflict { kind, key }));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    /// Staged files and the paths they replace.
+    renames: Vec<(PathBuf, PathBuf)>,
+    deletes: Vec<PathBuf>,
+}
+
+impl<'a> Transactional for FileRepository<'a> {
+    type Transaction = FileTransaction<'a>;
+
+    fn begin(&self) -> FileTransaction<'a> {
+        FileTransaction {
+            repo: self.clone(),
+            staged: Mutex::new(BTreeMap::new()),
+            reads: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn commit(&self, tx: FileTransaction<'a>) -> Result<(), Box<dyn Error>> {
+        let _turn = FILE_COMMITS.lock().unwrap_or_else(|e| e.into_inner());
+        let reads = std::mem::take(&mut *tx.reads.lock().unwrap());
+        check_reads(reads, |kind, key| tx.repo.read(kind, key))?;
+        if let Some(journal) = tx.prepare()? {
+            apply(&journal)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> FileTransaction<'a> {
+    fn staged(&self, kind: &'static str, key: &str) -> Option<Option<Vec<u8>>> {
+        self.staged
+            .lock()
+            .unwrap()
+            .get(&(kind, key.to_owned()))
+            .cloned()
+    }
+
+    /// Reads a stored record, remembering what the transaction first saw
+    /// of it for the commit to check.
+    fn read(&self, kind: &'static str, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let data = self.repo.read(kind, key)?;
+        let mut reads = self.reads.lock().unwrap();
+        Ok(reads.entry((kind, key.to_owned())).or_insert(data).clone())
+    }
+
+    /// Writes the staged records next to their files and the journal that
+    /// commits them; the path of the journal, if anything was staged.
+    fn prepare(self) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        use std::fs;
+        let staged = self.staged.into_inner().unwrap();
+        if staged.is_empty() {
+            return Ok(None);
+        }
+        let id = crate::crypto::to_hex(&rand::random::<[u8; 8]>());
+        let mut journal = Journal {
+            renames: vec![],
+            deletes: vec![],
+        };
+        for ((kind, key), data) in staged {
+            let path = self.repo.path_for(kind, &key)?;
+            match data {
+                Some(data) => {
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir).map_err(|e| io_error(kind, e))?;
+                    }
+                    let partial = path.with_extension(format!("tx-{}", id));
+                    fs::write(&partial, data).map_err(|e| io_error(kind, e))?;
+                    journal.renames.push((partial, path));
+                }
+                None => journal.deletes.push(path),
+            }
+        }
+        let dir = self.repo.dir().join(TRANSACTIONS);
+        fs::create_dir_all(&dir).map_err(|e| io_error(TRANSACTIONS, e))?;
+        let path = dir.join(format!("{}.json", id));
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&journal)?)
+            .map_err(|e| io_error(TRANSACTIONS, e))?;
+        fs::rename(&partial, &path).map_err(|e| io_error(TRANSACTIONS, e))?;
+        Ok(Some(path))
+    }
+}
+
+/// Carries out a journal, again if need be, and removes it.
+fn apply(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::fs;
+    use std::io::ErrorKind;
+    let journal: Journal = serde_json::from_slice(&fs::read(path)?)?;
+    for (staged, target) in &journal.renames {
+        match fs::rename(staged, target) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(io_error(TRANSACTIONS, e)),
+            _ => {}
+        }
+    }
+    for target in &journal.deletes {
+        match fs::remove_file(target) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(io_error(TRANSACTIONS, e)),
+            _ => {}
+        }
+    }
+    fs::remove_file(path).map_err(|e| io_error(TRANSACTIONS, e))?;
+    Ok(())
+}
+
+fn not_found() -> Box<dyn Error> {
+    Box::new(std::io::Error::from(std::io::ErrorKind::NotFound))
+}
+
+/// Whether `e` says the record isn't there, rather than that it couldn't be
+/// read.
+pub fn is_not_found(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<std::io::Error>(),
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound
+    )
+}
+
+impl<'a, T: DeserializeOwned + Serialize + Entity> Repository<T> for FileTransaction<'a> {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        let key = obj.key();
+        self.repo.path_for(T::KIND, &key)?;
+        let data = serde_json::to_vec(&obj)?;
+        self.staged
+            .lock()
+            .unwrap()
+            .insert((T::KIND, key), Some(data));
+        Ok(obj)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        match self.staged(T::KIND, key) {
+            Some(Some(data)) => Ok(serde_json::from_slice(&data)?),
+            Some(None) => Err(not_found()),
+            None => match self.read(T::KIND, key)? {
+                Some(data) => serde_json::from_slice(&data).map_err(|e| corrupt(T::KIND, e)),
+                None => Err(not_found()),
+            },
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        match self.staged(T::KIND, key) {
+            Some(Some(_)) => {}
+            Some(None) => return Err(not_found()),
+            None => {
+                if self.read(T::KIND, key)?.is_none() {
+                    return Err(not_found());
+                }
+            }
+        }
+        self.staged
+            .lock()
+            .unwrap()
+            .insert((T::KIND, key.to_owned()), None);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        let stored: Vec<T> = self.repo.list(prefix)?;
+        let staged = self.staged.lock().unwrap();
+        let mut result: Vec<T> = stored
+            .into_iter()
+            .filter(|obj| !staged.contains_key(&(T::KIND, obj.key())))
+            .collect();
+        for ((kind, key), data) in staged.iter() {
+            if let (true, true, Some(data)) = (*kind == T::KIND, directly_under(key, prefix), data)
+            {
+                result.push(serde_json::from_slice(data)?);
+            }
+        }
+        result.sort_by_key(|obj| obj.key());
+        Ok(result)
+    }
+}
+
+impl<'a> FileRepository<'a> {
+    pub fn new(path: &'a str) -> FileRepository<'a> {
+        FileRepository { path, tenant: None }
+    }
+
+    /// The same repository narrowed to `<path>/tenants/<tenant>`.
+    pub fn for_tenant(&self, tenant: &str) -> FileRepository<'a> {
+        FileRepository {
+            path: self.path,
+            tenant: Some(tenant.to_owned()),
+        }
+    }
+
+    /// The directory this repository stores under, its tenant's partition
+    /// if narrowed to one.
+    pub fn dir(&self) -> std::path::PathBuf {
+        let path = std::path::Path::new(&self.path);
+        match &self.tenant {
+            Some(tenant) => path.join("tenants").join(tenant),
+            None => path.to_path_buf(),
+        }
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// One repository per tenant that has stored anything.
+    pub fn tenants(&self) -> Vec<FileRepository<'a>> {
+        let dir = std::path::Path::new(&self.path).join("tenants");
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .map(|tenant| self.for_tenant(&tenant))
+            .collect()
+    }
+
+    /// The key of every `kind` entity stored, partitioned or not.
+    pub fn keys(&self, kind: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        fn walk(
+            dir: &std::path::Path,
+            prefix: &str,
+            keys: &mut Vec<String>,
+        ) -> std::io::Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = if path.is_dir() {
+                    path.file_name()
+                } else {
+                    path.file_stem()
+                };
+                let name = match name.and_then(|n| n.to_str()) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let key = if prefix.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+                if path.is_dir() {
+                    walk(&path, &key, keys)?;
+                } else if path.extension().map(|e| e == "json").unwrap_or(false) {
+                    keys.push(key);
+                }
+            }
+            Ok(())
+        }
+
+        let mut keys = vec![];
+        match walk(&self.dir_for(kind, "")?, "", &mut keys) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(keys),
+        }
+    }
+
+    /// Finishes the commits of transactions cut short, e.g. by a crash.
+    pub fn recover(&self) -> Result<usize, Box<dyn Error>> {
+        let dir = self.dir().join(TRANSACTIONS);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut recovered = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                apply(&path)?;
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Checks the storage directory can be written to, by writing and
+    /// removing a probe file.
+    pub fn probe(&self) -> Result<(), Box<dyn Error>> {
+        use std::fs;
+        let dir = std::path::Path::new(&self.path);
+        fs::create_dir_all(dir)?;
+        let path = dir.join(".probe");
+        fs::write(&path, b"ok")?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// The stored record's JSON, `None` if there is none.
+    fn read(&self, kind: &str, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let path = self.path_for(kind, key)?;
+        debug!("{:?}", path);
+        match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(kind, e)),
+        }
+    }
+
+    /// Maps a key onto `<path>/<kind>/<key>.json`, each `/` separated part of
+    /// the key a directory. Parts that could leave the kind's directory are
+    /// rejected.
+    fn path_for(&self, kind: &str, key: &str) -> Result<std::path::PathBuf, Box<dyn Error>> {
+        let (prefix, name) = match key.rfind('/') {
+            Some(i) => (&key[..i], &key[i + 1..]),
+            None => ("", key),
+        };
+        if !valid_part(name) {
+            return Err(format!("invalid key {:?}", key).into());
+        }
+        Ok(self.dir_for(kind, prefix)?.join(format!("{}.json", name)))
+    }
+
+    fn dir_for(&self, kind: &str, prefix: &str) -> Result<std::path::PathBuf, Box<dyn Error>> {
+        let mut path = self.dir().join(kind);
+        if !prefix.is_empty() {
+            if !prefix.split('/').all(valid_part) {
+                return Err(format!("invalid key prefix {:?}", prefix).into());
+            }
+            path = path.join(prefix);
+        }
+        Ok(path)
+    }
+}
+
+fn valid_part(part: &str) -> bool {
+    !part.is_empty() && part != "." && part != ".." && !part.contains('\\')
+}
+
+/// Reports a storage failure; a missing file is the caller's concern.
+fn io_error(kind: &str, e: std::io::Error) -> Box<dyn Error> {
+    if e.kind() != std::io::ErrorKind::NotFound {
+        sentry::capture(format!("{} storage failed: {}", kind, e));
+    }
+    e.into()
+}
+
+/// Reports a stored entity that can't be read back.
+fn corrupt(kind: &str, e: serde_json::Error) -> Box<dyn Error> {
+    sentry::capture(format!("corrupt {}: {}", kind, e));
+    e.into()
+}
+
+/// Blobs are files under `<path>/blobs`, written under a temporary name and
+/// renamed into place so readers never see half a blob.
+impl<'a> Blobs for FileRepository<'a> {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        use std::fs;
+        let path = self.dir_for(BLOBS, key)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| io_error(BLOBS, e))?;
+        }
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data).map_err(|e| io_error(BLOBS, e))?;
+        fs::rename(&partial, &path).map_err(|e| io_error(BLOBS, e))?;
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = self.dir_for(BLOBS, key)?;
+        std::fs::read(&path).map_err(|e| io_error(BLOBS, e))
+    }
+
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        use std::fs;
+        use std::io::ErrorKind;
+        let path = self.dir_for(BLOBS, key)?;
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(io_error(BLOBS, e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a, T: DeserializeOwned + Serialize + Entity> Repository<T> for FileRepository<'a> {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        use std::fs::{self, File};
+
+        let path = self.path_for(T::KIND, &obj.key())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| io_error(T::KIND, e))?;
+        }
+        debug!("{:?}", path);
+
+        let f = File::create(path).map_err(|e| io_error(T::KIND, e))?;
+        serde_json::to_writer(f, &obj).expect("Unable to serialized");
+        Ok(obj)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        use std::fs::File;
+        let path = self.path_for(T::KIND, key)?;
+        debug!("{:?}", path);
+        let f = File::open(&path).map_err(|e| io_error(T::KIND, e))?;
+        // serde_json reads a byte at a time, so unbuffered every byte would
+        // be a read call.
+        let result: T =
+            serde_json::from_reader(BufReader::new(f)).map_err(|e| corrupt(T::KIND, e))?;
+        Ok(result)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        use std::fs;
+        let path = self.path_for(T::KIND, key)?;
+        debug!("{:?}", path);
+        fs::remove_file(&path).map_err(|e| io_error(T::KIND, e))?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        use std::fs::{self, File};
+        use std::io::ErrorKind;
+        let dir = self.dir_for(T::KIND, prefix)?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(io_error(T::KIND, e)),
+        };
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry.map_err(|e| io_error(T::KIND, e))?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+        // Directories are read in whatever order the file system keeps.
+        paths.sort_by(|a, b| a.file_stem().cmp(&b.file_stem()));
+        let mut result = vec![];
+        for path in paths {
+            let f = File::open(&path).map_err(|e| io_error(T::KIND, e))?;
+            result
+                .push(serde_json::from_reader(BufReader::new(f)).map_err(|e| corrupt(T::KIND, e))?);
+        }
+        Ok(result)
+    }
+
+    /// Each entity is read from its file only when the stream gets to it:
+    /// only the names of the files are held, however many there are.
+    fn stream(&self, prefix: &str) -> LocalBoxStream<'static, Result<T, Box<dyn Error>>>
+    where
+        T: 'static,
+    {
+        let dir = self.dir_for(T::KIND, prefix);
+        let paths = async move {
+            let mut entries = match tokio::fs::read_dir(dir?).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+                Err(e) => return Err(io_error(T::KIND, e)),
+            };
+            let mut paths = vec![];
+            while let Some(entry) = entries.next().await {
+                let path = entry.map_err(|e| io_error(T::KIND, e))?.path();
+                if path.extension().map(|e| e == "json").unwrap_or(false) {
+                    paths.push(path);
+                }
+            }
+            paths.sort_by(|a, b| a.file_stem().cmp(&b.file_stem()));
+            Ok(paths)
+        };
+        stream::once(paths)
+            .map(|paths: Result<Vec<PathBuf>, Box<dyn Error>>| match paths {
+                Ok(paths) => stream::iter(paths.into_iter().map(Ok)).left_stream(),
+                Err(e) => stream::once(future::ready(Err(e))).right_stream(),
+            })
+            .flatten()
+            .then(|path| async move {
+                let data = tokio::fs::read(path?)
+                    .await
+                    .map_err(|e| io_error(T::KIND, e))?;
+                serde_json::from_slice(&data).map_err(|e| corrupt(T::KIND, e))
+            })
+            .boxed_local()
+    }
+}
+
+/// Records by kind and key, as the JSON the file repository would write.
+type Records = BTreeMap<(&'static str, String), Vec<u8>>;
+
+/// A repository in memory, for tests and benchmarks that don't need the
+/// files themselves. Clones share the records, so one made at start-up and
+/// cloned into each actix worker's data is a single store rather than one
+/// per worker. Keys are checked like the file repository checks them.
+#[derive(Clone, Default)]
+pub struct MemoryRepository {
+    records: Arc<RwLock<Records>>,
+    blobs: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+    /// Each tenant's own repository, made on first use.
+    tenants: Arc<Mutex<BTreeMap<String, MemoryRepository>>>,
+}
+
+/// Writes staged until commit, which checks the transaction's reads and
+/// applies its writes under one lock: other readers see all of them or
+/// none.
+pub struct MemoryTransaction {
+    repo: MemoryRepository,
+    staged: Mutex<Staged>,
+    reads: Mutex<Staged>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> MemoryRepository {
+        MemoryRepository::default()
+    }
+
+    /// The partition of `tenant`, apart from the records stored here like
+    /// `FileRepository::for_tenant`'s.
+    pub fn for_tenant(&self, tenant: &str) -> MemoryRepository {
+        let mut tenants = self.tenants.lock().unwrap();
+        tenants.entry(tenant.to_owned()).or_default().clone()
+    }
+}
+
+fn check_key(key: &str) -> Result<(), Box<dyn Error>> {
+    match key.split('/').all(valid_part) {
+        true => Ok(()),
+        false => Err(format!("invalid key {:?}", key).into()),
+    }
+}
+
+fn check_prefix(prefix: &str) -> Result<(), Box<dyn Error>> {
+    match prefix.is_empty() || prefix.split('/').all(valid_part) {
+        true => Ok(()),
+        false => Err(format!("invalid key prefix {:?}", prefix).into()),
+    }
+}
+
+/// Whether `key` is directly under `prefix`.
+fn directly_under(key: &str, prefix: &str) -> bool {
+    key.rfind('/').map_or("", |i| &key[..i]) == prefix
+}
+
+impl<T: DeserializeOwned + Serialize + Entity> Repository<T> for MemoryRepository {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        let key = obj.key();
+        check_key(&key)?;
+        let data = serde_json::to_vec(&obj)?;
+        self.records.write().unwrap().insert((T::KIND, key), data);
+        Ok(obj)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        check_key(key)?;
+        match self.records.read().unwrap().get(&(T::KIND, key.to_owned())) {
+            Some(data) => Ok(serde_json::from_slice(data)?),
+            None => Err(not_found()),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        match self
+            .records
+            .write()
+            .unwrap()
+            .remove(&(T::KIND, key.to_owned()))
+        {
+            Some(_) => Ok(()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        check_prefix(prefix)?;
+        let records = self.records.read().unwrap();
+        records
+            .iter()
+            .filter(|((kind, key), _)| *kind == T::KIND && directly_under(key, prefix))
+            .map(|(_, data)| Ok(serde_json::from_slice(data)?))
+            .collect()
+    }
+}
+
+impl Blobs for MemoryRepository {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(key.to_owned(), data.to_vec());
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        check_key(key)?;
+        let blobs = self.blobs.read().unwrap();
+        blobs.get(key).cloned().ok_or_else(not_found)
+    }
+
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        let under = format!("{}/", key);
+        self.blobs
+            .write()
+            .unwrap()
+            .retain(|k, _| k != key && !k.starts_with(&under));
+        Ok(())
+    }
+}
+
+impl Transactional for MemoryRepository {
+    type Transaction = MemoryTransaction;
+
+    fn begin(&self) -> MemoryTransaction {
+        MemoryTransaction {
+            repo: self.clone(),
+            staged: Mutex::new(BTreeMap::new()),
+            reads: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn commit(&self, tx: MemoryTransaction) -> Result<(), Box<dyn Error>> {
+        let staged = tx.staged.into_inner().unwrap();
+        let mut records = self.records.write().unwrap();
+        check_reads(tx.reads.into_inner().unwrap(), |kind, key| {
+            Ok(records.get(&(kind, key.to_owned())).cloned())
+        })?;
+        for (record, data) in staged {
+            match data {
+                Some(data) => records.insert(record, data),
+                None => records.remove(&record),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl MemoryTransaction {
+    fn staged(&self, kind: &'static str, key: &str) -> Option<Option<Vec<u8>>> {
+        self.staged
+            .lock()
+            .unwrap()
+            .get(&(kind, key.to_owned()))
+            .cloned()
+    }
+
+    /// Like `FileTransaction::read`.
+    fn read(&self, kind: &'static str, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        check_key(key)?;
+        let data = {
+            let records = self.repo.records.read().unwrap();
+            records.get(&(kind, key.to_owned())).cloned()
+        };
+        let mut reads = self.reads.lock().unwrap();
+        Ok(reads.entry((kind, key.to_owned())).or_insert(data).clone())
+    }
+}
+
+impl<T: DeserializeOwned + Serialize + Entity> Repository<T> for MemoryTransaction {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        let key = obj.key();
+        check_key(&key)?;
+        let data = serde_json::to_vec(&obj)?;
+        self.staged
+            .lock()
+            .unwrap()
+            .insert((T::KIND, key), Some(data));
+        Ok(obj)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        match self.staged(T::KIND, key) {
+            Some(Some(data)) => Ok(serde_json::from_slice(&data)?),
+            Some(None) => Err(not_found()),
+            None => match self.read(T::KIND, key)? {
+                Some(data) => Ok(serde_json::from_slice(&data)?),
+                None => Err(not_found()),
+            },
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        match self.staged(T::KIND, key) {
+            Some(Some(_)) => {}
+            Some(None) => return Err(not_found()),
+            None if self.read(T::KIND, key)?.is_none() => return Err(not_found()),
+            None => {}
+        }
+        self.staged
+            .lock()
+            .unwrap()
+            .insert((T::KIND, key.to_owned()), None);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        let stored: Vec<T> = self.repo.list(prefix)?;
+        let staged = self.staged.lock().unwrap();
+        let mut result: Vec<T> = stored
+            .into_iter()
+            .filter(|obj| !staged.contains_key(&(T::KIND, obj.key())))
+            .collect();
+        for ((kind, key), data) in staged.iter() {
+            if let (true, true, Some(data)) = (*kind == T::KIND, directly_under(key, prefix), data)
+            {
+                result.push(serde_json::from_slice(data)?);
+            }
+        }
+        result.sort_by_key(|obj| obj.key());
+        Ok(result)
+    }
+}
+
+/// The repository the schema serves from: the files, or for tests and
+/// benchmarks that don't need them, memory.
+#[derive(Clone)]
+pub enum StorageBackend {
+    Files(FileRepository<'static>),
+    Memory(MemoryRepository),
+}
+
+pub enum BackendTransaction {
+    Files(FileTransaction<'static>),
+    Memory(MemoryTransaction),
+}
+
+impl StorageBackend {
+    /// The name metrics and spans label calls with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageBackend::Files(_) => "file",
+            StorageBackend::Memory(_) => "memory",
+        }
+    }
+
+    pub fn for_tenant(&self, tenant: &str) -> StorageBackend {
+        match self {
+            StorageBackend::Files(repo) => StorageBackend::Files(repo.for_tenant(tenant)),
+            StorageBackend::Memory(repo) => StorageBackend::Memory(repo.for_tenant(tenant)),
+        }
+    }
+
+    /// The files, for operations on the stored records themselves such as
+    /// backups; `None` in memory.
+    pub fn files(&self) -> Option<&FileRepository<'static>> {
+        match self {
+            StorageBackend::Files(repo) => Some(repo),
+            StorageBackend::Memory(_) => None,
+        }
+    }
+}
+
+/// Calls `$call` on whichever repository `$backend` holds.
+macro_rules! each_backend {
+    ($backend:expr, $repo:ident => $call:expr) => {
+        match $backend {
+            StorageBackend::Files($repo) => $call,
+            StorageBackend::Memory($repo) => $call,
+        }
+    };
+    (tx $tx:expr, $repo:ident => $call:expr) => {
+        match $tx {
+            BackendTransaction::Files($repo) => $call,
+            BackendTransaction::Memory($repo) => $call,
+        }
+    };
+}
+
+impl<T: DeserializeOwned + Serialize + Entity> Repository<T> for StorageBackend {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        each_backend!(self, repo => repo.set(obj))
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        each_backend!(self, repo => repo.get(key))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        each_backend!(self, repo => Repository::<T>::delete(repo, key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        each_backend!(self, repo => repo.list(prefix))
+    }
+
+    fn stream(&self, prefix: &str) -> LocalBoxStream<'static, Result<T, Box<dyn Error>>>
+    where
+        T: 'static,
+    {
+        each_backend!(self, repo => repo.stream(prefix))
+    }
+}
+
+impl Blobs for StorageBackend {
+    fn put_blob(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        each_backend!(self, repo => repo.put_blob(key, data))
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        each_backend!(self, repo => repo.get_blob(key))
+    }
+
+    fn delete_blobs(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        each_backend!(self, repo => repo.delete_blobs(key))
+    }
+}
+
+impl Transactional for StorageBackend {
+    type Transaction = BackendTransaction;
+
+    fn begin(&self) -> BackendTransaction {
+        match self {
+            StorageBackend::Files(repo) => BackendTransaction::Files(repo.begin()),
+            StorageBackend::Memory(repo) => BackendTransaction::Memory(repo.begin()),
+        }
+    }
+
+    fn commit(&self, tx: BackendTransaction) -> Result<(), Box<dyn Error>> {
+        match (self, tx) {
+            (StorageBackend::Files(repo), BackendTransaction::Files(tx)) => repo.commit(tx),
+            (StorageBackend::Memory(repo), BackendTransaction::Memory(tx)) => repo.commit(tx),
+            _ => Err("the transaction was begun on another backend".into()),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Serialize + Entity> Repository<T> for BackendTransaction {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        each_backend!(tx self, tx => tx.set(obj))
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        each_backend!(tx self, tx => tx.get(key))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        each_backend!(tx self, tx => Repository::<T>::delete(tx, key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        each_backend!(tx self, tx => tx.list(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Storage, StorageMode, StoredEvent};
+    use crate::models::{Contact, Group};
+    use crate::outbox::OutboxMessage;
+    use crate::testing::{ContactFixture, MockRepository};
+    use crate::usecases;
+
+    repository_contract!(files, FileRepository::new);
+    repository_contract!(tenant_files, |path| FileRepository::new(path)
+        .for_tenant("t1"));
+    repository_contract!(state_storage, |path| Storage::new(
+        FileRepository::new(path),
+        StorageMode::State
+    ));
+    repository_contract!(event_storage, |path| Storage::new(
+        FileRepository::new(path),
+        StorageMode::Events
+    ));
+    repository_contract!(mock, |_| MockRepository::default());
+    repository_contract!(transaction, |path| FileRepository::new(path).begin());
+    repository_contract!(memory, |_| MemoryRepository::new());
+    repository_contract!(memory_transaction, |_| MemoryRepository::new().begin());
+    repository_contract!(tenant_memory, |_| MemoryRepository::new().for_tenant("t1"));
+    repository_contract!(file_backend, |path| StorageBackend::Files(
+        FileRepository::new(path)
+    ));
+    repository_contract!(memory_backend, |_| StorageBackend::Memory(
+        MemoryRepository::new()
+    ));
+    repository_contract!(backend_transaction, |_| StorageBackend::Memory(
+        MemoryRepository::new()
+    )
+    .begin());
+
+    fn scratch() -> (tempfile::TempDir, FileRepository<'static>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        (dir, FileRepository::new(Box::leak(path.into_boxed_str())))
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut found = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(files(&path));
+            } else {
+                found.push(path.display().to_string());
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn commit_applies_every_write() {
+        let (dir, repo) = scratch();
+        repo.set(ContactFixture::new("alan").build()).unwrap();
+        repo.with_tx(|tx| {
+            tx.set(ContactFixture::new("ada").build())?;
+            tx.set(Group {
+                id: "friends".to_owned(),
+                name: "Friends".to_owned(),
+                member_ids: vec!["ada".to_owned()],
+            })?;
+            Repository::<Contact>::delete(tx, "u1/alan")?;
+            // Unapplied yet.
+            assert!(Repository::<Contact>::get(&repo, "u1/ada").is_err());
+            assert!(Repository::<Contact>::get(&repo, "u1/alan").is_ok());
+            Ok(())
+        })
+        .unwrap();
+
+        let ada: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(ada.first_name, "Ada");
+        let group: Group = repo.get("friends").unwrap();
+        assert_eq!(group.member_ids, vec!["ada"]);
+        assert!(Repository::<Contact>::get(&repo, "u1/alan").is_err());
+        let mut left = files(dir.path());
+        left.retain(|f| !f.ends_with(".json"));
+        assert!(left.is_empty(), "{:?}", left);
+        assert_eq!(repo.recover().unwrap(), 0);
+    }
+
+    #[test]
+    fn failed_work_applies_nothing() {
+        let (_dir, files) = scratch();
+        let repo = Storage::new(files.clone(), StorageMode::Events);
+        let result: Result<(), Box<dyn Error>> = repo.with_tx(|tx| {
+            usecases::create("u1", "u1", ContactFixture::new("ada").build(), tx)?;
+            Err("a later step failed".into())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "a later step failed");
+
+        assert!(usecases::get("u1", "ada", &repo).is_err());
+        assert!(usecases::audit_log("u1", "ada", &repo).unwrap().is_empty());
+        let outbox: Vec<OutboxMessage> = repo.list("").unwrap();
+        assert!(outbox.is_empty());
+        assert!(files.keys(StoredEvent::KIND).unwrap().is_empty());
+    }
+
+    /// Two transactions read ada, or find her missing, and both write her:
+    /// the first to commit wins.
+    fn conflicting_commits<R: Transactional + Repository<Contact>>(repo: &R)
+    where
+        R::Transaction: Repository<Contact>,
+    {
+        for existing in &[true, false] {
+            Repository::<Contact>::delete(repo, "u1/ada").ok();
+            if *existing {
+                repo.set(ContactFixture::new("ada").build()).unwrap();
+            }
+            let (first, second) = (repo.begin(), repo.begin());
+            for (tx, name) in &[(&first, "Augusta"), (&second, "Countess")] {
+                assert_eq!(Repository::<Contact>::get(*tx, "u1/ada").is_ok(), *existing);
+                let mut ada = ContactFixture::new("ada").build();
+                ada.first_name = name.to_string();
+                tx.set(ada).unwrap();
+            }
+            repo.commit(first).unwrap();
+            let e = repo.commit(second).unwrap_err();
+            assert!(is_conflict(e.as_ref()), "{}", e);
+            let ada: Contact = repo.get("u1/ada").unwrap();
+            assert_eq!(ada.first_name, "Augusta");
+        }
+
+        // Deleting checks too, and unread records don't conflict.
+        let (first, second) = (repo.begin(), repo.begin());
+        Repository::<Contact>::delete(&first, "u1/ada").unwrap();
+        Repository::<Contact>::delete(&second, "u1/ada").unwrap();
+        repo.commit(first).unwrap();
+        assert!(is_conflict(repo.commit(second).unwrap_err().as_ref()));
+        let (first, second) = (repo.begin(), repo.begin());
+        first.set(ContactFixture::new("grace").build()).unwrap();
+        second.set(ContactFixture::new("grace").build()).unwrap();
+        repo.commit(first).unwrap();
+        repo.commit(second).unwrap();
+    }
+
+    #[test]
+    fn file_commits_fail_on_conflicting_reads() {
+        let (_dir, repo) = scratch();
+        conflicting_commits(&repo);
+    }
+
+    #[test]
+    fn memory_commits_fail_on_conflicting_reads() {
+        conflicting_commits(&MemoryRepository::new());
+    }
+
+    #[test]
+    fn recover_finishes_an_interrupted_commit() {
+        let (_dir, repo) = scratch();
+        repo.set(ContactFixture::new("alan").build()).unwrap();
+        let tx = repo.begin();
+        tx.set(ContactFixture::new("ada").build()).unwrap();
+        Repository::<Contact>::delete(&tx, "u1/alan").unwrap();
+        // As if the process died after writing the journal.
+        tx.prepare().unwrap().unwrap();
+        assert!(Repository::<Contact>::get(&repo, "u1/ada").is_err());
+
+        assert_eq!(repo.recover().unwrap(), 1);
+        let ada: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(ada.first_name, "Ada");
+        assert!(Repository::<Contact>::get(&repo, "u1/alan").is_err());
+        assert_eq!(repo.recover().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn stream_reads_what_list_returns_in_key_order() {
+        let (dir, repo) = scratch();
+        for id in &["grace", "ada-b", "ada"] {
+            repo.set(ContactFixture::new(id).build()).unwrap();
+        }
+        repo.set(ContactFixture::new("alan").owner("u2").build())
+            .unwrap();
+        let ids = |contacts: Vec<Result<Contact, Box<dyn Error>>>| -> Vec<String> {
+            contacts.into_iter().map(|c| c.unwrap().id).collect()
+        };
+        assert_eq!(
+            ids(repo.stream("u1").collect().await),
+            vec!["ada", "ada-b", "grace"]
+        );
+        assert!(ids(repo.stream("u3").collect().await).is_empty());
+
+        std::fs::write(dir.path().join("contacts/u1/bad.json"), b"{").unwrap();
+        let contacts: Vec<Result<Contact, Box<dyn Error>>> = repo.stream("u1").collect().await;
+        assert_eq!(contacts.len(), 4);
+        assert!(contacts[2].is_err());
+    }
+
+    #[test]
+    fn memory_tenants_are_kept_apart() {
+        let repo = MemoryRepository::new();
+        repo.for_tenant("t1")
+            .set(ContactFixture::new("ada").build())
+            .unwrap();
+        assert!(Repository::<Contact>::get(&repo.for_tenant("t1"), "u1/ada").is_ok());
+        assert!(Repository::<Contact>::get(&repo, "u1/ada").is_err());
+        assert!(Repository::<Contact>::get(&repo.for_tenant("t2"), "u1/ada").is_err());
+    }
+
+    #[test]
+    fn memory_clones_share_records_across_threads() {
+        let repo = MemoryRepository::new();
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let repo = repo.clone();
+                std::thread::spawn(move || {
+                    repo.with_tx(|tx| {
+                        tx.set(ContactFixture::new(&format!("c{}", i)).build())?;
+                        Ok(())
+                    })
+                    .unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let mut ids: Vec<String> = Repository::<Contact>::list(&repo, "u1")
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["c0", "c1", "c2", "c3"]);
+    }
+}
@@ -0,0 +1,1054 @@
+//! REST routes for integrators who don't speak GraphQL. `/api/v1/contacts`
+//! serves the caller's contacts through the same usecases as the GraphQL
+//! API, with the same roles: anyone signed in reads, editors write and
+//! admins delete or pass `ownerId` for other owners. Contacts are masked
+//! alike, too: without the `contacts:pii` scope, their emails, phones and
+//! addresses come back empty. Lists come a page at a time, by `limit` and
+//! `offset`, at most the configured page size; `/export.ndjson` streams
+//! every contact as newline-delimited JSON. The OpenAPI document
+//! describing the routes is served at `/api/openapi.json`.
+
+use crate::auth::{CurrentUser, Role};
+use crate::cache::ResponseCache;
+use crate::events::{Storage, StorageMode};
+use crate::graphql::{on_blocking_pool, Page};
+use crate::i18n;
+use crate::instrumented::InstrumentedRepository;
+use crate::models::{Address, Contact};
+use crate::repo::{self, FileRepository, Transactional};
+use crate::settings::{Limits, LiveLimits, DEFAULT_PAGE_SIZE};
+use crate::tenant;
+use crate::usecases;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{guard, web, HttpRequest, HttpResponse};
+use futures::{future, stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error;
+
+const MAX_ID_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 100;
+
+pub fn configure(cfg: &mut web::ServiceConfig, limits: &Limits) {
+    let json = web::JsonConfig::default()
+        .limit(limits.max_body_bytes)
+        .error_handler(|e, _| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()).into());
+    cfg.service(
+        web::resource("/api/openapi.json")
+            .guard(guard::Get())
+            .to(openapi_json),
+    )
+    .service(
+        web::resource("/api/v1/contacts")
+            .app_data(json.clone())
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    )
+    .service(
+        web::resource("/api/v1/contacts/{id}")
+            .app_data(json)
+            .route(web::get().to(get))
+            .route(web::put().to(put))
+            .route(web::delete().to(delete)),
+    )
+    .service(
+        web::resource("/export.ndjson")
+            .guard(guard::Get())
+            .to(export_ndjson),
+    );
+}
+
+/// An error response, `{"error": {"code": ..., "message": ...}}`.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// Missing records are 404s and writes that lost a race 409s; anything
+    /// else the usecases refuse is the request's fault.
+    fn from_usecase(e: Box<dyn Error>) -> ApiError {
+        let e = match e.downcast::<ApiError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        if repo::is_conflict(e.as_ref()) {
+            return ApiError::new(StatusCode::CONFLICT, e.to_string());
+        }
+        match e.downcast_ref::<std::io::Error>() {
+            Some(io) if io.kind() == std::io::ErrorKind::NotFound => {
+                ApiError::new(StatusCode::NOT_FOUND, i18n::text("contact_not_found", &[]))
+            }
+            Some(_) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "storage failed"),
+            None => ApiError::new(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+impl actix_web::ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let code = self
+            .status
+            .canonical_reason()
+            .unwrap_or("Error")
+            .to_ascii_uppercase()
+            .replace(' ', "_");
+        HttpResponse::build(self.status).json(json!({
+            "error": { "code": code, "message": self.message }
+        }))
+    }
+}
+
+type ApiResult = Result<HttpResponse, ApiError>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OwnerQuery {
+    owner_id: Option<String>,
+}
+
+/// A page of the owner's contacts: `limit` of them, at most and by default
+/// the configured page size, after skipping `offset`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    owner_id: Option<String>,
+    limit: Option<i32>,
+    offset: Option<i32>,
+}
+
+/// A contact as the REST API reads and writes it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ContactBody {
+    #[serde(default)]
+    id: String,
+    /// Ignored on writes, the owner is the caller or `ownerId`.
+    #[serde(default)]
+    owner_id: String,
+    first_name: String,
+    last_name: String,
+    #[serde(default)]
+    emails: Vec<String>,
+    #[serde(default)]
+    phones: Vec<String>,
+    #[serde(default)]
+    addresses: Vec<AddressBody>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct AddressBody {
+    #[serde(default)]
+    street: String,
+    #[serde(default)]
+    locality: String,
+    #[serde(default)]
+    region: String,
+    #[serde(default)]
+    postal_code: String,
+    #[serde(default)]
+    country: String,
+}
+
+impl From<Contact> for ContactBody {
+    fn from(c: Contact) -> Self {
+        ContactBody {
+            id: c.id,
+            owner_id: c.owner_id,
+            first_name: c.first_name,
+            last_name: c.last_name,
+            emails: c.emails,
+            phones: c.phones,
+            addresses: c
+                .addresses
+                .into_iter()
+                .map(|a| AddressBody {
+                    street: a.street,
+                    locality: a.locality,
+                    region: a.region,
+                    postal_code: a.postal_code,
+                    country: a.country,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ContactBody {
+    /// The contact to store, held to the same limits as the GraphQL input.
+    fn into_contact(self) -> Result<Contact, ApiError> {
+        let invalid = |m: String| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, m);
+        let id = self.id.trim().to_owned();
+        let first_name = self.first_name.trim().to_owned();
+        let last_name = self.last_name.trim().to_owned();
+        for (field, value, max) in &[
+            ("id", &id, MAX_ID_LEN),
+            ("firstName", &first_name, MAX_NAME_LEN),
+            ("lastName", &last_name, MAX_NAME_LEN),
+        ] {
+            let len = value.chars().count();
+            if len == 0 || len > *max {
+                return Err(invalid(format!(
+                    "{} must be 1 to {} characters",
+                    field, max
+                )));
+            }
+        }
+        Ok(Contact {
+            id,
+            owner_id: String::new(),
+            first_name,
+            last_name,
+            emails: self.emails,
+            phones: self.phones,
+            addresses: self
+                .addresses
+                .into_iter()
+                .map(|a| Address {
+                    street: a.street,
+                    locality: a.locality,
+                    region: a.region,
+                    postal_code: a.postal_code,
+                    country: a.country,
+                })
+                .collect(),
+            avatar: None,
+        })
+    }
+}
+
+/// The signed in caller, the owner whose contacts they act on and the
+/// repository partition of their tenant.
+struct Caller {
+    user: CurrentUser,
+    owner_id: String,
+    repo: Storage<InstrumentedRepository<FileRepository<'static>>>,
+}
+
+fn caller(
+    req: &HttpRequest,
+    repo: &FileRepository<'static>,
+    mode: StorageMode,
+    owner_id: Option<String>,
+    role: Role,
+) -> Result<Caller, ApiError> {
+    let user = req
+        .extensions()
+        .get::<CurrentUser>()
+        .cloned()
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Unauthorized"))?;
+    if user.role < role {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("Forbidden, requires role {}", role),
+        ));
+    }
+    let tenant = tenant::resolve(req.headers(), Some(&user))
+        .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?;
+    let owner_id = match owner_id {
+        Some(owner_id) if owner_id != user.id && user.role < Role::Admin => {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "Forbidden, only admins can access other owners' contacts",
+            ))
+        }
+        Some(owner_id) => owner_id,
+        None => user.id.clone(),
+    };
+    let files = match tenant {
+        Some(tenant) => repo.for_tenant(&tenant.0),
+        None => repo.clone(),
+    };
+    Ok(Caller {
+        user,
+        owner_id,
+        repo: Storage::new(InstrumentedRepository::new("file", files), mode),
+    })
+}
+
+/// Runs the usecases' work on the blocking thread pool, off the worker.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    match on_blocking_pool(move || async move { work() }).await {
+        Ok(result) => result,
+        Err(e) => Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            e.to_string(),
+        )),
+    }
+}
+
+fn invalidate(cache: &Option<ResponseCache>) {
+    if let Some(cache) = cache {
+        cache.invalidate("Contact");
+    }
+}
+
+async fn list(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    limits: Option<web::Data<LiveLimits>>,
+    query: web::Query<ListQuery>,
+    req: HttpRequest,
+) -> ApiResult {
+    let query = query.into_inner();
+    let max = limits.map_or(DEFAULT_PAGE_SIZE as usize, |l| l.get().max_page_size);
+    let page = Page::within(max, "limit_range", query.limit, query.offset)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let caller = caller(&req, &repo, **mode, query.owner_id, Role::Viewer)?;
+    let user = caller.user;
+    let contacts = usecases::stream_contacts(&caller.owner_id, &caller.repo)
+        .skip(page.offset)
+        .take(page.size)
+        .map(move |contact| contact.map(|c| ContactBody::from(c.masked_for(&user))));
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(json_array(contacts)))
+}
+
+/// The owner's contacts as newline-delimited JSON, by id. The body is
+/// sent chunked, each contact read from its file as the client takes the
+/// one before, so an export of any size runs in constant memory. That is
+/// why it isn't held to the page size: it is the route for taking a whole
+/// address book, which a page at a time couldn't do any cheaper.
+async fn export_ndjson(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+) -> ApiResult {
+    let caller = caller(
+        &req,
+        &repo,
+        **mode,
+        query.into_inner().owner_id,
+        Role::Viewer,
+    )?;
+    let user = caller.user;
+    let contacts = usecases::stream_contacts(&caller.owner_id, &caller.repo)
+        .map(move |contact| contact.map(|c| ContactBody::from(c.masked_for(&user))));
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(json_lines(contacts)))
+}
+
+/// Ends a streamed body early, as the status is already sent.
+fn failed_part_way(e: Box<dyn Error>) -> actix_web::Error {
+    warn!("streaming failed part way: {}", e);
+    actix_web::error::ErrorInternalServerError(e.to_string())
+}
+
+/// A JSON array written an element at a time as `items` yields them.
+fn json_array<T: Serialize>(
+    items: impl Stream<Item = Result<T, Box<dyn Error>>> + 'static,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> + Unpin {
+    let mut first = true;
+    let elements = items.map(move |item| {
+        let item = item.map_err(failed_part_way)?;
+        let mut data = if first { vec![] } else { vec![b','] };
+        first = false;
+        serde_json::to_writer(&mut data, &item)?;
+        Ok(Bytes::from(data))
+    });
+    Box::pin(
+        stream::once(future::ready(Ok(Bytes::from_static(b"["))))
+            .chain(elements)
+            .chain(stream::once(future::ready(Ok(Bytes::from_static(b"]"))))),
+    )
+}
+
+/// A JSON document a line, written as `items` yields them.
+fn json_lines<T: Serialize>(
+    items: impl Stream<Item = Result<T, Box<dyn Error>>> + 'static,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> + Unpin {
+    Box::pin(items.map(|item| {
+        let mut line = serde_json::to_vec(&item.map_err(failed_part_way)?)?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    }))
+}
+
+async fn get(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    id: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+) -> ApiResult {
+    let caller = caller(
+        &req,
+        &repo,
+        **mode,
+        query.into_inner().owner_id,
+        Role::Viewer,
+    )?;
+    let user = caller.user.clone();
+    let id = id.into_inner();
+    let contact = blocking(move || {
+        usecases::get(&caller.owner_id, &id, &caller.repo).map_err(ApiError::from_usecase)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(ContactBody::from(contact.masked_for(&user))))
+}
+
+/// Creates a contact, refusing ids that are taken.
+async fn create(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    cache: web::Data<Option<ResponseCache>>,
+    query: web::Query<OwnerQuery>,
+    body: web::Json<ContactBody>,
+    req: HttpRequest,
+) -> ApiResult {
+    let caller = caller(
+        &req,
+        &repo,
+        **mode,
+        query.into_inner().owner_id,
+        Role::Editor,
+    )?;
+    let contact = body.into_inner().into_contact()?;
+    let user = caller.user.clone();
+    let contact = blocking(move || {
+        caller
+            .repo
+            .with_tx(|tx| {
+                if usecases::get(&caller.owner_id, &contact.id, tx).is_ok() {
+                    return Err(Box::new(ApiError::new(
+                        StatusCode::CONFLICT,
+                        format!("contact {:?} already exists", contact.id),
+                    )) as Box<dyn Error>);
+                }
+                usecases::create(&caller.user.id, &caller.owner_id, contact, tx)
+            })
+            .map_err(ApiError::from_usecase)
+    })
+    .await?;
+    invalidate(&cache);
+    Ok(HttpResponse::Created()
+        .header(
+            "Location",
+            format!("/api/v1/contacts/{}", contact.id).as_str(),
+        )
+        .json(ContactBody::from(contact.masked_for(&user))))
+}
+
+/// Creates or replaces the contact at the path's id.
+async fn put(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    cache: web::Data<Option<ResponseCache>>,
+    id: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    body: web::Json<ContactBody>,
+    req: HttpRequest,
+) -> ApiResult {
+    let caller = caller(
+        &req,
+        &repo,
+        **mode,
+        query.into_inner().owner_id,
+        Role::Editor,
+    )?;
+    let mut body = body.into_inner();
+    if !body.id.is_empty() && body.id.trim() != id.as_str() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "id doesn't match the path",
+        ));
+    }
+    body.id = id.into_inner();
+    let contact = body.into_contact()?;
+    let user = caller.user.clone();
+    let (existed, contact) = blocking(move || {
+        caller
+            .repo
+            .with_tx(|tx| {
+                let existed = usecases::get(&caller.owner_id, &contact.id, tx).is_ok();
+                let contact = usecases::create(&caller.user.id, &caller.owner_id, contact, tx)?;
+                Ok((existed, contact))
+            })
+            .map_err(ApiError::from_usecase)
+    })
+    .await?;
+    invalidate(&cache);
+    let mut resp = if existed {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::Created()
+    };
+    Ok(resp.json(ContactBody::from(contact.masked_for(&user))))
+}
+
+async fn delete(
+    repo: web::Data<FileRepository<'static>>,
+    mode: web::Data<StorageMode>,
+    cache: web::Data<Option<ResponseCache>>,
+    id: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+) -> ApiResult {
+    let caller = caller(
+        &req,
+        &repo,
+        **mode,
+        query.into_inner().owner_id,
+        Role::Admin,
+    )?;
+    let id = id.into_inner();
+    blocking(move || {
+        caller
+            .repo
+            .with_tx(|tx| usecases::delete(&caller.user.id, &caller.owner_id, &id, tx))
+            .map_err(ApiError::from_usecase)
+    })
+    .await?;
+    invalidate(&cache);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(openapi())
+}
+
+/// The OpenAPI 3.0 document of the REST routes.
+pub fn openapi() -> serde_json::Value {
+    let string = json!({ "type": "string" });
+    let strings = json!({ "type": "array", "items": { "type": "string" } });
+    let id = json!({
+        "name": "id", "in": "path", "required": true, "schema": { "type": "string" }
+    });
+    let owner = json!({
+        "name": "ownerId", "in": "query", "required": false,
+        "description": "owner whose contacts to use, admins only; defaults to the caller",
+        "schema": { "type": "string" }
+    });
+    let limit = json!({
+        "name": "limit", "in": "query", "required": false,
+        "description": "contacts to return, at most and by default the server's page size",
+        "schema": { "type": "integer", "minimum": 1 }
+    });
+    let offset = json!({
+        "name": "offset", "in": "query", "required": false,
+        "description": "contacts to skip",
+        "schema": { "type": "integer", "minimum": 0 }
+    });
+    let contact = json!({ "$ref": "#/components/schemas/Contact" });
+    let body = json!({
+        "required": true,
+        "content": { "application/json": { "schema": contact } }
+    });
+    let returns = |description: &str| {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": contact } }
+        })
+    };
+    let error = |description: &str| {
+        json!({
+            "description": description,
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/Error" } }
+            }
+        })
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Contacts REST API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "security": [{ "bearerAuth": [] }, { "apiKey": [] }],
+        "paths": {
+            "/api/v1/contacts": {
+                "get": {
+                    "operationId": "listContacts",
+                    "summary": "A page of the owner's contacts, by id",
+                    "parameters": [owner, limit, offset],
+                    "responses": {
+                        "200": {
+                            "description": "the contacts",
+                            "content": { "application/json": {
+                                "schema": { "type": "array", "items": contact }
+                            } }
+                        },
+                        "400": error("limit or offset out of range"),
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                    }
+                },
+                "post": {
+                    "operationId": "createContact",
+                    "summary": "Creates a contact; needs the editor role",
+                    "parameters": [owner],
+                    "requestBody": body,
+                    "responses": {
+                        "201": returns("the created contact"),
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                        "409": error("the id is taken, or a concurrent write won"),
+                        "422": error("invalid contact"),
+                    }
+                }
+            },
+            "/api/v1/contacts/{id}": {
+                "get": {
+                    "operationId": "getContact",
+                    "summary": "A contact",
+                    "parameters": [id, owner],
+                    "responses": {
+                        "200": returns("the contact"),
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                        "404": error("no such contact"),
+                    }
+                },
+                "put": {
+                    "operationId": "putContact",
+                    "summary": "Creates or replaces a contact; needs the editor role",
+                    "parameters": [id, owner],
+                    "requestBody": body,
+                    "responses": {
+                        "200": returns("the replaced contact"),
+                        "201": returns("the created contact"),
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                        "409": error("a concurrent write won"),
+                        "422": error("invalid contact"),
+                    }
+                },
+                "delete": {
+                    "operationId": "deleteContact",
+                    "summary": "Deletes a contact; needs the admin role",
+                    "parameters": [id, owner],
+                    "responses": {
+                        "204": { "description": "deleted" },
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                        "404": error("no such contact"),
+                        "409": error("a concurrent write won"),
+                    }
+                }
+            },
+            "/export.ndjson": {
+                "get": {
+                    "operationId": "exportContacts",
+                    "summary": "The owner's contacts, by id, as newline-delimited JSON",
+                    "parameters": [owner],
+                    "responses": {
+                        "200": {
+                            "description": "a contact a line",
+                            "content": { "application/x-ndjson": { "schema": contact } }
+                        },
+                        "401": error("not signed in"),
+                        "403": error("not allowed"),
+                    }
+                }
+            }
+        },
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+            "schemas": {
+                "Contact": {
+                    "type": "object",
+                    "required": ["firstName", "lastName"],
+                    "properties": {
+                        "id": {
+                            "type": "string", "minLength": 1, "maxLength": MAX_ID_LEN,
+                            "description": "required when creating with POST"
+                        },
+                        "ownerId": { "type": "string", "readOnly": true },
+                        "firstName": { "type": "string", "minLength": 1, "maxLength": MAX_NAME_LEN },
+                        "lastName": { "type": "string", "minLength": 1, "maxLength": MAX_NAME_LEN },
+                        "emails": strings,
+                        "phones": strings,
+                        "addresses": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Address" }
+                        },
+                    }
+                },
+                "Address": {
+                    "type": "object",
+                    "properties": {
+                        "street": string,
+                        "locality": string,
+                        "region": string,
+                        "postalCode": string,
+                        "country": string,
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "error": {
+                            "type": "object",
+                            "properties": { "code": string, "message": string }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Repository;
+    use crate::settings::Config;
+    use crate::testing::{caller as signed_in, ContactFixture};
+    use actix_service::Service;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use serde_json::Value;
+
+    const PAGE_SIZE: usize = 2;
+
+    fn scratch() -> (tempfile::TempDir, FileRepository<'static>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        let repo = FileRepository::new(Box::leak(path.into_boxed_str()));
+        for contact in [
+            ContactFixture::new("ada").email("ada@example.com").build(),
+            ContactFixture::new("grace").name("Grace", "Hopper").build(),
+            ContactFixture::new("alan").owner("u2").build(),
+        ] {
+            repo.set(contact).unwrap();
+        }
+        (dir, repo)
+    }
+
+    /// Serves `req` through the routes to `user`, answering its status and
+    /// JSON body, `Null` if it has none.
+    async fn call(
+        repo: &FileRepository<'static>,
+        user: Option<CurrentUser>,
+        req: TestRequest,
+    ) -> (StatusCode, Value) {
+        let limits = Limits {
+            max_page_size: PAGE_SIZE,
+            ..Limits::load(&Config::default()).unwrap()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .data(repo.clone())
+                .data(StorageMode::State)
+                .data(None::<ResponseCache>)
+                .data(LiveLimits::new(limits))
+                .wrap_fn(move |req, srv| {
+                    if let Some(user) = &user {
+                        req.extensions_mut().insert(user.clone());
+                    }
+                    srv.call(req)
+                })
+                .configure(|cfg| configure(cfg, &limits)),
+        )
+        .await;
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        if body.is_empty() {
+            return (status, Value::Null);
+        }
+        let json = match serde_json::from_slice(&body) {
+            Ok(json) => json,
+            // Newline-delimited JSON, as an array.
+            Err(_) => std::str::from_utf8(&body)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .collect(),
+        };
+        (status, json)
+    }
+
+    fn body(id: &str) -> Value {
+        json!({ "id": id, "firstName": "Edsger", "lastName": "Dijkstra" })
+    }
+
+    fn ids(list: &Value) -> Vec<&str> {
+        list.as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn each_verb_needs_its_role() {
+        let requests = || {
+            vec![
+                ("list", TestRequest::get().uri("/api/v1/contacts")),
+                ("get", TestRequest::get().uri("/api/v1/contacts/ada")),
+                ("export", TestRequest::get().uri("/export.ndjson")),
+                (
+                    "create",
+                    TestRequest::post()
+                        .uri("/api/v1/contacts")
+                        .set_json(&body("edsger")),
+                ),
+                (
+                    "put",
+                    TestRequest::put()
+                        .uri("/api/v1/contacts/grace")
+                        .set_json(&body("grace")),
+                ),
+                ("delete", TestRequest::delete().uri("/api/v1/contacts/ada")),
+            ]
+        };
+        let ok = |verb: &str| match verb {
+            "create" => StatusCode::CREATED,
+            "delete" => StatusCode::NO_CONTENT,
+            _ => StatusCode::OK,
+        };
+        for (role, allowed) in &[
+            (Role::Viewer, vec!["list", "get", "export"]),
+            (Role::Editor, vec!["list", "get", "export", "create", "put"]),
+            (
+                Role::Admin,
+                vec!["list", "get", "export", "create", "put", "delete"],
+            ),
+        ] {
+            let (_dir, repo) = scratch();
+            for (verb, req) in requests() {
+                let (status, resp) = call(&repo, Some(signed_in("u1", *role)), req).await;
+                if allowed.contains(&verb) {
+                    assert_eq!(status, ok(verb), "{} as {}: {}", verb, role, resp);
+                } else {
+                    assert_eq!(status, StatusCode::FORBIDDEN, "{} as {}", verb, role);
+                    assert_eq!(resp["error"]["code"], "FORBIDDEN");
+                }
+            }
+            let stored: Result<Contact, _> = repo.get("u1/edsger");
+            assert_eq!(stored.is_ok(), *role >= Role::Editor);
+            let grace: Contact = repo.get("u1/grace").unwrap();
+            assert_eq!(grace.last_name == "Dijkstra", *role >= Role::Editor);
+            let ada: Result<Contact, _> = repo.get("u1/ada");
+            assert_eq!(ada.is_ok(), *role < Role::Admin);
+        }
+
+        let (_dir, repo) = scratch();
+        for (verb, req) in requests() {
+            let (status, _) = call(&repo, None, req).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", verb);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_admins_pass_an_owner_id() {
+        let (_dir, repo) = scratch();
+        for role in &[Role::Viewer, Role::Editor] {
+            for req in [
+                TestRequest::get().uri("/api/v1/contacts?ownerId=u2"),
+                TestRequest::get().uri("/api/v1/contacts/alan?ownerId=u2"),
+                TestRequest::put()
+                    .uri("/api/v1/contacts/alan?ownerId=u2")
+                    .set_json(&body("alan")),
+            ] {
+                let (status, _) = call(&repo, Some(signed_in("u1", *role)), req).await;
+                assert_eq!(status, StatusCode::FORBIDDEN);
+            }
+        }
+        // Their own id is no other owner's.
+        let (status, list) = call(
+            &repo,
+            Some(signed_in("u1", Role::Viewer)),
+            TestRequest::get().uri("/api/v1/contacts?ownerId=u1"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&list), vec!["ada", "grace"]);
+
+        let admin = || Some(signed_in("u1", Role::Admin));
+        let (status, list) = call(
+            &repo,
+            admin(),
+            TestRequest::get().uri("/api/v1/contacts?ownerId=u2"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&list), vec!["alan"]);
+        assert_eq!(list[0]["ownerId"], "u2");
+        let (status, _) = call(
+            &repo,
+            admin(),
+            TestRequest::delete().uri("/api/v1/contacts/alan?ownerId=u2"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(Repository::<Contact>::get(&repo, "u2/alan").is_err());
+    }
+
+    #[tokio::test]
+    async fn contact_details_need_the_pii_scope() {
+        let (_dir, repo) = scratch();
+        let mut without_pii = signed_in("u1", Role::Editor);
+        without_pii.scopes.clear();
+        let get = || TestRequest::get().uri("/api/v1/contacts/ada");
+
+        let (_, ada) = call(&repo, Some(signed_in("u1", Role::Viewer)), get()).await;
+        assert_eq!(ada["emails"], json!(["ada@example.com"]));
+        let (_, ada) = call(&repo, Some(without_pii.clone()), get()).await;
+        assert_eq!(ada["firstName"], "Ada");
+        assert_eq!(ada["emails"], json!([]));
+        let (_, list) = call(
+            &repo,
+            Some(without_pii.clone()),
+            TestRequest::get().uri("/api/v1/contacts"),
+        )
+        .await;
+        assert_eq!(list[0]["emails"], json!([]));
+        let (_, export) = call(
+            &repo,
+            Some(without_pii.clone()),
+            TestRequest::get().uri("/export.ndjson"),
+        )
+        .await;
+        assert_eq!(export[0]["emails"], json!([]));
+
+        // Writes answer masked too, but store what was sent.
+        let mut edsger = body("edsger");
+        edsger["emails"] = json!(["edsger@example.com"]);
+        let (status, created) = call(
+            &repo,
+            Some(without_pii),
+            TestRequest::post()
+                .uri("/api/v1/contacts")
+                .set_json(&edsger),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["emails"], json!([]));
+        let stored: Contact = repo.get("u1/edsger").unwrap();
+        assert_eq!(stored.emails, vec!["edsger@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn creating_a_taken_id_is_a_conflict() {
+        let (_dir, repo) = scratch();
+        let editor = || Some(signed_in("u1", Role::Editor));
+        let create = |id: &str| {
+            TestRequest::post()
+                .uri("/api/v1/contacts")
+                .set_json(&body(id))
+        };
+
+        let (status, resp) = call(&repo, editor(), create("ada")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(resp["error"]["code"], "CONFLICT");
+        let ada: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(ada.first_name, "Ada");
+
+        let (status, _) = call(&repo, editor(), create("edsger")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = call(&repo, editor(), create("edsger")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        // Another owner's id space is their own.
+        let (status, _) = call(&repo, editor(), create("alan")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn lists_are_paged_up_to_the_page_size() {
+        let (_dir, repo) = scratch();
+        repo.set(ContactFixture::new("edsger").build()).unwrap();
+        let list = |query: &str| TestRequest::get().uri(&format!("/api/v1/contacts{}", query));
+        let viewer = || Some(signed_in("u1", Role::Viewer));
+
+        let (status, page) = call(&repo, viewer(), list("")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&page), vec!["ada", "edsger"]);
+        let (_, page) = call(&repo, viewer(), list("?limit=2&offset=2")).await;
+        assert_eq!(ids(&page), vec!["grace"]);
+        let (_, page) = call(&repo, viewer(), list("?limit=1&offset=1")).await;
+        assert_eq!(ids(&page), vec!["edsger"]);
+
+        for query in &["?limit=3", "?limit=0", "?offset=-1"] {
+            let (status, resp) = call(&repo, viewer(), list(query)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+            assert_eq!(resp["error"]["code"], "BAD_REQUEST");
+        }
+        let (_, resp) = call(&repo, viewer(), list("?limit=3")).await;
+        assert_eq!(
+            resp["error"]["message"],
+            format!("limit must be between 1 and {}, got 3", PAGE_SIZE)
+        );
+        // The export isn't paged.
+        let (_, export) = call(&repo, viewer(), TestRequest::get().uri("/export.ndjson")).await;
+        assert_eq!(ids(&export), vec!["ada", "edsger", "grace"]);
+    }
+
+    #[tokio::test]
+    async fn openapi_documents_every_route() {
+        let (_dir, repo) = scratch();
+        let (status, doc) = call(&repo, None, TestRequest::get().uri("/api/openapi.json")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(doc["openapi"], "3.0.3");
+
+        let mut operations = vec![];
+        for (path, methods) in doc["paths"].as_object().unwrap() {
+            for (method, operation) in methods.as_object().unwrap() {
+                operations.push((path.clone(), method.clone(), operation.clone()));
+            }
+        }
+        let mut routes: Vec<(&str, &str)> = operations
+            .iter()
+            .map(|(path, method, _)| (path.as_str(), method.as_str()))
+            .collect();
+        routes.sort();
+        assert_eq!(
+            routes,
+            vec![
+                ("/api/v1/contacts", "get"),
+                ("/api/v1/contacts", "post"),
+                ("/api/v1/contacts/{id}", "delete"),
+                ("/api/v1/contacts/{id}", "get"),
+                ("/api/v1/contacts/{id}", "put"),
+                ("/export.ndjson", "get"),
+            ]
+        );
+
+        // Each is served, answering one of its documented responses.
+        for (path, method, operation) in operations {
+            let (_dir, repo) = scratch();
+            let uri = path.replace("{id}", "ada");
+            let req = TestRequest::with_uri(&uri).method(method.to_uppercase().parse().unwrap());
+            let req = match method.as_str() {
+                "post" => req.set_json(&body("edsger")),
+                "put" => req.set_json(&body("ada")),
+                _ => req,
+            };
+            let admin = signed_in("u1", Role::Admin);
+            let (status, _) = call(&repo, Some(admin), req).await;
+            let documented = &operation["responses"][status.as_str()];
+            assert!(documented.is_object(), "{} {}: {}", method, path, status);
+        }
+    }
+}
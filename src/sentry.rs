@@ -0,0 +1,223 @@
+//! Error reporting to Sentry, built with the `sentry` feature and enabled by
+//! `SENTRY_DSN`. Resolver panics and storage failures are reported, not the
+//! errors callers cause such as bad input or missing entities. Events carry
+//! the request id, the operation name and the operation's variables with
+//! every string replaced unless it's an id, so no personal data leaves.
+//! Only the store endpoint over plain HTTP is spoken, e.g. to a relay.
+
+use crate::logging;
+use crate::metrics;
+use async_graphql::extensions::Extension;
+use async_graphql::parser::query::Document;
+use async_graphql::Variables;
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Events captured beyond this many before a send are dropped.
+const MAX_QUEUED_EVENTS: usize = 100;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
+tokio::task_local! {
+    static OPERATION: RefCell<Operation>;
+}
+
+#[derive(Default)]
+struct Operation {
+    name: Option<String>,
+    variables: Option<serde_json::Value>,
+}
+
+/// Runs a request's handler with room for the `OperationContext` extension
+/// to note what the request is running.
+pub async fn scope<F: Future>(f: F) -> F::Output {
+    OPERATION.scope(RefCell::default(), f).await
+}
+
+/// Queues an error event for the reporter, tagged with the current request.
+pub fn capture(message: impl Into<String>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let (operation, variables) = OPERATION
+        .try_with(|o| {
+            let o = o.borrow();
+            (o.name.clone(), o.variables.clone())
+        })
+        .unwrap_or_default();
+    let event = serde_json::json!({
+        "event_id": crate::crypto::to_hex(&rand::random::<[u8; 16]>()),
+        "timestamp": crate::auth::now(),
+        "level": "error",
+        "platform": "other",
+        "logger": "backend",
+        "release": concat!("backend@", env!("CARGO_PKG_VERSION")),
+        "message": { "formatted": message.into() },
+        "tags": {
+            "request_id": logging::request_id(),
+            "operation": operation,
+        },
+        "extra": { "variables": variables },
+    });
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() < MAX_QUEUED_EVENTS {
+        queue.push(event);
+    }
+}
+
+/// The variables with every string that isn't an id replaced.
+fn sanitize(key: &str, value: &serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::String(_) if key == "id" || key.ends_with("Id") => value.clone(),
+        Value::String(_) => Value::String("[Filtered]".to_owned()),
+        Value::Array(items) => Value::Array(items.iter().map(|v| sanitize(key, v)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.clone(), sanitize(k, v)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// Notes the operation name and sanitized variables of the request being
+/// run, for the events it may raise.
+#[derive(Default)]
+pub struct OperationContext;
+
+impl Extension for OperationContext {
+    fn parse_start(&mut self, _query_source: &str, variables: &Variables) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let variables = serde_json::to_value(variables)
+            .map(|v| sanitize("", &v))
+            .ok();
+        let _ = OPERATION.try_with(|o| o.borrow_mut().variables = variables);
+    }
+
+    fn parse_end(&mut self, document: &Document) {
+        if let Some((_, Some(name))) = metrics::first_operation(document) {
+            let _ = OPERATION.try_with(|o| o.borrow_mut().name = Some(name.to_owned()));
+        }
+    }
+}
+
+#[cfg(feature = "sentry")]
+pub use reporter::spawn_reporter;
+
+#[cfg(feature = "sentry")]
+mod reporter {
+    use super::{ENABLED, QUEUE};
+    use crate::shutdown::Shutdown;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    const SEND_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Where and as whom events are sent, from a DSN of the form
+    /// `http://<key>@<host>[:port]/<project>`.
+    struct Dsn {
+        store_url: String,
+        key: String,
+    }
+
+    impl Dsn {
+        fn parse(dsn: &str) -> Result<Dsn, String> {
+            let invalid = || format!("SENTRY_DSN is not a valid DSN: {:?}", dsn);
+            let rest = match dsn.trim() {
+                d if d.starts_with("http://") => &d["http://".len()..],
+                d if d.starts_with("https://") => {
+                    return Err("SENTRY_DSN over https needs a TLS-enabled build".to_owned())
+                }
+                _ => return Err(invalid()),
+            };
+            let at = rest.find('@').ok_or_else(invalid)?;
+            let (key, location) = (&rest[..at], &rest[at + 1..]);
+            let key = key.split(':').next().unwrap_or_default();
+            let slash = location.rfind('/').ok_or_else(invalid)?;
+            let (host, project) = (&location[..slash], &location[slash + 1..]);
+            if key.is_empty() || host.is_empty() || project.is_empty() {
+                return Err(invalid());
+            }
+            Ok(Dsn {
+                store_url: format!("http://{}/api/{}/store/", host, project),
+                key: key.to_owned(),
+            })
+        }
+    }
+
+    /// Starts the reporter thread when `SENTRY_DSN` is set. On shutdown the
+    /// events still queued are sent.
+    pub fn spawn_reporter(shutdown: Shutdown) -> Result<Option<thread::JoinHandle<()>>, String> {
+        let dsn = match std::env::var("SENTRY_DSN") {
+            Ok(dsn) => Dsn::parse(&dsn)?,
+            Err(_) => return Ok(None),
+        };
+        let environment = std::env::var("SENTRY_ENVIRONMENT").ok();
+        ENABLED.store(true, Ordering::SeqCst);
+        Ok(Some(thread::spawn(move || {
+            let mut sys = actix_rt::System::new("sentry");
+            sys.block_on(async move {
+                let client = awc::Client::build()
+                    .timeout(Duration::from_secs(10))
+                    .finish();
+                loop {
+                    let stopping = shutdown.is_triggered();
+                    let events = std::mem::take(&mut *QUEUE.lock().unwrap());
+                    for mut event in events {
+                        if let Some(environment) = &environment {
+                            event["environment"] = environment.as_str().into();
+                        }
+                        if let Err(e) = send(&client, &dsn, &event).await {
+                            warn!("sending error event failed: {}", e);
+                        }
+                    }
+                    if stopping {
+                        return;
+                    }
+                    shutdown.delay(SEND_INTERVAL).await;
+                }
+            })
+        })))
+    }
+
+    async fn send(
+        client: &awc::Client,
+        dsn: &Dsn,
+        event: &serde_json::Value,
+    ) -> Result<(), String> {
+        let auth = format!(
+            "Sentry sentry_version=7, sentry_client=backend/{}, sentry_key={}",
+            env!("CARGO_PKG_VERSION"),
+            dsn.key
+        );
+        let response = client
+            .post(&dsn.store_url)
+            .header("X-Sentry-Auth", auth)
+            .send_json(event)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Sentry answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Without the `sentry` feature there's nothing to report to.
+#[cfg(not(feature = "sentry"))]
+pub fn spawn_reporter(
+    _shutdown: crate::shutdown::Shutdown,
+) -> Result<Option<std::thread::JoinHandle<()>>, String> {
+    if std::env::var("SENTRY_DSN").is_ok() {
+        warn!("SENTRY_DSN is ignored, the server was built without the sentry feature");
+    }
+    Ok(None)
+}
@@ -0,0 +1,258 @@
+use crate::auth::{now, CurrentUser};
+use crate::crypto;
+use crate::models::{Session, User};
+use crate::repo::*;
+use actix_web::cookie::{Cookie, SameSite};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+pub const COOKIE_NAME: &str = "session";
+
+/// How long a session stays valid after login.
+pub const SESSION_TTL_SECS: u64 = 24 * 3600;
+
+/// Server-side sessions. The cookie only carries the session id and an
+/// HMAC over it, so a cookie can't be forged and a session can be revoked by
+/// deleting its record.
+#[derive(Clone)]
+pub struct SessionStore {
+    secret: Vec<u8>,
+    secure: bool,
+    repo: FileRepository<'static>,
+}
+
+impl SessionStore {
+    /// Enabled when `SESSION_SECRET` is set. `secure` marks the cookie
+    /// HTTPS-only.
+    pub fn from_env(repo: FileRepository<'static>, secure: bool) -> Option<SessionStore> {
+        let secret = std::env::var("SESSION_SECRET").ok()?;
+        Some(SessionStore {
+            secret: secret.into_bytes(),
+            secure,
+            repo,
+        })
+    }
+
+    /// Starts a session for `user` with the `scopes` their login granted and
+    /// returns the cookie value for it.
+    pub fn create(
+        &self,
+        user: &User,
+        scopes: Vec<String>,
+        tenant: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let id: [u8; 24] = rand::random();
+        let session = Session {
+            id: base64::encode_config(id, base64::URL_SAFE_NO_PAD),
+            user_id: user.id.clone(),
+            role: user.role,
+            expires_at: now() + SESSION_TTL_SECS,
+            tenant: tenant.map(|t| t.to_owned()),
+            scopes,
+        };
+        let session = self.repo.set(session)?;
+        Ok(self.sign(&session.id))
+    }
+
+    /// Resolves a cookie value to its session, if the signature holds and the
+    /// session hasn't expired or been ended.
+    pub fn load(&self, value: &str) -> Option<Session> {
+        let mut parts = value.splitn(2, '.');
+        let id = parts.next()?;
+        let tag = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        if !crypto::constant_time_eq(&crypto::hmac_sha256(&self.secret, id.as_bytes()), &tag) {
+            return None;
+        }
+        let session: Session = self.repo.get(id).ok()?;
+        if session.expires_at <= now() {
+            let _ = Repository::<Session>::delete(&self.repo, id);
+            return None;
+        }
+        Some(session)
+    }
+
+    pub fn destroy(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        Repository::<Session>::delete(&self.repo, id)
+    }
+
+    pub fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(COOKIE_NAME, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(SESSION_TTL_SECS as i64)
+            .finish()
+    }
+
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        Cookie::build(COOKIE_NAME, "")
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(0)
+            .finish()
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let tag = crypto::hmac_sha256(&self.secret, id.as_bytes());
+        format!(
+            "{}.{}",
+            id,
+            base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl Session {
+    pub fn current_user(&self) -> CurrentUser {
+        CurrentUser {
+            id: self.user_id.clone(),
+            role: self.role,
+            scopes: self.scopes.clone(),
+            tenant: self.tenant.clone(),
+        }
+    }
+}
+
+/// The session the request came in with, stored in the request extensions
+/// by the authentication middleware.
+#[derive(Clone)]
+pub struct ActiveSession(pub String);
+
+/// Shared slot resolvers use to hand the handler a cookie to set on the
+/// response.
+#[derive(Clone, Default)]
+pub struct SessionCookie(Arc<Mutex<Option<Cookie<'static>>>>);
+
+impl SessionCookie {
+    pub fn set(&self, cookie: Cookie<'static>) {
+        *self.0.lock().unwrap() = Some(cookie);
+    }
+
+    pub fn take(&self) -> Option<Cookie<'static>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Role, PII_SCOPE};
+
+    fn scratch() -> (tempfile::TempDir, SessionStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        let store = SessionStore {
+            secret: b"secret".to_vec(),
+            secure: true,
+            repo: FileRepository::new(Box::leak(path.into_boxed_str())),
+        };
+        (dir, store)
+    }
+
+    fn ada() -> User {
+        User {
+            id: "u1".to_owned(),
+            email: "ada@example.com".to_owned(),
+            password_hash: String::new(),
+            role: Role::Editor,
+        }
+    }
+
+    fn id_of(value: &str) -> &str {
+        value.split('.').next().unwrap()
+    }
+
+    #[test]
+    fn sessions_restore_the_caller_their_login_granted() {
+        let (_dir, store) = scratch();
+        let value = store
+            .create(&ada(), vec![PII_SCOPE.to_owned()], Some("t1"))
+            .unwrap();
+        let session = store.load(&value).unwrap();
+        assert_eq!(
+            session.current_user(),
+            CurrentUser {
+                id: "u1".to_owned(),
+                role: Role::Editor,
+                scopes: vec![PII_SCOPE.to_owned()],
+                tenant: Some("t1".to_owned()),
+            }
+        );
+
+        let value = store.create(&ada(), vec![], None).unwrap();
+        let user = store.load(&value).unwrap().current_user();
+        assert!(!user.has_scope(PII_SCOPE));
+        assert_eq!(user.tenant, None);
+    }
+
+    #[test]
+    fn sessions_stored_before_scopes_grant_none() {
+        let (dir, store) = scratch();
+        let value = store.sign("old");
+        std::fs::create_dir_all(dir.path().join("sessions")).unwrap();
+        std::fs::write(
+            dir.path().join("sessions/old.json"),
+            format!(
+                r#"{{"id":"old","user_id":"u1","role":"viewer","expires_at":{}}}"#,
+                now() + 60
+            ),
+        )
+        .unwrap();
+        let user = store.load(&value).unwrap().current_user();
+        assert_eq!(user.role, Role::Viewer);
+        assert!(user.scopes.is_empty());
+    }
+
+    #[test]
+    fn forged_and_tampered_cookies_are_refused() {
+        let (_dir, store) = scratch();
+        let value = store.create(&ada(), vec![], None).unwrap();
+        let (id, tag) = value.split_at(value.find('.').unwrap());
+        let other = SessionStore {
+            secret: b"another secret".to_vec(),
+            ..store.clone()
+        };
+
+        for forged in &[
+            format!("{}x{}", id, tag),
+            format!("{}.{}", id, &tag[2..]),
+            id.to_owned(),
+            other.sign(id),
+            String::new(),
+            "..".to_owned(),
+        ] {
+            assert!(store.load(forged).is_none(), "{:?}", forged);
+        }
+        // Signed but never started, e.g. after a restore.
+        assert!(store.load(&store.sign("unknown")).is_none());
+        assert!(store.load(&value).is_some());
+    }
+
+    #[test]
+    fn expired_sessions_are_deleted() {
+        let (_dir, store) = scratch();
+        let value = store.create(&ada(), vec![], None).unwrap();
+        let mut session: Session = store.repo.get(id_of(&value)).unwrap();
+        session.expires_at = now() - 1;
+        store.repo.set(session).unwrap();
+
+        assert!(store.load(&value).is_none());
+        assert!(Repository::<Session>::get(&store.repo, id_of(&value)).is_err());
+    }
+
+    #[test]
+    fn logging_out_destroys_the_session() {
+        let (_dir, store) = scratch();
+        let value = store.create(&ada(), vec![], None).unwrap();
+        let other = store.create(&ada(), vec![], None).unwrap();
+        store.destroy(id_of(&value)).unwrap();
+
+        assert!(store.load(&value).is_none());
+        assert!(Repository::<Session>::get(&store.repo, id_of(&value)).is_err());
+        assert!(store.load(&other).is_some());
+        assert!(store.destroy(id_of(&value)).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::repo::FileRepository;
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Runtime mode, read from `APP_ENV`. Anything other than `production`
+/// runs in dev mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Dev,
+    Production,
+}
+
+impl Mode {
+    pub fn from_env() -> Mode {
+        match std::env::var("APP_ENV") {
+            Ok(v) if v.eq_ignore_ascii_case("production") => Mode::Production,
+            _ => Mode::Dev,
+        }
+    }
+
+    /// Introspection and the IDE are only exposed in dev mode.
+    pub fn allows_introspection(self) -> bool {
+        self == Mode::Dev
+    }
+}
+
+/// Where the server listens and keeps its data. Read from the TOML file
+/// named by `CONFIG_FILE` (default `config.toml`, skipped when missing),
+/// then overridden by `HOST`, `PORT`, `REPOSITORY_PATH`, `BACKUP_PATH`,
+/// `BACKUP_INTERVAL_SECS`, `BACKUP_KEEP`, `GRAPHQL_IDE`, `PLAYGROUND`,
+/// `WORKERS`, `KEEP_ALIVE_SECS`, `CLIENT_TIMEOUT_MS` and `BACKLOG`.
+///
+/// The file also holds the settings a reload can change, each overridden
+/// by the environment variable of the same name in upper case: the log
+/// level, the `Limits` and the rate limits.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Directory the file repository stores entities under.
+    pub repository_path: String,
+    /// Directory backups are written to; defaults to `backups` under the
+    /// repository path.
+    pub backup_path: Option<String>,
+    /// Seconds between scheduled backups; none are made without it.
+    pub backup_interval_secs: Option<u64>,
+    /// Archives kept in the backup directory, older ones are deleted after
+    /// each scheduled backup.
+    pub backup_keep: usize,
+    /// The IDE served on `GET /`, with the SDL; defaults to the playground
+    /// in dev mode and none in production.
+    pub ide: Option<Ide>,
+    /// Kept from before `ide`, which wins over it: true for the playground,
+    /// false for none.
+    pub playground: Option<bool>,
+    /// Worker threads serving requests; one per CPU without it.
+    pub workers: Option<usize>,
+    /// Seconds an idle connection is kept open for its next request, 0 to
+    /// close connections after each response.
+    pub keep_alive_secs: u64,
+    /// Milliseconds a client has to send the request head before it's
+    /// answered with `408 Request Timeout`, 0 for no limit. actix-http 1.0
+    /// times out the first request of an HTTP/1 connection after the
+    /// keep-alive instead, so there it's `keep_alive_secs` that counts.
+    pub client_timeout_ms: u64,
+    /// Connections waiting to be accepted before new ones are refused.
+    pub backlog: i32,
+    /// A level, or a log filter like `info,repo=debug`.
+    pub log_level: Option<String>,
+    pub request_timeout_secs: Option<u64>,
+    pub max_body_bytes: Option<u64>,
+    pub max_upload_bytes: Option<u64>,
+    pub max_uploads: Option<u64>,
+    pub max_variables_bytes: Option<u64>,
+    pub max_batch_size: Option<u64>,
+    pub max_page_size: Option<u64>,
+    /// Requests a minute each client may make; unlimited without it.
+    pub rate_limit: Option<u32>,
+    /// Requests a client may make at once, defaults to `rate_limit`.
+    pub rate_limit_burst: Option<u32>,
+}
+
+/// A GraphQL IDE to serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ide {
+    Graphiql,
+    Playground,
+    ApolloSandbox,
+    None,
+}
+
+impl Ide {
+    fn parse(name: &str) -> Result<Ide, String> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "graphiql" => Ok(Ide::Graphiql),
+            "playground" => Ok(Ide::Playground),
+            "apollo-sandbox" => Ok(Ide::ApolloSandbox),
+            "none" => Ok(Ide::None),
+            _ => Err(format!(
+                "GRAPHQL_IDE must be graphiql, playground, apollo-sandbox or none, got {:?}",
+                name
+            )),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_owned(),
+            port: 8000,
+            repository_path: "/tmp".to_owned(),
+            backup_path: None,
+            backup_interval_secs: None,
+            backup_keep: 7,
+            ide: None,
+            playground: None,
+            workers: None,
+            keep_alive_secs: 5,
+            client_timeout_ms: 5000,
+            backlog: 2048,
+            log_level: None,
+            request_timeout_secs: None,
+            max_body_bytes: None,
+            max_upload_bytes: None,
+            max_uploads: None,
+            max_variables_bytes: None,
+            max_batch_size: None,
+            max_page_size: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(mode: Mode) -> Result<Config, String> {
+        let (path, required) = match std::env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => ("config.toml".to_owned(), false),
+        };
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(source) => toml::from_str(&source).map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("{}: {}", path, e))
+            }
+            Err(_) => Config::default(),
+        };
+        if let Ok(host) = std::env::var("HOST") {
+            config.host = host;
+        }
+        if let Ok(port) = std::env::var("PORT") {
+            config.port = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("PORT must be a port number, got {:?}", port))?;
+        }
+        if let Ok(path) = std::env::var("REPOSITORY_PATH") {
+            config.repository_path = path;
+        }
+        if let Ok(path) = std::env::var("BACKUP_PATH") {
+            config.backup_path = Some(path);
+        }
+        if let Ok(secs) = std::env::var("BACKUP_INTERVAL_SECS") {
+            config.backup_interval_secs = Some(secs.trim().parse().map_err(|_| {
+                format!(
+                    "BACKUP_INTERVAL_SECS must be a number of seconds, got {:?}",
+                    secs
+                )
+            })?);
+        }
+        if let Ok(keep) = std::env::var("BACKUP_KEEP") {
+            config.backup_keep = keep
+                .trim()
+                .parse()
+                .map_err(|_| format!("BACKUP_KEEP must be a number, got {:?}", keep))?;
+        }
+        match std::env::var("PLAYGROUND").as_deref() {
+            Ok("1") | Ok("true") => config.playground = Some(true),
+            Ok("0") | Ok("false") => config.playground = Some(false),
+            Ok(v) => return Err(format!("PLAYGROUND must be true or false, got {:?}", v)),
+            Err(_) => {}
+        }
+        if let Ok(ide) = std::env::var("GRAPHQL_IDE") {
+            config.ide = Some(Ide::parse(&ide)?);
+        }
+        if let Some(workers) = number("WORKERS")? {
+            config.workers = Some(workers);
+        }
+        if let Some(secs) = number("KEEP_ALIVE_SECS")? {
+            config.keep_alive_secs = secs;
+        }
+        if let Some(ms) = number("CLIENT_TIMEOUT_MS")? {
+            config.client_timeout_ms = ms;
+        }
+        if let Some(backlog) = number("BACKLOG")? {
+            config.backlog = backlog;
+        }
+        config.validate(mode)?;
+        Ok(config)
+    }
+
+    fn validate(&self, mode: Mode) -> Result<(), String> {
+        if self.host.trim().is_empty() {
+            return Err("host must not be empty".to_owned());
+        }
+        if self.port == 0 {
+            return Err("port must not be 0".to_owned());
+        }
+        if self.repository_path.trim().is_empty() {
+            return Err("repository_path must not be empty".to_owned());
+        }
+        if self.backup_interval_secs == Some(0) {
+            return Err("backup_interval_secs must not be 0".to_owned());
+        }
+        if self.backup_keep == 0 {
+            return Err("backup_keep must not be 0".to_owned());
+        }
+        if self.workers == Some(0) {
+            return Err("workers must not be 0".to_owned());
+        }
+        if self.backlog <= 0 {
+            return Err("backlog must be positive".to_owned());
+        }
+        let explicit = self.ide.is_some() || self.playground.is_some();
+        if explicit && self.ide(mode).is_some() && !mode.allows_introspection() {
+            return Err("the IDE needs introspection, which production disables".to_owned());
+        }
+        Ok(())
+    }
+
+    /// The settings that differ in `other` but only change on a restart.
+    pub fn needs_restart(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.address() != other.address() {
+            changed.push("host and port");
+        }
+        if self.repository_path != other.repository_path {
+            changed.push("repository_path");
+        }
+        if self.backup_dir() != other.backup_dir()
+            || self.backup_interval_secs != other.backup_interval_secs
+            || self.backup_keep != other.backup_keep
+        {
+            changed.push("the backup settings");
+        }
+        if self.ide != other.ide || self.playground != other.playground {
+            changed.push("the IDE");
+        }
+        if self.workers != other.workers
+            || self.keep_alive_secs != other.keep_alive_secs
+            || self.client_timeout_ms != other.client_timeout_ms
+            || self.backlog != other.backlog
+        {
+            changed.push("the server options");
+        }
+        changed
+    }
+
+    /// The file repository under `repository_path`. Repositories borrow
+    /// their path for the life of the process.
+    pub fn repository(&self) -> FileRepository<'static> {
+        FileRepository::new(Box::leak(self.repository_path.clone().into_boxed_str()))
+    }
+
+    pub fn backup_dir(&self) -> std::path::PathBuf {
+        match &self.backup_path {
+            Some(path) => path.into(),
+            None => std::path::Path::new(&self.repository_path).join("backups"),
+        }
+    }
+
+    pub fn backup_interval(&self) -> Option<Duration> {
+        self.backup_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// The IDE to serve, `None` for none.
+    pub fn ide(&self, mode: Mode) -> Option<Ide> {
+        let ide = match (self.ide, self.playground) {
+            (Some(ide), _) => ide,
+            (None, Some(true)) => Ide::Playground,
+            (None, Some(false)) => Ide::None,
+            (None, None) if mode.allows_introspection() => Ide::Playground,
+            (None, None) => Ide::None,
+        };
+        Some(ide).filter(|ide| *ide != Ide::None)
+    }
+}
+
+/// Entries a list field returns unless `MAX_PAGE_SIZE` says otherwise.
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+
+/// Bounds on the work a single request may cause.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Time a request may run before its execution is cancelled.
+    pub request_timeout: Duration,
+    /// Size of a request body, not counting uploaded files.
+    pub max_body_bytes: usize,
+    /// Size of each uploaded file.
+    pub max_upload_bytes: usize,
+    /// Number of files a request may upload.
+    pub max_uploads: usize,
+    /// Size of the JSON encoded variables of an operation.
+    pub max_variables_bytes: usize,
+    /// Number of operations a batched request may carry.
+    pub max_batch_size: usize,
+    /// Entries a list field returns at most, and by default.
+    pub max_page_size: usize,
+}
+
+impl Limits {
+    /// Reads `REQUEST_TIMEOUT_SECS` (default 30), `MAX_BODY_BYTES` (1 MiB),
+    /// `MAX_UPLOAD_BYTES` (10 MiB), `MAX_UPLOADS` (3), `MAX_VARIABLES_BYTES`
+    /// (64 KiB), `MAX_BATCH_SIZE` (10) and `MAX_PAGE_SIZE` (100), falling
+    /// back on their lower case keys in the config file.
+    pub fn load(config: &Config) -> Result<Limits, String> {
+        Ok(Limits {
+            request_timeout: Duration::from_secs(positive(
+                "REQUEST_TIMEOUT_SECS",
+                config.request_timeout_secs,
+                30,
+            )?),
+            max_body_bytes: positive("MAX_BODY_BYTES", config.max_body_bytes, 1 << 20)? as usize,
+            max_upload_bytes: positive("MAX_UPLOAD_BYTES", config.max_upload_bytes, 10 << 20)?
+                as usize,
+            max_uploads: positive("MAX_UPLOADS", config.max_uploads, 3)? as usize,
+            max_variables_bytes: positive(
+                "MAX_VARIABLES_BYTES",
+                config.max_variables_bytes,
+                64 << 10,
+            )? as usize,
+            max_batch_size: positive("MAX_BATCH_SIZE", config.max_batch_size, 10)? as usize,
+            max_page_size: positive("MAX_PAGE_SIZE", config.max_page_size, DEFAULT_PAGE_SIZE)?
+                as usize,
+        })
+    }
+
+    /// Size a whole request body may have; multipart bodies also carry the
+    /// uploaded files.
+    pub fn body_limit(&self, multipart: bool) -> usize {
+        if multipart {
+            self.max_body_bytes + self.max_uploads * self.max_upload_bytes
+        } else {
+            self.max_body_bytes
+        }
+    }
+}
+
+/// A number from the environment variable `name`, if it's set.
+fn number<T: std::str::FromStr>(name: &str) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Ok(v) => match v.trim().parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("{} must be a number, got {:?}", name, v)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// The limits requests are held to, replaced as a whole on reload. Each
+/// request works with the limits current when it arrived.
+#[derive(Clone)]
+pub struct LiveLimits(Arc<RwLock<Limits>>);
+
+impl LiveLimits {
+    pub fn new(limits: Limits) -> LiveLimits {
+        LiveLimits(Arc::new(RwLock::new(limits)))
+    }
+
+    pub fn get(&self) -> Limits {
+        *self.0.read().unwrap()
+    }
+
+    pub fn set(&self, limits: Limits) {
+        *self.0.write().unwrap() = limits;
+    }
+}
+
+/// A positive integer from the environment variable `name`, else from the
+/// config file, else `default`.
+fn positive(name: &str, file: Option<u64>, default: u64) -> Result<u64, String> {
+    match std::env::var(name) {
+        Ok(v) => match v.trim().parse() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("{} must be a positive number, got {:?}", name, v)),
+        },
+        Err(_) => match file {
+            Some(0) => Err(format!("{} must not be 0", name.to_ascii_lowercase())),
+            Some(n) => Ok(n),
+            None => Ok(default),
+        },
+    }
+}
@@ -0,0 +1,51 @@
+//! Graceful shutdown. On SIGTERM or SIGINT the server stops accepting
+//! connections and lets in-flight requests finish; the background workers
+//! are then told to stop, finish the batch they're on and exit.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often sleeping workers check whether they should stop.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shared flag telling background workers to stop.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps for `duration` on the current thread, waking early on shutdown.
+    pub fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.is_triggered() && Instant::now() < until {
+            std::thread::sleep(CHECK_INTERVAL.min(until - Instant::now()));
+        }
+    }
+
+    /// `sleep` for workers running on an async runtime.
+    pub async fn delay(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.is_triggered() && Instant::now() < until {
+            tokio::time::delay_for(CHECK_INTERVAL.min(until - Instant::now())).await;
+        }
+    }
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+pub async fn signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = interrupt.recv() => Ok("SIGINT"),
+    }
+}
@@ -0,0 +1,232 @@
+//! Address book statistics, computed from the owner's `ContactIndex`
+//! rather than from every contact. The use cases that change contacts keep
+//! an existing index current; a missing one, for owners whose contacts
+//! predate it or after merging a backup in, is built from the contacts the
+//! first time stats or aggregates are asked for.
+
+use crate::auth;
+use crate::i18n;
+use crate::models::*;
+use crate::repo::*;
+use crate::telemetry;
+use crate::usecases::audit_log;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// The most days `created_per_day` covers.
+pub const MAX_DAYS: i32 = 366;
+const DAY: i64 = 24 * 60 * 60;
+
+/// Records the stored contact in its owner's index, if there is one;
+/// `created` is whether it's new.
+pub fn index_contact<T: Repository<ContactIndex>>(
+    contact: &Contact,
+    created: bool,
+    repo: &T,
+) -> Result<(), Box<dyn Error>> {
+    let mut index: ContactIndex = match repo.get(&contact.owner_id) {
+        Ok(index) => index,
+        Err(_) => return Ok(()),
+    };
+    let (created_at, group_ids) = match index.entries.get(&contact.id) {
+        Some(entry) if !created => (entry.created_at, entry.group_ids.clone()),
+        _ => (auth::now() as i64, vec![]),
+    };
+    index
+        .entries
+        .insert(contact.id.clone(), entry(contact, created_at, group_ids)?);
+    repo.set(index)?;
+    Ok(())
+}
+
+/// Drops a deleted contact from its owner's index, if there is one.
+pub fn unindex_contact<T: Repository<ContactIndex>>(
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<(), Box<dyn Error>> {
+    if let Ok(mut index) = Repository::<ContactIndex>::get(repo, owner_id) {
+        if index.entries.remove(id).is_some() {
+            repo.set(index)?;
+        }
+    }
+    Ok(())
+}
+
+/// Records a contact joining a group in its owner's index, if there is one.
+pub fn index_membership<T: Repository<ContactIndex>>(
+    owner_id: &str,
+    id: &str,
+    group_id: &str,
+    repo: &T,
+) -> Result<(), Box<dyn Error>> {
+    if let Ok(mut index) = Repository::<ContactIndex>::get(repo, owner_id) {
+        if let Some(entry) = index.entries.get_mut(id) {
+            if !entry.group_ids.iter().any(|g| g == group_id) {
+                entry.group_ids.push(group_id.to_owned());
+                repo.set(index)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn entry(
+    contact: &Contact,
+    created_at: i64,
+    group_ids: Vec<String>,
+) -> Result<IndexEntry, Box<dyn Error>> {
+    let mut countries: Vec<String> = contact
+        .addresses
+        .iter()
+        .map(|a| a.country.trim().to_owned())
+        .filter(|c| !c.is_empty())
+        .collect();
+    countries.sort();
+    countries.dedup();
+    Ok(IndexEntry {
+        created_at,
+        countries,
+        group_ids,
+        bytes: serde_json::to_vec(contact)?.len() as u64,
+    })
+}
+
+/// The owner's index, built from their contacts, their groups and when the
+/// audit trail says each was created if there isn't one yet.
+pub fn contact_index<
+    T: Repository<ContactIndex> + Repository<Contact> + Repository<Group> + Repository<AuditEntry>,
+>(
+    owner_id: &str,
+    repo: &T,
+) -> Result<ContactIndex, Box<dyn Error>> {
+    if let Ok(index) = Repository::<ContactIndex>::get(repo, owner_id) {
+        return Ok(index);
+    }
+    let _span = telemetry::span("stats::rebuild_index");
+    let groups: Vec<Group> = repo.list("")?;
+    let mut entries = BTreeMap::new();
+    for contact in Repository::<Contact>::list(repo, owner_id)? {
+        let created_at = audit_log(owner_id, &contact.id, repo)?
+            .iter()
+            .find(|e| e.action == "create")
+            .map(|e| e.at)
+            .unwrap_or(0);
+        let group_ids = groups
+            .iter()
+            .filter(|g| g.member_ids.contains(&contact.key()))
+            .map(|g| g.id.clone())
+            .collect();
+        entries.insert(contact.id.clone(), entry(&contact, created_at, group_ids)?);
+    }
+    info!(
+        "built the contact index of {:?}, {} contacts",
+        owner_id,
+        entries.len()
+    );
+    repo.set(ContactIndex {
+        owner_id: owner_id.to_owned(),
+        entries,
+    })
+}
+
+/// The owner's stats, with contacts created on each of the last `days`
+/// UTC days, today included.
+pub fn contact_stats<
+    T: Repository<ContactIndex> + Repository<Contact> + Repository<Group> + Repository<AuditEntry>,
+>(
+    owner_id: &str,
+    days: i32,
+    repo: &T,
+) -> Result<ContactStats, Box<dyn Error>> {
+    let _span = telemetry::span("stats::contact_stats");
+    if !(0..=MAX_DAYS).contains(&days) {
+        return Err(i18n::text("days_range", &[("max", &MAX_DAYS)]).into());
+    }
+    let index = contact_index(owner_id, repo)?;
+    let today = auth::now() as i64 / DAY;
+    let first = today - days as i64 + 1;
+    let mut per_day = vec![0; days as usize];
+    let mut by_group: BTreeMap<&str, i32> = BTreeMap::new();
+    let mut storage_bytes = 0;
+    for entry in index.entries.values() {
+        let day = entry.created_at / DAY;
+        if entry.created_at > 0 && day >= first && day <= today {
+            per_day[(day - first) as usize] += 1;
+        }
+        for group_id in &entry.group_ids {
+            *by_group.entry(group_id).or_default() += 1;
+        }
+        storage_bytes += entry.bytes as i64;
+    }
+    let mut by_group: Vec<GroupCount> = by_group
+        .into_iter()
+        .map(|(group_id, count)| GroupCount {
+            group_id: group_id.to_owned(),
+            count,
+        })
+        .collect();
+    by_group.sort_by_key(|g| std::cmp::Reverse(g.count));
+    Ok(ContactStats {
+        total: index.entries.len() as i32,
+        created_per_day: per_day
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| DayCount {
+                day: civil_day(first + i as i64),
+                count,
+            })
+            .collect(),
+        by_group,
+        storage_bytes,
+    })
+}
+
+/// The owner's contacts bucketed by `by`, largest bucket first.
+pub fn aggregate<
+    T: Repository<ContactIndex> + Repository<Contact> + Repository<Group> + Repository<AuditEntry>,
+>(
+    owner_id: &str,
+    by: AggregateBy,
+    repo: &T,
+) -> Result<Vec<Bucket>, Box<dyn Error>> {
+    let _span = telemetry::span("stats::aggregate");
+    let index = contact_index(owner_id, repo)?;
+    let mut counts: BTreeMap<Option<&str>, i32> = BTreeMap::new();
+    for entry in index.entries.values() {
+        let keys = match by {
+            AggregateBy::Group => &entry.group_ids,
+            AggregateBy::Country => &entry.countries,
+        };
+        if keys.is_empty() {
+            *counts.entry(None).or_default() += 1;
+        }
+        for key in keys {
+            *counts.entry(Some(key)).or_default() += 1;
+        }
+    }
+    let mut buckets: Vec<Bucket> = counts
+        .into_iter()
+        .map(|(key, count)| Bucket {
+            key: key.map(str::to_owned),
+            count,
+        })
+        .collect();
+    buckets.sort_by_key(|b| std::cmp::Reverse(b.count));
+    Ok(buckets)
+}
+
+/// Days since the unix epoch as `YYYY-MM-DD`.
+fn civil_day(days: i64) -> String {
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
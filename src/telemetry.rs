@@ -0,0 +1,410 @@
+//! Distributed tracing. Each HTTP request gets a server span, continuing the
+//! trace of an incoming W3C `traceparent` header; GraphQL execution, usecases
+//! and repository calls open child spans while the request runs. Finished
+//! spans are batched and exported as OTLP/JSON to
+//! `OTEL_EXPORTER_OTLP_ENDPOINT`. Without an endpoint no spans are recorded.
+
+use crate::crypto;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::HeaderValue;
+use actix_web::Error;
+use async_graphql::extensions::Extension;
+use async_graphql::parser::query::Document;
+use async_graphql::Variables;
+use futures::future::{ok, Ready};
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Spans finished beyond this many before an export are dropped, so an
+/// unreachable collector can't grow the queue without bound.
+const MAX_QUEUED_SPANS: usize = 10_000;
+
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+
+tokio::task_local! {
+    static TRACE: Trace;
+}
+
+/// The trace a request belongs to and the spans open in it, innermost last.
+struct Trace {
+    trace_id: [u8; 16],
+    open: RefCell<Vec<[u8; 8]>>,
+}
+
+struct FinishedSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: u128,
+    end: u128,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+/// An open span, finished when dropped.
+pub struct Span {
+    inner: Option<FinishedSpan>,
+}
+
+impl Span {
+    fn start(trace_id: [u8; 16], parent: Option<[u8; 8]>, name: String, kind: u8) -> Span {
+        Span {
+            inner: Some(FinishedSpan {
+                trace_id,
+                span_id: rand::random(),
+                parent,
+                name,
+                kind,
+                start: unix_nanos(),
+                end: 0,
+                attributes: vec![],
+                error: None,
+            }),
+        }
+    }
+
+    fn id(&self) -> [u8; 8] {
+        self.inner.as_ref().unwrap().span_id
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl Into<String>) -> Span {
+        self.set_attribute(key, value);
+        self
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<String>) {
+        if let Some(span) = self.inner.as_mut() {
+            span.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        if let Some(span) = self.inner.as_mut() {
+            span.name = name.into();
+        }
+    }
+
+    /// Marks the span as failed.
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        if let Some(span) = self.inner.as_mut() {
+            span.error = Some(message.into());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut span = match self.inner.take() {
+            Some(span) => span,
+            None => return,
+        };
+        let _ = TRACE.try_with(|trace| trace.open.borrow_mut().retain(|id| *id != span.span_id));
+        span.end = unix_nanos();
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.len() < MAX_QUEUED_SPANS {
+            queue.push(span);
+        }
+    }
+}
+
+/// Opens a span as a child of the innermost open span of the current
+/// request. `None` when tracing is off or outside a request, e.g. in the
+/// background workers.
+pub fn span(name: impl Into<String>) -> Option<Span> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    TRACE
+        .try_with(|trace| {
+            let parent = trace.open.borrow().last().copied();
+            let span = Span::start(trace.trace_id, parent, name.into(), KIND_INTERNAL);
+            trace.open.borrow_mut().push(span.id());
+            span
+        })
+        .ok()
+}
+
+/// Where the current request's trace is at, for work it hands to another
+/// task to open its spans under the innermost open span.
+#[derive(Clone, Copy)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    parent: Option<[u8; 8]>,
+}
+
+impl TraceContext {
+    pub fn current() -> Option<TraceContext> {
+        TRACE
+            .try_with(|trace| TraceContext {
+                trace_id: trace.trace_id,
+                parent: trace.open.borrow().last().copied(),
+            })
+            .ok()
+    }
+
+    /// Runs `f` within the trace, if there is one.
+    pub async fn within<F: Future>(context: Option<TraceContext>, f: F) -> F::Output {
+        match context {
+            Some(context) => {
+                let trace = Trace {
+                    trace_id: context.trace_id,
+                    open: RefCell::new(context.parent.into_iter().collect()),
+                };
+                TRACE.scope(trace, f).await
+            }
+            None => f.await,
+        }
+    }
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+/// The trace and parent span ids of a `traceparent` header,
+/// `00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`.
+fn parse_traceparent(value: &HeaderValue) -> Option<([u8; 16], [u8; 8])> {
+    let parts: Vec<&str> = value.to_str().ok()?.trim().split('-').collect();
+    match parts.as_slice() {
+        [version, trace_id, span_id, flags]
+            if version.len() == 2 && *version != "ff" && flags.len() == 2 =>
+        {
+            let mut trace = [0u8; 16];
+            let mut span = [0u8; 8];
+            from_hex(trace_id, &mut trace)?;
+            from_hex(span_id, &mut span)?;
+            if trace == [0; 16] || span == [0; 8] {
+                return None;
+            }
+            Some((trace, span))
+        }
+        _ => None,
+    }
+}
+
+fn from_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != out.len() * 2 {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+/// Starts the exporter thread when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, e.g.
+/// `http://localhost:4318`. `OTEL_SERVICE_NAME` names the service in the
+/// exported resource. On shutdown the remaining spans are exported.
+pub fn spawn_exporter(shutdown: Shutdown) -> Option<thread::JoinHandle<()>> {
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "backend".to_owned());
+    ENABLED.store(true, Ordering::SeqCst);
+    Some(thread::spawn(move || {
+        let mut sys = actix_rt::System::new("telemetry");
+        sys.block_on(async move {
+            let client = awc::Client::build()
+                .timeout(Duration::from_secs(10))
+                .finish();
+            loop {
+                let stopping = shutdown.is_triggered();
+                let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+                if !spans.is_empty() {
+                    if let Err(e) = export(&client, &url, &service, spans).await {
+                        warn!("span export failed: {}", e);
+                    }
+                }
+                if stopping {
+                    return;
+                }
+                shutdown.delay(EXPORT_INTERVAL).await;
+            }
+        })
+    }))
+}
+
+async fn export(
+    client: &awc::Client,
+    url: &str,
+    service: &str,
+    spans: Vec<FinishedSpan>,
+) -> Result<(), String> {
+    let count = spans.len();
+    let spans: Vec<_> = spans.iter().map(otlp_span).collect();
+    let body = serde_json::json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", service)] },
+            "scopeSpans": [{ "scope": { "name": "backend" }, "spans": spans }],
+        }]
+    });
+    let response = client
+        .post(url)
+        .send_json(&body)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("collector answered {}", response.status()));
+    }
+    debug!("exported {} spans", count);
+    Ok(())
+}
+
+fn otlp_span(span: &FinishedSpan) -> serde_json::Value {
+    let status = match &span.error {
+        Some(message) => serde_json::json!({ "code": 2, "message": message }),
+        None => serde_json::json!({ "code": 0 }),
+    };
+    serde_json::json!({
+        "traceId": crypto::to_hex(&span.trace_id),
+        "spanId": crypto::to_hex(&span.span_id),
+        "parentSpanId": span.parent.map(|p| crypto::to_hex(&p)).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind,
+        "startTimeUnixNano": span.start.to_string(),
+        "endTimeUnixNano": span.end.to_string(),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<_>>(),
+        "status": status,
+    })
+}
+
+fn attribute(key: &str, value: &str) -> serde_json::Value {
+    serde_json::json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// Traces the execution of one GraphQL operation, named after the
+/// document's first operation like the metrics are.
+#[derive(Default)]
+pub struct OperationSpan {
+    span: Option<Span>,
+}
+
+impl Extension for OperationSpan {
+    fn parse_start(&mut self, _query_source: &str, _variables: &Variables) {
+        self.span = span("graphql.execute");
+    }
+
+    fn parse_end(&mut self, document: &Document) {
+        let span = match self.span.as_mut() {
+            Some(span) => span,
+            None => return,
+        };
+        let (ty, name) = match metrics::first_operation(document) {
+            Some(operation) => operation,
+            None => return,
+        };
+        span.set_attribute("graphql.operation.type", ty);
+        match name {
+            Some(name) => {
+                span.set_name(format!("{} {}", ty, name));
+                span.set_attribute("graphql.operation.name", name);
+            }
+            None => span.set_name(ty),
+        }
+    }
+
+    fn error(&mut self, err: &async_graphql::Error) {
+        if let Some(span) = self.span.as_mut() {
+            span.set_error(err.to_string());
+        }
+    }
+}
+
+/// Actix middleware opening the server span of each request and making its
+/// trace current while the request is handled. It's the outermost
+/// middleware, so the span covers everything the server does for a request.
+pub struct Tracing;
+
+impl<S, B> Transform<S> for Tracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TracingMiddleware { service })
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for TracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Box::pin(self.service.call(req));
+        }
+        let (trace_id, parent) = match req.headers().get("traceparent").and_then(parse_traceparent)
+        {
+            Some((trace_id, parent)) => (trace_id, Some(parent)),
+            None => (rand::random(), None),
+        };
+        let name = format!("{} {}", req.method(), req.path());
+        let mut span = Span::start(trace_id, parent, name, KIND_SERVER)
+            .with("http.method", req.method().as_str())
+            .with("http.target", req.path());
+        let trace = Trace {
+            trace_id,
+            open: RefCell::new(vec![span.id()]),
+        };
+        let fut = TRACE.scope(trace, self.service.call(req));
+        Box::pin(async move {
+            let result = fut.await;
+            match &result {
+                Ok(resp) => {
+                    let status = resp.status();
+                    span.set_attribute("http.status_code", status.as_str());
+                    if status.is_server_error() {
+                        span.set_error(status.to_string());
+                    }
+                }
+                Err(e) => span.set_error(e.to_string()),
+            }
+            result
+        })
+    }
+}
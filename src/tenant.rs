@@ -0,0 +1,100 @@
+use crate::auth::CurrentUser;
+use actix_web::http::HeaderMap;
+
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// The tenant a request is served for. Each tenant gets its own partition of
+/// the repository, so address books of different tenants never mix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub String);
+
+/// Takes the tenant from the caller's token when it carries one, otherwise,
+/// for anonymous requests such as signing in, from the `X-Tenant-Id` header.
+/// A header naming a different tenant than the token is refused rather than
+/// silently ignored, and so is any header sent with credentials that carry
+/// no tenant: user ids are derived from emails, so such a caller could
+/// otherwise act as a namesake in every tenant.
+pub fn resolve(headers: &HeaderMap, user: Option<&CurrentUser>) -> Result<Option<Tenant>, String> {
+    let header = match headers.get(TENANT_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| "invalid tenant header".to_string())?
+                .trim(),
+        ),
+        None => None,
+    };
+    let claimed = user.map(|u| u.tenant.as_deref());
+    let tenant = match (claimed, header) {
+        (Some(Some(claimed)), Some(header)) if claimed != header => {
+            return Err(format!("credentials are not valid for tenant {:?}", header))
+        }
+        (Some(None), Some(header)) => {
+            return Err(format!("credentials are not valid for tenant {:?}", header))
+        }
+        (Some(Some(tenant)), _) | (None, Some(tenant)) => tenant,
+        (_, None) => return Ok(None),
+    };
+    Tenant::parse(tenant).map(Some)
+}
+
+impl Tenant {
+    /// Tenant ids name a repository directory, so only short ids of letters,
+    /// digits, `-` and `_` are accepted.
+    pub fn parse(tenant: &str) -> Result<Tenant, String> {
+        if tenant.is_empty()
+            || tenant.len() > 64
+            || !tenant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("invalid tenant {:?}", tenant));
+        }
+        Ok(Tenant(tenant.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::testing::caller;
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    fn headers(tenant: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(TENANT_HEADER),
+            HeaderValue::from_str(tenant).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn anonymous_requests_pick_their_tenant() {
+        assert_eq!(
+            resolve(&headers("acme"), None),
+            Ok(Some(Tenant("acme".to_owned())))
+        );
+        assert_eq!(resolve(&HeaderMap::new(), None), Ok(None));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_tenant() {
+        let mut user = caller("u1", Role::Viewer);
+        user.tenant = Some("acme".to_owned());
+        assert_eq!(
+            resolve(&HeaderMap::new(), Some(&user)),
+            Ok(Some(Tenant("acme".to_owned())))
+        );
+        assert!(resolve(&headers("acme"), Some(&user)).is_ok());
+        assert!(resolve(&headers("globex"), Some(&user)).is_err());
+    }
+
+    #[test]
+    fn untenanted_credentials_cannot_reach_another_tenant() {
+        let user = caller("u1", Role::Admin);
+        assert!(resolve(&headers("globex"), Some(&user)).is_err());
+        assert_eq!(resolve(&HeaderMap::new(), Some(&user)), Ok(None));
+    }
+}
@@ -0,0 +1,547 @@
+//! Test support: the schema over a repository in memory, or for tests of
+//! the files themselves in a temporary directory, run in-process as any
+//! caller, so tests exercise the resolvers, guards and validators without a
+//! server; and for use case tests, a repository that fails or stalls on cue
+//! and a builder of contacts.
+
+use crate::auth::{CurrentUser, Role, PII_SCOPE};
+use crate::backup::BackupDir;
+use crate::events::StorageMode;
+use crate::graphql::{self, ContactsSchema};
+use crate::jobs::Jobs;
+use crate::models::{Address, Contact};
+use crate::repo::{Entity, FileRepository, MemoryRepository, Repository, StorageBackend};
+use async_graphql::http::GQLResponse;
+use async_graphql::{QueryBuilder, Variables};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tempfile::TempDir;
+
+pub struct TestApp {
+    /// Removed with the app.
+    _dir: TempDir,
+    pub repo: StorageBackend,
+    schema: ContactsSchema,
+}
+
+impl TestApp {
+    /// The app over a repository in memory.
+    pub fn new() -> TestApp {
+        TestApp::with_storage(StorageMode::State)
+    }
+
+    pub fn with_storage(storage_mode: StorageMode) -> TestApp {
+        let dir = tempfile::tempdir().expect("no temporary directory");
+        TestApp::over(
+            dir,
+            StorageBackend::Memory(MemoryRepository::new()),
+            storage_mode,
+        )
+    }
+
+    /// The app over files in a temporary directory, for tests that need
+    /// them, e.g. of backups or of how slow reads are served.
+    pub fn with_files() -> TestApp {
+        let dir = tempfile::tempdir().expect("no temporary directory");
+        let path = dir
+            .path()
+            .to_str()
+            .expect("temporary directory isn't UTF-8");
+        let repo = FileRepository::new(Box::leak(path.to_owned().into_boxed_str()));
+        TestApp::over(dir, StorageBackend::Files(repo), StorageMode::State)
+    }
+
+    fn over(dir: TempDir, repo: StorageBackend, storage_mode: StorageMode) -> TestApp {
+        let schema = graphql::schema_builder(repo.clone(), storage_mode)
+            .data(BackupDir(dir.path().join("backups")))
+            .data(Jobs::default())
+            .finish();
+        TestApp {
+            _dir: dir,
+            repo,
+            schema,
+        }
+    }
+
+    /// Runs `query` as `caller`, anonymously without one, and returns the
+    /// response as clients receive it: `data` and, if any, `errors`.
+    pub async fn execute(
+        &self,
+        caller: Option<&CurrentUser>,
+        query: &str,
+        variables: Value,
+    ) -> Value {
+        let variables = Variables::parse_from_json(variables).expect("variables aren't an object");
+        let mut builder = QueryBuilder::new(query).variables(variables);
+        if let Some(caller) = caller {
+            builder = builder.data(caller.clone());
+        }
+        let response = GQLResponse(builder.execute(&self.schema).await);
+        serde_json::to_value(response).expect("response doesn't serialize")
+    }
+}
+
+/// A caller of the untenanted deployment, allowed to read contacts' PII.
+pub fn caller(id: &str, role: Role) -> CurrentUser {
+    CurrentUser {
+        id: id.to_owned(),
+        role,
+        scopes: vec![PII_SCOPE.to_owned()],
+        tenant: None,
+    }
+}
+
+/// The messages of the response's errors, none if it succeeded.
+pub fn errors(response: &Value) -> Vec<&str> {
+    response["errors"]
+        .as_array()
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|e| e["message"].as_str())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Compares `value` with the golden file `src/snapshots/<name>.json`. A
+/// missing file is recorded and fails the test until checked in; with
+/// `UPDATE_SNAPSHOTS=1` every compared file is rerecorded, for reviewing
+/// an intended change in the diff.
+pub fn assert_snapshot(name: &str, value: &Value) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/snapshots")
+        .join(format!("{}.json", name));
+    let actual = serde_json::to_string_pretty(value).unwrap() + "\n";
+    let update = std::env::var("UPDATE_SNAPSHOTS").as_deref() == Ok("1");
+    match std::fs::read_to_string(&path) {
+        Ok(expected) if expected == actual => {}
+        Ok(_) | Err(_) if update => std::fs::write(&path, actual).unwrap(),
+        Ok(expected) => panic!(
+            "snapshot {} changed, rerun with UPDATE_SNAPSHOTS=1 if intended\n\
+             --- expected\n{}+++ actual\n{}",
+            name, expected, actual
+        ),
+        Err(_) => {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, actual).unwrap();
+            panic!("recorded new snapshot {}, check it in", path.display());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Set,
+    Get,
+    Delete,
+    List,
+}
+
+/// A programmed failure of the calls of one operation on one kind.
+struct Failure {
+    operation: Operation,
+    kind: &'static str,
+    message: String,
+    /// Calls left to fail, `None` for every call.
+    times: Option<usize>,
+}
+
+/// A repository in memory, standing in for every kind at once. Records are
+/// kept as JSON, like the file repository keeps them, and missing ones fail
+/// with `NotFound`. Calls can be made to fail or to take a while, and every
+/// call is recorded for tests to check what was attempted.
+#[derive(Default)]
+pub struct MockRepository {
+    records: Mutex<BTreeMap<(&'static str, String), Value>>,
+    failures: Mutex<Vec<Failure>>,
+    delay: Mutex<Option<Duration>>,
+    calls: Mutex<Vec<(Operation, &'static str, String)>>,
+}
+
+impl MockRepository {
+    /// A repository already holding `contacts`.
+    pub fn with_contacts(contacts: Vec<Contact>) -> MockRepository {
+        let repo = MockRepository::default();
+        for contact in contacts {
+            repo.set(contact).unwrap();
+        }
+        repo.calls.lock().unwrap().clear();
+        repo
+    }
+
+    /// Fails every `operation` on `kind` with `message` until healed.
+    pub fn fail(&self, operation: Operation, kind: &'static str, message: &str) {
+        self.program(operation, kind, message, None);
+    }
+
+    /// Fails the next `operation` on `kind` with `message`.
+    pub fn fail_once(&self, operation: Operation, kind: &'static str, message: &str) {
+        self.program(operation, kind, message, Some(1));
+    }
+
+    fn program(
+        &self,
+        operation: Operation,
+        kind: &'static str,
+        message: &str,
+        times: Option<usize>,
+    ) {
+        self.failures.lock().unwrap().push(Failure {
+            operation,
+            kind,
+            message: message.to_owned(),
+            times,
+        });
+    }
+
+    /// Drops every programmed failure.
+    pub fn heal(&self) {
+        self.failures.lock().unwrap().clear();
+    }
+
+    /// Makes every call take `delay` longer.
+    pub fn delay(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = Some(delay);
+    }
+
+    /// The calls made of `kind`, oldest first, as operations and keys.
+    pub fn calls(&self, kind: &str) -> Vec<(Operation, String)> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, k, _)| *k == kind)
+            .map(|(operation, _, key)| (*operation, key.clone()))
+            .collect()
+    }
+
+    /// Records the call, stalls and fails it as programmed.
+    fn call(
+        &self,
+        operation: Operation,
+        kind: &'static str,
+        key: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((operation, kind, key.to_owned()));
+        if let Some(delay) = *self.delay.lock().unwrap() {
+            std::thread::sleep(delay);
+        }
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures
+            .iter_mut()
+            .position(|f| f.operation == operation && f.kind == kind);
+        let i = match failure {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let message = failures[i].message.clone();
+        match &mut failures[i].times {
+            Some(1) => {
+                failures.remove(i);
+            }
+            Some(times) => *times -= 1,
+            None => {}
+        }
+        Err(message.into())
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Entity> Repository<T> for MockRepository {
+    fn set(&self, obj: T) -> Result<T, Box<dyn Error>> {
+        let key = obj.key();
+        self.call(Operation::Set, T::KIND, &key)?;
+        let value = serde_json::to_value(&obj)?;
+        self.records.lock().unwrap().insert((T::KIND, key), value);
+        Ok(obj)
+    }
+
+    fn get(&self, key: &str) -> Result<T, Box<dyn Error>> {
+        self.call(Operation::Get, T::KIND, key)?;
+        match self.records.lock().unwrap().get(&(T::KIND, key.to_owned())) {
+            Some(value) => Ok(serde_json::from_value(value.clone())?),
+            None => Err(Box::new(std::io::Error::from(std::io::ErrorKind::NotFound))),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.call(Operation::Delete, T::KIND, key)?;
+        match self
+            .records
+            .lock()
+            .unwrap()
+            .remove(&(T::KIND, key.to_owned()))
+        {
+            Some(_) => Ok(()),
+            None => Err(Box::new(std::io::Error::from(std::io::ErrorKind::NotFound))),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        self.call(Operation::List, T::KIND, prefix)?;
+        let parent = |key: &str| {
+            key.rfind('/')
+                .map(|i| key[..i].to_owned())
+                .unwrap_or_default()
+        };
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((kind, key), _)| *kind == T::KIND && parent(key) == prefix)
+            .map(|(_, value)| Ok(serde_json::from_value(value.clone())?))
+            .collect()
+    }
+}
+
+/// Builds a contact, Ada Lovelace of owner `u1` unless told otherwise.
+pub struct ContactFixture(Contact);
+
+impl ContactFixture {
+    pub fn new(id: &str) -> ContactFixture {
+        ContactFixture(Contact {
+            id: id.to_owned(),
+            owner_id: "u1".to_owned(),
+            first_name: "Ada".to_owned(),
+            last_name: "Lovelace".to_owned(),
+            ..Contact::default()
+        })
+    }
+
+    pub fn owner(mut self, owner_id: &str) -> ContactFixture {
+        self.0.owner_id = owner_id.to_owned();
+        self
+    }
+
+    pub fn name(mut self, first_name: &str, last_name: &str) -> ContactFixture {
+        self.0.first_name = first_name.to_owned();
+        self.0.last_name = last_name.to_owned();
+        self
+    }
+
+    pub fn email(mut self, email: &str) -> ContactFixture {
+        self.0.emails.push(email.to_owned());
+        self
+    }
+
+    pub fn phone(mut self, phone: &str) -> ContactFixture {
+        self.0.phones.push(phone.to_owned());
+        self
+    }
+
+    /// Adds an address in `country`, elsewhere blank.
+    pub fn address_in(mut self, country: &str) -> ContactFixture {
+        self.0.addresses.push(Address {
+            country: country.to_owned(),
+            ..Address::default()
+        });
+        self
+    }
+
+    pub fn build(self) -> Contact {
+        self.0
+    }
+}
+
+/// The semantics every `Repository` has to keep, checked on contacts and
+/// groups, a partitioned and an unpartitioned kind. `repository_contract!`
+/// runs each as a test against a backend.
+pub mod contract {
+    use super::ContactFixture;
+    use crate::models::{Contact, Group};
+    use crate::repo::Repository;
+    use futures::TryStreamExt;
+    use std::io::ErrorKind;
+
+    pub trait Backend: Repository<Contact> + Repository<Group> {}
+
+    impl<R: Repository<Contact> + Repository<Group>> Backend for R {}
+
+    fn json<T: serde::Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    fn group(id: &str, name: &str) -> Group {
+        Group {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            member_ids: vec![],
+        }
+    }
+
+    fn is_not_found(e: &(dyn std::error::Error + 'static)) -> bool {
+        e.downcast_ref::<std::io::Error>()
+            .map(|e| e.kind() == ErrorKind::NotFound)
+            .unwrap_or(false)
+    }
+
+    fn ids<R: Backend>(repo: &R, prefix: &str) -> Vec<String> {
+        let contacts: Vec<Contact> = repo.list(prefix).unwrap();
+        contacts.into_iter().map(|c| c.id).collect()
+    }
+
+    pub fn set_then_get_round_trips<R: Backend>(repo: &R) {
+        let contact = ContactFixture::new("ada")
+            .email("ada@example.com")
+            .phone("+44 20 7946 0000")
+            .address_in("GB")
+            .build();
+        let stored = repo.set(contact.clone()).unwrap();
+        assert_eq!(json(&stored), json(&contact));
+        let got: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(json(&got), json(&contact));
+
+        repo.set(group("friends", "Friends")).unwrap();
+        let got: Group = repo.get("friends").unwrap();
+        assert_eq!(got.name, "Friends");
+    }
+
+    pub fn set_replaces<R: Backend>(repo: &R) {
+        repo.set(ContactFixture::new("ada").build()).unwrap();
+        repo.set(ContactFixture::new("ada").name("Ada", "King").build())
+            .unwrap();
+        let got: Contact = repo.get("u1/ada").unwrap();
+        assert_eq!(got.last_name, "King");
+        assert_eq!(ids(repo, "u1"), vec!["ada"]);
+    }
+
+    pub fn get_of_a_missing_key_is_not_found<R: Backend>(repo: &R) {
+        let err = Repository::<Contact>::get(repo, "u1/nobody").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+        let err = Repository::<Group>::get(repo, "nobody").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+    }
+
+    pub fn delete_removes<R: Backend>(repo: &R) {
+        repo.set(ContactFixture::new("ada").build()).unwrap();
+        repo.set(ContactFixture::new("grace").build()).unwrap();
+        Repository::<Contact>::delete(repo, "u1/ada").unwrap();
+        let err = Repository::<Contact>::get(repo, "u1/ada").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+        assert_eq!(ids(repo, "u1"), vec!["grace"]);
+    }
+
+    pub fn delete_of_a_missing_key_fails<R: Backend>(repo: &R) {
+        let err = Repository::<Contact>::delete(repo, "u1/nobody").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+    }
+
+    pub fn list_returns_what_is_directly_under_the_prefix<R: Backend>(repo: &R) {
+        for contact in [
+            ContactFixture::new("grace").build(),
+            ContactFixture::new("ada").build(),
+            ContactFixture::new("alan").owner("u2").build(),
+        ] {
+            repo.set(contact).unwrap();
+        }
+        assert_eq!(ids(repo, "u1"), vec!["ada", "grace"]);
+        assert_eq!(ids(repo, "u2"), vec!["alan"]);
+        assert!(ids(repo, "u3").is_empty());
+        assert!(ids(repo, "").is_empty());
+
+        repo.set(group("friends", "Friends")).unwrap();
+        repo.set(group("family", "Family")).unwrap();
+        let groups: Vec<Group> = repo.list("").unwrap();
+        let names: Vec<String> = groups.into_iter().map(|g| g.name).collect();
+        assert_eq!(names, vec!["Family", "Friends"]);
+    }
+
+    pub fn list_is_sorted_by_key<R: Backend>(repo: &R) {
+        // Neither the order written nor the names agree with the keys.
+        for (id, first, last) in [
+            ("grace", "Aaron", "Zed"),
+            ("ada-b", "Zoe", "Adams"),
+            ("ada", "Mia", "Moss"),
+            ("b", "Eve", "Ng"),
+        ] {
+            repo.set(ContactFixture::new(id).name(first, last).build())
+                .unwrap();
+        }
+        assert_eq!(ids(repo, "u1"), vec!["ada", "ada-b", "b", "grace"]);
+
+        repo.set(group("friends", "Friends")).unwrap();
+        repo.set(group("family", "Family")).unwrap();
+        repo.set(group("co-workers", "Work")).unwrap();
+        let groups: Vec<Group> = repo.list("").unwrap();
+        let keys: Vec<String> = groups.into_iter().map(|g| g.id).collect();
+        assert_eq!(keys, vec!["co-workers", "family", "friends"]);
+    }
+
+    pub fn stream_yields_what_list_returns<R: Backend>(repo: &R) {
+        for id in ["grace", "ada", "alan"] {
+            repo.set(ContactFixture::new(id).build()).unwrap();
+        }
+        // The file repository reads with tokio, so needs its runtime.
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut streamed = |prefix: &str| -> Vec<String> {
+            let all: Vec<Contact> = runtime
+                .block_on(Repository::<Contact>::stream(repo, prefix).try_collect())
+                .unwrap();
+            all.into_iter().map(|c| c.id).collect()
+        };
+        assert_eq!(streamed("u1"), ids(repo, "u1"));
+        assert!(streamed("u3").is_empty());
+    }
+
+    pub fn kinds_are_kept_apart<R: Backend>(repo: &R) {
+        repo.set(group("u1", "Owners")).unwrap();
+        repo.set(ContactFixture::new("u1").owner("u1").build())
+            .unwrap();
+        let err = Repository::<Contact>::get(repo, "u1").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+        let err = Repository::<Group>::get(repo, "u1/u1").unwrap_err();
+        assert!(is_not_found(err.as_ref()), "{}", err);
+        Repository::<Group>::delete(repo, "u1").unwrap();
+        let got: Contact = repo.get("u1/u1").unwrap();
+        assert_eq!(got.first_name, "Ada");
+    }
+}
+
+/// A test per `contract` property against the backend `$make` returns,
+/// given the path of a fresh temporary directory it may store under.
+macro_rules! repository_contract {
+    ($backend:ident, $make:expr) => {
+        mod $backend {
+            #[allow(unused_imports)]
+            use super::*;
+
+            fn check<R: $crate::testing::contract::Backend>(
+                make: impl FnOnce(&'static str) -> R,
+                property: fn(&R),
+            ) {
+                let dir = tempfile::tempdir().unwrap();
+                let path = dir.path().to_str().unwrap().to_owned();
+                property(&make(Box::leak(path.into_boxed_str())));
+            }
+
+            repository_contract!(@properties $make;
+                set_then_get_round_trips,
+                set_replaces,
+                get_of_a_missing_key_is_not_found,
+                delete_removes,
+                delete_of_a_missing_key_fails,
+                list_returns_what_is_directly_under_the_prefix,
+                list_is_sorted_by_key,
+                stream_yields_what_list_returns,
+                kinds_are_kept_apart
+            );
+        }
+    };
+    (@properties $make:expr; $($property:ident),*) => {
+        $(
+            #[test]
+            fn $property() {
+                check($make, $crate::testing::contract::$property);
+            }
+        )*
+    };
+}
@@ -0,0 +1,1348 @@
+use crate::auth::{self, Role};
+use crate::crypto;
+use crate::events::ContactHistory;
+use crate::i18n;
+use crate::idempotency::StoredResponse;
+use crate::logging::Pii;
+use crate::models::*;
+use crate::outbox::OutboxMessage;
+use crate::png;
+use crate::repo::*;
+use crate::stats;
+use crate::telemetry;
+use futures::{future, Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::collections::hash_map::{Entry, HashMap};
+use std::error::Error;
+
+pub fn create<
+    T: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>,
+>(
+    actor: &str,
+    owner_id: &str,
+    mut contact: Contact,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::create");
+    check_id(&contact.id)?;
+    contact.owner_id = owner_id.to_owned();
+    let before: Option<Contact> = repo.get(&contact.key()).ok();
+    // The avatar is only changed by uploading one.
+    contact.avatar = before.as_ref().and_then(|b| b.avatar.clone());
+    let action = if before.is_some() { "update" } else { "create" };
+    let subject = format!("contacts.{}d", action);
+    let r = with_outbox(&subject, &contact, repo, || repo.set(contact.clone()))?;
+    stats::index_contact(&r, before.is_none(), repo)?;
+    enqueue_webhooks(&subject, &r, repo)?;
+    audit(actor, action, before.as_ref(), Some(&r), &r.key(), repo)?;
+    info!("contact created {}", Pii(&contact));
+    Ok(r)
+}
+
+fn check_id(id: &str) -> Result<(), Box<dyn Error>> {
+    if id.contains('/') {
+        return Err(i18n::text("invalid_contact_id", &[]).into());
+    }
+    Ok(())
+}
+
+/// Changes only the fields the patch sets, keeping the rest of the stored
+/// contact.
+pub fn update_partial<
+    T: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>,
+>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    patch: ContactPatch,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::update_partial");
+    let contact: Contact = repo.get(&Contact::key_for(owner_id, id))?;
+    create(actor, owner_id, patch.apply(contact), repo)
+}
+
+pub fn get<T: Repository<Contact>>(
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::get");
+    repo.get(&Contact::key_for(owner_id, id))
+}
+
+/// An owner's address book, by id.
+pub fn list_contacts<T: Repository<Contact>>(
+    owner_id: &str,
+    repo: &T,
+) -> Result<Vec<Contact>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::list_contacts");
+    let mut contacts = repo.list(owner_id)?;
+    contacts.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(contacts)
+}
+
+/// An owner's address book, by id, read a contact at a time as the stream
+/// is polled where the repository can.
+pub fn stream_contacts<T: Repository<Contact>>(
+    owner_id: &str,
+    repo: &T,
+) -> impl Stream<Item = Result<Contact, Box<dyn Error>>> {
+    repo.stream(owner_id)
+}
+
+/// A page of an owner's address book by id: up to `size` contacts after
+/// the one with id `after`, and whether any follow. Contacts are read off
+/// the stream only as far as the page goes.
+pub async fn contacts_after<T: Repository<Contact>>(
+    owner_id: &str,
+    after: Option<&str>,
+    size: usize,
+    repo: &T,
+) -> Result<(Vec<Contact>, bool), Box<dyn Error>> {
+    let _span = telemetry::span("usecases::contacts_after");
+    let after = after.map(str::to_owned);
+    let mut contacts: Vec<Contact> = stream_contacts(owner_id, repo)
+        .try_skip_while(|c| future::ready(Ok(matches!(&after, Some(a) if c.id <= *a))))
+        .take(size + 1)
+        .try_collect()
+        .await?;
+    let more = contacts.len() > size;
+    contacts.truncate(size);
+    Ok((contacts, more))
+}
+
+pub fn delete<
+    T: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>,
+>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::delete");
+    let key = Contact::key_for(owner_id, id);
+    let contact: Contact = repo.get(&key)?;
+    with_outbox("contacts.deleted", &contact, repo, || {
+        Repository::<Contact>::delete(repo, &key)
+    })?;
+    stats::unindex_contact(owner_id, id, repo)?;
+    enqueue_webhooks("contacts.deleted", &contact, repo)?;
+    audit(actor, "delete", Some(&contact), None, &key, repo)?;
+    info!("contact deleted {}", Pii(&contact));
+    Ok(contact)
+}
+
+/// Stores an uploaded PNG as the contact's new avatar and queues making its
+/// thumbnails; the contact keeps its current avatar until they're made.
+pub fn upload_avatar<T: Repository<Contact> + Repository<AvatarTask> + Blobs>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    image: &[u8],
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::upload_avatar");
+    let contact: Contact = repo.get(&Contact::key_for(owner_id, id))?;
+    png::decode(image)?;
+    let version = crypto::to_hex(&crypto::sha256(image)[..8]);
+    repo.put_blob(
+        &format!("{}/original.png", avatar_key(owner_id, id, Some(&version))),
+        image,
+    )?;
+    repo.set(AvatarTask {
+        id: sortable_id()?,
+        owner_id: owner_id.to_owned(),
+        contact_id: id.to_owned(),
+        version,
+        actor: actor.to_owned(),
+    })?;
+    Ok(contact)
+}
+
+/// Points the contact at avatar `version`, whose thumbnails are stored.
+pub fn set_avatar<
+    T: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>,
+>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    version: &str,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::set_avatar");
+    let before: Contact = repo.get(&Contact::key_for(owner_id, id))?;
+    let mut contact = before.clone();
+    contact.avatar = Some(version.to_owned());
+    let r = with_outbox("contacts.updated", &contact, repo, || {
+        repo.set(contact.clone())
+    })?;
+    stats::index_contact(&r, false, repo)?;
+    enqueue_webhooks("contacts.updated", &r, repo)?;
+    audit(actor, "update", Some(&before), Some(&r), &r.key(), repo)?;
+    Ok(r)
+}
+
+/// The blob key of a contact's avatar `version`, or of all its avatars.
+pub fn avatar_key(owner_id: &str, id: &str, version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!("avatars/{}/{}/{}", owner_id, id, version),
+        None => format!("avatars/{}/{}", owner_id, id),
+    }
+}
+
+/// The contact as it was at unix time `at`.
+pub fn contact_at<T: ContactHistory>(
+    owner_id: &str,
+    id: &str,
+    at: u64,
+    repo: &T,
+) -> Result<Option<Contact>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::contact_at");
+    repo.contact_at(&Contact::key_for(owner_id, id), at)
+}
+
+/// The recorded changes to a contact, oldest first.
+pub fn audit_log<T: Repository<AuditEntry>>(
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::audit_log");
+    let mut entries = repo.list(&Contact::key_for(owner_id, id))?;
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(entries)
+}
+
+/// A contact's revisions, oldest first, from its audit trail.
+pub fn contact_history<T: Repository<AuditEntry>>(
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Vec<Revision>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::contact_history");
+    let entries = audit_log(owner_id, id, repo)?;
+    if entries.is_empty() {
+        return Err(format!("no history of contact {:?}", id).into());
+    }
+    Ok(entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| Revision {
+            revision: i as i32 + 1,
+            at: entry.at,
+            actor: entry.actor,
+            action: entry.action,
+            changes: entry.changes,
+        })
+        .collect())
+}
+
+/// Stages a domain event in the outbox, then runs `write`. The event is taken
+/// back out if the write fails, so only changes that were stored get
+/// published.
+fn with_outbox<T, D, R>(
+    subject: &str,
+    data: &D,
+    repo: &T,
+    write: impl FnOnce() -> Result<R, Box<dyn Error>>,
+) -> Result<R, Box<dyn Error>>
+where
+    T: Repository<OutboxMessage>,
+    D: Serialize,
+{
+    let message = repo.set(OutboxMessage {
+        id: sortable_id()?,
+        subject: subject.to_owned(),
+        payload: serde_json::to_value(data)?,
+    })?;
+    let result = write();
+    if result.is_err() {
+        let _ = repo.delete(&message.id);
+    }
+    result
+}
+
+/// Queues a delivery of the event for every registered webhook.
+fn enqueue_webhooks<T, D>(subject: &str, data: &D, repo: &T) -> Result<(), Box<dyn Error>>
+where
+    T: Repository<Webhook> + Repository<WebhookDelivery>,
+    D: Serialize,
+{
+    let webhooks: Vec<Webhook> = repo.list("")?;
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_value(data)?;
+    for webhook in webhooks {
+        repo.set(WebhookDelivery {
+            id: sortable_id()?,
+            webhook_id: webhook.id,
+            subject: subject.to_owned(),
+            payload: payload.clone(),
+            status: "pending".to_owned(),
+            attempts: 0,
+            next_attempt_at: 0,
+            response_status: None,
+            last_error: None,
+        })?;
+    }
+    Ok(())
+}
+
+pub fn register_webhook<T: Repository<Webhook>>(
+    url: &str,
+    repo: &T,
+) -> Result<Webhook, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::register_webhook");
+    if url.starts_with("https://") {
+        return Err("https webhooks need a TLS-enabled build".into());
+    }
+    if !url.starts_with("http://") {
+        return Err("webhook url must start with http://".into());
+    }
+    let id: [u8; 8] = rand::random();
+    let secret: [u8; 24] = rand::random();
+    let webhook = repo.set(Webhook {
+        id: crypto::to_hex(&id),
+        url: url.to_owned(),
+        secret: base64::encode_config(secret, base64::URL_SAFE_NO_PAD),
+    })?;
+    info!("webhook registered {:?}", webhook.id);
+    Ok(webhook)
+}
+
+pub fn list_webhooks<T: Repository<Webhook>>(repo: &T) -> Result<Vec<Webhook>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::list_webhooks");
+    repo.list("")
+}
+
+/// Removes the webhook; its delivery history is kept.
+pub fn delete_webhook<T: Repository<Webhook>>(id: &str, repo: &T) -> Result<(), Box<dyn Error>> {
+    let _span = telemetry::span("usecases::delete_webhook");
+    repo.delete(id)?;
+    info!("webhook deleted {:?}", id);
+    Ok(())
+}
+
+/// Deliveries to a webhook, oldest first.
+pub fn webhook_deliveries<T: Repository<WebhookDelivery>>(
+    webhook_id: &str,
+    repo: &T,
+) -> Result<Vec<WebhookDelivery>, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::webhook_deliveries");
+    let mut deliveries = repo.list(webhook_id)?;
+    deliveries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(deliveries)
+}
+
+/// Unique id that sorts in creation order.
+fn sortable_id() -> Result<String, Box<dyn Error>> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let suffix: u16 = rand::random();
+    Ok(format!("{:020}-{:04x}", now.as_nanos(), suffix))
+}
+
+fn audit<T: Repository<AuditEntry>>(
+    actor: &str,
+    action: &str,
+    before: Option<&Contact>,
+    after: Option<&Contact>,
+    contact_key: &str,
+    repo: &T,
+) -> Result<(), Box<dyn Error>> {
+    repo.set(AuditEntry {
+        id: sortable_id()?,
+        contact_key: contact_key.to_owned(),
+        action: action.to_owned(),
+        actor: actor.to_owned(),
+        at: auth::now() as i64,
+        changes: diff(before, after)?,
+    })?;
+    Ok(())
+}
+
+/// Field level changes between two versions of a contact. Identity fields
+/// are part of the key, not of the change.
+fn diff(
+    before: Option<&Contact>,
+    after: Option<&Contact>,
+) -> Result<Vec<FieldChange>, Box<dyn Error>> {
+    let fields = |c: Option<&Contact>| -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
+        match c.map(serde_json::to_value).transpose()? {
+            Some(serde_json::Value::Object(map)) => Ok(map),
+            _ => Ok(Default::default()),
+        }
+    };
+    let (before, after) = (fields(before)?, fields(after)?);
+    let text = |v: Option<&serde_json::Value>| {
+        v.map(|v| match v {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+    };
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    Ok(names
+        .into_iter()
+        .filter(|name| *name != "id" && *name != "owner_id")
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| FieldChange {
+            field: name.clone(),
+            before: text(before.get(name)),
+            after: text(after.get(name)),
+        })
+        .collect())
+}
+
+/// Everything stored about a contact, for data subject access requests.
+#[derive(Debug, Serialize)]
+pub struct ContactExport {
+    pub contact: Contact,
+    pub groups: Vec<Group>,
+    pub audit: Vec<AuditEntry>,
+}
+
+pub fn export_contact<T: Repository<Contact> + Repository<Group> + Repository<AuditEntry>>(
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<ContactExport, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::export_contact");
+    let contact: Contact = repo.get(&Contact::key_for(owner_id, id))?;
+    let groups = Repository::<Group>::list(repo, "")?
+        .into_iter()
+        .filter(|g| g.member_ids.contains(&contact.key()))
+        .collect();
+    let audit = audit_log(owner_id, id, repo)?;
+    Ok(ContactExport {
+        contact,
+        groups,
+        audit,
+    })
+}
+
+/// Hard-deletes a contact and its event history and drops it from every group
+/// it belongs to. Its audit trail holds the same personal data, so it is
+/// replaced by a single entry recording the erasure; the one exception to the
+/// trail being append-only. Events about it still waiting in the outbox are
+/// dropped, webhook deliveries of them keep only its id, and stored
+/// idempotent responses showing its data are deleted. All of it happens in
+/// one transaction; the avatar, as blobs aren't transactional, once it's
+/// committed.
+pub fn erase_contact<T>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>>
+where
+    T: Transactional + Blobs,
+    T::Transaction: Repository<Contact>
+        + Repository<Group>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>
+        + Repository<StoredResponse>
+        + ContactHistory,
+{
+    let _span = telemetry::span("usecases::erase_contact");
+    let contact = repo.with_tx(|tx| erase_records(actor, owner_id, id, tx))?;
+    repo.delete_blobs(&avatar_key(owner_id, id, None))?;
+    info!("contact erased {:?}", contact.id);
+    Ok(contact)
+}
+
+fn erase_records<
+    T: Repository<Contact>
+        + Repository<Group>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>
+        + Repository<StoredResponse>
+        + ContactHistory,
+>(
+    actor: &str,
+    owner_id: &str,
+    id: &str,
+    repo: &T,
+) -> Result<Contact, Box<dyn Error>> {
+    let key = Contact::key_for(owner_id, id);
+    let contact: Contact = repo.get(&key)?;
+    Repository::<Contact>::delete(repo, &key)?;
+    stats::unindex_contact(owner_id, id, repo)?;
+    for mut group in Repository::<Group>::list(repo, "")? {
+        if group.member_ids.contains(&key) {
+            group.member_ids.retain(|m| m != &key);
+            repo.set(group)?;
+        }
+    }
+    repo.forget(&key)?;
+    for entry in audit_log(owner_id, id, repo)? {
+        Repository::<AuditEntry>::delete(repo, &entry.key())?;
+    }
+    let erased = serde_json::json!({ "id": contact.id, "owner_id": contact.owner_id });
+    for message in Repository::<OutboxMessage>::list(repo, "")? {
+        if is_about(&message.payload, &contact) {
+            Repository::<OutboxMessage>::delete(repo, &message.id)?;
+        }
+    }
+    for webhook in Repository::<Webhook>::list(repo, "")? {
+        for mut delivery in Repository::<WebhookDelivery>::list(repo, &webhook.id)? {
+            if is_about(&delivery.payload, &contact) {
+                delivery.payload = erased.clone();
+                repo.set(delivery)?;
+            }
+        }
+    }
+    for response in Repository::<StoredResponse>::list(repo, "")? {
+        if shows(&response.body, &contact) {
+            Repository::<StoredResponse>::delete(repo, &response.id)?;
+        }
+    }
+    audit(actor, "erase", None, None, &key, repo)?;
+    repo.set(OutboxMessage {
+        id: sortable_id()?,
+        subject: "contacts.erased".to_owned(),
+        payload: erased.clone(),
+    })?;
+    enqueue_webhooks("contacts.erased", &erased, repo)?;
+    Ok(contact)
+}
+
+/// Whether an event's payload is the contact, as contact events carry it.
+fn is_about(payload: &serde_json::Value, contact: &Contact) -> bool {
+    payload["id"] == contact.id.as_str() && payload["owner_id"] == contact.owner_id.as_str()
+}
+
+/// Whether a response body shows any of the contact's emails or phones, or
+/// its full name.
+fn shows(body: &str, contact: &Contact) -> bool {
+    let quoted = |value: &str| serde_json::to_string(value).unwrap_or_default();
+    let has = |value: &String| !value.is_empty() && body.contains(&quoted(value));
+    contact.emails.iter().chain(&contact.phones).any(has)
+        || (has(&contact.first_name) && has(&contact.last_name))
+}
+
+/// A contact read from the numbered row or card of an import, or why it
+/// isn't one.
+pub type ImportRow = (usize, Result<Contact, String>);
+
+/// How an import resolves conflicts, and whether it writes at all.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportOptions {
+    pub strategy: ConflictStrategy,
+    /// Reports what would be imported, with a preview of each row, without
+    /// writing anything.
+    pub dry_run: bool,
+}
+
+/// Contacts an import writes between progress reports.
+const IMPORT_BATCH: usize = 100;
+
+/// Creates the contacts of an import, numbered by the row they were read
+/// from. Rows without an owner go to `default_owner`. A row with the id or
+/// an email of one of the owner's contacts, or of an earlier row, is
+/// resolved by the options' strategy. A row that fails is recorded in the
+/// report and the import carries on with the next.
+pub fn import_contacts<
+    T: Repository<Contact>
+        + Repository<AuditEntry>
+        + Repository<OutboxMessage>
+        + Repository<Webhook>
+        + Repository<WebhookDelivery>
+        + Repository<ContactIndex>,
+>(
+    actor: &str,
+    default_owner: Option<&str>,
+    rows: Vec<ImportRow>,
+    options: ImportOptions,
+    repo: &T,
+) -> ImportReport {
+    let _span = telemetry::span("usecases::import_contacts");
+    let mut report = ImportReport::default();
+    // The owners' contacts as the rows so far leave them.
+    let mut existing: HashMap<String, Vec<Contact>> = HashMap::new();
+    for batch in rows.chunks(IMPORT_BATCH) {
+        for (row, contact) in batch {
+            let result: Result<_, Box<dyn Error>> =
+                contact.clone().map_err(|e| e.into()).and_then(|c| {
+                    let owner = match (c.owner_id.as_str(), default_owner) {
+                        ("", Some(owner)) => owner.to_owned(),
+                        ("", None) => return Err("no owner".into()),
+                        (owner, _) => owner.to_owned(),
+                    };
+                    check_id(&c.id)?;
+                    let contacts = match existing.entry(owner.clone()) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(list_contacts(&owner, repo)?),
+                    };
+                    let (action, conflict, mut contact) = resolve(c, contacts, options.strategy);
+                    if action != ImportAction::Skip {
+                        if !options.dry_run {
+                            contact = create(actor, &owner, contact, repo)?;
+                        }
+                        contact.owner_id = owner;
+                        match contacts.iter_mut().find(|e| e.id == contact.id) {
+                            Some(e) => *e = contact.clone(),
+                            None => contacts.push(contact.clone()),
+                        }
+                    }
+                    Ok(RowPreview {
+                        row: *row as i32,
+                        id: contact.id,
+                        action,
+                        conflicts_with: conflict,
+                    })
+                });
+            match result {
+                Ok(preview) => {
+                    match preview.action {
+                        ImportAction::Skip => report.skipped += 1,
+                        _ => report.imported += 1,
+                    }
+                    if options.dry_run {
+                        report.preview.push(preview);
+                    }
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    report.errors.push(RowError {
+                        row: *row as i32,
+                        message: e.to_string(),
+                    });
+                }
+            }
+        }
+        info!(
+            "import progress: {} imported, {} skipped, {} failed",
+            report.imported, report.skipped, report.failed
+        );
+    }
+    report
+}
+
+/// What importing `contact` among `existing` comes to by `strategy`: the
+/// action, the id of the contact it conflicts with, and the contact to
+/// write. A contact with the same id conflicts first, then one sharing an
+/// email.
+fn resolve(
+    mut contact: Contact,
+    existing: &[Contact],
+    strategy: ConflictStrategy,
+) -> (ImportAction, Option<String>, Contact) {
+    let shares_email = |e: &Contact| {
+        e.emails
+            .iter()
+            .any(|m| contact.emails.iter().any(|n| m.eq_ignore_ascii_case(n)))
+    };
+    let conflict = match existing.iter().find(|e| e.id == contact.id) {
+        Some(e) => Some(e),
+        None => existing.iter().find(|e| shares_email(e)),
+    };
+    let conflict = match conflict {
+        Some(conflict) => conflict,
+        None => return (ImportAction::Create, None, contact),
+    };
+    let action = match strategy {
+        ConflictStrategy::Skip => ImportAction::Skip,
+        ConflictStrategy::Overwrite => {
+            contact.id = conflict.id.clone();
+            ImportAction::Overwrite
+        }
+        ConflictStrategy::Merge => {
+            contact = merged(conflict, contact);
+            ImportAction::Merge
+        }
+        ConflictStrategy::Duplicate => {
+            let id = contact.id.clone();
+            let mut n = 2;
+            while existing.iter().any(|e| e.id == contact.id) {
+                contact.id = format!("{}-{}", id, n);
+                n += 1;
+            }
+            ImportAction::Duplicate
+        }
+    };
+    (action, Some(conflict.id.clone()), contact)
+}
+
+/// `existing` with the names of `row`, and its emails, phones and
+/// addresses added to those it has.
+fn merged(existing: &Contact, row: Contact) -> Contact {
+    let mut contact = existing.clone();
+    contact.first_name = row.first_name;
+    contact.last_name = row.last_name;
+    for email in row.emails {
+        if !contact
+            .emails
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(&email))
+        {
+            contact.emails.push(email);
+        }
+    }
+    for phone in row.phones {
+        if !contact.phones.contains(&phone) {
+            contact.phones.push(phone);
+        }
+    }
+    for address in row.addresses {
+        if !contact.addresses.contains(&address) {
+            contact.addresses.push(address);
+        }
+    }
+    contact
+}
+
+pub fn create_group<T: Repository<Group> + Repository<OutboxMessage>>(
+    group: Group,
+    repo: &T,
+) -> Result<Group, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::create_group");
+    let r = with_outbox("groups.created", &group, repo, || repo.set(group.clone()))?;
+    info!("group created {}", Pii(&group));
+    Ok(r)
+}
+
+pub fn get_group<T: Repository<Group>>(id: &str, repo: &T) -> Result<Group, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::get_group");
+    repo.get(id)
+}
+
+pub fn add_group_member<
+    T: Repository<Group> + Repository<Contact> + Repository<OutboxMessage> + Repository<ContactIndex>,
+>(
+    group_id: &str,
+    owner_id: &str,
+    contact_id: &str,
+    repo: &T,
+) -> Result<Group, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::add_group_member");
+    let contact: Contact = repo.get(&Contact::key_for(owner_id, contact_id))?;
+    let mut group: Group = repo.get(group_id)?;
+    if group.member_ids.contains(&contact.key()) {
+        return Ok(group);
+    }
+    group.member_ids.push(contact.key());
+    let event = serde_json::json!({ "group_id": group.id, "contact_id": contact.id });
+    let group = with_outbox("groups.member_added", &event, repo, || {
+        repo.set(group.clone())
+    })?;
+    stats::index_membership(owner_id, &contact.id, &group.id, repo)?;
+    Ok(group)
+}
+
+pub fn sign_up<T: Repository<User>>(
+    email: &str,
+    password: &str,
+    repo: &T,
+) -> Result<User, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::sign_up");
+    let email = User::normalize_email(email);
+    if !email.contains('@') {
+        return Err(i18n::text("invalid_email", &[]).into());
+    }
+    let id = User::id_for(&email);
+    if repo.get(&id).is_ok() {
+        return Err(i18n::text("email_registered", &[]).into());
+    }
+    let user = User {
+        id,
+        email,
+        password_hash: auth::hash_password(password),
+        role: Role::Editor,
+    };
+    let r = repo.set(user)?;
+    info!("user signed up {}", Pii(&r));
+    Ok(r)
+}
+
+pub fn login<T: Repository<User>>(
+    email: &str,
+    password: &str,
+    repo: &T,
+) -> Result<User, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::login");
+    let mut user = match repo.get(&User::id_for(email)) {
+        Ok(user) if auth::verify_password(password, &user.password_hash) => user,
+        _ => return Err(i18n::text("invalid_login", &[]).into()),
+    };
+    // The password is at hand only now, so outdated hashes are replaced here.
+    if auth::needs_rehash(&user.password_hash) {
+        user.password_hash = auth::hash_password(password);
+        user = repo.set(user)?;
+    }
+    Ok(user)
+}
+
+/// Starts a refresh token family for `user` and returns its first token.
+pub fn issue_refresh_token<T: Repository<RefreshToken> + Repository<RefreshFamily>>(
+    user: &User,
+    repo: &T,
+) -> Result<String, Box<dyn Error>> {
+    let _span = telemetry::span("usecases::issue_refresh_token");
+    let family_id: [u8; 16] = rand::random();
+    let family = repo.set(RefreshFamily {
+        id: crypto::to_hex(&family_id),
+        user_id: user.id.clone(),
+        revoked: false,
+    })?;
+    next_refresh_token(&family, repo)
+}
+
+/// Exchanges a refresh token for the next one in its family. Presenting a
+/// token that was already used means it leaked, so the whole family is
+/// revoked. The token is marked used in a transaction, so of two requests
+/// presenting it at once only one rotates it; the other fails to commit
+/// and counts as the reuse it is.
+pub fn rotate_refresh_token<T>(token: &str, repo: &T) -> Result<(User, String), Box<dyn Error>>
+where
+    T: Transactional + Repository<RefreshToken> + Repository<RefreshFamily> + Repository<User>,
+    T::Transaction: Repository<RefreshToken> + Repository<RefreshFamily>,
+{
+    let _span = telemetry::span("usecases::rotate_refresh_token");
+    let invalid = || -> Box<dyn Error> { "invalid refresh token".into() };
+    let rotated = repo.with_tx(|tx| {
+        let mut record: RefreshToken = tx.get(&refresh_token_id(token)).map_err(|_| invalid())?;
+        let mut family: RefreshFamily = tx.get(&record.family_id)?;
+        if family.revoked || record.expires_at <= auth::now() {
+            return Err(invalid());
+        }
+        if record.used {
+            family.revoked = true;
+            tx.set(family)?;
+            return Ok(Err(record.family_id));
+        }
+        record.used = true;
+        tx.set(record.clone())?;
+        let next = next_refresh_token(&family, tx)?;
+        Ok(Ok((record.user_id, next)))
+    });
+    match rotated {
+        Ok(Ok((user_id, next))) => Ok((repo.get(&user_id)?, next)),
+        Ok(Err(family_id)) => {
+            warn!("refresh token reused, family revoked {:?}", family_id);
+            Err(invalid())
+        }
+        Err(e) if is_conflict(e.as_ref()) => {
+            warn!("refresh token rotated concurrently, revoking its family");
+            revoke_refresh_token(token, repo)?;
+            Err(invalid())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Revokes the family `token` belongs to, invalidating every token in it.
+pub fn revoke_refresh_token<T: Repository<RefreshToken> + Repository<RefreshFamily>>(
+    token: &str,
+    repo: &T,
+) -> Result<(), Box<dyn Error>> {
+    let _span = telemetry::span("usecases::revoke_refresh_token");
+    let record: RefreshToken = repo
+        .get(&refresh_token_id(token))
+        .map_err(|_| -> Box<dyn Error> { "invalid refresh token".into() })?;
+    let mut family: RefreshFamily = repo.get(&record.family_id)?;
+    family.revoked = true;
+    repo.set(family)?;
+    info!("refresh token family revoked {:?}", record.family_id);
+    Ok(())
+}
+
+fn next_refresh_token<T: Repository<RefreshToken>>(
+    family: &RefreshFamily,
+    repo: &T,
+) -> Result<String, Box<dyn Error>> {
+    let secret: [u8; 32] = rand::random();
+    let token = base64::encode_config(secret, base64::URL_SAFE_NO_PAD);
+    repo.set(RefreshToken {
+        id: refresh_token_id(&token),
+        family_id: family.id.clone(),
+        user_id: family.user_id.clone(),
+        expires_at: auth::now() + auth::REFRESH_TTL_SECS,
+        used: false,
+    })?;
+    Ok(token)
+}
+
+fn refresh_token_id(token: &str) -> String {
+    crypto::to_hex(&crypto::sha256(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ContactFixture, MockRepository, Operation};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn create_stores_audits_and_publishes() {
+        let repo = MockRepository::default();
+        let contact = ContactFixture::new("ada").email("ada@example.com").build();
+        let created = create("u1", "u1", contact, &repo).unwrap();
+        assert_eq!(created.emails, vec!["ada@example.com"]);
+
+        let stored = get("u1", "ada", &repo).unwrap();
+        assert_eq!(stored.first_name, "Ada");
+        let trail = audit_log("u1", "ada", &repo).unwrap();
+        assert_eq!(trail.len(), 1);
+        assert_eq!(trail[0].action, "create");
+        let messages: Vec<OutboxMessage> = repo.list("").unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].subject, "contacts.created");
+    }
+
+    #[test]
+    fn create_of_an_existing_contact_updates_it() {
+        let repo = MockRepository::with_contacts(vec![ContactFixture::new("ada").build()]);
+        let contact = ContactFixture::new("ada").name("Ada", "King").build();
+        create("u1", "u1", contact, &repo).unwrap();
+
+        assert_eq!(get("u1", "ada", &repo).unwrap().last_name, "King");
+        assert_eq!(audit_log("u1", "ada", &repo).unwrap()[0].action, "update");
+    }
+
+    #[test]
+    fn failed_write_withdraws_the_outbox_message() {
+        let repo = MockRepository::default();
+        repo.fail(Operation::Set, Contact::KIND, "disk full");
+        let err = create("u1", "u1", ContactFixture::new("ada").build(), &repo).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+
+        let messages: Vec<OutboxMessage> = repo.list("").unwrap();
+        assert!(messages.is_empty());
+        assert!(audit_log("u1", "ada", &repo).unwrap().is_empty());
+        let outbox = repo.calls(OutboxMessage::KIND);
+        assert_eq!(outbox[0].0, Operation::Set);
+        assert_eq!(outbox[1].0, Operation::Delete);
+    }
+
+    #[test]
+    fn create_succeeds_once_storage_recovers() {
+        let repo = MockRepository::default();
+        repo.fail_once(Operation::Set, Contact::KIND, "disk full");
+        let contact = ContactFixture::new("ada").build();
+        assert!(create("u1", "u1", contact.clone(), &repo).is_err());
+        create("u1", "u1", contact, &repo).unwrap();
+
+        repo.fail(Operation::Get, Contact::KIND, "unreadable");
+        assert!(get("u1", "ada", &repo).is_err());
+        repo.heal();
+        assert!(get("u1", "ada", &repo).is_ok());
+    }
+
+    #[tokio::test]
+    async fn contacts_after_reads_only_as_far_as_the_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_owned();
+        let repo = FileRepository::new(Box::leak(path.into_boxed_str()));
+        for id in &["ada", "alan", "barbara", "grace"] {
+            repo.set(ContactFixture::new(id).build()).unwrap();
+        }
+        // Sorts after barbara, so only pages reaching past her read it.
+        std::fs::write(dir.path().join("contacts/u1/edsger.json"), b"{").unwrap();
+        let ids = |contacts: Vec<Contact>| -> Vec<String> {
+            contacts.into_iter().map(|c| c.id).collect()
+        };
+
+        let (page, more) = contacts_after("u1", None, 2, &repo).await.unwrap();
+        assert_eq!(
+            (ids(page), more),
+            (vec!["ada".to_owned(), "alan".to_owned()], true)
+        );
+        let (page, _) = contacts_after("u1", Some("ada"), 1, &repo).await.unwrap();
+        assert_eq!(ids(page), vec!["alan"]);
+        assert!(contacts_after("u1", Some("alan"), 2, &repo).await.is_err());
+
+        std::fs::remove_file(dir.path().join("contacts/u1/edsger.json")).unwrap();
+        let (page, more) = contacts_after("u1", Some("alan"), 2, &repo).await.unwrap();
+        assert_eq!(
+            (ids(page), more),
+            (vec!["barbara".to_owned(), "grace".to_owned()], false)
+        );
+    }
+
+    #[test]
+    fn get_of_a_missing_contact_is_not_found() {
+        let repo = MockRepository::default();
+        let err = get("u1", "nobody", &repo).unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn delete_of_a_missing_contact_changes_nothing() {
+        let repo = MockRepository::default();
+        assert!(delete("u1", "u1", "nobody", &repo).is_err());
+        assert!(repo.calls(OutboxMessage::KIND).is_empty());
+        assert!(repo.calls(AuditEntry::KIND).is_empty());
+    }
+
+    #[test]
+    fn update_partial_keeps_unpatched_fields() {
+        let repo = MockRepository::with_contacts(vec![ContactFixture::new("ada")
+            .phone("+44 20 7946 0000")
+            .address_in("GB")
+            .build()]);
+        let patch = ContactPatch {
+            last_name: Some("King".to_owned()),
+            ..ContactPatch::default()
+        };
+        let updated = update_partial("u1", "u1", "ada", patch.clone(), &repo).unwrap();
+        assert_eq!(updated.last_name, "King");
+        assert_eq!(updated.phones, vec!["+44 20 7946 0000"]);
+        assert_eq!(updated.addresses[0].country, "GB");
+
+        assert!(update_partial("u1", "u1", "nobody", patch, &repo).is_err());
+    }
+
+    #[test]
+    fn list_contacts_is_sorted_and_per_owner() {
+        let repo = MockRepository::with_contacts(vec![
+            ContactFixture::new("grace").build(),
+            ContactFixture::new("ada").build(),
+            ContactFixture::new("alan").owner("u2").build(),
+        ]);
+        let ids: Vec<String> = list_contacts("u1", &repo)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec!["ada", "grace"]);
+    }
+
+    #[test]
+    fn group_members_are_told_apart_by_owner() {
+        let repo = MockRepository::with_contacts(vec![
+            ContactFixture::new("ada").build(),
+            ContactFixture::new("ada").owner("u2").build(),
+        ]);
+        let group = |id: &str| Group {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            member_ids: vec![],
+        };
+        create_group(group("friends"), &repo).unwrap();
+        create_group(group("family"), &repo).unwrap();
+        add_group_member("friends", "u1", "ada", &repo).unwrap();
+        let family = add_group_member("family", "u2", "ada", &repo).unwrap();
+        assert_eq!(family.member_ids, vec!["u2/ada"]);
+
+        let export = export_contact("u1", "ada", &repo).unwrap();
+        let groups: Vec<&str> = export.groups.iter().map(|g| g.id.as_str()).collect();
+        assert_eq!(groups, vec!["friends"]);
+    }
+
+    #[test]
+    fn erasure_leaves_no_personal_data_in_events_or_stored_responses() {
+        use crate::events::{Storage, StorageMode};
+
+        let repo = Storage::new(MemoryRepository::new(), StorageMode::Events);
+        repo.set(Webhook {
+            id: "hook".to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            secret: "secret".to_owned(),
+        })
+        .unwrap();
+        let ada = ContactFixture::new("ada").email("ada@example.com").build();
+        create("u1", "u1", ada, &repo).unwrap();
+        let response = |id: &str, body: &str| StoredResponse {
+            id: id.to_owned(),
+            fingerprint: String::new(),
+            body: body.to_owned(),
+            expires_at: u64::MAX,
+            in_progress: false,
+        };
+        repo.set(response("created", "{\"emails\":[\"ada@example.com\"]}"))
+            .unwrap();
+        repo.set(response("other", "{\"emails\":[\"grace@example.com\"]}"))
+            .unwrap();
+
+        erase_contact("admin", "u1", "ada", &repo).unwrap();
+        let leaks = |value: serde_json::Value| value.to_string().contains("ada@example.com");
+        let messages: Vec<OutboxMessage> = repo.list("").unwrap();
+        let subjects: Vec<&str> = messages.iter().map(|m| m.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["contacts.erased"]);
+        let deliveries: Vec<WebhookDelivery> = repo.list("hook").unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert!(!deliveries.into_iter().any(|d| leaks(d.payload)));
+        let responses: Vec<StoredResponse> = repo.list("").unwrap();
+        let ids: Vec<&str> = responses.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["other"]);
+        assert!(get("u1", "ada", &repo).is_err());
+    }
+
+    #[test]
+    fn login_replaces_outdated_password_hashes() {
+        let repo = MockRepository::default();
+        let user = sign_up("ada@example.com", "secret", &repo).unwrap();
+        assert!(!auth::needs_rehash(&user.password_hash));
+
+        let salt = b"0123456789abcdef";
+        let weak = format!(
+            "$pbkdf2-sha256$1000${}${}",
+            base64::encode_config(salt, base64::STANDARD_NO_PAD),
+            base64::encode_config(
+                crypto::pbkdf2_sha256(b"secret", salt, 1000),
+                base64::STANDARD_NO_PAD
+            )
+        );
+        assert!(auth::needs_rehash(&weak));
+        repo.set(User {
+            password_hash: weak.clone(),
+            ..user
+        })
+        .unwrap();
+        let user = login("ada@example.com", "secret", &repo).unwrap();
+        assert_ne!(user.password_hash, weak);
+        assert!(!auth::needs_rehash(&user.password_hash));
+    }
+
+    #[test]
+    fn slow_storage_slows_every_call() {
+        let repo = MockRepository::with_contacts(vec![ContactFixture::new("ada").build()]);
+        repo.delay(Duration::from_millis(20));
+        let start = Instant::now();
+        get("u1", "ada", &repo).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn import_resolves_conflicts_by_the_strategy() {
+        let stored = || {
+            MockRepository::with_contacts(vec![ContactFixture::new("ada")
+                .email("ada@example.com")
+                .phone("+44 20 7946 0000")
+                .build()])
+        };
+        // A new id for Ada under the same email, and a new contact.
+        let rows = || {
+            vec![
+                (
+                    1,
+                    Ok(ContactFixture::new("countess")
+                        .name("Ada", "King")
+                        .email("ADA@example.com")
+                        .build()),
+                ),
+                (
+                    2,
+                    Ok(ContactFixture::new("grace").name("Grace", "Hopper").build()),
+                ),
+            ]
+        };
+        let import = |strategy, repo: &MockRepository| {
+            let options = ImportOptions {
+                strategy,
+                dry_run: false,
+            };
+            import_contacts("u1", None, rows(), options, repo)
+        };
+        let ids = |repo: &MockRepository| -> Vec<String> {
+            list_contacts("u1", repo)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.id)
+                .collect()
+        };
+
+        let repo = stored();
+        let report = import(ConflictStrategy::Skip, &repo);
+        assert_eq!((report.imported, report.skipped), (1, 1));
+        assert_eq!(get("u1", "ada", &repo).unwrap().last_name, "Lovelace");
+
+        let repo = stored();
+        import(ConflictStrategy::Overwrite, &repo);
+        assert_eq!(ids(&repo), vec!["ada", "grace"]);
+        let ada = get("u1", "ada", &repo).unwrap();
+        assert_eq!((ada.last_name.as_str(), ada.phones.len()), ("King", 0));
+
+        let repo = stored();
+        import(ConflictStrategy::Merge, &repo);
+        let ada = get("u1", "ada", &repo).unwrap();
+        assert_eq!(ada.last_name, "King");
+        assert_eq!(ada.emails, vec!["ada@example.com"]);
+        assert_eq!(ada.phones, vec!["+44 20 7946 0000"]);
+
+        let repo = stored();
+        import(ConflictStrategy::Duplicate, &repo);
+        assert_eq!(ids(&repo), vec!["ada", "countess", "grace"]);
+        let report = import(ConflictStrategy::Duplicate, &repo);
+        assert_eq!(report.imported, 2);
+        assert_eq!(
+            ids(&repo),
+            vec!["ada", "countess", "countess-2", "grace", "grace-2"]
+        );
+    }
+
+    #[test]
+    fn a_dry_run_previews_the_import_without_writing() {
+        let repo = MockRepository::with_contacts(vec![ContactFixture::new("ada").build()]);
+        let rows = vec![
+            (
+                1,
+                Ok(ContactFixture::new("ada").name("Ada", "King").build()),
+            ),
+            (
+                2,
+                Ok(ContactFixture::new("grace")
+                    .email("grace@example.com")
+                    .build()),
+            ),
+            (
+                3,
+                Ok(ContactFixture::new("hopper")
+                    .email("grace@example.com")
+                    .build()),
+            ),
+            (4, Err("no last name".to_owned())),
+        ];
+        let options = ImportOptions {
+            strategy: ConflictStrategy::Merge,
+            dry_run: true,
+        };
+        let report = import_contacts("u1", None, rows, options, &repo);
+
+        assert_eq!((report.imported, report.failed), (3, 1));
+        let preview: Vec<_> = report
+            .preview
+            .iter()
+            .map(|p| (p.row, p.id.as_str(), p.action, p.conflicts_with.as_deref()))
+            .collect();
+        assert_eq!(
+            preview,
+            vec![
+                (1, "ada", ImportAction::Merge, Some("ada")),
+                (2, "grace", ImportAction::Create, None),
+                (3, "grace", ImportAction::Merge, Some("grace")),
+            ]
+        );
+        assert_eq!(get("u1", "ada", &repo).unwrap().last_name, "Lovelace");
+        assert!(get("u1", "grace", &repo).is_err());
+        assert!(repo.calls(OutboxMessage::KIND).is_empty());
+    }
+
+    /// A user signed up in `repo`, with the first token of a login.
+    fn signed_in(repo: &MemoryRepository) -> (User, String) {
+        let user = repo
+            .set(User {
+                id: User::id_for("ada@example.com"),
+                email: "ada@example.com".to_owned(),
+                password_hash: String::new(),
+                role: Role::Editor,
+            })
+            .unwrap();
+        let token = issue_refresh_token(&user, repo).unwrap();
+        (user, token)
+    }
+
+    fn family_of(token: &str, repo: &MemoryRepository) -> RefreshFamily {
+        let record: RefreshToken = repo.get(&refresh_token_id(token)).unwrap();
+        repo.get(&record.family_id).unwrap()
+    }
+
+    #[test]
+    fn refresh_tokens_rotate_into_the_next() {
+        let repo = MemoryRepository::new();
+        let (ada, first) = signed_in(&repo);
+        let (user, second) = rotate_refresh_token(&first, &repo).unwrap();
+        assert_eq!(user.id, ada.id);
+        assert_ne!(second, first);
+        let (_, third) = rotate_refresh_token(&second, &repo).unwrap();
+        assert_eq!(family_of(&third, &repo).id, family_of(&first, &repo).id);
+        let used: RefreshToken = repo.get(&refresh_token_id(&first)).unwrap();
+        assert!(used.used);
+        assert!(rotate_refresh_token("no such token", &repo).is_err());
+    }
+
+    #[test]
+    fn a_reused_refresh_token_revokes_its_family() {
+        let repo = MemoryRepository::new();
+        let (ada, first) = signed_in(&repo);
+        let other_login = issue_refresh_token(&ada, &repo).unwrap();
+        let (_, second) = rotate_refresh_token(&first, &repo).unwrap();
+
+        let e = rotate_refresh_token(&first, &repo).unwrap_err();
+        assert_eq!(e.to_string(), "invalid refresh token");
+        assert!(family_of(&first, &repo).revoked);
+        // The token rotated from it is dead too, other logins aren't.
+        assert!(rotate_refresh_token(&second, &repo).is_err());
+        assert!(rotate_refresh_token(&other_login, &repo).is_ok());
+    }
+
+    #[test]
+    fn expired_refresh_tokens_are_refused() {
+        let repo = MemoryRepository::new();
+        let (_, token) = signed_in(&repo);
+        let mut record: RefreshToken = repo.get(&refresh_token_id(&token)).unwrap();
+        record.expires_at = auth::now() - 1;
+        repo.set(record).unwrap();
+
+        assert!(rotate_refresh_token(&token, &repo).is_err());
+        // Expiring isn't reuse, nor is it a use.
+        assert!(!family_of(&token, &repo).revoked);
+        let record: RefreshToken = repo.get(&refresh_token_id(&token)).unwrap();
+        assert!(!record.used);
+    }
+
+    #[test]
+    fn revoking_a_refresh_token_ends_its_family() {
+        let repo = MemoryRepository::new();
+        let (_, first) = signed_in(&repo);
+        let (_, second) = rotate_refresh_token(&first, &repo).unwrap();
+        revoke_refresh_token(&first, &repo).unwrap();
+        assert!(family_of(&second, &repo).revoked);
+        assert!(rotate_refresh_token(&second, &repo).is_err());
+        assert!(revoke_refresh_token("no such token", &repo).is_err());
+    }
+
+    #[test]
+    fn only_one_concurrent_rotation_of_a_token_succeeds() {
+        let repo = MemoryRepository::new();
+        let (_, token) = signed_in(&repo);
+        let rotations: Vec<_> = (0..8)
+            .map(|_| {
+                let (repo, token) = (repo.clone(), token.clone());
+                std::thread::spawn(move || {
+                    rotate_refresh_token(&token, &repo)
+                        .map(|(_, next)| next)
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        let rotated: Vec<String> = rotations
+            .into_iter()
+            .filter_map(|rotation| rotation.join().unwrap().ok())
+            .collect();
+        assert_eq!(rotated.len(), 1);
+        // The others presented a used token, so the family is revoked.
+        assert!(family_of(&token, &repo).revoked);
+        assert!(rotate_refresh_token(&rotated[0], &repo).is_err());
+    }
+}
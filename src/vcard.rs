@@ -0,0 +1,324 @@
+//! Contacts as vCards. Cards are written in vCard 4.0 and read in 3.0 or 4.0
+//! with their name, emails, phones and postal addresses; other properties
+//! are ignored. A card's `UID` becomes the contact id, so importing the same
+//! file twice updates the contacts instead of duplicating them; cards
+//! without a usable `UID` get an id derived from their name.
+
+use crate::crypto;
+use crate::models::{Address, Contact};
+use crate::usecases::ImportRow;
+
+/// Lines longer than this many bytes are folded.
+const MAX_LINE: usize = 75;
+const MAX_ID_LEN: usize = 64;
+
+/// One contact as a vCard.
+pub fn write(contact: &Contact) -> String {
+    let mut card = String::new();
+    line(&mut card, "BEGIN:VCARD");
+    line(&mut card, "VERSION:4.0");
+    line(&mut card, &format!("UID:{}", escape(&contact.id)));
+    line(
+        &mut card,
+        &format!(
+            "FN:{}",
+            escape(format!("{} {}", contact.first_name, contact.last_name).trim())
+        ),
+    );
+    line(
+        &mut card,
+        &format!(
+            "N:{};{};;;",
+            escape(&contact.last_name),
+            escape(&contact.first_name)
+        ),
+    );
+    for email in &contact.emails {
+        line(&mut card, &format!("EMAIL:{}", escape(email)));
+    }
+    for phone in &contact.phones {
+        line(&mut card, &format!("TEL:{}", escape(phone)));
+    }
+    for a in &contact.addresses {
+        line(
+            &mut card,
+            &format!(
+                "ADR:;;{};{};{};{};{}",
+                escape(&a.street),
+                escape(&a.locality),
+                escape(&a.region),
+                escape(&a.postal_code),
+                escape(&a.country)
+            ),
+        );
+    }
+    line(&mut card, "END:VCARD");
+    card
+}
+
+/// Appends a content line, folded at `MAX_LINE` bytes.
+fn line(card: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > MAX_LINE {
+            card.push_str("\r\n ");
+            width = 1;
+        }
+        card.push(c);
+        width += c.len_utf8();
+    }
+    card.push_str("\r\n");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) | ('\\', Some('N')) => {
+                chars.next();
+                out.push('\n');
+            }
+            ('\\', Some(escaped)) => {
+                chars.next();
+                out.push(escaped);
+            }
+            (c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+/// Splits a structured value such as `N` or `ADR` at unescaped `;`.
+fn components(value: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                part.push(c);
+                part.extend(chars.next());
+            }
+            ';' => parts.push(unescape(&std::mem::take(&mut part))),
+            c => part.push(c),
+        }
+    }
+    parts.push(unescape(&part));
+    parts
+}
+
+/// Reads the cards of a .vcf file, each numbered by its position in the file.
+pub fn read(input: &str) -> Vec<ImportRow> {
+    let mut rows = vec![];
+    let mut card: Option<Contact> = None;
+    let mut uid = None;
+    let mut formatted = None;
+    let mut n = 0;
+    for line in unfold(input) {
+        let (name, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => continue,
+        };
+        // Parameters such as `;TYPE=work` are ignored; properties may be
+        // grouped, as in `item1.EMAIL`.
+        let property = name.split(';').next().unwrap_or_default();
+        let property = property.rsplit('.').next().unwrap_or_default();
+        match property.to_ascii_uppercase().as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => {
+                n += 1;
+                card = Some(Contact::default());
+                uid = None;
+                formatted = None;
+            }
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some(contact) = card.take() {
+                    rows.push((n, finish(contact, uid.take(), formatted.take())));
+                }
+            }
+            property => {
+                let contact = match card.as_mut() {
+                    Some(contact) => contact,
+                    None => continue,
+                };
+                match property {
+                    "UID" => uid = Some(unescape(value)),
+                    "FN" => formatted = Some(unescape(value)),
+                    "N" => {
+                        let parts = components(value);
+                        contact.last_name = parts.first().cloned().unwrap_or_default();
+                        contact.first_name = parts.get(1).cloned().unwrap_or_default();
+                    }
+                    "EMAIL" => contact.emails.push(unescape(value)),
+                    // 4.0 phones may be `tel:` URIs.
+                    "TEL" => contact
+                        .phones
+                        .push(unescape(value.trim_start_matches("tel:"))),
+                    "ADR" => {
+                        let parts = components(value);
+                        let part = |i: usize| parts.get(i).cloned().unwrap_or_default();
+                        contact.addresses.push(Address {
+                            street: part(2),
+                            locality: part(3),
+                            region: part(4),
+                            postal_code: part(5),
+                            country: part(6),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    if card.is_some() {
+        rows.push((n, Err("card is missing END:VCARD".to_owned())));
+    }
+    rows
+}
+
+/// Joins folded lines, which continue with a space or tab.
+fn unfold(input: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in input.lines() {
+        match (line.chars().next(), lines.last_mut()) {
+            (Some(' '), Some(last)) | (Some('\t'), Some(last)) => last.push_str(&line[1..]),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    lines
+}
+
+fn finish(
+    mut contact: Contact,
+    uid: Option<String>,
+    formatted: Option<String>,
+) -> Result<Contact, String> {
+    if contact.first_name.is_empty() && contact.last_name.is_empty() {
+        let formatted = formatted.unwrap_or_default();
+        let mut words = formatted.trim().rsplitn(2, ' ');
+        contact.last_name = words.next().unwrap_or_default().to_owned();
+        contact.first_name = words.next().unwrap_or_default().to_owned();
+    }
+    contact.first_name = contact.first_name.trim().to_owned();
+    contact.last_name = contact.last_name.trim().to_owned();
+    if contact.first_name.is_empty() && contact.last_name.is_empty() {
+        return Err("card has no name".to_owned());
+    }
+    contact.id = match uid {
+        Some(uid) => {
+            let uid = uid.trim();
+            let uid = uid.strip_prefix("urn:uuid:").unwrap_or(uid);
+            if !uid.is_empty() && uid.len() <= MAX_ID_LEN && !uid.contains('/') {
+                uid.to_owned()
+            } else {
+                derived_id(uid)
+            }
+        }
+        None => derived_id(&format!("{}\n{}", contact.first_name, contact.last_name)),
+    };
+    Ok(contact)
+}
+
+fn derived_id(seed: &str) -> String {
+    format!(
+        "vcard-{}",
+        crypto::to_hex(&crypto::sha256(seed.as_bytes())[..8])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ContactFixture;
+
+    fn read_one(card: &str) -> Contact {
+        let mut rows = read(card);
+        assert_eq!(rows.len(), 1);
+        rows.remove(0).1.unwrap()
+    }
+
+    #[test]
+    fn written_cards_read_back_the_same() {
+        let mut contact = ContactFixture::new("ada")
+            .name("Ada; \\Augusta\\", "King, Countess\nof Lovelace")
+            .email("ada@example.com")
+            .email("countess@example.org")
+            .phone("+44 20 7946 0000")
+            .phone("+44 20 7946 0001")
+            .address_in("GB")
+            .address_in("IT")
+            .build();
+        contact.addresses[0].street = "12 St James's Square; London, SW1".to_owned();
+        contact.owner_id = String::new();
+        let card = write(&contact);
+        assert!(card.contains(r"N:King\, Countess\nof Lovelace;Ada\; \\Augusta\\;;;"));
+
+        let read = read_one(&card);
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&contact).unwrap()
+        );
+    }
+
+    #[test]
+    fn long_lines_are_folded_and_unfolded() {
+        let street = "Ünter den Linden ".repeat(8);
+        let mut contact = ContactFixture::new("ada").address_in("DE").build();
+        contact.addresses[0].street = street.clone();
+        let card = write(&contact);
+        assert!(card.split("\r\n").all(|l| l.len() <= MAX_LINE));
+        assert!(card.contains("\r\n "));
+        assert_eq!(read_one(&card).addresses[0].street, street);
+    }
+
+    #[test]
+    fn reads_folded_grouped_3_0_cards() {
+        let card = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Grace Hopper\r\n\
+            item1.EMAIL;TYPE=INTERNET,WORK:grace@\r\n\texample.com\r\n\
+            EMAIL;TYPE=HOME:amazing@example.com\r\n\
+            TEL;VALUE=uri:tel:+1-202-555-0100\r\n\
+            TEL;TYPE=CELL:+1 202 555 0101\r\n\
+            ADR;TYPE=WORK:;;1 Navy Way;Arlington;VA;22201;US\r\n\
+            ADR;TYPE=HOME:;;;New York;NY;;US\r\nEND:VCARD\r\n";
+        let grace = read_one(card);
+        assert_eq!(
+            (grace.first_name.as_str(), grace.last_name.as_str()),
+            ("Grace", "Hopper")
+        );
+        assert_eq!(
+            grace.emails,
+            vec!["grace@example.com", "amazing@example.com"]
+        );
+        assert_eq!(grace.phones, vec!["+1-202-555-0100", "+1 202 555 0101"]);
+        let localities: Vec<&str> = grace
+            .addresses
+            .iter()
+            .map(|a| a.locality.as_str())
+            .collect();
+        assert_eq!(localities, vec!["Arlington", "New York"]);
+        assert_eq!(grace.addresses[0].postal_code, "22201");
+        assert!(grace.id.starts_with("vcard-"));
+    }
+
+    #[test]
+    fn reports_cards_without_a_name_or_an_end() {
+        let rows = read("BEGIN:VCARD\nUID:a\nEND:VCARD\nBEGIN:VCARD\nFN:Alan Turing\n");
+        let errors: Vec<(usize, String)> =
+            rows.into_iter().map(|(n, r)| (n, r.unwrap_err())).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, "card has no name".to_owned()),
+                (2, "card is missing END:VCARD".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Delivery of queued webhook events. Each delivery is POSTed as
+//! `{"id", "event", "data"}` with an `X-Webhook-Signature: sha256=<hex>`
+//! header, the HMAC-SHA256 of the body under the webhook's secret. Failed
+//! attempts are retried with exponential backoff up to `MAX_ATTEMPTS`.
+
+use crate::auth::now;
+use crate::crypto;
+use crate::jobs::{self, Job};
+use crate::models::{Webhook, WebhookDelivery};
+use crate::repo::*;
+use std::error::Error;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: i32 = 8;
+const MAX_BACKOFF_SECS: u64 = 3600;
+
+/// The job sending due deliveries, first attempts and retries alike. It
+/// runs on the job runner's actix system, so slow endpoints never hold up
+/// request handling; pending deliveries left at shutdown are picked up after
+/// the restart.
+pub fn deliveries(repo: FileRepository<'static>) -> Job {
+    Job::new(
+        "webhook-deliveries",
+        POLL_INTERVAL,
+        Box::new(move || {
+            let repo = repo.clone();
+            Box::pin(async move {
+                let client = awc::Client::build()
+                    .timeout(Duration::from_secs(10))
+                    .finish();
+                let mut sent = 0;
+                for repo in jobs::partitions(&repo) {
+                    sent += dispatch(&client, &repo)
+                        .await
+                        .map_err(|e| format!("webhook dispatch failed: {}", e))?;
+                }
+                Ok(format!("attempted {} deliveries", sent))
+            })
+        }),
+    )
+}
+
+async fn dispatch(
+    client: &awc::Client,
+    repo: &FileRepository<'static>,
+) -> Result<usize, Box<dyn Error>> {
+    let webhooks: Vec<Webhook> = repo.list("")?;
+    let mut attempted = 0;
+    for webhook in webhooks {
+        let deliveries: Vec<WebhookDelivery> = repo.list(&webhook.id)?;
+        for delivery in deliveries {
+            if delivery.status == "pending" && delivery.next_attempt_at as u64 <= now() {
+                let delivery = attempt(client, &webhook, delivery).await?;
+                repo.set(delivery)?;
+                attempted += 1;
+            }
+        }
+    }
+    Ok(attempted)
+}
+
+async fn attempt(
+    client: &awc::Client,
+    webhook: &Webhook,
+    mut delivery: WebhookDelivery,
+) -> Result<WebhookDelivery, Box<dyn Error>> {
+    let body = serde_json::to_string(&serde_json::json!({
+        "id": delivery.id,
+        "event": delivery.subject,
+        "data": delivery.payload,
+    }))?;
+    let signature = crypto::hmac_sha256(webhook.secret.as_bytes(), body.as_bytes());
+    let result = client
+        .post(&webhook.url)
+        .content_type("application/json")
+        .header("X-Webhook-Id", delivery.id.as_str())
+        .header(
+            "X-Webhook-Signature",
+            format!("sha256={}", crypto::to_hex(&signature)),
+        )
+        .send_body(body)
+        .await;
+
+    delivery.attempts += 1;
+    match result {
+        Ok(response) if response.status().is_success() => {
+            delivery.status = "delivered".to_owned();
+            delivery.response_status = Some(response.status().as_u16() as i32);
+            delivery.last_error = None;
+            return Ok(delivery);
+        }
+        Ok(response) => {
+            delivery.response_status = Some(response.status().as_u16() as i32);
+            delivery.last_error = Some(format!("endpoint answered {}", response.status()));
+        }
+        Err(e) => {
+            delivery.response_status = None;
+            delivery.last_error = Some(e.to_string());
+        }
+    }
+    if delivery.attempts >= MAX_ATTEMPTS {
+        delivery.status = "failed".to_owned();
+    } else {
+        let backoff = (1u64 << delivery.attempts).min(MAX_BACKOFF_SECS);
+        delivery.next_attempt_at = (now() + backoff) as i64;
+    }
+    debug!(
+        "webhook delivery {} attempt {} failed",
+        delivery.id, delivery.attempts
+    );
+    Ok(delivery)
+}